@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+
+/// Bearer token shared by the authenticated routes.  Without a configured
+/// token every authenticated request is refused.
+#[derive(Clone)]
+pub struct ApiToken(pub Option<Arc<str>>);
+
+/// Rejects requests that do not carry the configured bearer token.
+pub async fn authenticate(
+    State(ApiToken(token)): State<ApiToken>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let Some(token) = token else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => {
+            Ok(next.run(request).await)
+        }
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
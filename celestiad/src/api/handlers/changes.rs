@@ -0,0 +1,24 @@
+use axum::{extract::{Query, State}, http::StatusCode, Json};
+use serde::Deserialize;
+
+use crate::holobank::{changes::Change, Holobank};
+
+#[derive(Deserialize)]
+pub struct Cursor {
+    /// Sequence number of the last change seen.  Absent starts from the
+    /// oldest logged change.
+    pub after: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+pub async fn changes(
+    State(holobank): State<Holobank>,
+    Query(cursor): Query<Cursor>,
+) -> Result<Json<Vec<Change>>, (StatusCode, String)> {
+    let (after, limit) = (cursor.after.unwrap_or(0), cursor.limit.unwrap_or(1000));
+    tokio::task::spawn_blocking(move || holobank.changes_after(after, limit))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
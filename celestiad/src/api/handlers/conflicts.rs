@@ -0,0 +1,46 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    Json,
+};
+use ulid::Ulid;
+
+use crate::holobank::{conflict::{Conflict, ConflictError, Resolution}, Holobank};
+
+pub async fn conflicts(State(holobank): State<Holobank>) -> Result<Json<Vec<Conflict>>, (StatusCode, String)> {
+    tokio::task::spawn_blocking(move || holobank.conflicts())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Settles the conflicts of an asset with `{"keep": "<version id>"}` or
+/// `{"merge": "<base64 content>"}`, or merges with content sent as is with
+/// the type `application/octet-stream`.
+pub async fn resolve(
+    State(holobank): State<Holobank>,
+    Path(id): Path<Ulid>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let raw = headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/octet-stream"));
+    let resolution = if raw {
+        Resolution::Merge(body.to_vec())
+    } else {
+        serde_json::from_slice(&body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+    };
+
+    tokio::task::spawn_blocking(move || holobank.resolve_conflict(id, resolution))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| match e {
+            ConflictError::NoConflict(_) => (StatusCode::NOT_FOUND, e.to_string()),
+            ConflictError::UnknownVersion(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+            ConflictError::Database(_) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,87 @@
+use axum::{extract::{Path, Query, State}, http::StatusCode, Json};
+use constellations::connection::{Connection, ConnectionType};
+use serde::Deserialize;
+use ulid::Ulid;
+
+use super::blocking;
+use crate::holobank::{graph::{Direction, Reached}, Holobank};
+
+type GraphResult<T> = Result<Json<T>, (StatusCode, String)>;
+
+/// Connection types as a comma separated list.  Absent means every type.
+#[derive(Deserialize, Default)]
+pub struct TypeFilter {
+    pub types: Option<String>,
+}
+
+impl TypeFilter {
+    fn parse(&self) -> Vec<ConnectionType> {
+        self.types
+            .as_deref()
+            .map(|types| types.split(',').filter(|t| !t.is_empty()).map(ConnectionType::from).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NeighborsQuery {
+    #[serde(default)]
+    pub direction: Direction,
+    pub types: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PathQuery {
+    pub from: Ulid,
+    pub to: Ulid,
+    pub types: Option<String>,
+}
+
+pub async fn connect(State(holobank): State<Holobank>, Json(connection): Json<Connection>) -> Result<StatusCode, (StatusCode, String)> {
+    blocking(move || holobank.connect(&connection)).await?;
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn disconnect(State(holobank): State<Holobank>, Json(connection): Json<Connection>) -> Result<StatusCode, (StatusCode, String)> {
+    blocking(move || holobank.disconnect(&connection)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn neighbors(
+    State(holobank): State<Holobank>,
+    Path(id): Path<Ulid>,
+    Query(query): Query<NeighborsQuery>,
+) -> GraphResult<Vec<Connection>> {
+    let types = TypeFilter { types: query.types }.parse();
+    blocking(move || holobank.neighbors(id, query.direction, &types)).await.map(Json)
+}
+
+pub async fn ancestors(
+    State(holobank): State<Holobank>,
+    Path(id): Path<Ulid>,
+    Query(filter): Query<TypeFilter>,
+) -> GraphResult<Vec<Reached>> {
+    let types = filter.parse();
+    blocking(move || holobank.ancestors(id, &types)).await.map(Json)
+}
+
+pub async fn descendants(
+    State(holobank): State<Holobank>,
+    Path(id): Path<Ulid>,
+    Query(filter): Query<TypeFilter>,
+) -> GraphResult<Vec<Reached>> {
+    let types = filter.parse();
+    blocking(move || holobank.descendants(id, &types)).await.map(Json)
+}
+
+pub async fn lineage(State(holobank): State<Holobank>, Path(id): Path<Ulid>) -> GraphResult<Vec<Connection>> {
+    blocking(move || holobank.lineage(id)).await.map(Json)
+}
+
+pub async fn shortest_path(State(holobank): State<Holobank>, Query(query): Query<PathQuery>) -> GraphResult<Vec<Ulid>> {
+    let types = TypeFilter { types: query.types }.parse();
+    match blocking(move || holobank.shortest_path(query.from, query.to, &types)).await? {
+        Some(path) => Ok(Json(path)),
+        None => Err((StatusCode::NOT_FOUND, "no path".to_string())),
+    }
+}
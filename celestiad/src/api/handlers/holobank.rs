@@ -0,0 +1,74 @@
+use std::{fs, sync::Arc};
+
+use axum::{extract::{Path, State}, http::StatusCode, Json};
+use serde::Deserialize;
+use ulid::Ulid;
+
+use super::blocking;
+use crate::holobank::{archive::{self, Manifest}, quota::Usage, Holobank};
+
+#[derive(Deserialize)]
+pub struct BackupRequest {
+    /// File name of the archive in the daemon's backup directory.
+    pub name: String,
+}
+
+pub async fn backup(
+    State((holobank, backups)): State<(Holobank, Arc<std::path::Path>)>,
+    Json(request): Json<BackupRequest>,
+) -> Result<Json<Manifest>, (StatusCode, String)> {
+    let Some(out) = archive::archive_path(&backups, &request.name) else {
+        return Err((StatusCode::BAD_REQUEST, "name must be a plain file name".to_string()));
+    };
+
+    tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(&backups)?;
+        holobank.backup(&out)
+    })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[derive(Deserialize)]
+pub struct QuotaRequest {
+    /// Absent clears the quota.
+    pub bytes: Option<u64>,
+}
+
+pub async fn usage(State(holobank): State<Holobank>) -> Result<Json<Usage>, (StatusCode, String)> {
+    blocking(move || holobank.usage()).await.map(Json)
+}
+
+pub async fn evict(State(holobank): State<Holobank>) -> Result<Json<Vec<Ulid>>, (StatusCode, String)> {
+    blocking(move || holobank.evict()).await.map(Json)
+}
+
+pub async fn set_quota(
+    State(holobank): State<Holobank>,
+    Path(id): Path<Ulid>,
+    Json(request): Json<QuotaRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    blocking(move || {
+        holobank.set_collection_quota(id, request.bytes)?;
+        holobank.evict()
+    }).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn pin(
+    State(holobank): State<Holobank>,
+    Path(id): Path<Ulid>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    blocking(move || holobank.pin(id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn unpin(
+    State(holobank): State<Holobank>,
+    Path(id): Path<Ulid>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    blocking(move || holobank.unpin(id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,40 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::holobank::{inbox::InboxItem, Holobank};
+
+#[derive(Deserialize)]
+pub struct FileRequest {
+    pub assets: Vec<Ulid>,
+    pub collection: Ulid,
+}
+
+#[derive(Serialize)]
+pub struct FileResponse {
+    /// Assets added to the collection.
+    pub filed: usize,
+}
+
+pub async fn items(State(holobank): State<Holobank>) -> Result<Json<Vec<InboxItem>>, (StatusCode, String)> {
+    tokio::task::spawn_blocking(move || holobank.inbox_items())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Files assets from the inbox into a collection.
+pub async fn file(
+    State(holobank): State<Holobank>,
+    Json(request): Json<FileRequest>,
+) -> Result<Json<FileResponse>, (StatusCode, String)> {
+    if request.collection == holobank.inbox() {
+        return Err((StatusCode::BAD_REQUEST, "assets cannot be filed into the inbox".to_string()));
+    }
+    tokio::task::spawn_blocking(move || holobank.file(&request.assets, request.collection))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(|filed| Json(FileResponse { filed }))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
@@ -1,4 +1,28 @@
 pub mod hello;
 pub mod dock;
 pub mod open;
-pub mod close;
\ No newline at end of file
+pub mod close;
+pub mod query;
+pub mod graph;
+pub mod search;
+pub mod similar;
+pub mod temporal;
+pub mod holobank;
+pub mod inbox;
+pub mod changes;
+pub mod conflicts;
+
+
+use axum::http::StatusCode;
+
+/// Runs a holobank call off the async runtime.
+pub(crate) async fn blocking<T, F>(f: F) -> Result<T, (StatusCode, String)>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, cozo::Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
@@ -0,0 +1,37 @@
+use std::collections::BTreeMap;
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::holobank::{query::{QueryError, QueryLimits, QueryResult}, Holobank};
+
+#[derive(Deserialize)]
+pub struct QueryRequest {
+    pub script: String,
+    /// Parameters of the script.  Ids are written `{"ulid": "<id>"}`.
+    #[serde(default)]
+    pub params: BTreeMap<String, Value>,
+    pub timeout_secs: Option<u64>,
+    pub max_rows: Option<usize>,
+}
+
+pub async fn query(
+    State((holobank, limits)): State<(Holobank, QueryLimits)>,
+    Json(request): Json<QueryRequest>,
+) -> Result<Json<QueryResult>, (StatusCode, String)> {
+    let limits = limits.narrow(request.timeout_secs, request.max_rows);
+
+    let result = tokio::task::spawn_blocking(move || {
+        holobank.query(&request.script, request.params, limits)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    match result {
+        Ok(rows) => Ok(Json(rows)),
+        Err(e @ QueryError::Forbidden(_)) => Err((StatusCode::FORBIDDEN, e.to_string())),
+        Err(e @ QueryError::Invalid(_)) => Err((StatusCode::BAD_REQUEST, e.to_string())),
+        Err(e @ QueryError::Database(_)) => Err((StatusCode::UNPROCESSABLE_ENTITY, e.to_string())),
+    }
+}
@@ -0,0 +1,19 @@
+use axum::{extract::State, http::StatusCode, Json};
+
+use crate::holobank::{search::{SearchHit, SearchQuery, MAX_SEARCH_LIMIT}, Holobank};
+
+pub async fn search(
+    State(holobank): State<Holobank>,
+    Json(mut query): Json<SearchQuery>,
+) -> Result<Json<Vec<SearchHit>>, (StatusCode, String)> {
+    if query.text.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "empty search".to_string()));
+    }
+    query.limit = query.limit.map(|limit| limit.min(MAX_SEARCH_LIMIT));
+
+    tokio::task::spawn_blocking(move || holobank.search(&query))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))
+}
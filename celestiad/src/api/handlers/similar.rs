@@ -0,0 +1,19 @@
+use axum::{extract::State, http::StatusCode, Json};
+
+use crate::holobank::{embedding::{Neighbor, SimilarQuery, MAX_SIMILAR_LIMIT}, Holobank};
+
+pub async fn similar(
+    State(holobank): State<Holobank>,
+    Json(mut query): Json<SimilarQuery>,
+) -> Result<Json<Vec<Neighbor>>, (StatusCode, String)> {
+    if query.text.is_none() && query.asset.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "either text or asset is required".to_string()));
+    }
+    query.limit = query.limit.map(|limit| limit.min(MAX_SIMILAR_LIMIT));
+
+    tokio::task::spawn_blocking(move || holobank.similar(&query))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))
+}
@@ -0,0 +1,44 @@
+use axum::{extract::{Path, Query, State}, http::StatusCode, Json};
+use serde::Deserialize;
+use ulid::Ulid;
+
+use super::blocking;
+use crate::holobank::{now, temporal::Holder, Holobank};
+
+/// Point in time in microseconds since the epoch.  Absent means now.
+#[derive(Deserialize)]
+pub struct AsOf {
+    pub at: Option<i64>,
+}
+
+pub async fn owners(
+    State(holobank): State<Holobank>,
+    Path(id): Path<Ulid>,
+    Query(as_of): Query<AsOf>,
+) -> Result<Json<Vec<Ulid>>, (StatusCode, String)> {
+    let at = as_of.at.unwrap_or_else(now);
+    blocking(move || holobank.owners_at(id, at)).await.map(Json)
+}
+
+pub async fn snapshot(
+    State(holobank): State<Holobank>,
+    Path(id): Path<Ulid>,
+    Query(as_of): Query<AsOf>,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let at = as_of.at.unwrap_or_else(now);
+    blocking(move || holobank.snapshot_at(id, at))
+        .await?
+        .ok_or((StatusCode::NOT_FOUND, "no snapshot".to_string()))
+}
+
+pub async fn holder(
+    State(holobank): State<Holobank>,
+    Path(id): Path<Ulid>,
+    Query(as_of): Query<AsOf>,
+) -> Result<Json<Holder>, (StatusCode, String)> {
+    let at = as_of.at.unwrap_or_else(now);
+    blocking(move || holobank.holder_at(id, at))
+        .await?
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "not held".to_string()))
+}
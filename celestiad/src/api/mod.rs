@@ -1,15 +1,21 @@
+use std::path::PathBuf;
+
 use axum::Router;
 use cozo::DbInstance;
 use zenoh::Session;
 use tower_http::trace::TraceLayer;
 
+use crate::holobank::Holobank;
+use crate::settings;
+
+mod auth;
 mod handlers;
 mod v0;
 
-pub fn configure(db: DbInstance, zenoh_session: Session) -> Router {
+pub fn configure(db: DbInstance, zenoh_session: Session, holobank: Holobank, api: &settings::Api, backups: PathBuf) -> Router {
     Router::new()
         .with_state(db.clone())
         .with_state(zenoh_session.clone())
-        .nest("/v0", v0::configure(db, zenoh_session))
+        .nest("/v0", v0::configure(db, zenoh_session, holobank, api, backups))
         .layer(TraceLayer::new_for_http())
-}
\ No newline at end of file
+}
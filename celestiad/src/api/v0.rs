@@ -1,13 +1,52 @@
+use std::{path::PathBuf, sync::Arc};
+
+use super::auth::{self, ApiToken};
 use super::handlers;
-use axum::routing::{get, put};
+use crate::holobank::{query::QueryLimits, Holobank};
+use crate::settings;
+use axum::middleware;
+use axum::routing::{get, post, put};
 use axum::Router;
 use cozo::DbInstance;
 use zenoh::Session;
 
-pub fn configure(db: DbInstance, zenoh_session: Session) -> Router {
+pub fn configure(db: DbInstance, zenoh_session: Session, holobank: Holobank, api: &settings::Api, backups: PathBuf) -> Router {
+    let token = ApiToken(api.token.as_deref().map(Arc::from));
+    let defaults = QueryLimits::default();
+    let limits = QueryLimits {
+        timeout_secs: api.query_timeout_secs.unwrap_or(defaults.timeout_secs),
+        max_rows: api.query_max_rows.unwrap_or(defaults.max_rows),
+    };
+
+    let authenticated = Router::new()
+        .route("/query", post(handlers::query::query).with_state((holobank.clone(), limits)))
+        .route("/connections", post(handlers::graph::connect).delete(handlers::graph::disconnect).with_state(holobank.clone()))
+        .route("/assets/:id/neighbors", get(handlers::graph::neighbors).with_state(holobank.clone()))
+        .route("/assets/:id/ancestors", get(handlers::graph::ancestors).with_state(holobank.clone()))
+        .route("/assets/:id/descendants", get(handlers::graph::descendants).with_state(holobank.clone()))
+        .route("/assets/:id/lineage", get(handlers::graph::lineage).with_state(holobank.clone()))
+        .route("/assets/:id/owners", get(handlers::temporal::owners).with_state(holobank.clone()))
+        .route("/assets/:id/snapshot", get(handlers::temporal::snapshot).with_state(holobank.clone()))
+        .route("/assets/:id/holder", get(handlers::temporal::holder).with_state(holobank.clone()))
+        .route("/path", get(handlers::graph::shortest_path).with_state(holobank.clone()))
+        .route("/search", post(handlers::search::search).with_state(holobank.clone()))
+        .route("/holobank/backup", post(handlers::holobank::backup).with_state((holobank.clone(), Arc::from(backups))))
+        .route("/holobank/usage", get(handlers::holobank::usage).with_state(holobank.clone()))
+        .route("/holobank/evict", post(handlers::holobank::evict).with_state(holobank.clone()))
+        .route("/collections/:id/quota", put(handlers::holobank::set_quota).with_state(holobank.clone()))
+        .route("/collections/:id/pin", put(handlers::holobank::pin).delete(handlers::holobank::unpin).with_state(holobank.clone()))
+        .route("/inbox", get(handlers::inbox::items).with_state(holobank.clone()))
+        .route("/inbox/file", post(handlers::inbox::file).with_state(holobank.clone()))
+        .route("/changes", get(handlers::changes::changes).with_state(holobank.clone()))
+        .route("/conflicts", get(handlers::conflicts::conflicts).with_state(holobank.clone()))
+        .route("/assets/:id/resolve", post(handlers::conflicts::resolve).with_state(holobank.clone()))
+        .route("/similar", post(handlers::similar::similar).with_state(holobank.clone()))
+        .route_layer(middleware::from_fn_with_state(token, auth::authenticate));
+
     Router::new()
         .with_state(db.clone())
         .with_state(zenoh_session)
         .route("/hello", get(handlers::hello::hello))
         .route("/open", get(handlers::open::open).with_state(db.clone()))
-}
\ No newline at end of file
+        .merge(authenticated)
+}
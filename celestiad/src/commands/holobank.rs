@@ -0,0 +1,449 @@
+use std::{fs, io::{self, Read, Write}, net::{SocketAddr, TcpStream}, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, bail};
+use clap::{value_parser, Arg, ArgAction, ArgGroup, ArgMatches, Command};
+use constellations::{asset::{assembly::export::ExportFormat, Export}, collection::export::MANIFEST};
+use ulid::Ulid;
+
+use crate::{holobank::{archive::{self, Manifest}, cipher::KeySource, fsck::FsckMode, identity::SignatureError, Holobank}, settings::Settings, CELESTIAD_PORT, LOCALHOST};
+
+pub const COMMAND_NAME: &str = "holobank";
+
+pub fn configure() -> Command {
+    Command::new(COMMAND_NAME)
+        .about("Maintains the holobank.")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("backup")
+                .about("Writes an archive of the holobank into the backup directory.  Works while the daemon is running.")
+                .arg(
+                    Arg::new("name")
+                        .required(true)
+                        .help("File name of the archive.")
+                )
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Restores an archive into an empty holobank directory.")
+                .arg(
+                    Arg::new("archive")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    Arg::new("directory")
+                        .short('d')
+                        .long("directory")
+                        .help("Directory to restore into.  Defaults to the configured holobank directory.")
+                        .value_parser(value_parser!(PathBuf))
+                )
+        )
+        .subcommand(
+            Command::new("fsck")
+                .about("Checks the holobank for dangling references and broken content.  Stop the daemon first.")
+                .arg(
+                    Arg::new("repair")
+                        .long("repair")
+                        .help("Deletes offending rows.")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("quarantine")
+                )
+                .arg(
+                    Arg::new("quarantine")
+                        .long("quarantine")
+                        .help("Moves offending rows into the quarantine relation.")
+                        .action(ArgAction::SetTrue)
+                )
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Shows how much storage deduplicating content saves.  Stop the daemon first.")
+        )
+        .subcommand(
+            Command::new("gc")
+                .about("Removes content no asset refers to any more.  Stop the daemon first.")
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Publishes an assembly as Markdown, an HTML site or an EPUB, or writes a collection to a directory it can be imported from.  Stop the daemon first.")
+                .arg(
+                    Arg::new("id")
+                        .required(true)
+                        .help("Assembly or collection to export.")
+                        .value_parser(value_parser!(Ulid))
+                )
+                .arg(
+                    Arg::new("path")
+                        .required(true)
+                        .help("File to write, or directory for an HTML site or a collection.")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .value_parser(["markdown", "html", "epub"])
+                        .default_value("markdown")
+                )
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Imports a vault of Markdown notes, e.g. from Obsidian, or a directory a collection was exported to.  Importing a vault again updates what changed.  Stop the daemon first.")
+                .arg(
+                    Arg::new("directory")
+                        .required(true)
+                        .help("Vault, or directory holding an exported collection's manifest.json.")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Reports what importing would do without changing the holobank.")
+                        .action(ArgAction::SetTrue)
+                )
+        )
+        .subcommand(
+            Command::new("key")
+                .about("Manages the keys commanders sign with.  Stop the daemon first.")
+                .subcommand_required(true)
+                .arg(
+                    Arg::new("commander")
+                        .long("commander")
+                        .help("Commander the key belongs to.  Defaults to holobank.commander.")
+                        .value_parser(value_parser!(Ulid))
+                        .global(true)
+                )
+                .subcommand(
+                    Command::new("generate")
+                        .about("Creates the first key of a commander, or of a new commander.")
+                )
+                .subcommand(
+                    Command::new("rotate")
+                        .about("Replaces a commander's key.  The rotation is signed with the old key and replicated, so peers accept the new one.")
+                )
+                .subcommand(
+                    Command::new("show")
+                        .about("Lists the keys the holobank knows of a commander.")
+                )
+        )
+        .subcommand(
+            Command::new("rekey")
+                .about("Re-encrypts every payload with a new key, or decrypts them.  Encrypted banks keep no search index or embeddings.  Stop the daemon first.")
+                .arg(
+                    Arg::new("key-file")
+                        .long("key-file")
+                        .help("File holding the new key as 32 raw bytes or 64 hex digits.")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    Arg::new("passphrase")
+                        .long("passphrase")
+                        .help("Derives the new key from a passphrase read from standard input.")
+                        .action(ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("decrypt")
+                        .long("decrypt")
+                        .help("Stores every payload in plaintext.")
+                        .action(ArgAction::SetTrue)
+                )
+                .group(
+                    ArgGroup::new("key")
+                        .args(["key-file", "passphrase", "decrypt"])
+                        .required(true)
+                )
+        )
+}
+
+pub fn handle(matches: &ArgMatches, settings: &Settings) -> anyhow::Result<()> {
+    match matches.subcommand() {
+        Some(("backup", matches)) => {
+            let name = matches.get_one::<String>("name").unwrap();
+            let archive = archive::archive_path(&settings.backup_directory(), name)
+                .ok_or_else(|| anyhow!("{} is not a plain file name", name))?;
+            let manifest = backup(name, &archive, settings)?;
+            println!("Backed up {} relations to {}", manifest.relations.len(), archive.display());
+            for entry in manifest.entries {
+                println!("- {} ({} bytes, sha256 {})", entry.name, entry.length, entry.sha256);
+            }
+        }
+        Some(("restore", matches)) => {
+            let archive = matches.get_one::<PathBuf>("archive").unwrap();
+            let directory = matches
+                .get_one::<PathBuf>("directory")
+                .cloned()
+                .unwrap_or_else(|| settings.holobank_directory());
+            Holobank::restore(archive, settings.holobank_backend(), &directory)?;
+            println!("Restored {} into {}", archive.display(), directory.display());
+        }
+        Some(("fsck", matches)) => {
+            let mode = if matches.get_flag("repair") {
+                FsckMode::Repair
+            } else if matches.get_flag("quarantine") {
+                FsckMode::Quarantine
+            } else {
+                FsckMode::Report
+            };
+            let holobank = open(settings)?;
+            let report = holobank.fsck(mode)
+                .map_err(|e| anyhow!("Check failed: {}", e))?;
+
+            for finding in &report.findings {
+                println!("- {}", finding);
+            }
+            match mode {
+                FsckMode::Report => println!("{} problems found", report.findings.len()),
+                FsckMode::Repair => println!("{} problems found, {} repaired", report.findings.len(), report.fixed),
+                FsckMode::Quarantine => println!("{} problems found, {} quarantined", report.findings.len(), report.fixed),
+            }
+        }
+        Some(("stats", _)) => {
+            let stats = open(settings)?.dedup_stats()
+                .map_err(|e| anyhow!("Could not read stats: {}", e))?;
+            println!("{} assets share {} blobs", stats.assets, stats.blobs);
+            println!("{} bytes of content, {} bytes stored", stats.logical_bytes, stats.stored_bytes);
+            let percent = match stats.logical_bytes {
+                0 => 0.0,
+                logical => stats.saved_bytes as f64 * 100.0 / logical as f64,
+            };
+            println!("{} bytes saved ({:.1}%)", stats.saved_bytes, percent);
+        }
+        Some(("gc", _)) => {
+            let report = open(settings)?.collect_garbage()
+                .map_err(|e| anyhow!("Garbage collection failed: {}", e))?;
+            println!(
+                "Removed {} blobs, freeing {} bytes; fixed {} reference counts",
+                report.blobs_removed,
+                report.bytes_freed,
+                report.refs_fixed
+            );
+        }
+        Some(("export", matches)) => {
+            let id = *matches.get_one::<Ulid>("id").unwrap();
+            let path = matches.get_one::<PathBuf>("path").unwrap();
+            let format: ExportFormat = matches.get_one::<String>("format").unwrap().parse().map_err(|e: String| anyhow!(e))?;
+            let holobank = open(settings)?;
+            if let Some(collection) = holobank.collection(id).map_err(|e| anyhow!("Could not read collection: {}", e))? {
+                collection.export(format, &holobank, path)?;
+                println!("Exported {} assets of {} to {}", collection.len(), collection.name, path.display());
+                return Ok(());
+            }
+            let assembly = holobank.assembly(id)
+                .map_err(|e| anyhow!("Could not read assembly: {}", e))?
+                .ok_or_else(|| anyhow!("No assembly {} in the holobank", id))?;
+            assembly.export(format, &holobank, path)?;
+            println!("Exported {} to {}", assembly.name, path.display());
+        }
+        Some(("import", matches)) => {
+            let directory = matches.get_one::<PathBuf>("directory").unwrap();
+            let dry_run = matches.get_flag("dry-run");
+            if directory.join(MANIFEST).is_file() {
+                if dry_run {
+                    bail!("Dry runs are only for vaults; {} holds an exported collection", directory.display());
+                }
+                let collection = open(settings)?.import_collection(directory)
+                    .map_err(|e| anyhow!("Import failed: {}", e))?;
+                println!("Imported {} assets of {}", collection.len(), collection.name);
+                return Ok(());
+            }
+            let report = open(settings)?.import_vault(directory, dry_run)
+                .map_err(|e| anyhow!("Import failed: {}", e))?;
+
+            for path in &report.created {
+                println!("+ {}", path);
+            }
+            for path in &report.updated {
+                println!("~ {}", path);
+            }
+            for path in &report.missing {
+                println!("- {} (no longer in the vault)", path);
+            }
+            for (note, target) in &report.unresolved {
+                println!("? {}: [[{}]] leads nowhere", note, target);
+            }
+            for (path, reason) in &report.skipped {
+                println!("! {}: {}", path, reason);
+            }
+            println!(
+                "{}{} created, {} updated, {} unchanged, {} new collections, {} links",
+                if dry_run { "Would import: " } else { "" },
+                report.created.len(),
+                report.updated.len(),
+                report.unchanged.len(),
+                report.collections.len(),
+                report.links
+            );
+        }
+        Some(("key", matches)) => {
+            let commander = matches.get_one::<Ulid>("commander").copied().or(settings.holobank.commander);
+            let store = settings.key_store();
+            match matches.subcommand() {
+                Some(("generate", _)) => {
+                    let commander = commander.unwrap_or_else(Ulid::new);
+                    let keypair = store.create(commander)?;
+                    load(settings)?.with_commander(commander, keypair.clone())?;
+                    println!("Created key {} for commander {} in {}", keypair.public(), commander, store.directory().display());
+                    if settings.holobank.commander != Some(commander) {
+                        println!("Set holobank.commander to {} to sign as this commander.", commander);
+                    }
+                }
+                Some(("rotate", _)) => {
+                    let commander = commander.ok_or_else(|| anyhow!("Name the commander with --commander or holobank.commander"))?;
+                    // The bank takes the rotation before the key files change.  A
+                    // rotation it could not store waits in the key store to be
+                    // tried again.
+                    let holobank = load(settings)?;
+                    let (next, rotation) = store.rotate(commander)?;
+                    match holobank.rotate_key(&rotation) {
+                        Ok(()) => store.finish_rotation(commander)?,
+                        Err(SignatureError::Database(e)) => {
+                            bail!("Could not store the rotation, run the command again to retry: {}", e)
+                        }
+                        Err(e) => {
+                            store.cancel_rotation(commander)?;
+                            bail!("The holobank refused the rotation: {}", e);
+                        }
+                    }
+                    println!("Rotated the key of commander {} from {} to {}", commander, rotation.key, next.public());
+                }
+                Some(("show", _)) => {
+                    let commander = commander.ok_or_else(|| anyhow!("Name the commander with --commander or holobank.commander"))?;
+                    let stored = store.load(commander)?.map(|keypair| keypair.public());
+                    for key in load(settings)?.commander_keys(commander).map_err(|e| anyhow!("Could not read keys: {}", e))? {
+                        let state = match key.time_retired {
+                            Some(_) => "retired",
+                            None if Some(key.key) == stored => "current, stored here",
+                            None => "current",
+                        };
+                        println!("- {} ({})", key.key, state);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(("rekey", matches)) => {
+            let key = if let Some(path) = matches.get_one::<PathBuf>("key-file") {
+                Some(KeySource::KeyFile(std::path::absolute(path)?))
+            } else if matches.get_flag("passphrase") {
+                print!("New passphrase: ");
+                io::stdout().flush()?;
+                let mut passphrase = String::new();
+                io::stdin().read_line(&mut passphrase)?;
+                let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+                if passphrase.is_empty() {
+                    bail!("The passphrase is empty");
+                }
+                Some(KeySource::Passphrase(passphrase.to_string()))
+            } else {
+                None
+            };
+
+            let (_, rewritten) = load(settings)?.rekey(key.as_ref())?;
+            match key {
+                Some(KeySource::KeyFile(path)) => println!(
+                    "Re-encrypted {} payloads.  Set holobank.key_file to {} before starting the daemon.",
+                    rewritten,
+                    path.display()
+                ),
+                Some(KeySource::Passphrase(_)) => println!(
+                    "Re-encrypted {} payloads.  Set holobank.passphrase to the new passphrase before starting the daemon.",
+                    rewritten
+                ),
+                None => println!(
+                    "Decrypted {} payloads.  Remove holobank.key_file and holobank.passphrase before starting the daemon.",
+                    rewritten
+                ),
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Opens the bank directly with its configured key, signing as the
+/// configured commander.
+fn open(settings: &Settings) -> anyhow::Result<Holobank> {
+    let holobank = load(settings)?;
+    let Some(commander) = settings.holobank.commander else {
+        return Ok(holobank);
+    };
+    let keypair = settings.key_store()
+        .load(commander)?
+        .ok_or_else(|| anyhow!("No key for commander {}; create one with `holobank key generate`", commander))?;
+    Ok(holobank.with_commander(commander, keypair)?)
+}
+
+/// Opens the bank directly with its configured key.
+fn load(settings: &Settings) -> anyhow::Result<Holobank> {
+    let holobank = Holobank::load(settings.holobank_backend(), &settings.holobank_directory())
+        .map_err(|e| anyhow!("Could not open holobank: {}", e))?;
+    Ok(match settings.holobank_key() {
+        Some(key) => holobank.with_encryption(&key)?,
+        None => holobank,
+    })
+}
+
+/// Asks the running daemon for the backup so the bank stays online, or
+/// opens the bank directly when the daemon is not running.
+fn backup(name: &str, archive: &Path, settings: &Settings) -> anyhow::Result<Manifest> {
+    let body = serde_json::json!({ "name": name }).to_string();
+    match post(settings, "/v0/holobank/backup", &body) {
+        Ok(response) => Ok(serde_json::from_str(&response)?),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            let holobank = Holobank::load(settings.holobank_backend(), &settings.holobank_directory())
+                .map_err(|e| anyhow!("Could not open holobank: {}", e))?;
+            fs::create_dir_all(settings.backup_directory())?;
+            Ok(holobank.backup(archive)?)
+        }
+        Err(e) => bail!("Backup through the daemon failed: {}", e),
+    }
+}
+
+/// Minimal HTTP POST to the daemon's local API.  Asking in HTTP/1.0 keeps
+/// the daemon from chunking the response, which ends with the connection or
+/// at its `Content-Length`.
+fn post(settings: &Settings, path: &str, body: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(SocketAddr::new(LOCALHOST, CELESTIAD_PORT))?;
+    let token = settings.api.token.as_deref().unwrap_or_default();
+    write!(
+        stream,
+        "POST {} HTTP/1.0\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        path,
+        token,
+        body.len(),
+        body
+    )?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let malformed = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("malformed response: {}", what));
+    let end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| malformed("no end of headers"))?;
+    let head = std::str::from_utf8(&response[..end]).map_err(|_| malformed("headers are not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let status: u16 = match lines.next().unwrap_or_default().split_whitespace().collect::<Vec<_>>()[..] {
+        [version, code, ..] if version.starts_with("HTTP/1.") => code.parse().map_err(|_| malformed("status"))?,
+        _ => return Err(malformed("status line")),
+    };
+    let mut length = None;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or_else(|| malformed("header"))?;
+        if name.eq_ignore_ascii_case("content-length") {
+            length = Some(value.trim().parse::<usize>().map_err(|_| malformed("Content-Length"))?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(malformed("transfer encoding"));
+        }
+    }
+    let mut body = &response[end + 4..];
+    if let Some(length) = length {
+        body = body.get(..length).ok_or_else(|| malformed("body shorter than its Content-Length"))?;
+    }
+    let body = String::from_utf8_lossy(body);
+    if !(200..300).contains(&status) {
+        return Err(io::Error::other(format!("daemon answered {}: {}", status, body)));
+    }
+    Ok(body.into_owned())
+}
@@ -1,3 +1,4 @@
+mod holobank;
 mod test;
 
 use clap::{ArgMatches, Command};
@@ -7,16 +8,23 @@ use crate::settings::Settings;
 pub fn configure(command: Command) -> Command {
     command
         .subcommand(test::configure())
+        .subcommand(holobank::configure())
         .arg_required_else_help(false)
 }
 
-pub fn handle(matches: &ArgMatches, settings: &Settings) -> anyhow::Result<()> {
+/// Runs the chosen subcommand.  Returns whether the daemon should start
+/// afterwards.
+pub fn handle(matches: &ArgMatches, settings: &Settings) -> anyhow::Result<bool> {
     if let Some((cmd, matches)) = matches.subcommand() {
         match cmd {
             test::COMMAND_NAME => test::handle(matches, settings)?,
+            holobank::COMMAND_NAME => {
+                holobank::handle(matches, settings)?;
+                return Ok(false);
+            }
             &_ => {}
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(true)
+}
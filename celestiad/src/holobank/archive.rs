@@ -0,0 +1,319 @@
+// A holobank archive is a single file:
+//
+//   magic (8 bytes) | version (u32 LE) | manifest length (u64 LE)
+//   | manifest (JSON) | manifest sha256 (32 bytes) | entries...
+//
+// The manifest lists every entry with its length and sha256 checksum.
+// Entries follow the manifest in the order they are listed.  The database
+// entry is a cozo backup, which restores into any storage backend.
+
+use std::{fmt, fs, io::{self, Read, Write}, path::{Component, Path, PathBuf}};
+
+use cozo::DbInstance;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ulid::Ulid;
+
+use super::{now, Holobank, HOLOBANK_FILE};
+use crate::storage::Backend;
+
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"HOLOBANK";
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Name of the entry holding the database backup.
+const DATABASE_ENTRY: &str = "holobank.db";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Time of the backup in microseconds.
+    pub created: i64,
+    /// Stored relations in the backup.
+    pub relations: Vec<String>,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub name: String,
+    pub length: u64,
+    /// Hex encoded sha256 of the entry.
+    pub sha256: String,
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(io::Error),
+    /// The file is not a holobank archive or does not match its checksums.
+    Corrupt(String),
+    /// The archive was written by a newer version.
+    Unsupported(u32),
+    /// Restoring requires an empty directory.
+    NotEmpty(PathBuf),
+    Database(cozo::Error),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Io(error) => write!(f, "archive i/o failed: {}", error),
+            ArchiveError::Corrupt(reason) => write!(f, "corrupt archive: {}", reason),
+            ArchiveError::Unsupported(version) => write!(f, "unsupported archive version {}", version),
+            ArchiveError::NotEmpty(path) => write!(f, "{} is not empty", path.display()),
+            ArchiveError::Database(error) => write!(f, "database failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<io::Error> for ArchiveError {
+    fn from(value: io::Error) -> Self {
+        ArchiveError::Io(value)
+    }
+}
+
+impl Holobank {
+    /// Writes an archive of the whole bank to `out` while the bank stays
+    /// in use.
+    pub fn backup(&self, out: &Path) -> Result<Manifest, ArchiveError> {
+        let relations = stored_relations(&self.persistent)?;
+
+        let scratch = scratch_path();
+        self.persistent.backup_db(&scratch).map_err(ArchiveError::Database)?;
+        let database = fs::read(&scratch);
+        let _ = fs::remove_file(&scratch);
+        let database = database?;
+
+        let manifest = Manifest {
+            version: ARCHIVE_VERSION,
+            created: now(),
+            relations,
+            entries: vec![Entry {
+                name: DATABASE_ENTRY.to_string(),
+                length: database.len() as u64,
+                sha256: hex(&Sha256::digest(&database)),
+            }],
+        };
+        let encoded = serde_json::to_vec(&manifest).map_err(|e| ArchiveError::Corrupt(e.to_string()))?;
+
+        // Write next to the destination first so a failed backup never
+        // leaves a truncated archive behind.
+        let partial = out.with_extension("partial");
+        let mut file = fs::File::create(&partial)?;
+        file.write_all(ARCHIVE_MAGIC)?;
+        file.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+        file.write_all(&(encoded.len() as u64).to_le_bytes())?;
+        file.write_all(&encoded)?;
+        file.write_all(&Sha256::digest(&encoded))?;
+        file.write_all(&database)?;
+        file.sync_all()?;
+        fs::rename(&partial, out)?;
+
+        Ok(manifest)
+    }
+
+    /// Restores an archive into an empty data directory on the given
+    /// backend and loads it.
+    pub fn restore(archive: &Path, backend: Backend, directory: &Path) -> Result<Holobank, ArchiveError> {
+        if directory.exists() && fs::read_dir(directory)?.next().is_some() {
+            return Err(ArchiveError::NotEmpty(directory.to_path_buf()));
+        }
+
+        let (manifest, mut entries) = read_archive(archive)?;
+        let database = entries
+            .iter()
+            .position(|(name, _)| name == DATABASE_ENTRY)
+            .map(|i| entries.swap_remove(i).1)
+            .ok_or_else(|| ArchiveError::Corrupt(format!("missing {}", DATABASE_ENTRY)))?;
+
+        let scratch = scratch_path();
+        fs::write(&scratch, database)?;
+        let restored = backend
+            .open(directory, HOLOBANK_FILE)
+            .and_then(|db| db.restore_backup(&scratch).map(|_| db));
+        let _ = fs::remove_file(&scratch);
+        let db = restored.map_err(ArchiveError::Database)?;
+
+        let present = stored_relations(&db)?;
+        if let Some(missing) = manifest.relations.iter().find(|r| !present.contains(r)) {
+            return Err(ArchiveError::Corrupt(format!("relation {} was not restored", missing)));
+        }
+
+        Holobank::from_db(backend, db).map_err(ArchiveError::Database)
+    }
+}
+
+/// Name and bytes of an archive entry.
+pub type Contents = (String, Vec<u8>);
+
+/// Where an archive called `name` goes in `directory`.  Only plain file
+/// names are accepted, so archives cannot land outside the directory.
+pub fn archive_path(directory: &Path, name: &str) -> Option<PathBuf> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Some(directory.join(name)),
+        _ => None,
+    }
+}
+
+/// Reads an archive, checking its format and every checksum.
+pub fn read_archive(path: &Path) -> Result<(Manifest, Vec<Contents>), ArchiveError> {
+    let file = fs::File::open(path)?;
+    // Lengths in the archive are checked against what is left of the file
+    // before anything is allocated for them.
+    let mut remaining = file.metadata()?.len();
+    let mut take = |length: u64, what: &str| {
+        remaining = remaining
+            .checked_sub(length)
+            .ok_or_else(|| ArchiveError::Corrupt(format!("{} runs past the end of the archive", what)))?;
+        usize::try_from(length).map_err(|_| ArchiveError::Corrupt(format!("{} is too long", what)))
+    };
+    let mut file = io::BufReader::new(file);
+
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != ARCHIVE_MAGIC {
+        return Err(ArchiveError::Corrupt("not a holobank archive".to_string()));
+    }
+    let mut version = [0u8; 4];
+    file.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version > ARCHIVE_VERSION {
+        return Err(ArchiveError::Unsupported(version));
+    }
+
+    let mut length = [0u8; 8];
+    file.read_exact(&mut length)?;
+    take(8 + 4 + 8, "header")?;
+    let mut encoded = vec![0u8; take(u64::from_le_bytes(length), "manifest")?];
+    file.read_exact(&mut encoded)?;
+    let mut checksum = [0u8; 32];
+    take(checksum.len() as u64, "manifest checksum")?;
+    file.read_exact(&mut checksum)?;
+    if Sha256::digest(&encoded).as_slice() != checksum {
+        return Err(ArchiveError::Corrupt("manifest checksum mismatch".to_string()));
+    }
+    let manifest: Manifest = serde_json::from_slice(&encoded)
+        .map_err(|e| ArchiveError::Corrupt(e.to_string()))?;
+
+    let mut entries = Vec::with_capacity(manifest.entries.len());
+    for entry in &manifest.entries {
+        let mut data = vec![0u8; take(entry.length, &entry.name)?];
+        file.read_exact(&mut data)?;
+        if hex(&Sha256::digest(&data)) != entry.sha256 {
+            return Err(ArchiveError::Corrupt(format!("checksum mismatch in {}", entry.name)));
+        }
+        entries.push((entry.name.clone(), data));
+    }
+
+    Ok((manifest, entries))
+}
+
+/// Stored relations, leaving out indices which live inside their relation.
+fn stored_relations(db: &DbInstance) -> Result<Vec<String>, ArchiveError> {
+    let relations = db.run_default("::relations").map_err(ArchiveError::Database)?;
+    Ok(relations.rows
+        .iter()
+        .filter_map(|row| row[0].get_str())
+        .filter(|name| !name.contains(':'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn scratch_path() -> PathBuf {
+    std::env::temp_dir().join(format!("holobank-{}.backup", Ulid::new()))
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holobank::each_backend;
+    use constellations::connection::{Connection, ConnectionType};
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}", name, Ulid::new()))
+    }
+
+    #[test]
+    fn round_trip_into_empty_directory() {
+        each_backend(|bank| {
+            let (a, b, owner) = (Ulid::new(), Ulid::new(), Ulid::new());
+            bank.connect(&Connection::new(a, b, ConnectionType::Derivation)).unwrap();
+            bank.assert_owner(a, owner, 10).unwrap();
+            bank.index_text(a, "backed up text").unwrap();
+
+            let archive = temp("archive");
+            let manifest = bank.backup(&archive).unwrap();
+            assert!(manifest.relations.iter().any(|r| r == "connection"));
+
+            let directory = temp("restored");
+            let restored = Holobank::restore(&archive, bank.backend(), &directory).unwrap();
+
+            assert_eq!(restored.lineage(b).unwrap(), vec![Connection::new(a, b, ConnectionType::Derivation)]);
+            assert_eq!(restored.owners_at(a, 20).unwrap(), vec![owner]);
+
+            drop(restored);
+            let _ = fs::remove_file(&archive);
+            let _ = fs::remove_dir_all(&directory);
+        });
+    }
+
+    #[test]
+    fn refuses_corrupt_archives() {
+        each_backend(|bank| {
+            let archive = temp("archive");
+            bank.backup(&archive).unwrap();
+
+            let mut bytes = fs::read(&archive).unwrap();
+            let last = bytes.len() - 1;
+            bytes[last] ^= 0xff;
+            fs::write(&archive, bytes).unwrap();
+
+            let directory = temp("restored");
+            assert!(matches!(Holobank::restore(&archive, bank.backend(), &directory), Err(ArchiveError::Corrupt(_))));
+            let _ = fs::remove_file(&archive);
+        });
+    }
+
+    #[test]
+    fn refuses_lengths_past_the_end() {
+        let archive = temp("archive");
+        let mut bytes = ARCHIVE_MAGIC.to_vec();
+        bytes.extend(ARCHIVE_VERSION.to_le_bytes());
+        bytes.extend(u64::MAX.to_le_bytes());
+        fs::write(&archive, bytes).unwrap();
+        let read = read_archive(&archive);
+        let _ = fs::remove_file(&archive);
+        assert!(matches!(read, Err(ArchiveError::Corrupt(_))));
+    }
+
+    #[test]
+    fn refuses_non_empty_directories() {
+        each_backend(|bank| {
+            let archive = temp("archive");
+            bank.backup(&archive).unwrap();
+
+            let directory = temp("occupied");
+            fs::create_dir_all(&directory).unwrap();
+            fs::write(directory.join("file"), b"").unwrap();
+
+            assert!(matches!(Holobank::restore(&archive, bank.backend(), &directory), Err(ArchiveError::NotEmpty(_))));
+            let _ = fs::remove_file(&archive);
+            let _ = fs::remove_dir_all(&directory);
+        });
+    }
+
+    #[test]
+    fn keeps_archives_in_their_directory() {
+        let directory = Path::new("/var/backups");
+        assert_eq!(archive_path(directory, "nightly.holobank"), Some(directory.join("nightly.holobank")));
+        for name in ["", ".", "..", "../escape", "nested/archive", "/etc/passwd"] {
+            assert_eq!(archive_path(directory, name), None, "{}", name);
+        }
+    }
+}
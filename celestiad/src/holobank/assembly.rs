@@ -0,0 +1,266 @@
+// An assembly is stored as an asset whose content is the assembly itself,
+// which keeps the blueprint as it was when the assembly was built, and whose
+// structure is kept in `connection`: a construction edge from the blueprint
+// and a `part:<slot>:<index>` edge to each part.  The bank reads the parts
+// back from the edges, so rebuilding an assembly replaces its edges.  The
+// bank is the source exports read texts, images and nested assemblies from,
+// and collection exports everything else known of their assets.
+
+use std::{collections::BTreeMap, fmt};
+
+use constellations::{
+    asset::{
+        assembly::{export::{Content, Source}, Assembly, AssemblyError, Part, PartKind},
+        block::{image::Image, text::Text},
+        blueprint::Blueprint,
+        Asset,
+    },
+    collection::{export::Record, Collection},
+};
+use cozo::{DataValue, ScriptMutability};
+use ulid::Ulid;
+
+use super::{graph::Direction, ulid, Holobank};
+
+#[derive(Debug)]
+pub enum ConstructionError {
+    UnknownCollection(Ulid),
+    Assembly(AssemblyError),
+    Database(cozo::Error),
+}
+
+impl fmt::Display for ConstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConstructionError::UnknownCollection(id) => write!(f, "collection {} does not exist", id),
+            ConstructionError::Assembly(e) => write!(f, "{}", e),
+            ConstructionError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<AssemblyError> for ConstructionError {
+    fn from(e: AssemblyError) -> Self {
+        ConstructionError::Assembly(e)
+    }
+}
+
+impl From<cozo::Error> for ConstructionError {
+    fn from(e: cozo::Error) -> Self {
+        ConstructionError::Database(e)
+    }
+}
+
+impl Holobank {
+    /// Builds an assembly from the blocks and assemblies of a collection
+    /// and stores it.  Members are taken in the order of their names, with
+    /// numbers in them compared by value so `Chapter 2` comes before
+    /// `Chapter 10`, then unnamed ones in the order they joined the
+    /// collection.  Members whose content is not in the bank are left out.
+    pub fn construct(&self, name: &str, blueprint: &Blueprint, collection: Ulid) -> Result<Assembly, ConstructionError> {
+        let Some(collection) = self.collection(collection)? else {
+            return Err(ConstructionError::UnknownCollection(collection));
+        };
+        let assembly = Assembly::construct(name, blueprint, &self.parts(&collection)?)?;
+        self.store_assembly(&assembly)?;
+        Ok(assembly)
+    }
+
+    /// Stores an assembly and replaces the edges of its parts.
+    pub fn store_assembly(&self, assembly: &Assembly) -> Result<(), cozo::Error> {
+        self.dematerialize(assembly, true)?;
+        let connections = assembly.connections();
+        for stale in self.neighbors(assembly.id, Direction::Outgoing, &[])? {
+            if stale.kind.as_part().is_some() && !connections.contains(&stale) {
+                self.disconnect(&stale)?;
+            }
+        }
+        for connection in &connections {
+            self.connect(connection)?;
+        }
+        Ok(())
+    }
+
+    pub fn assembly(&self, id: Ulid) -> Result<Option<Assembly>, cozo::Error> {
+        let Some((content_type, bytes)) = self.content(id)? else {
+            return Ok(None);
+        };
+        if content_type != Assembly::CONTENT_TYPE {
+            return Ok(None);
+        }
+        let stored: Assembly = serde_json::from_slice(&bytes).map_err(cozo::Error::msg)?;
+        let connections = self.neighbors(id, Direction::Outgoing, &[])?;
+        Ok(Some(Assembly::from_parts(
+            id,
+            stored.name.clone(),
+            stored.blueprint().clone(),
+            stored.origin(),
+            &connections,
+        )))
+    }
+
+    /// Members of a collection that can fill slots, in order.
+    fn parts(&self, collection: &Collection) -> Result<Vec<Part>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[asset_id, name, content_type, time_added] := asset_id in $assets, *asset{asset_id, name},
+                *content{asset_id, content_type}, *collection{asset_id, collection_id: $collection_id, time_added}",
+            BTreeMap::from([
+                ("assets".to_string(), DataValue::List(collection.assets().map(|(id, _)| ulid(id)).collect())),
+                ("collection_id".to_string(), ulid(collection.id)),
+            ]),
+            ScriptMutability::Immutable
+        )?;
+        let mut found: Vec<(Ulid, Option<String>, String, i64)> = rows.rows
+            .iter()
+            .filter_map(|row| {
+                let name = row[1].get_str().map(str::to_string);
+                Some((row[0].get_ulid()?, name, row[2].get_str()?.to_string(), row[3].get_int()?))
+            })
+            .collect();
+        found.sort_by_cached_key(|(id, name, _, time_added)| {
+            (name.is_none(), name.as_deref().map(natural), *time_added, *id)
+        });
+
+        let mut parts = Vec::new();
+        for (id, name, content_type, _) in found {
+            let kind = if content_type == Assembly::CONTENT_TYPE {
+                match self.assembly(id)? {
+                    Some(nested) => PartKind::Assembly(nested.blueprint().name.clone()),
+                    None => continue,
+                }
+            } else {
+                PartKind::Block(content_type)
+            };
+            parts.push(Part { id, name, kind });
+        }
+        Ok(parts)
+    }
+}
+
+/// Sort key of a name: its runs of text, ignoring case, each followed by
+/// the value of the number after it.
+fn natural(name: &str) -> Vec<(String, Option<u64>)> {
+    let mut key = Vec::new();
+    let mut rest = name;
+    while !rest.is_empty() {
+        let text_end = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let (text, tail) = rest.split_at(text_end);
+        let digits_end = tail.find(|c: char| !c.is_ascii_digit()).unwrap_or(tail.len());
+        let (digits, tail) = tail.split_at(digits_end);
+        key.push((text.to_lowercase(), (!digits.is_empty()).then(|| digits.parse().unwrap_or(u64::MAX))));
+        rest = tail;
+    }
+    key
+}
+
+impl Source for Holobank {
+    fn resolve(&self, id: Ulid) -> Option<Content> {
+        let (content_type, bytes) = self.content(id).ok()??;
+        match content_type.as_str() {
+            Text::CONTENT_TYPE => Text::decode(&bytes).map(|text| Content::Text(text.as_str().to_string())),
+            Assembly::CONTENT_TYPE => self.assembly(id).ok()?.map(|assembly| Content::Assembly(Box::new(assembly))),
+            Image::CONTENT_TYPE => Some(Content::Image(bytes)),
+            _ => Some(Content::Other { content_type, bytes }),
+        }
+    }
+
+    fn record(&self, id: Ulid) -> Option<Record> {
+        let params = BTreeMap::from([("asset_id".to_string(), ulid(id))]);
+        let query = |script: &str| self.persistent.run_script(script, params.clone(), ScriptMutability::Immutable).ok();
+        let asset = query("?[asset_type, name] := *asset{asset_id: $asset_id, asset_type, name}")?;
+        let row = asset.rows.first()?;
+        let mut record = Record::new(row[0].get_str()?.parse().ok()?);
+        record.name = row[1].get_str().map(str::to_string);
+        let labels = |script: &str| -> Option<_> {
+            Some(query(script)?.rows.iter().filter_map(|row| row[0].get_str().map(str::to_string)).collect())
+        };
+        record.tags = labels("?[tag] := *tags{asset_id: $asset_id, tag}")?;
+        record.flags = labels("?[flag] := *flags{asset_id: $asset_id, flag}")?;
+        record.connections = self.neighbors(id, Direction::Outgoing, &[]).ok()?;
+        record.connections.extend(self.neighbors(id, Direction::Incoming, &[]).ok()?);
+        Some(record)
+    }
+
+    fn history(&self, id: Ulid) -> Option<Vec<u8>> {
+        let (content_type, bytes) = self.content(id).ok()??;
+        (content_type == Text::CONTENT_TYPE).then_some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constellations::{
+        asset::{assembly::export::ExportFormat, Asset, Export},
+        connection::{Connection, ConnectionType},
+    };
+
+    use crate::holobank::each_backend;
+
+    #[test]
+    fn builds_a_book_from_chapters() {
+        each_backend(|bank| {
+            // Named out of order, and one chapter number past nine.
+            let names = ["Chapter 10", "Title", "Chapter 2", "chapter 1"];
+            let texts: Vec<Text> = names.iter().map(|name| Text::new(*name, 1)).collect();
+            let mut collection = Collection::new("manuscript");
+            for (text, name) in texts.iter().zip(names) {
+                bank.dematerialize(text, true).unwrap();
+                bank.rename(text.id(), Some(name)).unwrap();
+                collection.add_asset(text.id(), text.asset_type());
+            }
+            bank.store_collection(&collection).unwrap();
+
+            let blueprint = Blueprint::book();
+            let book = bank.construct("novel", &blueprint, collection.id).unwrap();
+            let chapters = [texts[3].id(), texts[2].id(), texts[0].id()];
+            assert_eq!(book.slot("title"), &[texts[1].id()]);
+            assert_eq!(book.slot("chapters"), &chapters);
+            assert_eq!(bank.assembly(book.id).unwrap(), Some(book.clone()));
+            let edges = bank.neighbors(book.id, Direction::Outgoing, &[ConnectionType::part("chapters", 0)]).unwrap();
+            assert_eq!(edges, vec![Connection::new(book.id, chapters[0], ConnectionType::part("chapters", 0))]);
+            let built = bank.neighbors(book.id, Direction::Incoming, &[ConnectionType::Construction]).unwrap();
+            assert_eq!(built.iter().map(|edge| edge.src).collect::<Vec<_>>(), vec![blueprint.id]);
+
+            // Building with a changed blueprint leaves the first book as it
+            // was built.
+            let mut changed = blueprint.clone();
+            changed.slots.retain(|slot| slot.name != "title");
+            let untitled = bank.construct("untitled", &changed, collection.id).unwrap();
+            assert_eq!(untitled.slot("chapters"), &[chapters[0], chapters[1], chapters[2], texts[1].id()]);
+            let stored = bank.assembly(book.id).unwrap().unwrap();
+            assert_eq!(stored.blueprint(), &blueprint);
+            assert_eq!(stored, book);
+
+            assert!(matches!(
+                bank.construct("nothing", &blueprint, Ulid::new()),
+                Err(ConstructionError::UnknownCollection(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn exports_from_the_bank() {
+        each_backend(|bank| {
+            let texts = [Text::new("Stored", 1), Text::new("# Only chapter", 1)];
+            let mut collection = Collection::new("stored");
+            for text in &texts {
+                bank.dematerialize(text, true).unwrap();
+                collection.add_asset(text.id(), text.asset_type());
+            }
+            bank.rename(texts[0].id(), Some("title")).unwrap();
+            bank.store_collection(&collection).unwrap();
+            let book = bank.construct("stored", &Blueprint::book(), collection.id).unwrap();
+            assert_eq!(bank.resolve(book.id), Some(Content::Assembly(Box::new(book.clone()))));
+
+            let path = std::env::temp_dir().join(format!("assembly-{}.md", book.id));
+            book.export(ExportFormat::Markdown, &bank, &path).unwrap();
+            let markdown = std::fs::read_to_string(&path).unwrap();
+            let _ = std::fs::remove_file(&path);
+            assert_eq!(
+                markdown,
+                format!("<a id=\"{}\"></a>\n\n# Stored\n\n<a id=\"{}\"></a>\n\n# Only chapter\n", texts[0].id(), texts[1].id())
+            );
+        });
+    }
+}
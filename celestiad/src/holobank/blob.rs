@@ -0,0 +1,455 @@
+// Content is stored once per distinct sequence of bytes.  Each asset's
+// `content` row names the sha256 digest of its bytes and the bytes themselves
+// live in `blob`, so identical content, e.g. an asset forked without changes
+// or the same file deposited twice, is kept once.  Blobs count the assets
+// referring to them; blobs nothing refers to any more are removed by
+// `collect_garbage`, which also recounts references from scratch.
+//
+// On encrypted banks the digest is an HMAC-SHA256 keyed from the bank key,
+// so neither the stored rows nor the change feed carry a fingerprint of the
+// plaintext, and deduplication works the same.  Blob payloads are sealed
+// like any other payload.  Signed edits and Merkle summaries use the plain
+// sha256, which other banks can compute too.
+
+use std::collections::{BTreeMap, HashMap};
+
+use cozo::{DataValue, DbInstance, ScriptMutability};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use ulid::Ulid;
+
+use super::{now, relation_exists, ulid, Holobank};
+
+/// Bytes ChaCha20Poly1305 appends to a sealed payload.
+const TAG_BYTES: usize = 16;
+
+/// How much storage deduplication saves.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DedupStats {
+    /// Assets with content stored.
+    pub assets: u64,
+    /// Distinct blobs stored.
+    pub blobs: u64,
+    /// Bytes of content as the assets see it.
+    pub logical_bytes: u64,
+    /// Bytes actually stored.
+    pub stored_bytes: u64,
+    /// Bytes saved by sharing blobs.
+    pub saved_bytes: u64,
+}
+
+/// What a garbage collection removed.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GcReport {
+    pub blobs_removed: u64,
+    pub bytes_freed: u64,
+    /// Blobs whose reference count was wrong.
+    pub refs_fixed: u64,
+}
+
+/// sha256 digest of content.
+pub fn digest(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes).to_vec()
+}
+
+impl Holobank {
+    /// Digest content is stored under: the sha256, keyed on encrypted banks.
+    pub(crate) fn storage_digest(&self, bytes: &[u8]) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => cipher.digest(bytes),
+            None => digest(bytes),
+        }
+    }
+
+    /// Stores the content of an asset, sharing the blob with every other
+    /// asset holding the same bytes, and reindexes it for search.
+    pub(crate) fn store_content(&self, id: Ulid, content_type: &str, bytes: Vec<u8>) -> Result<(), cozo::Error> {
+        let digest = self.storage_digest(&bytes);
+        let size = bytes.len();
+        self.reindex(id, content_type, &bytes)?;
+        put_content(&self.persistent, id, content_type, digest, size, self.seal(bytes))
+    }
+
+    /// Removes the content of an asset, releasing its blob, and its search
+    /// index.
+    pub(crate) fn remove_content(&self, id: Ulid) -> Result<(), cozo::Error> {
+        self.persistent.run_script(
+            "{
+                ?[digest, refs] := *content{asset_id: $asset_id, digest}, *blob{digest, refs: old}, refs = old - 1
+                :update blob {digest => refs}
+            }
+            {?[asset_id] <- [[$asset_id]] :rm content {asset_id}}",
+            BTreeMap::from([("asset_id".to_string(), ulid(id))]),
+            ScriptMutability::Mutable
+        )?;
+        self.unindex(id)
+    }
+
+    /// Digest of an asset's content.
+    pub(crate) fn content_digest(&self, id: Ulid) -> Result<Option<Vec<u8>>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[digest] := *content{asset_id: $asset_id, digest}",
+            BTreeMap::from([("asset_id".to_string(), ulid(id))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows.first().and_then(|row| row[0].get_bytes()).map(<[u8]>::to_vec))
+    }
+
+    /// Reads the sealed payload of an asset's content.
+    pub(crate) fn sealed_content(&self, id: Ulid) -> Result<Option<(String, DataValue)>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[content_type, payload] := *content{asset_id: $asset_id, content_type, digest}, *blob{digest, payload}",
+            BTreeMap::from([("asset_id".to_string(), ulid(id))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows.into_iter().next().and_then(|mut row| {
+            let payload = row.pop()?;
+            Some((row[0].get_str()?.to_string(), payload))
+        }))
+    }
+
+    /// Rewrites blobs under new digests with new payloads, along with
+    /// everything naming them, in one transaction.  Each move is the old
+    /// digest, the new digest and the payload stored under it.
+    pub(crate) fn move_blobs(&self, moves: Vec<(Vec<u8>, Vec<u8>, DataValue)>) -> Result<(), cozo::Error> {
+        if moves.is_empty() {
+            return Ok(());
+        }
+
+        // Quarantined content keeps its blob through the digest in its row.
+        let renamed: HashMap<&[u8], &[u8]> = moves.iter().map(|(old, new, _)| (old.as_slice(), new.as_slice())).collect();
+        let quarantined = self.persistent.run_default(
+            "?[row, reason, time_quarantined] := *quarantine{relation: 'content', row, reason, time_quarantined}"
+        )?;
+        let mut stale = Vec::new();
+        let mut moved = Vec::new();
+        for entry in quarantined.rows {
+            let DataValue::List(row) = &entry[0] else {
+                continue;
+            };
+            let Some(new) = row.get(2).and_then(DataValue::get_bytes).and_then(|old| renamed.get(old)) else {
+                continue;
+            };
+            let mut renamed_row = row.clone();
+            renamed_row[2] = DataValue::Bytes(new.to_vec());
+            stale.push(DataValue::List(vec![DataValue::from("content"), entry[0].clone()]));
+            moved.push(DataValue::List(vec![
+                DataValue::from("content"),
+                DataValue::List(renamed_row),
+                entry[1].clone(),
+                entry[2].clone(),
+            ]));
+        }
+
+        let digests = moves
+            .iter()
+            .map(|(old, new, _)| DataValue::List(vec![DataValue::Bytes(old.clone()), DataValue::Bytes(new.clone())]))
+            .collect();
+        let blobs = moves
+            .into_iter()
+            .map(|(old, new, payload)| DataValue::List(vec![DataValue::Bytes(old), DataValue::Bytes(new), payload]))
+            .collect();
+        self.persistent.run_script(
+            "{
+                moved[old, new, payload] <- $blobs
+                ?[digest, payload, size, refs] := moved[old, digest, payload], *blob{digest: old, size, refs}
+                :put blob {digest => payload, size, refs}
+            }
+            {
+                moved[old, new] <- $digests
+                ?[digest] := moved[digest, new], digest != new
+                :rm blob {digest}
+            }
+            {
+                moved[old, new] <- $digests
+                ?[asset_id, content_type, digest, size, time_attached] :=
+                    moved[old, digest], *content{asset_id, content_type, digest: old, size, time_attached}
+                :put content {asset_id => content_type, digest, size, time_attached}
+            }
+            {
+                moved[old, new] <- $digests
+                ?[source, asset_id, digest, time_synced] := moved[old, digest], *synced{source, asset_id, digest: old, time_synced}
+                :put synced {source, asset_id => digest, time_synced}
+            }
+            {?[relation, row] <- $stale :rm quarantine {relation, row}}
+            {
+                ?[relation, row, reason, time_quarantined] <- $moved
+                :put quarantine {relation, row => reason, time_quarantined}
+            }",
+            BTreeMap::from([
+                ("blobs".to_string(), DataValue::List(blobs)),
+                ("digests".to_string(), DataValue::List(digests)),
+                ("stale".to_string(), DataValue::List(stale)),
+                ("moved".to_string(), DataValue::List(moved)),
+            ]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Recounts the references to every blob and removes the blobs nothing
+    /// refers to.  Content held in quarantine keeps its blob.
+    pub fn collect_garbage(&self) -> Result<GcReport, cozo::Error> {
+        let counted = self.persistent.run_default(
+            "?[digest, count(asset_id)] := *content{asset_id, digest}"
+        )?;
+        let refs: HashMap<Vec<u8>, i64> = counted.rows
+            .iter()
+            .filter_map(|row| Some((row[0].get_bytes()?.to_vec(), row[1].get_int()?)))
+            .collect();
+        let quarantined = self.persistent.run_default(
+            "?[digest] := *quarantine{relation: 'content', row}, digest = get(row, 2), is_bytes(digest)"
+        )?;
+        let held: Vec<Vec<u8>> = quarantined.rows
+            .iter()
+            .filter_map(|row| Some(row[0].get_bytes()?.to_vec()))
+            .collect();
+
+        let mut report = GcReport::default();
+        let mut garbage = Vec::new();
+        let mut recount = Vec::new();
+        for row in self.persistent.run_default("?[digest, size, refs] := *blob{digest, size, refs}")?.rows {
+            let (Some(digest), Some(size), Some(stored)) = (row[0].get_bytes(), row[1].get_int(), row[2].get_int()) else {
+                continue;
+            };
+            let actual = refs.get(digest).copied().unwrap_or(0);
+            if actual == 0 && !held.iter().any(|d| d == digest) {
+                report.blobs_removed += 1;
+                report.bytes_freed += size as u64;
+                garbage.push(DataValue::List(vec![row[0].clone()]));
+            } else if actual != stored {
+                report.refs_fixed += 1;
+                recount.push(DataValue::List(vec![row[0].clone(), DataValue::from(actual)]));
+            }
+        }
+
+        if !garbage.is_empty() {
+            self.persistent.run_script(
+                "?[digest] <- $rows :rm blob {digest}",
+                BTreeMap::from([("rows".to_string(), DataValue::List(garbage))]),
+                ScriptMutability::Mutable
+            )?;
+        }
+        if !recount.is_empty() {
+            self.persistent.run_script(
+                "?[digest, refs] <- $rows :update blob {digest => refs}",
+                BTreeMap::from([("rows".to_string(), DataValue::List(recount))]),
+                ScriptMutability::Mutable
+            )?;
+        }
+        Ok(report)
+    }
+
+    /// Bytes stored against the bytes the assets would take without
+    /// deduplication.
+    pub fn dedup_stats(&self) -> Result<DedupStats, cozo::Error> {
+        let content = self.persistent.run_default(
+            "?[count(asset_id), sum(size)] := *content{asset_id, size}"
+        )?;
+        let blobs = self.persistent.run_default(
+            "?[count(digest), sum(size)] := *blob{digest, size}"
+        )?;
+
+        let mut stats = DedupStats::default();
+        if let Some(row) = content.rows.first() {
+            stats.assets = row[0].get_int().unwrap_or(0) as u64;
+            stats.logical_bytes = row[1].get_float().unwrap_or(0.0) as u64;
+        }
+        if let Some(row) = blobs.rows.first() {
+            stats.blobs = row[0].get_int().unwrap_or(0) as u64;
+            stats.stored_bytes = row[1].get_float().unwrap_or(0.0) as u64;
+        }
+        stats.saved_bytes = stats.logical_bytes.saturating_sub(stats.stored_bytes);
+        Ok(stats)
+    }
+}
+
+/// Points the content of an asset at the blob with `digest`, storing the blob
+/// if it is new and moving the reference off the blob it replaces.
+fn put_content(
+    db: &DbInstance,
+    id: Ulid,
+    content_type: &str,
+    digest: Vec<u8>,
+    size: usize,
+    payload: DataValue,
+) -> Result<(), cozo::Error> {
+    let mut params = BTreeMap::new();
+    params.insert("asset_id".to_string(), ulid(id));
+    params.insert("content_type".to_string(), DataValue::from(content_type));
+    params.insert("digest".to_string(), DataValue::Bytes(digest));
+    params.insert("size".to_string(), DataValue::from(size as i64));
+    params.insert("payload".to_string(), payload);
+    params.insert("time".to_string(), DataValue::from(now()));
+
+    // Runs as one transaction, so the counts never see half a swap.
+    db.run_script(
+        "{
+            ?[digest, payload, size, refs] := digest = $digest, payload = $payload, size = $size, refs = 0,
+                not *blob{digest: $digest}
+            :put blob {digest => payload, size, refs}
+        }
+        {
+            ?[digest, refs] := *content{asset_id: $asset_id, digest}, digest != $digest,
+                *blob{digest, refs: old}, refs = old - 1
+            :update blob {digest => refs}
+        }
+        {
+            ?[digest, refs] := *blob{digest, refs: old}, digest = $digest,
+                not *content{asset_id: $asset_id, digest: $digest}, refs = old + 1
+            :update blob {digest => refs}
+        }
+        {
+            ?[asset_id, content_type, digest, size, time_attached] <- [[$asset_id, $content_type, $digest, $size, $time]]
+            :put content {asset_id => content_type, digest, size, time_attached}
+        }",
+        params,
+        ScriptMutability::Mutable
+    )?;
+    Ok(())
+}
+
+/// Moves content stored inline by older banks into blobs.  Runs before the
+/// missing relations are created, so the new `content` and `blob` relations
+/// are created in its place.
+pub(crate) fn migrate_inline_content(db: &DbInstance) -> Result<(), cozo::Error> {
+    if !relation_exists(db, "content")? {
+        return Ok(());
+    }
+    let columns = db.run_default("::columns content")?;
+    if !columns.rows.iter().any(|row| row[0].get_str() == Some("content")) {
+        return Ok(());
+    }
+
+    db.run_default("::rename content -> content_inline")?;
+    db.run_default(super::schema::CONTENT_SCHEMA)?;
+    if !relation_exists(db, "blob")? {
+        db.run_default(super::schema::BLOB_SCHEMA)?;
+    }
+
+    let rows = db.run_default(
+        "?[asset_id, content_type, content, time_attached] := *content_inline{asset_id, content_type, content, time_attached}"
+    )?;
+    for row in rows.rows {
+        let (Some(id), Some(content_type)) = (row[0].get_ulid(), row[1].get_str()) else {
+            continue;
+        };
+        // Sealed payloads cannot be read without the key, so they are
+        // addressed by their ciphertext and only share a blob once stored
+        // again.
+        let (digest, size) = match &row[2] {
+            DataValue::Bytes(bytes) => (digest(bytes), bytes.len()),
+            DataValue::List(sealed) => match sealed.get(2).and_then(DataValue::get_bytes) {
+                Some(ciphertext) => (digest(ciphertext), ciphertext.len().saturating_sub(TAG_BYTES)),
+                None => continue,
+            },
+            _ => continue,
+        };
+        put_content(db, id, content_type, digest, size, row[2].clone())?;
+        if let Some(time) = row[3].get_int() {
+            db.run_script(
+                "?[asset_id, time_attached] <- [[$asset_id, $time]] :update content {asset_id => time_attached}",
+                BTreeMap::from([
+                    ("asset_id".to_string(), ulid(id)),
+                    ("time".to_string(), DataValue::from(time)),
+                ]),
+                ScriptMutability::Mutable
+            )?;
+        }
+    }
+    db.run_default("::remove content_inline")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holobank::{cipher::KeySource, each_backend};
+
+    fn blob_refs(bank: &Holobank) -> Vec<i64> {
+        let rows = bank.persistent.run_default("?[digest, refs] := *blob{digest, refs}").unwrap();
+        rows.rows.iter().map(|row| row[1].get_int().unwrap()).collect()
+    }
+
+    #[test]
+    fn identical_content_shares_a_blob() {
+        each_backend(|bank| {
+            let (a, b) = (Ulid::new(), Ulid::new());
+            bank.store_content(a, "file", b"same bytes".to_vec()).unwrap();
+            bank.store_content(b, "file", b"same bytes".to_vec()).unwrap();
+            assert_eq!(blob_refs(&bank), vec![2]);
+
+            let stats = bank.dedup_stats().unwrap();
+            assert_eq!(stats.assets, 2);
+            assert_eq!(stats.blobs, 1);
+            assert_eq!(stats.logical_bytes, 20);
+            assert_eq!(stats.stored_bytes, 10);
+            assert_eq!(stats.saved_bytes, 10);
+
+            assert_eq!(bank.content(a).unwrap().unwrap().1, b"same bytes");
+            assert_eq!(bank.content(b).unwrap().unwrap().1, b"same bytes");
+        });
+    }
+
+    #[test]
+    fn changed_content_moves_the_reference() {
+        each_backend(|bank| {
+            let (a, b) = (Ulid::new(), Ulid::new());
+            bank.store_content(a, "file", b"shared".to_vec()).unwrap();
+            bank.store_content(b, "file", b"shared".to_vec()).unwrap();
+            bank.store_content(b, "file", b"edited".to_vec()).unwrap();
+            let mut refs = blob_refs(&bank);
+            refs.sort();
+            assert_eq!(refs, vec![1, 1]);
+
+            // Storing the same bytes again does not count twice.
+            bank.store_content(b, "file", b"edited".to_vec()).unwrap();
+            assert_eq!(blob_refs(&bank).iter().sum::<i64>(), 2);
+        });
+    }
+
+    #[test]
+    fn garbage_collection_removes_unreferenced_blobs() {
+        each_backend(|bank| {
+            let (a, b) = (Ulid::new(), Ulid::new());
+            bank.store_content(a, "file", b"kept".to_vec()).unwrap();
+            bank.store_content(b, "file", b"dropped".to_vec()).unwrap();
+            bank.remove_content(b).unwrap();
+
+            let report = bank.collect_garbage().unwrap();
+            assert_eq!(report.blobs_removed, 1);
+            assert_eq!(report.bytes_freed, 7);
+            assert_eq!(report.refs_fixed, 0);
+            assert_eq!(blob_refs(&bank), vec![1]);
+
+            // A miscounted blob is recounted rather than removed.
+            bank.persistent.run_default("?[digest, refs] := *blob{digest}, refs = 0 :update blob {digest => refs}").unwrap();
+            let report = bank.collect_garbage().unwrap();
+            assert_eq!((report.blobs_removed, report.refs_fixed), (0, 1));
+            assert_eq!(bank.content(a).unwrap().unwrap().1, b"kept");
+        });
+    }
+
+    #[test]
+    fn encrypted_banks_key_their_digests() {
+        each_backend(|bank| {
+            let key = std::env::temp_dir().join(format!("holobank-key-{}", Ulid::new()));
+            std::fs::write(&key, [9; 32]).unwrap();
+            let (bank, _) = bank.rekey(Some(&KeySource::KeyFile(key.clone()))).unwrap();
+            let _ = std::fs::remove_file(&key);
+
+            let (a, b) = (Ulid::new(), Ulid::new());
+            bank.store_content(a, "file", b"same bytes".to_vec()).unwrap();
+            bank.store_content(b, "file", b"same bytes".to_vec()).unwrap();
+            let keyed = bank.content_digest(a).unwrap().unwrap();
+            assert_ne!(keyed, digest(b"same bytes"));
+            assert_eq!(blob_refs(&bank), vec![2]);
+
+            let (bank, _) = bank.rekey(None).unwrap();
+            assert_eq!(bank.content_digest(a).unwrap(), Some(digest(b"same bytes")));
+            assert_eq!(bank.content(b).unwrap().unwrap().1, b"same bytes");
+            assert_eq!(blob_refs(&bank), vec![2]);
+            assert_eq!(bank.collect_garbage().unwrap().blobs_removed, 0);
+        });
+    }
+}
@@ -0,0 +1,421 @@
+// The change feed records every committed mutation of the asset, tags,
+// flags, collection, content and signature relations in the `changes`
+// relation, each under the next sequence number.  Cozo reports commits
+// through callbacks, which a thread per bank turns into log entries and then
+// broadcasts, so consumers see entries in sequence order and a consumer that
+// falls behind or reconnects catches up from the log starting at its cursor.
+//
+// Entries are logged after their commit, so a crash in between loses them.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Condvar, Mutex},
+    thread,
+};
+
+use cozo::{CallbackOp, DataValue, DbInstance, NamedRows, ScriptMutability};
+use crossbeam_channel::{Receiver, Select, TryRecvError};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+use ulid::Ulid;
+use zenoh::Session;
+
+use super::{now, Holobank};
+
+/// Relations whose mutations are recorded.
+pub const WATCHED_RELATIONS: [&str; 6] = ["asset", "tags", "flags", "collection", "content", "signature"];
+
+/// Changes are published under `constellations/holobank/<bank>/changes/<relation>`.
+pub const HOLOBANK_KEY_PREFIX: &str = "constellations/holobank";
+
+/// Entries read from the log at a time when catching up.
+const CATCH_UP_BATCH: usize = 1000;
+
+/// Live entries buffered for each consumer before it has to catch up from
+/// the log.
+const BROADCAST_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Put,
+    Rm,
+}
+
+impl ChangeOp {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChangeOp::Put => "put",
+            ChangeOp::Rm => "rm",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: u64,
+    /// Time of the change in microseconds.
+    pub time: i64,
+    pub relation: String,
+    pub op: ChangeOp,
+    /// Columns of the row, keys first.  Removals may carry only the keys.
+    /// Content rows name the digest of their blob rather than the bytes.
+    pub row: Vec<Value>,
+}
+
+/// Records changes while any clone of its bank is alive.
+pub(crate) struct ChangeFeed {
+    db: DbInstance,
+    callbacks: Vec<u32>,
+    /// The callback receivers, watched for commits not yet logged.
+    pending: Vec<Receiver<Callback>>,
+    /// Held while a callback is taken and logged, and signalled after.
+    recording: Arc<(Mutex<()>, Condvar)>,
+    sender: broadcast::Sender<Change>,
+    latest: Arc<AtomicU64>,
+}
+
+impl ChangeFeed {
+    pub(crate) fn start(db: &DbInstance) -> Result<ChangeFeed, cozo::Error> {
+        let latest = db.run_default("?[max(seq)] := *changes{seq}")?
+            .rows
+            .first()
+            .and_then(|row| row[0].get_int())
+            .unwrap_or(0) as u64;
+        let latest = Arc::new(AtomicU64::new(latest));
+        let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+
+        let mut callbacks = Vec::new();
+        let mut receivers = Vec::new();
+        for relation in WATCHED_RELATIONS {
+            let (id, receiver) = db.register_callback(relation, None);
+            callbacks.push(id);
+            receivers.push((relation, receiver));
+        }
+
+        let pending = receivers.iter().map(|(_, receiver)| receiver.clone()).collect();
+        let recording = Arc::new((Mutex::new(()), Condvar::new()));
+        let (log, feed, counter, lock) = (db.clone(), sender.clone(), latest.clone(), recording.clone());
+        thread::spawn(move || record(log, receivers, feed, counter, lock));
+
+        Ok(ChangeFeed { db: db.clone(), callbacks, pending, recording, sender, latest })
+    }
+}
+
+impl Drop for ChangeFeed {
+    fn drop(&mut self) {
+        // Dropping the callbacks disconnects the recording thread, which then
+        // exits and releases its handle on the database.
+        for id in &self.callbacks {
+            self.db.unregister_callback(*id);
+        }
+    }
+}
+
+type Callback = (CallbackOp, NamedRows, NamedRows);
+
+/// Logs and broadcasts changes until every callback is unregistered.
+fn record(
+    db: DbInstance,
+    receivers: Vec<(&'static str, Receiver<Callback>)>,
+    sender: broadcast::Sender<Change>,
+    latest: Arc<AtomicU64>,
+    recording: Arc<(Mutex<()>, Condvar)>
+) {
+    let mut select = Select::new();
+    for (_, receiver) in &receivers {
+        select.recv(receiver);
+    }
+
+    let (lock, logged) = &*recording;
+    let mut open = receivers.len();
+    while open > 0 {
+        let i = select.ready();
+        let (relation, receiver) = &receivers[i];
+        // Taking and logging a callback under the lock lets
+        // `Holobank::flush_changes` tell an empty channel from one whose
+        // last callback is still being logged.
+        let guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let (op, rows, _) = match receiver.try_recv() {
+            Ok(callback) => callback,
+            Err(TryRecvError::Empty) => continue,
+            Err(TryRecvError::Disconnected) => {
+                select.remove(i);
+                open -= 1;
+                continue;
+            }
+        };
+
+        let op = match op {
+            CallbackOp::Put => ChangeOp::Put,
+            CallbackOp::Rm => ChangeOp::Rm,
+        };
+        for row in rows.rows {
+            let change = Change {
+                seq: latest.load(Ordering::SeqCst) + 1,
+                time: now(),
+                relation: relation.to_string(),
+                op,
+                row: row.iter().cloned().map(Value::from).collect(),
+            };
+            if let Err(e) = append(&db, &change, row) {
+                warn!("Could not log change to {}: {}", relation, e);
+                continue;
+            }
+            latest.store(change.seq, Ordering::SeqCst);
+            // Nobody listening is fine; the log keeps the change.
+            let _ = sender.send(change);
+        }
+        drop(guard);
+        logged.notify_all();
+    }
+    debug!("Change feed stopped");
+}
+
+fn append(db: &DbInstance, change: &Change, row: Vec<DataValue>) -> Result<(), cozo::Error> {
+    let mut params = BTreeMap::new();
+    params.insert("seq".to_string(), DataValue::from(change.seq as i64));
+    params.insert("time".to_string(), DataValue::from(change.time));
+    params.insert("relation".to_string(), DataValue::from(change.relation.as_str()));
+    params.insert("op".to_string(), DataValue::from(change.op.as_str()));
+    params.insert("row".to_string(), DataValue::List(row));
+    db.run_script(
+        "?[seq, time, relation, op, row] <- [[$seq, $time, $relation, $op, $row]]
+        :put changes {seq => time, relation, op, row}",
+        params,
+        ScriptMutability::Mutable
+    )?;
+    Ok(())
+}
+
+impl Holobank {
+    /// Sequence number of the latest change.
+    pub fn latest_change(&self) -> u64 {
+        self.feed.latest.load(Ordering::SeqCst)
+    }
+
+    /// Waits until every change committed so far is logged and returns the
+    /// sequence number of the latest one.
+    pub fn flush_changes(&self) -> u64 {
+        let (lock, logged) = &*self.feed.recording;
+        let mut recording = lock.lock().unwrap_or_else(|e| e.into_inner());
+        while self.feed.pending.iter().any(|receiver| !receiver.is_empty()) {
+            recording = logged.wait(recording).unwrap_or_else(|e| e.into_inner());
+        }
+        self.latest_change()
+    }
+
+    /// Logged changes after `cursor`, oldest first.
+    pub fn changes_after(&self, cursor: u64, limit: usize) -> Result<Vec<Change>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            &format!(
+                "?[seq, time, relation, op, row] := *changes{{seq, time, relation, op, row}}, seq > $cursor
+                :order seq
+                :limit {}",
+                limit.max(1)
+            ),
+            BTreeMap::from([("cursor".to_string(), DataValue::from(cursor as i64))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(Change {
+                    seq: row[0].get_int()? as u64,
+                    time: row[1].get_int()?,
+                    relation: row[2].get_str()?.to_string(),
+                    op: if row[3].get_str()? == "rm" { ChangeOp::Rm } else { ChangeOp::Put },
+                    row: row[4].get_slice()?.iter().cloned().map(Value::from).collect(),
+                })
+            })
+            .collect())
+    }
+
+    /// Drops logged changes up to and including `seq`.  Consumers with an
+    /// older cursor miss them.
+    pub fn trim_changes(&self, seq: u64) -> Result<(), cozo::Error> {
+        self.persistent.run_script(
+            "?[seq] := *changes{seq}, seq <= $seq :rm changes {seq}",
+            BTreeMap::from([("seq".to_string(), DataValue::from(seq as i64))]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Changes after `cursor` followed by live changes as they happen.
+    /// Without a cursor only live changes are streamed.  Keep the last `seq`
+    /// seen to resume later.
+    pub fn watch(&self, cursor: Option<u64>) -> impl Stream<Item = Change> + Send + 'static {
+        struct Watch {
+            bank: Holobank,
+            receiver: broadcast::Receiver<Change>,
+            cursor: u64,
+            backlog: VecDeque<Change>,
+            catching_up: bool,
+        }
+
+        // Subscribe before reading the log so nothing falls in between.
+        let watch = Watch {
+            bank: self.clone(),
+            receiver: self.feed.sender.subscribe(),
+            cursor: cursor.unwrap_or_else(|| self.latest_change()),
+            backlog: VecDeque::new(),
+            catching_up: cursor.is_some(),
+        };
+
+        futures::stream::unfold(watch, |mut watch| async move {
+            loop {
+                if let Some(change) = watch.backlog.pop_front() {
+                    watch.cursor = change.seq;
+                    return Some((change, watch));
+                }
+                if watch.catching_up {
+                    let (bank, cursor) = (watch.bank.clone(), watch.cursor);
+                    let batch = tokio::task::spawn_blocking(move || bank.changes_after(cursor, CATCH_UP_BATCH))
+                        .await
+                        .ok()?
+                        .map_err(|e| warn!("Could not read change log: {}", e))
+                        .ok()?;
+                    watch.catching_up = batch.len() == CATCH_UP_BATCH;
+                    watch.backlog.extend(batch);
+                    continue;
+                }
+                match watch.receiver.recv().await {
+                    Ok(change) if change.seq <= watch.cursor => continue,
+                    Ok(change) if change.seq == watch.cursor + 1 => {
+                        watch.cursor = change.seq;
+                        return Some((change, watch));
+                    }
+                    // Missed entries are in the log.
+                    Ok(_) | Err(RecvError::Lagged(_)) => watch.catching_up = true,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+/// Publishes the bank's changes over zenoh until the session closes.
+///
+/// Live changes are put on `constellations/holobank/<bank>/changes/<relation>`
+/// as JSON.  Getting `constellations/holobank/<bank>/changes/**?after=<seq>`
+/// replies with logged changes after the cursor, so a subscriber resumes by
+/// querying from its last `seq` before following live changes.
+pub async fn publish(holobank: Holobank, session: Session, bank: Ulid) {
+    let prefix = format!("{}/{}/changes", HOLOBANK_KEY_PREFIX, bank);
+
+    let queryable = match session.declare_queryable(format!("{}/**", prefix)).await {
+        Ok(queryable) => queryable,
+        Err(e) => {
+            warn!("Could not declare change queryable: {}", e);
+            return;
+        }
+    };
+    let replay = {
+        let (holobank, prefix) = (holobank.clone(), prefix.clone());
+        async move {
+            while let Ok(query) = queryable.recv_async().await {
+                let parameters = query.parameters();
+                let cursor = parameters.get("after").and_then(|s| s.parse().ok()).unwrap_or(0);
+                let limit = parameters.get("limit").and_then(|s| s.parse().ok()).unwrap_or(CATCH_UP_BATCH);
+                let bank = holobank.clone();
+                let changes = match tokio::task::spawn_blocking(move || bank.changes_after(cursor, limit)).await {
+                    Ok(Ok(changes)) => changes,
+                    Ok(Err(e)) => {
+                        warn!("Could not read change log: {}", e);
+                        continue;
+                    }
+                    Err(_) => continue,
+                };
+                for change in changes {
+                    let key = format!("{}/{}", prefix, change.relation);
+                    let Ok(payload) = serde_json::to_vec(&change) else { continue };
+                    if let Err(e) = query.reply(key, payload).await {
+                        warn!("Could not reply with change {}: {}", change.seq, e);
+                    }
+                }
+            }
+        }
+    };
+
+    let live = async {
+        let mut changes = Box::pin(holobank.watch(None));
+        while let Some(change) = changes.next().await {
+            let key = format!("{}/{}", prefix, change.relation);
+            let Ok(payload) = serde_json::to_vec(&change) else { continue };
+            if let Err(e) = session.put(key, payload).await {
+                warn!("Could not publish change {}: {}", change.seq, e);
+            }
+        }
+    };
+
+    tokio::join!(replay, live);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use constellations::asset::{block::text::Text, Asset};
+
+    use crate::holobank::each_backend;
+
+    /// Takes changes from the stream until one with `seq` arrives.
+    async fn until(changes: &mut (impl Stream<Item = Change> + Unpin), seq: u64) -> Vec<Change> {
+        let mut taken = Vec::new();
+        while taken.last().is_none_or(|c: &Change| c.seq < seq) {
+            let change = tokio::time::timeout(Duration::from_secs(5), changes.next())
+                .await
+                .expect("change feed stalled")
+                .expect("change feed closed");
+            taken.push(change);
+        }
+        taken
+    }
+
+    #[test]
+    fn records_changes_in_order() {
+        each_backend(|bank| {
+            let text = Text::new("watched", 1);
+            bank.dematerialize(&text, true).unwrap();
+            let latest = bank.flush_changes();
+
+            let changes = bank.changes_after(0, 100).unwrap();
+            assert_eq!(changes.last().unwrap().seq, latest);
+            assert!(changes.windows(2).all(|w| w[1].seq == w[0].seq + 1));
+            let asset = changes.iter().find(|c| c.relation == "asset").unwrap();
+            assert_eq!(asset.op, ChangeOp::Put);
+            assert_eq!(asset.row[0], Value::from(text.id().to_string()));
+
+            let content = changes.iter().find(|c| c.relation == "content").unwrap();
+            assert!(content.row[2].is_string(), "content should carry the blob digest");
+        });
+    }
+
+    #[test]
+    fn resumes_from_cursor() {
+        each_backend(|bank| {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            bank.dematerialize(&Text::new("first", 1), true).unwrap();
+            let cursor = bank.flush_changes();
+
+            bank.dematerialize(&Text::new("second", 1), true).unwrap();
+            let latest = bank.flush_changes();
+
+            runtime.block_on(async {
+                let mut changes = Box::pin(bank.watch(Some(cursor)));
+                let replayed = until(&mut changes, latest).await;
+                assert_eq!(replayed.first().unwrap().seq, cursor + 1);
+
+                // Live changes follow the replayed ones.
+                bank.dematerialize(&Text::new("third", 1), true).unwrap();
+                let live = until(&mut changes, latest + 1).await;
+                assert_eq!(live.first().unwrap().seq, latest + 1);
+            });
+        });
+    }
+}
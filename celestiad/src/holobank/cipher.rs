@@ -0,0 +1,536 @@
+// Payloads of the blob, snapshot and history relations can be encrypted
+// at rest with ChaCha20-Poly1305.  A sealed payload is stored as the list
+// `[key id, nonce, ciphertext]` in place of the plain bytes, so a bank can
+// hold plain and sealed payloads side by side while it is being re-encrypted.
+// The key id names the key a payload was sealed with, which lets a cipher
+// keep older keys around to open payloads sealed before a rotation.
+//
+// The search index and embeddings would give the text away, so encrypted
+// banks keep neither: text is not indexed or embedded while a key is set,
+// and rekeying drops the index when encrypting and rebuilds it when
+// decrypting.  Search and similarity find nothing on an encrypted bank.
+
+use std::{collections::{BTreeMap, HashMap}, fmt, fs, io, path::PathBuf, sync::Arc};
+
+use argon2::Argon2;
+use chacha20poly1305::{aead::{Aead, AeadCore, KeyInit, OsRng}, ChaCha20Poly1305, Key};
+use cozo::{DataValue, ScriptMutability};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::{archive::hex, blob, Holobank};
+
+pub const KEY_LENGTH: usize = 32;
+
+/// Purpose of the salt used to derive keys from passphrases.
+const PASSPHRASE_SALT: &str = "passphrase";
+
+/// Derives the key of content digests from the encryption key.
+const DIGEST_KEY_PURPOSE: &[u8] = b"holobank content digest";
+
+/// Rows rewritten per transaction when rekeying.
+pub const REKEY_BATCH: usize = 256;
+
+/// Where the key comes from.
+#[derive(Clone, Debug)]
+pub enum KeySource {
+    /// Derived with Argon2id and a salt stored in the bank.
+    Passphrase(String),
+    /// A file holding 32 raw bytes or 64 hex digits.
+    KeyFile(PathBuf),
+}
+
+#[derive(Debug)]
+pub enum CipherError {
+    Io(io::Error),
+    /// The key could not be read or derived.
+    Key(String),
+    /// A payload was sealed with a key the cipher does not have.
+    UnknownKey(String),
+    /// A payload does not open with its key; it was tampered with or the
+    /// key is wrong.
+    Decrypt,
+    Database(cozo::Error),
+}
+
+impl fmt::Display for CipherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherError::Io(error) => write!(f, "could not read key: {}", error),
+            CipherError::Key(reason) => write!(f, "invalid key: {}", reason),
+            CipherError::UnknownKey(id) => write!(f, "payload sealed with unknown key {}", id),
+            CipherError::Decrypt => write!(f, "payload could not be decrypted"),
+            CipherError::Database(error) => write!(f, "database failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for CipherError {}
+
+impl From<cozo::Error> for CipherError {
+    fn from(e: cozo::Error) -> Self {
+        CipherError::Database(e)
+    }
+}
+
+impl From<io::Error> for CipherError {
+    fn from(value: io::Error) -> Self {
+        CipherError::Io(value)
+    }
+}
+
+impl From<CipherError> for cozo::Error {
+    fn from(value: CipherError) -> Self {
+        match value {
+            CipherError::Database(error) => error,
+            other => cozo::Error::msg(other.to_string()),
+        }
+    }
+}
+
+/// Seals payloads with one key and opens payloads sealed with any key it
+/// knows.
+#[derive(Clone)]
+pub struct Cipher {
+    current: String,
+    keys: HashMap<String, ChaCha20Poly1305>,
+    /// Keys content digests, derived from the current key.
+    digest_key: Hmac<Sha256>,
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_LENGTH]) -> Cipher {
+        let id = key_id(key);
+        let keys = HashMap::from([(id.clone(), ChaCha20Poly1305::new(Key::from_slice(key)))]);
+        let mut derivation = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+        derivation.update(DIGEST_KEY_PURPOSE);
+        let digest_key = <Hmac<Sha256> as Mac>::new_from_slice(&derivation.finalize().into_bytes())
+            .expect("HMAC takes keys of any length");
+        Cipher { current: id, keys, digest_key }
+    }
+
+    /// Keeps the keys of `previous` for opening older payloads.
+    pub fn with_previous(mut self, previous: &Cipher) -> Cipher {
+        for (id, key) in &previous.keys {
+            self.keys.entry(id.clone()).or_insert_with(|| key.clone());
+        }
+        self
+    }
+
+    /// Whether payloads sealed with the key `id` open.
+    pub fn knows(&self, id: &str) -> bool {
+        self.keys.contains_key(id)
+    }
+
+    /// Id of the key new payloads are sealed with.
+    pub fn key_id(&self) -> &str {
+        &self.current
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> DataValue {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.keys[&self.current]
+            .encrypt(&nonce, plaintext)
+            .expect("encrypting in memory cannot fail");
+        DataValue::List(vec![
+            DataValue::from(self.current.as_str()),
+            DataValue::Bytes(nonce.to_vec()),
+            DataValue::Bytes(ciphertext),
+        ])
+    }
+
+    /// HMAC-SHA256 of content, which identifies it without revealing the
+    /// sha256 of the plaintext.
+    pub fn digest(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut mac = self.digest_key.clone();
+        mac.update(bytes);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    pub fn open(&self, sealed: &[DataValue]) -> Result<Vec<u8>, CipherError> {
+        let [id, nonce, ciphertext] = sealed else {
+            return Err(CipherError::Decrypt);
+        };
+        let (Some(id), Some(nonce), Some(ciphertext)) = (id.get_str(), nonce.get_bytes(), ciphertext.get_bytes()) else {
+            return Err(CipherError::Decrypt);
+        };
+        if nonce.len() != 12 {
+            return Err(CipherError::Decrypt);
+        }
+        let key = self.keys.get(id).ok_or_else(|| CipherError::UnknownKey(id.to_string()))?;
+        key.decrypt(nonce.into(), ciphertext).map_err(|_| CipherError::Decrypt)
+    }
+}
+
+/// Identifies a key without giving it away.
+fn key_id(key: &[u8; KEY_LENGTH]) -> String {
+    hex(&Sha256::digest(key)[..8])
+}
+
+/// Reads a key file of raw bytes or hex digits.
+pub fn read_key_file(path: &std::path::Path) -> Result<[u8; KEY_LENGTH], CipherError> {
+    let bytes = fs::read(path)?;
+    if let Ok(key) = <[u8; KEY_LENGTH]>::try_from(bytes.as_slice()) {
+        return Ok(key);
+    }
+    let digits = String::from_utf8_lossy(&bytes);
+    let digits = digits.trim();
+    if digits.len() != KEY_LENGTH * 2 {
+        return Err(CipherError::Key(format!("{} must hold {} bytes", path.display(), KEY_LENGTH)));
+    }
+    let mut key = [0u8; KEY_LENGTH];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16)
+            .map_err(|_| CipherError::Key(format!("{} is not hex", path.display())))?;
+    }
+    Ok(key)
+}
+
+impl Holobank {
+    /// Seals payloads with the key from `source` from now on.  Payloads
+    /// already stored are left as they are; see [`Holobank::rekey`].
+    pub fn with_encryption(mut self, source: &KeySource) -> Result<Holobank, CipherError> {
+        self.cipher = Some(Arc::new(self.cipher_for(source)?));
+        Ok(self)
+    }
+
+    /// Re-encrypts every payload with the key from `source`, or decrypts them
+    /// when there is none, and returns the bank using the new key with the
+    /// number of payloads rewritten.  Run it on a bank opened with its
+    /// current key; payloads sealed with keys it does not know fail the
+    /// rotation before anything is written.  Blobs move to digests keyed
+    /// with the new key.  The search index and embeddings are dropped when
+    /// encrypting and rebuilt when decrypting.
+    ///
+    /// Payloads are rewritten [`REKEY_BATCH`] rows at a time, each batch in
+    /// one transaction, and rows already in the new form are left alone.
+    /// While a rotation runs, the new key is kept alongside the old one, so
+    /// an interrupted rotation is finished by running it again from the old
+    /// key with the same new one.
+    pub fn rekey(mut self, source: Option<&KeySource>) -> Result<(Holobank, usize), CipherError> {
+        let previous = self.cipher.take();
+        let next = source.map(|source| self.cipher_for(source)).transpose()?;
+        let opener = match (&next, &previous) {
+            (Some(next), Some(previous)) => Some(next.clone().with_previous(previous)),
+            (Some(next), None) => Some(next.clone()),
+            (None, previous) => previous.as_deref().cloned(),
+        };
+
+        // Check every key first so an unknown key aborts the rotation.
+        for relation in ["blob", "snapshot", "history"] {
+            for id in self.sealing_keys(relation).map_err(CipherError::Database)? {
+                if !opener.as_ref().is_some_and(|cipher| cipher.knows(&id)) {
+                    return Err(CipherError::UnknownKey(id));
+                }
+            }
+        }
+
+        let mut rewritten = 0;
+        for relation in ["blob", "snapshot", "history"] {
+            let keys = self.payload_keys(relation).map_err(CipherError::Database)?;
+            for batch in keys.chunks(REKEY_BATCH) {
+                let mut rows = Vec::new();
+                let mut moves = Vec::new();
+                for (keys, payload) in self.payloads(relation, batch).map_err(CipherError::Database)? {
+                    let plain = match (&payload, &next) {
+                        (DataValue::Bytes(_), None) => continue,
+                        (DataValue::List(sealed), Some(cipher))
+                            if sealed.first().and_then(DataValue::get_str) == Some(cipher.key_id()) => continue,
+                        (DataValue::Bytes(bytes), Some(_)) => bytes.clone(),
+                        (DataValue::List(sealed), _) => match &opener {
+                            Some(cipher) => cipher.open(sealed)?,
+                            None => {
+                                let id = sealed.first().and_then(DataValue::get_str).unwrap_or_default();
+                                return Err(CipherError::UnknownKey(id.to_string()));
+                            }
+                        },
+                        _ => continue,
+                    };
+                    let payload = match &next {
+                        Some(cipher) => cipher.seal(&plain),
+                        None => DataValue::Bytes(plain.clone()),
+                    };
+                    // Blobs are addressed by a digest keyed like their payload.
+                    match (relation, keys.first()) {
+                        ("blob", Some(DataValue::Bytes(old))) => {
+                            let digest = match &next {
+                                Some(cipher) => cipher.digest(&plain),
+                                None => blob::digest(&plain),
+                            };
+                            moves.push((old.clone(), digest, payload));
+                        }
+                        _ => rows.push((keys, payload)),
+                    }
+                }
+                rewritten += rows.len() + moves.len();
+                self.put_payloads(relation, rows).map_err(CipherError::Database)?;
+                self.move_blobs(moves).map_err(CipherError::Database)?;
+            }
+        }
+
+        self.cipher = next.map(Arc::new);
+        self.rebuild_index()?;
+        Ok((self, rewritten))
+    }
+
+    /// Seals a payload for storage when encryption is on.
+    pub(crate) fn seal(&self, plaintext: Vec<u8>) -> DataValue {
+        match &self.cipher {
+            Some(cipher) => cipher.seal(&plaintext),
+            None => DataValue::Bytes(plaintext),
+        }
+    }
+
+    /// Opens a stored payload, plain or sealed.
+    pub(crate) fn unseal(&self, payload: &DataValue) -> Result<Option<Vec<u8>>, CipherError> {
+        match (payload, &self.cipher) {
+            (DataValue::Bytes(bytes), _) => Ok(Some(bytes.clone())),
+            (DataValue::List(sealed), Some(cipher)) => cipher.open(sealed).map(Some),
+            (DataValue::List(sealed), None) => {
+                let id = sealed.first().and_then(DataValue::get_str).unwrap_or_default();
+                Err(CipherError::UnknownKey(id.to_string()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn cipher_for(&self, source: &KeySource) -> Result<Cipher, CipherError> {
+        let key = match source {
+            KeySource::KeyFile(path) => read_key_file(path)?,
+            KeySource::Passphrase(passphrase) => {
+                let salt = self.salt(PASSPHRASE_SALT).map_err(CipherError::Database)?;
+                let mut key = [0u8; KEY_LENGTH];
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+                    .map_err(|e| CipherError::Key(e.to_string()))?;
+                key
+            }
+        };
+        Ok(Cipher::new(&key))
+    }
+
+    /// The bank's salt for a purpose, created on first use.
+    fn salt(&self, purpose: &str) -> Result<Vec<u8>, cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("purpose".to_string(), DataValue::from(purpose));
+        let rows = self.persistent.run_script(
+            "?[salt] := *salt{purpose: $purpose, salt}",
+            params.clone(),
+            ScriptMutability::Immutable
+        )?;
+        if let Some(salt) = rows.rows.first().and_then(|row| row[0].get_bytes()) {
+            return Ok(salt.to_vec());
+        }
+
+        let salt = ChaCha20Poly1305::generate_key(&mut OsRng).to_vec();
+        params.insert("salt".to_string(), DataValue::Bytes(salt.clone()));
+        self.persistent.run_script(
+            "?[purpose, salt] <- [[$purpose, $salt]] :put salt {purpose => salt}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(salt)
+    }
+
+    /// Ids of the keys payloads of a relation are sealed with.
+    fn sealing_keys(&self, relation: &str) -> Result<Vec<String>, cozo::Error> {
+        let (_, payload) = payload_columns(relation);
+        let rows = self.persistent.run_default(
+            &format!("?[id] := *{relation}{{{payload}}}, is_list({payload}), id = first({payload})", relation = relation, payload = payload)
+        )?;
+        Ok(rows.rows
+            .into_iter()
+            .filter_map(|row| row[0].get_str().map(str::to_string))
+            .collect())
+    }
+
+    /// Key columns of every row of a payload relation.
+    fn payload_keys(&self, relation: &str) -> Result<Vec<DataValue>, cozo::Error> {
+        let (keys, _) = payload_columns(relation);
+        let rows = self.persistent.run_default(
+            &format!("?[{keys}] := *{relation}{{{keys}}}", keys = keys.join(", "), relation = relation)
+        )?;
+        Ok(rows.rows.into_iter().map(DataValue::List).collect())
+    }
+
+    /// Key columns and payload of the rows of a payload relation with the
+    /// given keys.
+    fn payloads(&self, relation: &str, keys: &[DataValue]) -> Result<Vec<(Vec<DataValue>, DataValue)>, cozo::Error> {
+        let (columns, payload) = payload_columns(relation);
+        let rows = self.persistent.run_script(
+            &format!(
+                "wanted[{keys}] <- $keys
+                ?[{keys}, {payload}] := wanted[{keys}], *{relation}{{{keys}, {payload}}}",
+                keys = columns.join(", "),
+                payload = payload,
+                relation = relation
+            ),
+            BTreeMap::from([("keys".to_string(), DataValue::List(keys.to_vec()))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows
+            .into_iter()
+            .map(|mut row| {
+                let payload = row.pop().unwrap_or(DataValue::Null);
+                (row, payload)
+            })
+            .collect())
+    }
+
+    /// Replaces the payloads of rows of a payload relation in one
+    /// transaction.
+    fn put_payloads(&self, relation: &str, rows: Vec<(Vec<DataValue>, DataValue)>) -> Result<(), cozo::Error> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let (columns, column) = payload_columns(relation);
+        let rows = rows
+            .into_iter()
+            .map(|(mut keys, payload)| {
+                keys.push(payload);
+                DataValue::List(keys)
+            })
+            .collect();
+        self.persistent.run_script(
+            &format!(
+                "?[{keys}, {column}] <- $rows :update {relation} {{{keys} => {column}}}",
+                keys = columns.join(", "),
+                column = column,
+                relation = relation
+            ),
+            BTreeMap::from([("rows".to_string(), DataValue::List(rows))]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+}
+
+/// Key columns and payload column of the relations holding payloads.
+fn payload_columns(relation: &str) -> (&'static [&'static str], &'static str) {
+    match relation {
+        "blob" => (&["digest"], "payload"),
+        "snapshot" => (&["asset_id", "time"], "latest"),
+        _ => (&["asset_id", "time"], "edit"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constellations::asset::{block::text::Text, Asset};
+    use ulid::Ulid;
+
+    use crate::holobank::{each_backend, search::SearchQuery};
+
+    fn key_file(byte: u8) -> KeySource {
+        let path = std::env::temp_dir().join(format!("holobank-key-{}", Ulid::new()));
+        fs::write(&path, [byte; KEY_LENGTH]).unwrap();
+        KeySource::KeyFile(path)
+    }
+
+    fn stored(bank: &Holobank, id: Ulid) -> DataValue {
+        bank.sealed_content(id).unwrap().unwrap().1
+    }
+
+    #[test]
+    fn seals_and_opens() {
+        let cipher = Cipher::new(&[7; KEY_LENGTH]);
+        let DataValue::List(sealed) = cipher.seal(b"secret") else {
+            panic!("not sealed");
+        };
+        assert_eq!(cipher.open(&sealed).unwrap(), b"secret");
+
+        let other = Cipher::new(&[8; KEY_LENGTH]);
+        assert!(matches!(other.open(&sealed), Err(CipherError::UnknownKey(_))));
+        assert_eq!(other.with_previous(&cipher).open(&sealed).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let cipher = Cipher::new(&[7; KEY_LENGTH]);
+        let DataValue::List(mut sealed) = cipher.seal(b"secret") else {
+            panic!("not sealed");
+        };
+        if let DataValue::Bytes(ciphertext) = &mut sealed[2] {
+            ciphertext[0] ^= 1;
+        }
+        assert!(matches!(cipher.open(&sealed), Err(CipherError::Decrypt)));
+    }
+
+    #[test]
+    fn encrypts_content_at_rest() {
+        each_backend(|bank| {
+            let bank = bank.with_encryption(&KeySource::Passphrase("hunter2".to_string())).unwrap();
+            let text = Text::new("my password is swordfish", 1);
+            bank.dematerialize(&text, true).unwrap();
+
+            assert!(matches!(stored(&bank, text.id()), DataValue::List(_)));
+            let (_, content) = bank.content(text.id()).unwrap().unwrap();
+            assert_eq!(Text::decode(&content).unwrap().as_str(), "my password is swordfish");
+        });
+    }
+
+    #[test]
+    fn rotates_keys() {
+        each_backend(|bank| {
+            let text = Text::new("plain at first", 1);
+            bank.dematerialize(&text, true).unwrap();
+            bank.record_snapshot(text.id(), b"frozen".to_vec(), 10).unwrap();
+
+            let (bank, rewritten) = bank.rekey(Some(&key_file(1))).unwrap();
+            assert_eq!(rewritten, 2);
+            let (bank, _) = bank.rekey(Some(&key_file(2))).unwrap();
+
+            assert!(matches!(stored(&bank, text.id()), DataValue::List(_)));
+            assert_eq!(bank.snapshot_at(text.id(), 10).unwrap(), Some(b"frozen".to_vec()));
+
+            let (bank, _) = bank.rekey(None).unwrap();
+            assert!(matches!(stored(&bank, text.id()), DataValue::Bytes(_)));
+        });
+    }
+
+    #[test]
+    fn finishes_interrupted_rotations() {
+        each_backend(|bank| {
+            let (old, new) = (key_file(3), key_file(4));
+            let (bank, _) = bank.rekey(Some(&old)).unwrap();
+            let before = Text::new("sealed with the old key", 1);
+            bank.dematerialize(&before, true).unwrap();
+
+            // A rotation cut short leaves rows sealed with either key.
+            let bank = bank.with_encryption(&new).unwrap();
+            let after = Text::new("sealed with the new key", 1);
+            bank.dematerialize(&after, true).unwrap();
+
+            let bank = bank.with_encryption(&old).unwrap();
+            let (bank, rewritten) = bank.rekey(Some(&new)).unwrap();
+            assert_eq!(rewritten, 1);
+            for text in [&before, &after] {
+                let (_, content) = bank.content(text.id()).unwrap().unwrap();
+                assert_eq!(Text::decode(&content).unwrap().as_str(), text.as_str());
+            }
+        });
+    }
+
+    #[test]
+    fn keeps_no_plaintext_index() {
+        each_backend(|bank| {
+            let text = Text::new("the vault code is 4711", 1);
+            bank.dematerialize(&text, true).unwrap();
+            let query = SearchQuery { text: "vault".to_string(), ..Default::default() };
+            assert_eq!(bank.search(&query).unwrap().len(), 1);
+
+            let (bank, _) = bank.rekey(Some(&key_file(5))).unwrap();
+            assert!(bank.search(&query).unwrap().is_empty());
+            let indexed = bank.persistent.run_default("?[asset_id] := *text{asset_id}").unwrap();
+            assert!(indexed.rows.is_empty());
+
+            let other = Text::new("another vault", 1);
+            bank.dematerialize(&other, true).unwrap();
+            assert!(bank.search(&query).unwrap().is_empty());
+
+            let (bank, _) = bank.rekey(None).unwrap();
+            assert_eq!(bank.search(&query).unwrap().len(), 2);
+        });
+    }
+}
@@ -0,0 +1,210 @@
+// Collections live in two relations: `catalog` names them and `collection`
+// lists their assets.  The type of each asset comes from its registration,
+// and a collection forked from another is connected to it by a `fork`
+// connection like forked assets are.  Their sharing policies are kept by
+// the sharing module and the queries of dynamic collections by the dynamic
+// module.
+
+use std::collections::{BTreeMap, HashSet};
+
+use constellations::{
+    asset::AssetType,
+    collection::{Collection, SharingPolicy},
+    connection::ConnectionType,
+};
+use cozo::{DataValue, ScriptMutability};
+use ulid::Ulid;
+
+use super::{now, ulid, Holobank};
+
+impl Holobank {
+    /// Loads a collection, or `None` when the bank knows neither its name nor
+    /// any of its assets.  Assets that were never registered are left out.
+    pub fn collection(&self, id: Ulid) -> Result<Option<Collection>, cozo::Error> {
+        let params = BTreeMap::from([("collection_id".to_string(), ulid(id))]);
+        let name = self.persistent.run_script(
+            "?[name] := *catalog{collection_id: $collection_id, name}",
+            params.clone(),
+            ScriptMutability::Immutable
+        )?;
+        let assets = self.persistent.run_script(
+            "?[asset_id, asset_type] := *collection{asset_id, collection_id: $collection_id},
+                *asset{asset_id, asset_type}",
+            params.clone(),
+            ScriptMutability::Immutable
+        )?;
+        let name = name.rows.first().and_then(|row| row[0].get_str()).map(str::to_string);
+        if name.is_none() && assets.rows.is_empty() {
+            return Ok(None);
+        }
+
+        let origin = self.lineage(id)?
+            .into_iter()
+            .find(|connection| connection.dest == id && connection.kind == ConnectionType::Fork);
+        let assets = assets.rows.iter().filter_map(|row| {
+            Some((row[0].get_ulid()?, row[1].get_str()?.parse().ok()?))
+        });
+        let mut collection = Collection::from_parts(id, name.unwrap_or_default(), origin, assets);
+        collection.policy = self.sharing_policy(id)?;
+        collection.query = self.collection_query(id)?;
+        Ok(Some(collection))
+    }
+
+    /// Persists a collection: its name, its assets, registering those the
+    /// bank does not know yet, the fork it came from and its policy.  Assets
+    /// no longer in the collection leave it; the others keep the time they
+    /// were added.  The assets of a static collection leave the inbox, while
+    /// a dynamic collection stores its query and gets the assets matching it.
+    pub fn store_collection(&self, collection: &Collection) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("collection_id".to_string(), ulid(collection.id));
+        params.insert("name".to_string(), DataValue::from(collection.name.as_str()));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "?[collection_id, name, time_created] := collection_id = $collection_id, name = $name,
+                *catalog{collection_id: $collection_id, time_created}
+            ?[collection_id, name, time_created] := collection_id = $collection_id, name = $name,
+                not *catalog{collection_id: $collection_id}, time_created = $time
+            :put catalog {collection_id => name, time_created}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+
+        self.set_collection_query(collection.id, collection.query.as_ref())?;
+        match &collection.query {
+            Some(query) => {
+                let members = self.evaluate(query)?;
+                self.set_members(collection.id, &members)?;
+            }
+            None => {
+                let members: Vec<(Ulid, AssetType)> = collection.assets().collect();
+                self.set_members(collection.id, &members)?;
+                if collection.id != self.inbox {
+                    let filed: Vec<Ulid> = members.iter().map(|(id, _)| *id).collect();
+                    self.leave_inbox(&filed)?;
+                }
+            }
+        }
+
+        if let Some(origin) = collection.origin() {
+            self.connect(&origin)?;
+        }
+        self.set_sharing_policy(collection.id, &collection.policy)
+    }
+
+    /// Replaces the members of a collection, registering assets the bank
+    /// does not know yet.  Returns whether the membership changed.
+    pub(crate) fn set_members(&self, collection: Ulid, members: &[(Ulid, AssetType)]) -> Result<bool, cozo::Error> {
+        let assets = members
+            .iter()
+            .map(|(id, asset_type)| DataValue::List(vec![ulid(*id), DataValue::from(asset_type.as_str())]))
+            .collect();
+        let mut params = BTreeMap::new();
+        params.insert("collection_id".to_string(), ulid(collection));
+        params.insert("assets".to_string(), DataValue::List(assets));
+        params.insert("time".to_string(), DataValue::from(now()));
+
+        let current = self.persistent.run_script(
+            "?[asset_id] := *collection{asset_id, collection_id: $collection_id}",
+            params.clone(),
+            ScriptMutability::Immutable
+        )?;
+        let current: HashSet<Ulid> = current.rows.iter().filter_map(|row| row[0].get_ulid()).collect();
+        if current.len() == members.len() && members.iter().all(|(id, _)| current.contains(id)) {
+            return Ok(false);
+        }
+
+        self.persistent.run_script(
+            "{
+                member[asset_id, asset_type] <- $assets
+                ?[asset_id, asset_type, time_registered] := member[asset_id, asset_type],
+                    not *asset{asset_id}, time_registered = $time
+                :put asset {asset_id => asset_type, time_registered}
+            }
+            {
+                member[asset_id, asset_type] <- $assets
+                kept[asset_id] := member[asset_id, _]
+                ?[asset_id, collection_id] := *collection{asset_id, collection_id: $collection_id},
+                    collection_id = $collection_id, not kept[asset_id]
+                :rm collection {asset_id, collection_id}
+            }
+            {
+                member[asset_id, asset_type] <- $assets
+                ?[asset_id, collection_id, time_added] := member[asset_id, _], collection_id = $collection_id,
+                    not *collection{asset_id, collection_id: $collection_id}, time_added = $time
+                :put collection {asset_id, collection_id => time_added}
+            }",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(true)
+    }
+
+    /// Forgets a collection and stops sharing it.  Its assets stay in the
+    /// bank.
+    pub fn remove_collection(&self, id: Ulid) -> Result<(), cozo::Error> {
+        self.persistent.run_script(
+            "{
+                ?[collection_id] := *catalog{collection_id: $collection_id}, collection_id = $collection_id
+                :rm catalog {collection_id}
+            }
+            {
+                ?[asset_id, collection_id] := *collection{asset_id, collection_id: $collection_id},
+                    collection_id = $collection_id
+                :rm collection {asset_id, collection_id}
+            }",
+            BTreeMap::from([("collection_id".to_string(), ulid(id))]),
+            ScriptMutability::Mutable
+        )?;
+        self.set_collection_query(id, None)?;
+        self.set_sharing_policy(id, &SharingPolicy::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use constellations::collection::Role;
+
+    use super::*;
+    use crate::holobank::each_backend;
+
+    #[test]
+    fn bank_round_trip() {
+        each_backend(|bank| {
+            let mut collection = Collection::new("notes");
+            let (block, assembly) = (Ulid::new(), Ulid::new());
+            collection.add_asset(block, AssetType::Block);
+            collection.add_asset(assembly, AssetType::Assembly);
+            bank.store_collection(&collection).unwrap();
+            assert_eq!(bank.collection(collection.id).unwrap(), Some(collection.clone()));
+
+            collection.remove_asset(assembly);
+            collection.name = "renamed".to_string();
+            collection.share_with(Ulid::new());
+            collection.grant(Ulid::new(), Role::Write);
+            bank.store_collection(&collection).unwrap();
+            assert_eq!(bank.collection(collection.id).unwrap(), Some(collection.clone()));
+
+            let forked = collection.fork("forked");
+            bank.store_collection(&forked).unwrap();
+            let loaded = bank.collection(forked.id).unwrap().unwrap();
+            assert_eq!(loaded.origin(), forked.origin());
+            assert!(loaded.contains(block));
+
+            bank.remove_collection(collection.id).unwrap();
+            assert_eq!(bank.collection(collection.id).unwrap(), None);
+            assert!(bank.sharing_policy(collection.id).unwrap().is_private());
+            assert!(bank.collection(forked.id).unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn keeps_empty_collections() {
+        each_backend(|bank| {
+            let collection = Collection::new("empty");
+            bank.store_collection(&collection).unwrap();
+            assert_eq!(bank.collection(collection.id).unwrap(), Some(collection));
+            assert_eq!(bank.collection(Ulid::new()).unwrap(), None);
+        });
+    }
+}
@@ -0,0 +1,309 @@
+// Blocks have a single holder, yet two banks cut off from each other can
+// both change an asset, e.g. when a lease expired while its holder was
+// offline.  Replication notices when content arriving from a source differs
+// from both the local content and the content last received from that
+// source: both sides changed it.  The local version then moves to a fork of
+// the asset, connected to it by a `conflict` connection besides the usual
+// `fork` one, the incoming version takes its place and both are flagged for
+// review until the conflict is resolved.
+
+use std::{collections::BTreeMap, fmt};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use constellations::{
+    connection::{Connection, ConnectionType},
+    user::signed::Operation,
+};
+use cozo::{DataValue, ScriptMutability};
+use serde::{Deserialize, Deserializer, Serialize};
+use ulid::Ulid;
+
+use super::{blob, now, ulid, Holobank};
+
+/// Flag put on both versions of an asset in conflict.
+pub const CONFLICT_FLAG: &str = "conflict";
+
+/// An asset with a version that conflicts with it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Conflict {
+    pub asset_id: Ulid,
+    /// Fork holding the conflicting version.
+    pub fork_id: Ulid,
+    pub time_detected: i64,
+}
+
+/// How to settle a conflict.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    /// Keep the content of this version, either the asset or one of its
+    /// conflicting forks.
+    Keep(Ulid),
+    /// Replace the content with a merge of the versions, written as base64
+    /// in JSON.
+    Merge(#[serde(deserialize_with = "base64_bytes")] Vec<u8>),
+}
+
+fn base64_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    STANDARD.decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug)]
+pub enum ConflictError {
+    /// The asset has no conflict.
+    NoConflict(Ulid),
+    /// The version to keep is not part of the conflict.
+    UnknownVersion(Ulid),
+    Database(cozo::Error),
+}
+
+impl fmt::Display for ConflictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictError::NoConflict(id) => write!(f, "asset {} has no conflict", id),
+            ConflictError::UnknownVersion(id) => write!(f, "{} is not a version in the conflict", id),
+            ConflictError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<cozo::Error> for ConflictError {
+    fn from(e: cozo::Error) -> Self {
+        ConflictError::Database(e)
+    }
+}
+
+impl Holobank {
+    /// Moves the local version of an asset to a new fork in the same
+    /// collections and links the two by a conflict.  Returns the fork.
+    pub(crate) fn fork_conflict(&self, asset: Ulid) -> Result<Ulid, cozo::Error> {
+        let fork = Ulid::new();
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(asset));
+        params.insert("fork_id".to_string(), ulid(fork));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "{
+                ?[asset_id, name, derived_from, asset_type, derivation_type, time_registered] :=
+                    *asset{asset_id: $asset_id, name, asset_type},
+                    asset_id = $fork_id, derived_from = $asset_id, derivation_type = 'fork', time_registered = $time
+                :put asset {asset_id, name, derived_from => asset_type, derivation_type, time_registered}
+            }
+            {
+                ?[asset_id, collection_id, time_added] := *collection{asset_id: $asset_id, collection_id},
+                    asset_id = $fork_id, time_added = $time
+                :put collection {asset_id, collection_id => time_added}
+            }
+            {
+                ?[asset_id, tag, time_attached] := *tags{asset_id: $asset_id, tag},
+                    asset_id = $fork_id, time_attached = $time
+                :put tags {asset_id, tag => time_attached}
+            }",
+            params,
+            ScriptMutability::Mutable
+        )?;
+
+        if let Some((content_type, payload)) = self.sealed_content(asset)? {
+            if let Some(bytes) = self.unseal(&payload)? {
+                self.store_content(fork, &content_type, bytes)?;
+            }
+        }
+        self.connect(&Connection::new(asset, fork, ConnectionType::Fork))?;
+        self.connect(&Connection::new(asset, fork, ConnectionType::Conflict))?;
+        self.set_conflict_flag(&[asset, fork], true)?;
+        Ok(fork)
+    }
+
+    /// Assets with unresolved conflicts, one entry per conflicting fork.
+    pub fn conflicts(&self) -> Result<Vec<Conflict>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[src, dest, time_created] := *connection{src, dest, type: $type, time_created}",
+            BTreeMap::from([("type".to_string(), DataValue::from(ConnectionType::Conflict.as_str()))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows
+            .iter()
+            .filter_map(|row| Some(Conflict {
+                asset_id: row[0].get_ulid()?,
+                fork_id: row[1].get_ulid()?,
+                time_detected: row[2].get_int()?,
+            }))
+            .collect())
+    }
+
+    /// Settles every conflict of an asset.  The chosen or merged content
+    /// becomes the asset's content and the forks leave the asset's
+    /// collections, staying connected to it as forks.  The new content is
+    /// signed by the bank's commander.
+    pub fn resolve_conflict(&self, asset: Ulid, resolution: Resolution) -> Result<(), ConflictError> {
+        let forks: Vec<Ulid> = self.conflicts()?
+            .into_iter()
+            .filter(|conflict| conflict.asset_id == asset)
+            .map(|conflict| conflict.fork_id)
+            .collect();
+        if forks.is_empty() {
+            return Err(ConflictError::NoConflict(asset));
+        }
+
+        match resolution {
+            Resolution::Keep(version) if version == asset => {}
+            Resolution::Keep(version) if forks.contains(&version) => {
+                if let Some((content_type, payload)) = self.sealed_content(version)? {
+                    if let Some(bytes) = self.unseal(&payload).map_err(cozo::Error::from)? {
+                        let digest = blob::digest(&bytes);
+                        self.store_content(asset, &content_type, bytes)?;
+                        self.sign(Operation::Edit { asset, digest })?;
+                    }
+                }
+            }
+            Resolution::Keep(version) => return Err(ConflictError::UnknownVersion(version)),
+            Resolution::Merge(bytes) => {
+                let content_type = self.sealed_content(asset)?
+                    .map(|(content_type, _)| content_type)
+                    .unwrap_or_default();
+                let digest = blob::digest(&bytes);
+                self.store_content(asset, &content_type, bytes)?;
+                self.sign(Operation::Edit { asset, digest })?;
+            }
+        }
+
+        for fork in &forks {
+            self.disconnect(&Connection::new(asset, *fork, ConnectionType::Conflict))?;
+            self.persistent.run_script(
+                "?[asset_id, collection_id] := *collection{asset_id, collection_id}, asset_id = $asset_id
+                :rm collection {asset_id, collection_id}",
+                BTreeMap::from([("asset_id".to_string(), ulid(*fork))]),
+                ScriptMutability::Mutable
+            )?;
+        }
+        let mut settled = forks;
+        settled.push(asset);
+        self.set_conflict_flag(&settled, false)?;
+        Ok(())
+    }
+
+    fn set_conflict_flag(&self, assets: &[Ulid], flagged: bool) -> Result<(), cozo::Error> {
+        let rows = assets
+            .iter()
+            .map(|id| {
+                let mut row = vec![ulid(*id), DataValue::from(CONFLICT_FLAG)];
+                if flagged {
+                    row.push(DataValue::from(now()));
+                }
+                DataValue::List(row)
+            })
+            .collect();
+        let script = if flagged {
+            "?[asset_id, flag, time_attached] <- $rows :put flags {asset_id, flag => time_attached}"
+        } else {
+            "?[asset_id, flag] <- $rows :rm flags {asset_id, flag}"
+        };
+        self.persistent.run_script(
+            script,
+            BTreeMap::from([("rows".to_string(), DataValue::List(rows))]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constellations::asset::{block::text::Text, Asset};
+
+    use crate::holobank::{each_backend, search::SearchQuery};
+
+    fn flagged(bank: &Holobank, asset: Ulid) -> bool {
+        let rows = bank.persistent.run_script(
+            "?[flag] := *flags{asset_id: $asset_id, flag}, flag = $flag",
+            BTreeMap::from([
+                ("asset_id".to_string(), ulid(asset)),
+                ("flag".to_string(), DataValue::from(CONFLICT_FLAG)),
+            ]),
+            ScriptMutability::Immutable
+        ).unwrap();
+        !rows.rows.is_empty()
+    }
+
+    fn conflicted(bank: &Holobank) -> (Ulid, Ulid) {
+        let asset = Ulid::new();
+        bank.persistent.run_script(
+            "?[asset_id, asset_type, time_registered] <- [[$asset_id, 'block', 0]]
+            :put asset {asset_id => asset_type, time_registered}",
+            BTreeMap::from([("asset_id".to_string(), ulid(asset))]),
+            ScriptMutability::Mutable
+        ).unwrap();
+        bank.store_content(asset, "file", b"ours".to_vec()).unwrap();
+        let fork = bank.fork_conflict(asset).unwrap();
+        bank.store_content(asset, "file", b"theirs".to_vec()).unwrap();
+        (asset, fork)
+    }
+
+    #[test]
+    fn keeps_both_versions() {
+        each_backend(|bank| {
+            let (asset, fork) = conflicted(&bank);
+            assert_eq!(bank.conflicts().unwrap().len(), 1);
+            assert_eq!(bank.content(fork).unwrap().unwrap().1, b"ours");
+            assert_eq!(bank.content(asset).unwrap().unwrap().1, b"theirs");
+            assert!(flagged(&bank, asset) && flagged(&bank, fork));
+            assert!(bank.lineage(fork).unwrap().iter().any(|c| c.src == asset));
+        });
+    }
+
+    #[test]
+    fn resolves_by_choosing_or_merging() {
+        each_backend(|bank| {
+            let (asset, fork) = conflicted(&bank);
+            assert!(matches!(
+                bank.resolve_conflict(asset, Resolution::Keep(Ulid::new())),
+                Err(ConflictError::UnknownVersion(_))
+            ));
+            bank.resolve_conflict(asset, Resolution::Keep(fork)).unwrap();
+            assert_eq!(bank.content(asset).unwrap().unwrap().1, b"ours");
+            assert!(bank.conflicts().unwrap().is_empty());
+            assert!(!flagged(&bank, asset) && !flagged(&bank, fork));
+            assert!(matches!(
+                bank.resolve_conflict(asset, Resolution::Keep(asset)),
+                Err(ConflictError::NoConflict(_))
+            ));
+
+            let (asset, _) = conflicted(&bank);
+            bank.resolve_conflict(asset, Resolution::Merge(b"ours and theirs".to_vec())).unwrap();
+            assert_eq!(bank.content(asset).unwrap().unwrap().1, b"ours and theirs");
+        });
+    }
+
+    #[test]
+    fn reindexes_both_versions() {
+        each_backend(|bank| {
+            let hits = |text: &str| {
+                let query = SearchQuery { text: text.to_string(), ..Default::default() };
+                let mut hits: Vec<Ulid> = bank.search(&query).unwrap().into_iter().map(|hit| hit.asset_id).collect();
+                hits.sort();
+                hits
+            };
+            let mut text = Text::new("draft from the bridge", 1);
+            bank.dematerialize(&text, true).unwrap();
+            let fork = bank.fork_conflict(text.id()).unwrap();
+            text.replace("draft from engineering");
+            bank.dematerialize(&text, true).unwrap();
+            assert_eq!(hits("bridge"), vec![fork]);
+            assert_eq!(hits("engineering"), vec![text.id()]);
+
+            bank.resolve_conflict(text.id(), Resolution::Keep(fork)).unwrap();
+            let mut both = vec![text.id(), fork];
+            both.sort();
+            assert_eq!(hits("bridge"), both);
+            assert!(hits("engineering").is_empty());
+        });
+    }
+
+    #[test]
+    fn reads_merges_as_base64() {
+        let resolution: Resolution = serde_json::from_str(r#"{"merge": "b3VycyBhbmQgdGhlaXJz"}"#).unwrap();
+        assert_eq!(resolution, Resolution::Merge(b"ours and theirs".to_vec()));
+        assert!(serde_json::from_str::<Resolution>(r#"{"merge": [1, 2, 3]}"#).is_err());
+    }
+}
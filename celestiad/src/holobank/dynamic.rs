@@ -0,0 +1,248 @@
+// A dynamic collection keeps its query in the `dynamic` relation and its
+// members in the `collection` relation like any collection, so it is read,
+// shared and replicated the same way.  Members are refreshed whenever the
+// assets, tags, flags or content of the bank change, and every
+// `REFRESH_SECS` for what the change feed does not cover: connections and
+// assets growing older than a query allows.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use constellations::{asset::AssetType, collection::CollectionQuery};
+use cozo::{DataValue, ScriptMutability};
+use futures::{FutureExt, StreamExt};
+use tracing::{debug, warn};
+use ulid::Ulid;
+
+use super::{now, ulid, Holobank};
+
+/// Seconds between refreshes when nothing changes.
+pub const REFRESH_SECS: u64 = 60;
+
+impl Holobank {
+    /// Assets matching a query.
+    pub fn evaluate(&self, query: &CollectionQuery) -> Result<Vec<(Ulid, AssetType)>, cozo::Error> {
+        let mut params = BTreeMap::new();
+        let mut clauses = vec!["*asset{asset_id, name, asset_type, time_registered}".to_string()];
+        let mut rules = String::new();
+
+        if let Some(asset_type) = query.asset_type {
+            params.insert("asset_type".to_string(), DataValue::from(asset_type.as_str()));
+            clauses.push("asset_type = $asset_type".to_string());
+        }
+        for (i, tag) in query.tags.iter().enumerate() {
+            params.insert(format!("tag{}", i), DataValue::from(tag.as_str()));
+            clauses.push(format!("*tags{{asset_id, tag: $tag{}}}", i));
+        }
+        for (i, flag) in query.flags.iter().enumerate() {
+            params.insert(format!("flag{}", i), DataValue::from(flag.as_str()));
+            clauses.push(format!("*flags{{asset_id, flag: $flag{}}}", i));
+        }
+        if let Some(content_type) = &query.content_type {
+            params.insert("content_type".to_string(), DataValue::from(content_type.as_str()));
+            clauses.push("*content{asset_id, content_type: $content_type}".to_string());
+        }
+        if let Some(name) = &query.name {
+            params.insert("name".to_string(), DataValue::from(name.to_lowercase()));
+            clauses.push("!is_null(name), str_includes(lowercase(name), $name)".to_string());
+        }
+        if !query.connected_to.is_empty() {
+            rules.push_str(
+                "linked[a, b, type] := *connection{src: a, dest: b, type}
+                linked[a, b, type] := *connection{src: b, dest: a, type}\n"
+            );
+        }
+        for (i, filter) in query.connected_to.iter().enumerate() {
+            params.insert(format!("other{}", i), ulid(filter.id));
+            clauses.push(format!("linked[asset_id, other{i}, type{i}], other{i} = $other{i}", i = i));
+            if let Some(kind) = &filter.kind {
+                params.insert(format!("type{}", i), DataValue::from(kind.as_str()));
+                clauses.push(format!("type{i} = $type{i}", i = i));
+            }
+        }
+        if let Some(age) = query.max_age_secs {
+            let since = now() - Duration::from_secs(age).as_micros() as i64;
+            params.insert("since".to_string(), DataValue::from(since));
+            clauses.push("time_registered >= $since".to_string());
+        }
+
+        let rows = self.persistent.run_script(
+            &format!("{}?[asset_id, asset_type] := {}", rules, clauses.join(", ")),
+            params,
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows
+            .iter()
+            .filter_map(|row| Some((row[0].get_ulid()?, row[1].get_str()?.parse().ok()?)))
+            .collect())
+    }
+
+    /// Query of a dynamic collection, `None` for static collections.
+    pub fn collection_query(&self, collection: Ulid) -> Result<Option<CollectionQuery>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[query] := *dynamic{collection_id: $collection_id, query}",
+            BTreeMap::from([("collection_id".to_string(), ulid(collection))]),
+            ScriptMutability::Immutable
+        )?;
+        rows.rows
+            .first()
+            .and_then(|row| row[0].get_str())
+            .map(|query| serde_json::from_str(query).map_err(cozo::Error::msg))
+            .transpose()
+    }
+
+    /// Stores the query of a dynamic collection, or makes it static.
+    pub(crate) fn set_collection_query(&self, collection: Ulid, query: Option<&CollectionQuery>) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::from([("collection_id".to_string(), ulid(collection))]);
+        let script = match query {
+            Some(query) => {
+                let json = serde_json::to_string(query).map_err(cozo::Error::msg)?;
+                params.insert("query".to_string(), DataValue::from(json));
+                params.insert("time".to_string(), DataValue::from(now()));
+                "?[collection_id, query, time_refreshed] <- [[$collection_id, $query, $time]]
+                :put dynamic {collection_id => query, time_refreshed}"
+            }
+            None => "?[collection_id] := *dynamic{collection_id}, collection_id = $collection_id
+                :rm dynamic {collection_id}",
+        };
+        self.persistent.run_script(script, params, ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    /// Recomputes the members of every dynamic collection.  Returns the
+    /// collections whose members changed.
+    pub fn refresh_dynamic_collections(&self) -> Result<Vec<Ulid>, cozo::Error> {
+        let rows = self.persistent.run_default("?[collection_id, query] := *dynamic{collection_id, query}")?;
+        let mut changed = Vec::new();
+        for row in rows.rows {
+            let (Some(collection), Some(query)) = (row[0].get_ulid(), row[1].get_str()) else {
+                continue;
+            };
+            let query: CollectionQuery = match serde_json::from_str(query) {
+                Ok(query) => query,
+                Err(e) => {
+                    warn!("Dynamic collection {} has an unreadable query: {}", collection, e);
+                    continue;
+                }
+            };
+            if self.set_members(collection, &self.evaluate(&query)?)? {
+                changed.push(collection);
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// Keeps dynamic collections up to date until the bank's change feed ends.
+pub async fn follow(holobank: Holobank) {
+    let mut changes = Box::pin(holobank.watch(None));
+    let mut interval = tokio::time::interval(Duration::from_secs(REFRESH_SECS));
+    loop {
+        tokio::select! {
+            change = changes.next() => match change {
+                // Membership changes do not affect queries, and include our own.
+                Some(change) if change.relation == "collection" => continue,
+                // Coalesce a burst of changes into one refresh.
+                Some(_) => while let Some(Some(_)) = changes.next().now_or_never() {},
+                None => return,
+            },
+            _ = interval.tick() => {}
+        }
+        let bank = holobank.clone();
+        match tokio::task::spawn_blocking(move || bank.refresh_dynamic_collections()).await {
+            Ok(Ok(changed)) if !changed.is_empty() => debug!("Refreshed {} dynamic collections", changed.len()),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("Could not refresh dynamic collections: {}", e),
+            Err(e) => warn!("Could not refresh dynamic collections: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constellations::{
+        asset::{block::text::Text, Asset},
+        collection::{Collection, ConnectionFilter},
+        connection::{Connection, ConnectionType},
+    };
+
+    use crate::holobank::each_backend;
+
+    fn tag(bank: &Holobank, asset: Ulid, tag: &str) {
+        bank.persistent.run_script(
+            "?[asset_id, tag, time_attached] <- [[$asset_id, $tag, 0]] :put tags {asset_id, tag => time_attached}",
+            BTreeMap::from([
+                ("asset_id".to_string(), ulid(asset)),
+                ("tag".to_string(), DataValue::from(tag)),
+            ]),
+            ScriptMutability::Mutable
+        ).unwrap();
+    }
+
+    #[test]
+    fn members_follow_the_query() {
+        each_backend(|bank| {
+            let (meeting, other) = (Text::new("standup notes", 1), Text::new("groceries", 1));
+            bank.dematerialize(&meeting, true).unwrap();
+            bank.dematerialize(&other, true).unwrap();
+            tag(&bank, meeting.id(), "meeting");
+
+            let meetings = Collection::dynamic("recent meetings", CollectionQuery {
+                tags: vec!["meeting".to_string()],
+                asset_type: Some(AssetType::Block),
+                content_type: Some(Text::CONTENT_TYPE.to_string()),
+                max_age_secs: Some(30 * 24 * 60 * 60),
+                ..CollectionQuery::default()
+            });
+            bank.store_collection(&meetings).unwrap();
+            let stored = bank.collection(meetings.id).unwrap().unwrap();
+            assert_eq!(stored.query, meetings.query);
+            assert_eq!(stored.assets().map(|(id, _)| id).collect::<Vec<_>>(), vec![meeting.id()]);
+
+            tag(&bank, other.id(), "meeting");
+            assert_eq!(bank.refresh_dynamic_collections().unwrap(), vec![meetings.id]);
+            assert_eq!(bank.collection(meetings.id).unwrap().unwrap().len(), 2);
+            assert!(bank.refresh_dynamic_collections().unwrap().is_empty());
+
+            // Made static, the collection keeps its members as they are.
+            let mut fixed = bank.collection(meetings.id).unwrap().unwrap();
+            fixed.query = None;
+            bank.store_collection(&fixed).unwrap();
+            assert!(bank.collection_query(meetings.id).unwrap().is_none());
+            assert_eq!(bank.collection(meetings.id).unwrap().unwrap().len(), 2);
+        });
+    }
+
+    #[test]
+    fn matches_connections_and_names() {
+        each_backend(|bank| {
+            let (book, chapter, stray) = (Text::new("book", 1), Text::new("chapter", 1), Text::new("stray", 1));
+            for text in [&book, &chapter, &stray] {
+                bank.dematerialize(text, true).unwrap();
+            }
+            bank.connect(&Connection::new(book.id(), chapter.id(), ConnectionType::Other("contains".to_string()))).unwrap();
+
+            let query = CollectionQuery {
+                connected_to: vec![ConnectionFilter { id: book.id(), kind: Some(ConnectionType::Other("contains".to_string())) }],
+                ..CollectionQuery::default()
+            };
+            let found: Vec<Ulid> = bank.evaluate(&query).unwrap().into_iter().map(|(id, _)| id).collect();
+            assert_eq!(found, vec![chapter.id()]);
+
+            let query = CollectionQuery { name: Some("Nothing".to_string()), ..CollectionQuery::default() };
+            assert!(bank.evaluate(&query).unwrap().is_empty());
+            assert_eq!(bank.evaluate(&CollectionQuery::default()).unwrap().len(), 3);
+
+            let named = Ulid::new();
+            bank.persistent.run_script(
+                "?[asset_id, name, asset_type, time_registered] <- [[$asset_id, 'Mission Log', 'block', 0]]
+                :put asset {asset_id, name => asset_type, time_registered}",
+                BTreeMap::from([("asset_id".to_string(), ulid(named))]),
+                ScriptMutability::Mutable
+            ).unwrap();
+            let query = CollectionQuery { name: Some("mission".to_string()), ..CollectionQuery::default() };
+            let found: Vec<Ulid> = bank.evaluate(&query).unwrap().into_iter().map(|(id, _)| id).collect();
+            assert_eq!(found, vec![named]);
+        });
+    }
+}
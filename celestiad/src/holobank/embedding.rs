@@ -0,0 +1,352 @@
+use std::{collections::{BTreeMap, HashMap}, sync::Arc};
+
+use cozo::{DataValue, ScriptMutability};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ulid::Ulid;
+
+use super::{relation_exists, schema, ulid, Holobank};
+
+/// Turns text into vectors for similarity search.
+pub trait Embedder: Send + Sync {
+    /// Identifies the model.  Vectors from different models are not comparable.
+    fn model(&self) -> String;
+    /// Length of every vector the embedder produces.
+    fn dimensions(&self) -> usize;
+    fn embed(&self, text: &str) -> Vec<f32>;
+}
+
+/// Embeds text by hashing its words and character trigrams into a fixed
+/// number of buckets.  Deterministic and entirely local, which makes it
+/// suitable for tests and for devices without a model.
+pub struct HashingEmbedder {
+    dimensions: usize,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimensions: usize) -> HashingEmbedder {
+        HashingEmbedder { dimensions: dimensions.max(1) }
+    }
+}
+
+impl Embedder for HashingEmbedder {
+    fn model(&self) -> String {
+        format!("hashing-{}", self.dimensions)
+    }
+
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    fn embed(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0f32; self.dimensions];
+        let lowered = text.to_lowercase();
+
+        for word in lowered.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+            add_feature(&mut vector, word.as_bytes(), 1.0);
+
+            let chars: Vec<char> = format!(" {} ", word).chars().collect();
+            for trigram in chars.windows(3) {
+                let trigram: String = trigram.iter().collect();
+                add_feature(&mut vector, trigram.as_bytes(), 0.5);
+            }
+        }
+
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        vector
+    }
+}
+
+fn add_feature(vector: &mut [f32], feature: &[u8], weight: f32) {
+    let hash = fnv1a(feature);
+    let bucket = (hash % vector.len() as u64) as usize;
+    let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+    vector[bucket] += sign * weight;
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Most neighbors a query returns.
+pub const MAX_SIMILAR_LIMIT: usize = 1000;
+
+/// A nearest neighbor query.  Either text to embed or an asset whose
+/// embedding is used.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SimilarQuery {
+    pub text: Option<String>,
+    pub asset: Option<Ulid>,
+    /// Only match assets carrying every one of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Only match assets in this collection.
+    pub collection: Option<Ulid>,
+    /// Neighbors returned, 10 unless given.
+    pub limit: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Neighbor {
+    pub asset_id: Ulid,
+    /// Cosine distance; smaller is closer.
+    pub distance: f64,
+}
+
+impl Holobank {
+    /// Embeds text with the given embedder from now on.  The embedding
+    /// relation is rebuilt if the embedder's dimensions differ from the stored
+    /// ones, and texts without a current embedding are embedded.
+    pub fn with_embedder(mut self, embedder: Arc<dyn Embedder>) -> Result<Holobank, cozo::Error> {
+        let dimensions = embedder.dimensions();
+        match self.embedding_dimensions()? {
+            Some(stored) if stored == dimensions => {}
+            Some(_) => {
+                self.persistent.run_default("::hnsw drop embedding:semantic")?;
+                self.persistent.run_default("::remove embedding")?;
+                self.persistent.run_default(&schema::embedding_schema(dimensions))?;
+                self.persistent.run_default(&schema::embedding_index(dimensions))?;
+            }
+            None => {
+                self.persistent.run_default(&schema::embedding_schema(dimensions))?;
+                self.persistent.run_default(&schema::embedding_index(dimensions))?;
+            }
+        }
+
+        self.embedder = Some(embedder);
+        self.reembed()?;
+        Ok(self)
+    }
+
+    /// Embeds every text whose embedding is missing or out of date.
+    pub fn reembed(&self) -> Result<usize, cozo::Error> {
+        let Some(embedder) = &self.embedder else {
+            return Ok(0);
+        };
+        let model = embedder.model();
+
+        let current: HashMap<Ulid, Vec<u8>> = self.persistent
+            .run_script(
+                "?[asset_id, digest] := *embedding{asset_id, model, digest}, model = $model",
+                BTreeMap::from([("model".to_string(), DataValue::from(model.as_str()))]),
+                ScriptMutability::Immutable
+            )?
+            .rows
+            .into_iter()
+            .filter_map(|row| Some((row[0].get_ulid()?, row[1].get_bytes()?.to_vec())))
+            .collect();
+
+        let texts = self.persistent.run_default("?[asset_id, body] := *text{asset_id, body}")?;
+        let mut embedded = 0;
+        for row in texts.rows {
+            let (Some(id), Some(body)) = (row[0].get_ulid(), row[1].get_str()) else {
+                continue;
+            };
+            if current.get(&id).map(Vec::as_slice) != Some(digest(body).as_slice()) {
+                self.embed(id, body)?;
+                embedded += 1;
+            }
+        }
+        Ok(embedded)
+    }
+
+    /// Stores the embedding of an asset's text.  Unchanged text is not
+    /// embedded again.
+    pub(crate) fn embed(&self, id: Ulid, text: &str) -> Result<(), cozo::Error> {
+        let Some(embedder) = &self.embedder else {
+            return Ok(());
+        };
+        let model = embedder.model();
+        let digest = digest(text);
+
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(id));
+        params.insert("model".to_string(), DataValue::from(model.as_str()));
+        params.insert("digest".to_string(), DataValue::Bytes(digest));
+
+        let unchanged = self.persistent.run_script(
+            "?[asset_id] := *embedding{asset_id, model, digest}, asset_id = $asset_id, model = $model, digest = $digest",
+            params.clone(),
+            ScriptMutability::Immutable
+        )?;
+        if !unchanged.rows.is_empty() {
+            return Ok(());
+        }
+
+        params.insert("vector".to_string(), vector_param(&embedder.embed(text)));
+        self.persistent.run_script(
+            "?[asset_id, model, digest, vector] := asset_id = $asset_id, model = $model, digest = $digest, vector = vec($vector)
+            :put embedding {asset_id => model, digest, vector}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Finds the assets closest in meaning to some text or to another asset.
+    pub fn similar(&self, query: &SimilarQuery) -> Result<Vec<Neighbor>, cozo::Error> {
+        let Some(embedder) = &self.embedder else {
+            return Ok(vec![]);
+        };
+        let limit = query.limit.unwrap_or(10).max(1);
+
+        let mut params = BTreeMap::new();
+        let mut filters = String::new();
+        let target = match (&query.text, query.asset) {
+            (Some(text), _) => {
+                params.insert("vector".to_string(), vector_param(&embedder.embed(text)));
+                "target = vec($vector)"
+            }
+            (None, Some(asset)) => {
+                params.insert("asset".to_string(), ulid(asset));
+                filters.push_str(", asset_id != $asset");
+                "*embedding{asset_id: $asset, vector: target}"
+            }
+            (None, None) => return Ok(vec![]),
+        };
+        for (i, tag) in query.tags.iter().enumerate() {
+            params.insert(format!("tag{}", i), DataValue::from(tag.as_str()));
+            filters.push_str(&format!(", *tags{{asset_id, tag: $tag{}}}", i));
+        }
+        if let Some(collection) = query.collection {
+            params.insert("collection".to_string(), ulid(collection));
+            filters.push_str(", *collection{asset_id, collection_id: $collection}");
+        }
+
+        // Filters run after the index picks its candidates, so ask it for more.
+        let script = format!(
+            "?[asset_id, distance] := {}, ~embedding:semantic{{asset_id | query: target, k: {}, ef: 100, bind_distance: distance}}{}
+            :order distance
+            :limit {}",
+            target,
+            limit.saturating_mul(10).max(50),
+            filters,
+            limit
+        );
+        let rows = self.persistent.run_script(&script, params, ScriptMutability::Immutable)?;
+
+        Ok(rows.rows
+            .into_iter()
+            .filter_map(|row| Some(Neighbor { asset_id: row[0].get_ulid()?, distance: row[1].get_float()? }))
+            .collect())
+    }
+
+    /// Dimensions of the stored embedding relation, if there is one.
+    fn embedding_dimensions(&self) -> Result<Option<usize>, cozo::Error> {
+        if !relation_exists(&self.persistent, "embedding")? {
+            return Ok(None);
+        }
+        // Vector columns are typed like `<F32;64>`.
+        let columns = self.persistent.run_default("::columns embedding")?;
+        let dimensions = columns.rows
+            .iter()
+            .filter(|row| row[0].get_str() == Some("vector"))
+            .filter_map(|row| row[3].get_str())
+            .filter_map(|ty| ty.trim_end_matches('>').rsplit(';').next()?.trim().parse().ok())
+            .next();
+        Ok(dimensions)
+    }
+}
+
+fn vector_param(vector: &[f32]) -> DataValue {
+    DataValue::List(vector.iter().map(|x| DataValue::from(*x as f64)).collect())
+}
+
+fn digest(text: &str) -> Vec<u8> {
+    Sha256::digest(text.as_bytes()).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constellations::asset::{block::text::Text, Asset};
+
+    use crate::holobank::each_backend;
+
+    /// Places texts on the axes of the directions they name.
+    struct Compass;
+
+    impl Embedder for Compass {
+        fn model(&self) -> String {
+            "compass".to_string()
+        }
+
+        fn dimensions(&self) -> usize {
+            2
+        }
+
+        fn embed(&self, text: &str) -> Vec<f32> {
+            let north = text.contains("north") as u8 as f32;
+            let east = text.contains("east") as u8 as f32;
+            let length = (north * north + east * east).sqrt().max(1.0);
+            vec![north / length, east / length]
+        }
+    }
+
+    fn cosine(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| x * y).sum()
+    }
+
+    #[test]
+    fn hashing_is_deterministic_and_normalized() {
+        let embedder = HashingEmbedder::new(64);
+        let a = embedder.embed("Meeting notes from Tuesday");
+        let b = embedder.embed("Meeting notes from Tuesday");
+
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 64);
+        assert!((cosine(&a, &a) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn similar_text_is_closer() {
+        let embedder = HashingEmbedder::new(256);
+        let query = embedder.embed("garden tomatoes and basil");
+        let close = embedder.embed("planting tomatoes with basil in the garden");
+        let far = embedder.embed("quarterly revenue forecast spreadsheet");
+
+        assert!(cosine(&query, &close) > cosine(&query, &far));
+    }
+
+    #[test]
+    fn empty_text_embeds_to_zero() {
+        let embedder = HashingEmbedder::new(8);
+        assert!(embedder.embed("  ").iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn finds_nearest_neighbors() {
+        each_backend(|bank| {
+            let bank = bank.with_embedder(Arc::new(Compass)).unwrap();
+            let north = Text::new("due north", 1);
+            let northeast = Text::new("north by east", 1);
+            let east = Text::new("east", 1);
+            for text in [&east, &north, &northeast] {
+                bank.dematerialize(text, true).unwrap();
+            }
+            let nearest = |query: SimilarQuery| {
+                bank.similar(&query).unwrap().into_iter().map(|n| n.asset_id).collect::<Vec<_>>()
+            };
+
+            let by_text = SimilarQuery { text: Some("north".to_string()), ..Default::default() };
+            assert_eq!(nearest(by_text.clone()), vec![north.id(), northeast.id(), east.id()]);
+            let by_asset = SimilarQuery { asset: Some(east.id()), ..Default::default() };
+            assert_eq!(nearest(by_asset), vec![northeast.id(), north.id()]);
+            let limited = SimilarQuery { limit: Some(1), ..by_text.clone() };
+            assert_eq!(nearest(limited), vec![north.id()]);
+
+            bank.persistent.run_script(
+                "?[asset_id, tag, time_attached] <- [[$asset_id, 'bearing', 0]] :put tags {asset_id, tag => time_attached}",
+                BTreeMap::from([("asset_id".to_string(), ulid(east.id()))]),
+                ScriptMutability::Mutable
+            ).unwrap();
+            let tagged = SimilarQuery { tags: vec!["bearing".to_string()], ..by_text };
+            assert_eq!(nearest(tagged), vec![east.id()]);
+        });
+    }
+}
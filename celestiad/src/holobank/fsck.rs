@@ -0,0 +1,518 @@
+use std::{collections::{BTreeMap, HashMap}, fmt};
+
+use constellations::asset::block::text::Text;
+use cozo::{DataValue, ScriptMutability};
+use ulid::Ulid;
+
+use super::{cipher::CipherError, now, quota::EVICTED_FLAG, relation_exists, Holobank};
+
+/// Ids that references may point to besides assets.
+const KNOWN_ENTITIES: &str = "
+    known[id] := *asset{asset_id: id}
+    known[id] := *collection{collection_id: id}
+    known[id] := *commander{commander_id: id}
+    known[id] := *spaceport{spaceport_id: id}
+    known[id] := *system{system_id: id}
+";
+
+/// Relations whose rows must point at an existing asset, with their key
+/// columns and the column holding the reference.
+const ASSET_REFERENCES: [(&str, &[&str], &str); 6] = [
+    ("collection", &["asset_id", "collection_id"], "asset_id"),
+    ("tags", &["asset_id", "tag"], "asset_id"),
+    ("flags", &["asset_id", "flag"], "asset_id"),
+    ("ledger", &["asset", "time"], "asset"),
+    ("text", &["asset_id"], "asset_id"),
+    ("access", &["asset_id"], "asset_id"),
+];
+
+/// Asset types that always carry content.
+const CONTENT_TYPES: [&str; 2] = ["block", "file"];
+
+/// Flag put on assets whose content has to be fetched again.
+pub const MISSING_CONTENT_FLAG: &str = "missing_content";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsckMode {
+    /// Only report problems.
+    #[default]
+    Report,
+    /// Delete offending rows.
+    Repair,
+    /// Move offending rows into the quarantine relation.
+    Quarantine,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Problem {
+    /// A row refers to an id that does not exist.
+    DanglingReference,
+    /// Content is stored for an asset that is not registered.
+    OrphanedContent,
+    /// A registered block or file has no content and was not evicted.
+    MissingContent,
+    /// The ledger shows an asset materialized while someone else held it.
+    DuplicateHolder,
+    /// Content cannot be decrypted or decoded as its content type.
+    UndecodableContent,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Problem::DanglingReference => "dangling reference",
+            Problem::OrphanedContent => "orphaned content",
+            Problem::MissingContent => "missing content",
+            Problem::DuplicateHolder => "duplicate holder",
+            Problem::UndecodableContent => "undecodable content",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum FsckError {
+    /// Content is sealed with a key the bank was not opened with.  Nothing
+    /// is repaired, since the content is most likely intact.
+    Cipher(CipherError),
+    Database(cozo::Error),
+}
+
+impl fmt::Display for FsckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsckError::Cipher(e) => write!(f, "{}; open the bank with its key", e),
+            FsckError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FsckError {}
+
+impl From<cozo::Error> for FsckError {
+    fn from(e: cozo::Error) -> Self {
+        FsckError::Database(e)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Finding {
+    pub problem: Problem,
+    pub relation: String,
+    /// Key columns of the offending row.
+    pub row: Vec<DataValue>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in {}: {:?}", self.problem, self.relation, self.row)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub findings: Vec<Finding>,
+    /// Findings that were repaired or quarantined.
+    pub fixed: usize,
+}
+
+impl FsckReport {
+    pub fn count(&self, problem: Problem) -> usize {
+        self.findings.iter().filter(|f| f.problem == problem).count()
+    }
+}
+
+impl Holobank {
+    /// Checks the bank for broken references and content, fixing what it
+    /// finds according to `mode`.  Fails before fixing anything if content
+    /// is sealed with a key the bank does not have.
+    pub fn fsck(&self, mode: FsckMode) -> Result<FsckReport, FsckError> {
+        let mut report = FsckReport::default();
+
+        for (relation, keys, reference) in ASSET_REFERENCES {
+            let script = format!(
+                "known[id] := *asset{{asset_id: id}}
+                ?[{keys}] := *{relation}{{{keys}}}, not known[{reference}]",
+                keys = keys.join(", "),
+                relation = relation,
+                reference = reference
+            );
+            report.findings.extend(self.find(&script, Problem::DanglingReference, relation)?);
+        }
+        report.findings.extend(self.find(
+            &format!(
+                "{}
+                ?[src, dest, type] := *connection{{src, dest, type}}, not known[src]
+                ?[src, dest, type] := *connection{{src, dest, type}}, not known[dest]",
+                KNOWN_ENTITIES
+            ),
+            Problem::DanglingReference,
+            "connection",
+        )?);
+        report.findings.extend(self.find(
+            &format!("{}?[entity_id, owner_id, time] := *owner{{entity_id, owner_id, time}}, not known[entity_id]", KNOWN_ENTITIES),
+            Problem::DanglingReference,
+            "owner",
+        )?);
+        if relation_exists(&self.persistent, "embedding")? {
+            report.findings.extend(self.find(
+                "?[asset_id] := *embedding{asset_id}, not *asset{asset_id}",
+                Problem::DanglingReference,
+                "embedding",
+            )?);
+        }
+
+        report.findings.extend(self.find(
+            "?[asset_id] := *content{asset_id, digest}, not *blob{digest}",
+            Problem::DanglingReference,
+            "content",
+        )?);
+
+        report.findings.extend(self.find(
+            "?[asset_id] := *content{asset_id}, not *asset{asset_id}",
+            Problem::OrphanedContent,
+            "content",
+        )?);
+        let params = BTreeMap::from([
+            ("content_types".to_string(), DataValue::List(CONTENT_TYPES.iter().map(|&t| DataValue::from(t)).collect())),
+            ("evicted".to_string(), DataValue::from(EVICTED_FLAG)),
+        ]);
+        report.findings.extend(self.find_with(
+            "?[asset_id] := *asset{asset_id, asset_type}, asset_type in $content_types, not *content{asset_id},
+                not *flags{asset_id, flag: $evicted}",
+            params,
+            Problem::MissingContent,
+            "asset",
+        )?);
+        report.findings.extend(self.duplicate_holders()?);
+        report.findings.extend(self.undecodable_content()?);
+
+        if mode != FsckMode::Report {
+            for finding in &report.findings {
+                self.fix(finding, mode)?;
+                report.fixed += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    fn find(&self, script: &str, problem: Problem, relation: &str) -> Result<Vec<Finding>, cozo::Error> {
+        self.find_with(script, BTreeMap::new(), problem, relation)
+    }
+
+    fn find_with(
+        &self,
+        script: &str,
+        params: BTreeMap<String, DataValue>,
+        problem: Problem,
+        relation: &str,
+    ) -> Result<Vec<Finding>, cozo::Error> {
+        let rows = self.persistent.run_script(script, params, ScriptMutability::Immutable)?;
+        Ok(rows.rows
+            .into_iter()
+            .map(|row| Finding { problem, relation: relation.to_string(), row })
+            .collect())
+    }
+
+    /// Walks each asset's ledger in time order looking for a holder taking
+    /// over without the previous one releasing.
+    fn duplicate_holders(&self) -> Result<Vec<Finding>, cozo::Error> {
+        let rows = self.persistent.run_default(
+            "?[asset, time, at, by] := *ledger{asset, time, materialized_at: at, materialized_by: by}"
+        )?;
+
+        let mut ledgers: HashMap<Ulid, Vec<(i64, bool, Vec<DataValue>)>> = HashMap::new();
+        for row in rows.rows {
+            let (Some(asset), DataValue::Validity(validity)) = (row[0].get_ulid(), &row[1]) else {
+                continue;
+            };
+            let (timestamp, assert) = (validity.timestamp.0.0, validity.is_assert.0);
+            ledgers.entry(asset).or_default().push((timestamp, assert, row));
+        }
+
+        let mut findings = Vec::new();
+        for entries in ledgers.values_mut() {
+            entries.sort_by_key(|(timestamp, _, _)| *timestamp);
+            let mut holder: Option<&[DataValue]> = None;
+            for (_, assert, row) in entries.iter() {
+                if !assert {
+                    holder = None;
+                    continue;
+                }
+                if holder.is_some_and(|held| held != &row[2..]) {
+                    findings.push(Finding {
+                        problem: Problem::DuplicateHolder,
+                        relation: "ledger".to_string(),
+                        row: row[..2].to_vec(),
+                    });
+                }
+                holder = Some(&row[2..]);
+            }
+        }
+        Ok(findings)
+    }
+
+    /// Text that does not decode, or whose payload fails authentication
+    /// under a key the bank has.  A payload sealed with a key the bank does
+    /// not have means the bank was opened with the wrong key, not that the
+    /// content is broken.
+    fn undecodable_content(&self) -> Result<Vec<Finding>, FsckError> {
+        let rows = self.persistent.run_script(
+            "?[asset_id, payload] := *content{asset_id, content_type, digest}, content_type = $text, *blob{digest, payload}",
+            BTreeMap::from([("text".to_string(), DataValue::from(Text::CONTENT_TYPE))]),
+            ScriptMutability::Immutable
+        )?;
+        let mut findings = Vec::new();
+        for row in rows.rows {
+            let decodes = match self.unseal(&row[1]) {
+                Ok(content) => content.as_deref().and_then(Text::decode).is_some(),
+                Err(CipherError::Decrypt) => false,
+                Err(e) => return Err(FsckError::Cipher(e)),
+            };
+            if !decodes {
+                findings.push(Finding {
+                    problem: Problem::UndecodableContent,
+                    relation: "content".to_string(),
+                    row: vec![row[0].clone()],
+                });
+            }
+        }
+        Ok(findings)
+    }
+
+    fn fix(&self, finding: &Finding, mode: FsckMode) -> Result<(), cozo::Error> {
+        match finding.problem {
+            Problem::MissingContent => {
+                // Nothing to remove; mark the asset so its content is fetched again.
+                let mut params = BTreeMap::new();
+                params.insert("asset_id".to_string(), finding.row[0].clone());
+                params.insert("flag".to_string(), DataValue::from(MISSING_CONTENT_FLAG));
+                params.insert("time".to_string(), DataValue::from(now()));
+                self.persistent.run_script(
+                    "?[asset_id, flag, time_attached] <- [[$asset_id, $flag, $time]]
+                    :put flags {asset_id, flag => time_attached}",
+                    params,
+                    ScriptMutability::Mutable
+                )?;
+                return Ok(());
+            }
+            Problem::DuplicateHolder => {
+                // Close the earlier holding just before the later one began.
+                let (Some(asset), DataValue::Validity(validity)) = (finding.row[0].get_ulid(), &finding.row[1]) else {
+                    return Ok(());
+                };
+                return self.release_holder(asset, validity.timestamp.0.0 - 1);
+            }
+            _ => {}
+        }
+
+        let (columns, keys) = self.columns(&finding.relation)?;
+        if mode == FsckMode::Quarantine {
+            let mut params = BTreeMap::new();
+            params.insert("relation".to_string(), DataValue::from(finding.relation.as_str()));
+            params.insert("row".to_string(), DataValue::List(self.full_row(finding, &columns)?));
+            params.insert("reason".to_string(), DataValue::from(finding.problem.to_string()));
+            params.insert("time".to_string(), DataValue::from(now()));
+            self.persistent.run_script(
+                "?[relation, row, reason, time_quarantined] <- [[$relation, $row, $reason, $time]]
+                :put quarantine {relation, row => reason, time_quarantined}",
+                params,
+                ScriptMutability::Mutable
+            )?;
+        }
+
+        // Content goes through its blob so the blob's references stay counted.
+        if finding.relation == "content" {
+            if let Some(asset) = finding.row[0].get_ulid() {
+                return self.remove_content(asset);
+            }
+        }
+        let keys = columns[..keys].join(", ");
+        self.persistent.run_script(
+            &format!("?[{keys}] <- [$row] :rm {} {{{keys}}}", finding.relation, keys = keys),
+            BTreeMap::from([("row".to_string(), DataValue::List(finding.row.clone()))]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Names of a relation's columns, key columns first, and how many of
+    /// them are keys.
+    pub(crate) fn columns(&self, relation: &str) -> Result<(Vec<String>, usize), cozo::Error> {
+        let columns = self.persistent.run_default(&format!("::columns {}", relation))?;
+        let names = columns.rows
+            .iter()
+            .filter_map(|row| row[0].get_str().map(str::to_string))
+            .collect();
+        let keys = columns.rows.iter().filter(|row| row[1].get_bool() == Some(true)).count();
+        Ok((names, keys))
+    }
+
+    /// Every column of the offending row, keys first.
+    fn full_row(&self, finding: &Finding, columns: &[String]) -> Result<Vec<DataValue>, cozo::Error> {
+        let bindings = columns
+            .iter()
+            .zip(&finding.row)
+            .enumerate()
+            .map(|(i, (column, _))| format!("{}: $k{}", column, i))
+            .chain(columns.iter().skip(finding.row.len()).cloned())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let params = finding.row
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (format!("k{}", i), value.clone()))
+            .collect();
+        let values = columns.iter().skip(finding.row.len()).cloned().collect::<Vec<_>>().join(", ");
+        if values.is_empty() {
+            return Ok(finding.row.clone());
+        }
+        let rows = self.persistent.run_script(
+            &format!("?[{}] := *{}{{{}}}", values, finding.relation, bindings),
+            params,
+            ScriptMutability::Immutable
+        )?;
+
+        let mut full = finding.row.clone();
+        if let Some(rest) = rows.rows.into_iter().next() {
+            full.extend(rest);
+        }
+        Ok(full)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holobank::each_backend;
+    use constellations::{asset::Asset, connection::{Connection, ConnectionType}};
+
+    use crate::holobank::{cipher::KeySource, temporal::Holder, ulid};
+
+    fn tag(bank: &Holobank, asset: Ulid, tag: &str) {
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(asset));
+        params.insert("tag".to_string(), DataValue::from(tag));
+        bank.persistent.run_script(
+            "?[asset_id, tag, time_attached] <- [[$asset_id, $tag, 0]] :put tags {asset_id, tag => time_attached}",
+            params,
+            ScriptMutability::Mutable
+        ).unwrap();
+    }
+
+    fn put_content(bank: &Holobank, asset: Ulid, bytes: &[u8]) {
+        bank.store_content(asset, Text::CONTENT_TYPE, bytes.to_vec()).unwrap();
+    }
+
+    #[test]
+    fn finds_problems() {
+        each_backend(|bank| {
+            let text = Text::new("kept", 1);
+            bank.dematerialize(&text, true).unwrap();
+
+            let ghost = Ulid::new();
+            tag(&bank, ghost, "lost");
+            bank.connect(&Connection::new(text.id(), ghost, ConnectionType::Derivation)).unwrap();
+            put_content(&bank, ghost, b"garbage");
+
+            let port = Ulid::new();
+            bank.record_holder(text.id(), &Holder { spaceport: port, spacecraft: None, commander: Some(Ulid::new()) }, 10).unwrap();
+            bank.record_holder(text.id(), &Holder { spaceport: port, spacecraft: None, commander: Some(Ulid::new()) }, 20).unwrap();
+
+            let report = bank.fsck(FsckMode::Report).unwrap();
+            assert_eq!(report.count(Problem::DanglingReference), 2);
+            assert_eq!(report.count(Problem::OrphanedContent), 1);
+            assert_eq!(report.count(Problem::UndecodableContent), 1);
+            assert_eq!(report.count(Problem::DuplicateHolder), 1);
+            assert_eq!(report.count(Problem::MissingContent), 0);
+            assert_eq!(report.fixed, 0);
+        });
+    }
+
+    #[test]
+    fn repairs_problems() {
+        each_backend(|bank| {
+            let ghost = Ulid::new();
+            tag(&bank, ghost, "lost");
+            put_content(&bank, ghost, b"garbage");
+
+            let report = bank.fsck(FsckMode::Repair).unwrap();
+            assert!(report.fixed > 0);
+            assert!(bank.fsck(FsckMode::Report).unwrap().findings.is_empty());
+        });
+    }
+
+    #[test]
+    fn quarantines_problems() {
+        each_backend(|bank| {
+            let ghost = Ulid::new();
+            tag(&bank, ghost, "lost");
+
+            bank.fsck(FsckMode::Quarantine).unwrap();
+            assert!(bank.fsck(FsckMode::Report).unwrap().findings.is_empty());
+
+            let quarantined = bank.persistent.run_default("?[relation, reason] := *quarantine{relation, reason}").unwrap();
+            assert_eq!(quarantined.rows.len(), 1);
+            assert_eq!(quarantined.rows[0][0].get_str(), Some("tags"));
+        });
+    }
+
+    #[test]
+    fn stops_on_unknown_keys() {
+        each_backend(|bank| {
+            let key = std::env::temp_dir().join(format!("holobank-key-{}", Ulid::new()));
+            std::fs::write(&key, [3; 32]).unwrap();
+            let encrypted = bank.clone().with_encryption(&KeySource::KeyFile(key.clone())).unwrap();
+            let text = Text::new("sealed away", 1);
+            encrypted.dematerialize(&text, true).unwrap();
+            let _ = std::fs::remove_file(&key);
+
+            assert!(matches!(bank.fsck(FsckMode::Repair), Err(FsckError::Cipher(CipherError::UnknownKey(_)))));
+            assert!(bank.sealed_content(text.id()).unwrap().is_some());
+            assert!(encrypted.fsck(FsckMode::Report).unwrap().findings.is_empty());
+        });
+    }
+
+    #[test]
+    fn flags_tampered_payloads() {
+        each_backend(|bank| {
+            let key = std::env::temp_dir().join(format!("holobank-key-{}", Ulid::new()));
+            std::fs::write(&key, [4; 32]).unwrap();
+            let bank = bank.with_encryption(&KeySource::KeyFile(key.clone())).unwrap();
+            let _ = std::fs::remove_file(&key);
+            let text = Text::new("sealed away", 1);
+            bank.dematerialize(&text, true).unwrap();
+
+            let Some(DataValue::Bytes(digest)) = bank
+                .persistent
+                .run_script(
+                    "?[digest] := *content{asset_id: $asset_id, digest}",
+                    BTreeMap::from([("asset_id".to_string(), ulid(text.id()))]),
+                    ScriptMutability::Immutable
+                )
+                .unwrap()
+                .rows
+                .into_iter()
+                .next()
+                .and_then(|row| row.into_iter().next())
+            else {
+                panic!("no content");
+            };
+            let Some((_, DataValue::List(mut sealed))) = bank.sealed_content(text.id()).unwrap() else {
+                panic!("not sealed");
+            };
+            if let DataValue::Bytes(ciphertext) = &mut sealed[2] {
+                ciphertext[0] ^= 1;
+            }
+            bank.persistent.run_script(
+                "?[digest, payload] <- [[$digest, $payload]] :update blob {digest => payload}",
+                BTreeMap::from([
+                    ("digest".to_string(), DataValue::Bytes(digest)),
+                    ("payload".to_string(), DataValue::List(sealed)),
+                ]),
+                ScriptMutability::Mutable
+            ).unwrap();
+
+            assert_eq!(bank.fsck(FsckMode::Report).unwrap().count(Problem::UndecodableContent), 1);
+        });
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::BTreeMap;
+
+use constellations::connection::{Connection, ConnectionType};
+use cozo::{DataValue, NamedRows, ScriptMutability};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use super::{now, ulid, Holobank};
+
+/// Which way to follow connections from an id.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Connections where the id is the source.
+    #[default]
+    Outgoing,
+    /// Connections where the id is the destination.
+    Incoming,
+    Both,
+}
+
+/// An id reached by walking connections, with the number of hops taken.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Reached {
+    pub id: Ulid,
+    pub depth: i64,
+}
+
+impl Holobank {
+    /// Creates a typed edge.  Connecting the same pair with the same type
+    /// again only refreshes its creation time.
+    pub fn connect(&self, connection: &Connection) -> Result<(), cozo::Error> {
+        let mut params = edge_params(connection);
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "?[src, dest, type, time_created] <- [[$src, $dest, $type, $time]]
+            :put connection {src, dest, type => time_created}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Removes a typed edge.
+    pub fn disconnect(&self, connection: &Connection) -> Result<(), cozo::Error> {
+        self.persistent.run_script(
+            "?[src, dest, type] <- [[$src, $dest, $type]]
+            :rm connection {src, dest, type}",
+            edge_params(connection),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Lists the connections one hop away from an id, optionally limited to
+    /// some connection types.
+    pub fn neighbors(
+        &self,
+        id: Ulid,
+        direction: Direction,
+        types: &[ConnectionType],
+    ) -> Result<Vec<Connection>, cozo::Error> {
+        let script = format!(
+            "{}
+            ?[src, dest, type] := {}",
+            type_filter(types),
+            match direction {
+                Direction::Outgoing => "*connection{src, dest, type}, src = $id, wanted[type]",
+                Direction::Incoming => "*connection{src, dest, type}, dest = $id, wanted[type]",
+                Direction::Both => "*connection{src, dest, type}, src = $id, wanted[type]
+            ?[src, dest, type] := *connection{src, dest, type}, dest = $id, wanted[type]",
+            }
+        );
+        let rows = self.persistent.run_script(&script, id_params(id, types), ScriptMutability::Immutable)?;
+        Ok(connections(rows))
+    }
+
+    /// Every id that reaches `id` through connections of the given types,
+    /// closest first.
+    pub fn ancestors(&self, id: Ulid, types: &[ConnectionType]) -> Result<Vec<Reached>, cozo::Error> {
+        let script = format!(
+            "{}
+            reach[node, min(depth)] := *connection{{src: node, dest: $id, type}}, wanted[type], depth = 1
+            reach[node, min(depth)] := reach[next, d], *connection{{src: node, dest: next, type}}, wanted[type], depth = d + 1
+            ?[node, depth] := reach[node, depth], node != $id
+            :order depth",
+            type_filter(types)
+        );
+        let rows = self.persistent.run_script(&script, id_params(id, types), ScriptMutability::Immutable)?;
+        Ok(reached(rows))
+    }
+
+    /// Every id reachable from `id` through connections of the given types,
+    /// closest first.
+    pub fn descendants(&self, id: Ulid, types: &[ConnectionType]) -> Result<Vec<Reached>, cozo::Error> {
+        let script = format!(
+            "{}
+            reach[node, min(depth)] := *connection{{src: $id, dest: node, type}}, wanted[type], depth = 1
+            reach[node, min(depth)] := reach[prev, d], *connection{{src: prev, dest: node, type}}, wanted[type], depth = d + 1
+            ?[node, depth] := reach[node, depth], node != $id
+            :order depth",
+            type_filter(types)
+        );
+        let rows = self.persistent.run_script(&script, id_params(id, types), ScriptMutability::Immutable)?;
+        Ok(reached(rows))
+    }
+
+    /// The fewest hops from `from` to `to` following connections of the
+    /// given types, including both ends.  `None` when `to` is unreachable.
+    pub fn shortest_path(
+        &self,
+        from: Ulid,
+        to: Ulid,
+        types: &[ConnectionType],
+    ) -> Result<Option<Vec<Ulid>>, cozo::Error> {
+        let script = format!(
+            "{}
+            edges[src, dest] := *connection{{src, dest, type}}, wanted[type]
+            start[id] <- [[$from]]
+            goal[id] <- [[$to]]
+            ?[start, goal, path] <~ ShortestPathBFS(edges[], start[], goal[])",
+            type_filter(types)
+        );
+        let mut params = type_params(types);
+        params.insert("from".to_string(), ulid(from));
+        params.insert("to".to_string(), ulid(to));
+
+        let rows = self.persistent.run_script(&script, params, ScriptMutability::Immutable)?;
+        let path = rows.rows
+            .first()
+            .and_then(|row| row.get(2))
+            .and_then(|path| path.get_slice())
+            .map(|path| path.iter().filter_map(|id| id.get_ulid()).collect());
+        Ok(path)
+    }
+
+    /// Every derivation and fork connection leading up to an asset, from
+    /// the asset back to its original sources.
+    pub fn lineage(&self, id: Ulid) -> Result<Vec<Connection>, cozo::Error> {
+        let types = ConnectionType::lineage();
+        let script = format!(
+            "{}
+            edge[src, dest, type] := *connection{{src, dest, type}}, dest = $id, wanted[type]
+            edge[src, next, type] := edge[next, _, _], *connection{{src, dest: next, type}}, wanted[type]
+            ?[src, dest, type] := edge[src, dest, type]",
+            type_filter(&types)
+        );
+        let rows = self.persistent.run_script(&script, id_params(id, &types), ScriptMutability::Immutable)?;
+
+        // Order the edges by walking back from the asset.
+        let mut remaining = connections(rows);
+        let mut ordered = Vec::with_capacity(remaining.len());
+        let mut frontier = vec![id];
+        while !frontier.is_empty() && !remaining.is_empty() {
+            let (next, rest): (Vec<Connection>, Vec<Connection>) = remaining
+                .into_iter()
+                .partition(|c| frontier.contains(&c.dest));
+            frontier = next.iter().map(|c| c.src).collect();
+            ordered.extend(next);
+            remaining = rest;
+        }
+        Ok(ordered)
+    }
+}
+
+/// Defines the `wanted[type]` rule.  No types means every type.
+fn type_filter(types: &[ConnectionType]) -> &'static str {
+    if types.is_empty() {
+        "wanted[type] := *connection{type}"
+    } else {
+        "wanted[type] := type in $types"
+    }
+}
+
+fn type_params(types: &[ConnectionType]) -> BTreeMap<String, DataValue> {
+    let mut params = BTreeMap::new();
+    let types = types.iter().map(|t| DataValue::from(t.as_str())).collect::<Vec<_>>();
+    params.insert("types".to_string(), DataValue::List(types));
+    params
+}
+
+fn id_params(id: Ulid, types: &[ConnectionType]) -> BTreeMap<String, DataValue> {
+    let mut params = type_params(types);
+    params.insert("id".to_string(), ulid(id));
+    params
+}
+
+fn edge_params(connection: &Connection) -> BTreeMap<String, DataValue> {
+    let mut params = BTreeMap::new();
+    params.insert("src".to_string(), ulid(connection.src));
+    params.insert("dest".to_string(), ulid(connection.dest));
+    params.insert("type".to_string(), DataValue::from(connection.kind.as_str()));
+    params
+}
+
+fn connections(rows: NamedRows) -> Vec<Connection> {
+    rows.rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(Connection::new(
+                row[0].get_ulid()?,
+                row[1].get_ulid()?,
+                ConnectionType::from(row[2].get_str()?),
+            ))
+        })
+        .collect()
+}
+
+fn reached(rows: NamedRows) -> Vec<Reached> {
+    rows.rows
+        .into_iter()
+        .filter_map(|row| Some(Reached { id: row[0].get_ulid()?, depth: row[1].get_int()? }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::holobank::each_backend;
+
+    /// a -> b -> c -> a derive from each other in a cycle, c was forked
+    /// into d and e stands apart.
+    fn graph(bank: &Holobank) -> [Ulid; 5] {
+        let ids = [Ulid::new(), Ulid::new(), Ulid::new(), Ulid::new(), Ulid::new()];
+        let [a, b, c, d, _] = ids;
+        for (src, dest, kind) in [
+            (a, b, ConnectionType::Derivation),
+            (b, c, ConnectionType::Derivation),
+            (c, a, ConnectionType::Derivation),
+            (c, d, ConnectionType::Fork),
+        ] {
+            bank.connect(&Connection::new(src, dest, kind)).unwrap();
+        }
+        ids
+    }
+
+    fn ids(reached: Vec<Reached>) -> Vec<(Ulid, i64)> {
+        reached.into_iter().map(|r| (r.id, r.depth)).collect()
+    }
+
+    #[test]
+    fn lists_neighbors() {
+        each_backend(|bank| {
+            let [a, b, c, d, e] = graph(&bank);
+            let derivation = |src, dest| Connection::new(src, dest, ConnectionType::Derivation);
+            assert_eq!(bank.neighbors(b, Direction::Outgoing, &[]).unwrap(), vec![derivation(b, c)]);
+            assert_eq!(bank.neighbors(b, Direction::Incoming, &[]).unwrap(), vec![derivation(a, b)]);
+            let both: HashSet<Connection> = bank.neighbors(b, Direction::Both, &[]).unwrap().into_iter().collect();
+            assert_eq!(both, HashSet::from([derivation(a, b), derivation(b, c)]));
+            assert_eq!(
+                bank.neighbors(c, Direction::Outgoing, &[ConnectionType::Fork]).unwrap(),
+                vec![Connection::new(c, d, ConnectionType::Fork)]
+            );
+            assert!(bank.neighbors(e, Direction::Both, &[]).unwrap().is_empty());
+
+            bank.disconnect(&derivation(b, c)).unwrap();
+            assert!(bank.neighbors(b, Direction::Outgoing, &[]).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn walks_through_cycles() {
+        each_backend(|bank| {
+            let [a, b, c, d, e] = graph(&bank);
+            assert_eq!(ids(bank.descendants(a, &[]).unwrap()), vec![(b, 1), (c, 2), (d, 3)]);
+            assert_eq!(ids(bank.descendants(a, &[ConnectionType::Derivation]).unwrap()), vec![(b, 1), (c, 2)]);
+            assert_eq!(ids(bank.ancestors(d, &[]).unwrap()), vec![(c, 1), (b, 2), (a, 3)]);
+            assert_eq!(ids(bank.ancestors(a, &[]).unwrap()), vec![(c, 1), (b, 2)]);
+            assert!(bank.ancestors(e, &[]).unwrap().is_empty());
+            assert!(bank.descendants(d, &[]).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn finds_shortest_paths() {
+        each_backend(|bank| {
+            let [a, b, c, d, e] = graph(&bank);
+            assert_eq!(bank.shortest_path(a, d, &[]).unwrap(), Some(vec![a, b, c, d]));
+            assert_eq!(bank.shortest_path(c, b, &[]).unwrap(), Some(vec![c, a, b]));
+            assert_eq!(bank.shortest_path(a, d, &[ConnectionType::Derivation]).unwrap(), None);
+            assert_eq!(bank.shortest_path(a, e, &[]).unwrap(), None);
+            assert_eq!(bank.shortest_path(d, a, &[]).unwrap(), None);
+        });
+    }
+}
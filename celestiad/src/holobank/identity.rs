@@ -0,0 +1,372 @@
+// Commanders sign what they do with Ed25519 keys.  A bank opened for a
+// commander signs every edit, ledger entry and ownership change made through
+// it and keeps the signed operation in the `signature` relation, which the
+// change feed logs so replicas receive it.
+//
+// The keys of each commander are kept in `commander_key` with the time each
+// was in use.  A bank learns a commander's first key only when told to trust
+// it, from the settings or when opened for the commander, never from a
+// signature it is shown, and from then on only takes a new key from a
+// rotation signed with the current one.  An operation verifies when its
+// signature matches and the key was the commander's when it was signed.
+// Ownership changes and ledger entries from other banks are applied only once
+// they verify.
+//
+// The time an operation was signed is the signer's word.  A rotation retires
+// a key for what is signed afterwards, but whoever holds the retired key can
+// still sign operations dated before the rotation, and they verify.
+
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use constellations::user::{
+    keys::{Keypair, PublicKey},
+    signed::{Operation, Signed},
+};
+use cozo::{DataValue, ScriptMutability};
+use serde::Serialize;
+use ulid::Ulid;
+
+use super::{temporal::Holder, ulid, Holobank};
+
+#[derive(Debug)]
+pub enum SignatureError {
+    /// The signature does not match the operation and key.
+    Invalid,
+    /// The key was not the commander's when the operation was signed.
+    UnknownKey { commander: Ulid, key: PublicKey },
+    /// A request that has to be signed is not.
+    Unsigned,
+    /// A request was signed too long ago, or too far ahead, to be taken.
+    Expired,
+    Database(cozo::Error),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Invalid => write!(f, "invalid signature"),
+            SignatureError::UnknownKey { commander, key } => write!(f, "key {} is not a key of commander {}", key, commander),
+            SignatureError::Unsigned => write!(f, "not signed"),
+            SignatureError::Expired => write!(f, "signature expired"),
+            SignatureError::Database(error) => write!(f, "database failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+impl From<cozo::Error> for SignatureError {
+    fn from(value: cozo::Error) -> Self {
+        SignatureError::Database(value)
+    }
+}
+
+/// The commander a bank signs for.
+pub(crate) struct Signer {
+    pub(crate) commander: Ulid,
+    pub(crate) keypair: Keypair,
+}
+
+/// A key of a commander and when it was in use.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CommanderKey {
+    pub key: PublicKey,
+    pub time_added: i64,
+    pub time_retired: Option<i64>,
+}
+
+impl CommanderKey {
+    fn covers(&self, time: i64) -> bool {
+        self.time_added <= time && self.time_retired.is_none_or(|retired| time < retired)
+    }
+}
+
+impl Holobank {
+    /// Signs edits, ledger entries and ownership changes made through the
+    /// bank as `commander`.  The key becomes the commander's if the bank
+    /// knows no key of theirs; otherwise it has to be their current key.
+    pub fn with_commander(mut self, commander: Ulid, keypair: Keypair) -> Result<Holobank, SignatureError> {
+        let key = keypair.public();
+        let keys = self.commander_keys(commander)?;
+        if keys.is_empty() {
+            self.add_key(commander, key, 0)?;
+        } else if !keys.iter().any(|known| known.key == key && known.time_retired.is_none()) {
+            return Err(SignatureError::UnknownKey { commander, key });
+        }
+        self.signer = Some(Arc::new(Signer { commander, keypair }));
+        Ok(self)
+    }
+
+    /// Takes `key` as the first key of a commander, e.g. of one asking
+    /// for shared collections from another spaceport.  A commander whose
+    /// keys are known already only gets new ones through rotations.
+    pub fn trust_key(&self, commander: Ulid, key: PublicKey) -> Result<(), SignatureError> {
+        let keys = self.commander_keys(commander)?;
+        if keys.is_empty() {
+            self.add_key(commander, key, 0)?;
+        } else if !keys.iter().any(|known| known.key == key) {
+            return Err(SignatureError::UnknownKey { commander, key });
+        }
+        Ok(())
+    }
+
+    /// The commander the bank signs for.
+    pub fn commander(&self) -> Option<Ulid> {
+        self.signer.as_ref().map(|signer| signer.commander)
+    }
+
+    /// Keys of a commander, oldest first.
+    pub fn commander_keys(&self, commander: Ulid) -> Result<Vec<CommanderKey>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[public_key, time_added, time_retired] := *commander_key{commander_id: $commander, public_key, time_added, time_retired}
+            :order time_added",
+            BTreeMap::from([("commander".to_string(), ulid(commander))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows
+            .iter()
+            .filter_map(|row| {
+                Some(CommanderKey {
+                    key: PublicKey::from_bytes(row[0].get_bytes()?)?,
+                    time_added: row[1].get_int()?,
+                    time_retired: row[2].get_int(),
+                })
+            })
+            .collect())
+    }
+
+    /// Signed operations on an asset, or rotations of a commander's key,
+    /// oldest first.
+    pub fn signatures(&self, subject: Ulid) -> Result<Vec<Signed>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[time, signed] := *signature{subject: $subject, time, signed} :order time",
+            BTreeMap::from([("subject".to_string(), ulid(subject))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows.iter().filter_map(|row| decode(&row[1])).collect())
+    }
+
+    /// Signs an operation as the bank's commander and keeps it.  Banks
+    /// without a commander sign nothing.
+    pub(crate) fn sign(&self, operation: Operation) -> Result<(), cozo::Error> {
+        let Some(signer) = &self.signer else {
+            return Ok(());
+        };
+        self.store_signature(&Signed::sign(operation, signer.commander, &signer.keypair))
+    }
+
+    /// Checks that the commander signed the operation with a key that was
+    /// theirs at the time it claims.  Commanders whose keys the bank was not
+    /// told to trust verify nothing.
+    pub fn verify(&self, signed: &Signed) -> Result<(), SignatureError> {
+        if !signed.is_valid() {
+            return Err(SignatureError::Invalid);
+        }
+        self.check_key(signed.commander, signed.key, signed.time)
+    }
+
+    /// Checks that `key` was the commander's at `time`.
+    pub(crate) fn check_key(&self, commander: Ulid, key: PublicKey, time: i64) -> Result<(), SignatureError> {
+        if self.commander_keys(commander)?.iter().any(|known| known.key == key && known.covers(time)) {
+            Ok(())
+        } else {
+            Err(SignatureError::UnknownKey { commander, key })
+        }
+    }
+
+    /// Replaces a commander's current key with the one a rotation names.
+    /// The rotation has to be signed with the current key.  Applying a
+    /// rotation again does nothing.
+    pub fn rotate_key(&self, rotation: &Signed) -> Result<(), SignatureError> {
+        let Operation::Rotate { next } = rotation.operation else {
+            return Err(SignatureError::Invalid);
+        };
+        if !rotation.is_valid() {
+            return Err(SignatureError::Invalid);
+        }
+        let keys = self.commander_keys(rotation.commander)?;
+        let applied = keys.iter().any(|known| known.key == rotation.key && known.time_retired == Some(rotation.time));
+        if applied && keys.iter().any(|known| known.key == next && known.time_added == rotation.time) {
+            return Ok(());
+        }
+        // Signed while current, and still current.
+        self.verify(rotation)?;
+        if keys.iter().any(|known| known.key == rotation.key && known.time_retired.is_some()) {
+            return Err(SignatureError::UnknownKey { commander: rotation.commander, key: rotation.key });
+        }
+
+        let mut params = BTreeMap::new();
+        params.insert("commander".to_string(), ulid(rotation.commander));
+        params.insert("current".to_string(), DataValue::Bytes(rotation.key.as_bytes().to_vec()));
+        params.insert("next".to_string(), DataValue::Bytes(next.as_bytes().to_vec()));
+        params.insert("time".to_string(), DataValue::from(rotation.time));
+        self.persistent.run_script(
+            "{
+                ?[commander_id, public_key, time_added, time_retired] :=
+                    *commander_key{commander_id, public_key, time_added},
+                    commander_id = $commander, public_key = $current, time_retired = $time
+                :put commander_key {commander_id, public_key => time_added, time_retired}
+            }
+            {
+                ?[commander_id, public_key, time_added, time_retired] <- [[$commander, $next, $time, null]]
+                :put commander_key {commander_id, public_key => time_added, time_retired}
+            }",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        self.store_signature(rotation)?;
+        Ok(())
+    }
+
+    /// Verifies an operation signed at another bank and applies it.
+    /// Signed edits are only kept; their content arrives separately.
+    pub(crate) fn integrate(&self, signed: &Signed) -> Result<(), SignatureError> {
+        match signed.operation {
+            Operation::Rotate { .. } => return self.rotate_key(signed),
+            Operation::Edit { .. } => self.verify(signed)?,
+            Operation::Hold { asset, spaceport, spacecraft, commander, at, held } => {
+                self.verify(signed)?;
+                self.write_ledger(asset, &Holder { spaceport, spacecraft, commander }, at, held)?;
+            }
+            Operation::Own { entity, owner, at, owned } => {
+                self.verify(signed)?;
+                self.write_owner(entity, owner, at, owned)?;
+            }
+        }
+        self.store_signature(signed)?;
+        Ok(())
+    }
+
+    fn add_key(&self, commander: Ulid, key: PublicKey, time: i64) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("commander".to_string(), ulid(commander));
+        params.insert("key".to_string(), DataValue::Bytes(key.as_bytes().to_vec()));
+        params.insert("time".to_string(), DataValue::from(time));
+        self.persistent.run_script(
+            "?[commander_id, public_key, time_added, time_retired] <- [[$commander, $key, $time, null]]
+            :put commander_key {commander_id, public_key => time_added, time_retired}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    fn store_signature(&self, signed: &Signed) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("subject".to_string(), ulid(signed.subject()));
+        params.insert("kind".to_string(), DataValue::from(signed.operation.kind()));
+        params.insert("time".to_string(), DataValue::from(signed.time));
+        params.insert("signature".to_string(), DataValue::Bytes(signed.signature.clone()));
+        params.insert("commander".to_string(), ulid(signed.commander));
+        params.insert("signed".to_string(), DataValue::Bytes(encode(signed)));
+        self.persistent.run_script(
+            "?[subject, kind, time, signature, commander_id, signed] <- [[$subject, $kind, $time, $signature, $commander, $signed]]
+            :put signature {subject, kind, time, signature => commander_id, signed}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+}
+
+pub(crate) fn encode(signed: &Signed) -> Vec<u8> {
+    serde_json::to_vec(signed).expect("signed operations serialize")
+}
+
+pub(crate) fn decode(value: &DataValue) -> Option<Signed> {
+    serde_json::from_slice(value.get_bytes()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holobank::each_backend;
+    use constellations::asset::{block::text::Text, Asset};
+
+    #[test]
+    fn signs_edits_ledger_and_ownership() {
+        each_backend(|bank| {
+            let commander = Ulid::new();
+            let keypair = Keypair::generate();
+            let bank = bank.with_commander(commander, keypair.clone()).unwrap();
+            assert_eq!(bank.commander(), Some(commander));
+            assert!(bank.clone().with_commander(commander, Keypair::generate()).is_err());
+
+            let text = Text::new("signed", 1);
+            bank.dematerialize(&text, false).unwrap();
+            let holder = Holder { spaceport: Ulid::new(), spacecraft: None, commander: Some(commander) };
+            bank.record_holder(text.id(), &holder, 10).unwrap();
+            bank.assert_owner(text.id(), commander, 10).unwrap();
+
+            let signed = bank.signatures(text.id()).unwrap();
+            let kinds: Vec<&str> = signed.iter().map(|s| s.operation.kind()).collect();
+            assert_eq!(kinds, ["edit", "hold", "own"]);
+            assert!(signed.iter().all(|s| s.commander == commander && s.key == keypair.public() && s.is_valid()));
+            let digest = bank.content_digest(text.id()).unwrap().unwrap();
+            assert_eq!(signed[0].operation, Operation::Edit { asset: text.id(), digest });
+            for s in &signed {
+                bank.verify(s).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn integrates_verified_operations_only() {
+        each_backend(|bank| {
+            let commander = Ulid::new();
+            let keypair = Keypair::generate();
+            let (entity, owner) = (Ulid::new(), Ulid::new());
+            let own = Signed::sign(Operation::Own { entity, owner, at: 10, owned: true }, commander, &keypair);
+
+            let mut forged = own.clone();
+            forged.operation = Operation::Own { entity, owner: Ulid::new(), at: 10, owned: true };
+            assert!(matches!(bank.integrate(&forged), Err(SignatureError::Invalid)));
+            assert!(matches!(bank.integrate(&own), Err(SignatureError::UnknownKey { .. })));
+            assert!(bank.commander_keys(commander).unwrap().is_empty());
+            assert!(bank.owners_at(entity, 20).unwrap().is_empty());
+
+            bank.trust_key(commander, keypair.public()).unwrap();
+            bank.integrate(&own).unwrap();
+            assert_eq!(bank.owners_at(entity, 20).unwrap(), vec![owner]);
+            assert_eq!(bank.commander_keys(commander).unwrap()[0].key, keypair.public());
+
+            let impostor = Signed::sign(Operation::Own { entity, owner: Ulid::new(), at: 15, owned: true }, commander, &Keypair::generate());
+            assert!(matches!(bank.integrate(&impostor), Err(SignatureError::UnknownKey { .. })));
+            assert_eq!(bank.owners_at(entity, 20).unwrap(), vec![owner]);
+        });
+    }
+
+    #[test]
+    fn rotates_keys() {
+        each_backend(|bank| {
+            let commander = Ulid::new();
+            let first = Keypair::generate();
+            let bank = bank.with_commander(commander, first.clone()).unwrap();
+            let asset = Ulid::new();
+            let before = Signed::sign(Operation::Edit { asset, digest: vec![1] }, commander, &first);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+
+            let next = Keypair::generate();
+            let rotation = Signed::sign(Operation::Rotate { next: next.public() }, commander, &first);
+            bank.rotate_key(&rotation).unwrap();
+            bank.rotate_key(&rotation).unwrap();
+            let keys = bank.commander_keys(commander).unwrap();
+            assert_eq!(keys.len(), 2);
+            assert_eq!(keys[0].time_retired, Some(rotation.time));
+            assert_eq!((keys[1].key, keys[1].time_retired), (next.public(), None));
+            assert_eq!(bank.signatures(commander).unwrap(), vec![rotation]);
+
+            // Operations signed before the rotation still verify; the old
+            // key signs nothing new and cannot rotate again.
+            bank.verify(&before).unwrap();
+            let after = Signed::sign(Operation::Edit { asset, digest: vec![2] }, commander, &first);
+            assert!(bank.verify(&after).is_err());
+            let again = Signed::sign(Operation::Rotate { next: Keypair::generate().public() }, commander, &first);
+            assert!(bank.rotate_key(&again).is_err());
+            bank.verify(&Signed::sign(Operation::Edit { asset, digest: vec![3] }, commander, &next)).unwrap();
+
+            assert!(bank.clone().with_commander(commander, first).is_err());
+            assert!(bank.with_commander(commander, next).is_ok());
+        });
+    }
+}
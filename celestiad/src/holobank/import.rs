@@ -0,0 +1,634 @@
+// Vaults of Markdown notes are imported file by file: notes become text
+// blocks, images image blocks and other attachments heap blocks.  Each folder
+// becomes a collection of the files directly in it, frontmatter tags become
+// tags and wikilinks become `link` connections from the note.  The `imported`
+// relation remembers the asset made from each file by the directory and path
+// it came from, so importing a vault again updates those assets, and only
+// those whose file changed, instead of duplicating them.  Files gone from the
+// vault leave their folder's collection but stay in the bank, and folders
+// gone from it lose the files imported into their collection, which is
+// removed once nothing else is in it.  Either is reported missing once and
+// then forgotten.
+//
+// Collections exported to a directory come back with their ids, and with the
+// names, tags, flags and connections the manifest keeps for their assets.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt, io,
+    path::Path,
+};
+
+use cola::ReplicaId;
+use constellations::{
+    asset::{
+        assembly::export::Content,
+        block::{heap::Heap, image::Image, text::Text},
+        vault::{self, FileKind, Vault, VaultFile},
+        Asset, AssetType, Import, Materializable,
+    },
+    collection::{export::{Manifest, Record}, Collection},
+    connection::{Connection, ConnectionType},
+    user::signed::Operation,
+};
+use cozo::{DataValue, ScriptMutability};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use ulid::Ulid;
+
+use super::{blob, graph::Direction, now, ulid, Holobank};
+
+/// Type of the connections made from wikilinks and embeds.
+pub const LINK_TYPE: &str = "link";
+
+/// What an import did, or would do on a dry run.  Paths are relative to
+/// the vault.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ImportReport {
+    /// Files imported for the first time.
+    pub created: Vec<String>,
+    /// Files changed since they were last imported.
+    pub updated: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Files and folders imported before that are gone from the vault.
+    pub missing: Vec<String>,
+    /// Folders imported as collections for the first time.
+    pub collections: Vec<String>,
+    /// Links resolved to a file of the vault.
+    pub links: usize,
+    /// Links to nothing in the vault, by the note they are in.
+    pub unresolved: Vec<(String, String)>,
+    /// Files that could not be read, with the reason.
+    pub skipped: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    Database(cozo::Error),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Io(e) => write!(f, "could not read vault: {}", e),
+            ImportError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+
+impl From<cozo::Error> for ImportError {
+    fn from(e: cozo::Error) -> Self {
+        ImportError::Database(e)
+    }
+}
+
+/// A block read from a file of the vault.
+enum Block {
+    Text(Text),
+    Image(Image),
+    Heap(Heap),
+}
+
+impl Block {
+    fn read(vault: &Vault, file: &VaultFile) -> io::Result<Block> {
+        let path = vault.path(file);
+        Ok(match file.kind {
+            FileKind::Note => Block::Text(Text::import(&path)?),
+            FileKind::Image => Block::Image(Image::import(&path)?),
+            FileKind::Attachment => Block::Heap(Heap::import(&path)?),
+        })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Block::Text(text) => text.as_str().as_bytes(),
+            Block::Image(image) => image.as_bytes(),
+            Block::Heap(heap) => heap.as_bytes(),
+        }
+    }
+
+    fn id(&self) -> Ulid {
+        match self {
+            Block::Text(text) => text.id(),
+            Block::Image(image) => image.id(),
+            Block::Heap(heap) => heap.id(),
+        }
+    }
+}
+
+/// A file of the vault as it will be imported.
+struct Planned<'a> {
+    file: &'a VaultFile,
+    id: Ulid,
+    digest: Vec<u8>,
+    block: Block,
+    /// Whether the file is new, or changed since it was last imported.
+    store: bool,
+}
+
+impl Holobank {
+    /// Imports the vault at `root`, or only reports what importing it would
+    /// do when `dry_run` is set.
+    pub fn import_vault(&self, root: &Path, dry_run: bool) -> Result<ImportReport, ImportError> {
+        let root = root.canonicalize()?;
+        let vault = Vault::scan(&root)?;
+        let source = root.to_string_lossy().into_owned();
+        let known = self.imported(&source)?;
+        let mut report = ImportReport::default();
+
+        let mut planned = Vec::new();
+        for file in vault.files() {
+            let block = match Block::read(&vault, file) {
+                Ok(block) => block,
+                Err(e) => {
+                    report.skipped.push((file.path.clone(), e.to_string()));
+                    continue;
+                }
+            };
+            let digest = Sha256::digest(block.bytes()).to_vec();
+            let (id, store) = match known.get(&file.path) {
+                Some((id, previous)) if *previous == digest => {
+                    report.unchanged.push(file.path.clone());
+                    (*id, false)
+                }
+                Some((id, _)) => {
+                    report.updated.push(file.path.clone());
+                    (*id, true)
+                }
+                None => {
+                    report.created.push(file.path.clone());
+                    (block.id(), true)
+                }
+            };
+            planned.push(Planned { file, id, digest, block, store });
+        }
+        let ids: HashMap<&str, Ulid> = planned.iter().map(|plan| (plan.file.path.as_str(), plan.id)).collect();
+        let present: HashSet<&str> = vault
+            .files()
+            .iter()
+            .map(|file| file.path.as_str())
+            .chain(vault.folders().iter().map(String::as_str))
+            .collect();
+        report.missing = known.keys().filter(|path| !present.contains(path.as_str())).cloned().collect();
+        report.missing.sort();
+
+        let mut links: Vec<(Ulid, BTreeSet<Ulid>)> = Vec::new();
+        for plan in &planned {
+            let Block::Text(text) = &plan.block else {
+                continue;
+            };
+            let mut targets = BTreeSet::new();
+            for link in vault::wikilinks(text.as_str()) {
+                match vault.resolve(&plan.file.path, &link.target).and_then(|file| ids.get(file.path.as_str())) {
+                    Some(target) if *target != plan.id => {
+                        targets.insert(*target);
+                    }
+                    Some(_) => {}
+                    None => report.unresolved.push((plan.file.path.clone(), link.target)),
+                }
+            }
+            report.links += targets.len();
+            links.push((plan.id, targets));
+        }
+        report.collections = vault
+            .folders()
+            .iter()
+            .filter(|folder| !known.contains_key(folder.as_str()))
+            .cloned()
+            .collect();
+        if dry_run {
+            return Ok(report);
+        }
+
+        for plan in planned {
+            if plan.store {
+                self.store_imported(plan.id, plan.block)?;
+                self.record_import(&source, &plan.file.path, plan.id, &plan.digest)?;
+            }
+        }
+        for (note, targets) in links {
+            self.set_links(note, &targets)?;
+        }
+
+        let imported: HashSet<Ulid> = known.values().map(|(id, _)| *id).chain(ids.values().copied()).collect();
+        for folder in vault.folders() {
+            let id = known.get(folder).map_or_else(Ulid::new, |(id, _)| *id);
+            let mut collection = match self.collection(id)? {
+                Some(collection) => collection,
+                None => Collection::from_parts(id, collection_name(&vault, folder), None, []),
+            };
+            let members: HashSet<Ulid> = vault
+                .files()
+                .iter()
+                .filter(|file| file.folder() == folder)
+                .filter_map(|file| ids.get(file.path.as_str()).copied())
+                .collect();
+            let stale: Vec<Ulid> = collection
+                .assets()
+                .map(|(asset, _)| asset)
+                .filter(|asset| imported.contains(asset) && !members.contains(asset))
+                .collect();
+            for asset in stale {
+                collection.remove_asset(asset);
+            }
+            for asset in members {
+                collection.add_asset(asset, AssetType::Block);
+            }
+            self.store_collection(&collection)?;
+            self.record_import(&source, folder, id, &[])?;
+        }
+
+        for path in &report.missing {
+            let Some((id, digest)) = known.get(path) else {
+                continue;
+            };
+            // Folders are recorded without a digest.
+            if digest.is_empty() {
+                if let Some(mut collection) = self.collection(*id)? {
+                    let stale: Vec<Ulid> = collection
+                        .assets()
+                        .map(|(asset, _)| asset)
+                        .filter(|asset| imported.contains(asset))
+                        .collect();
+                    for asset in stale {
+                        collection.remove_asset(asset);
+                    }
+                    if collection.is_empty() {
+                        self.remove_collection(*id)?;
+                    } else {
+                        self.store_collection(&collection)?;
+                    }
+                }
+            }
+            self.forget_import(&source, path)?;
+        }
+        Ok(report)
+    }
+
+    /// Restores the collection exported to `directory` with its assets.
+    /// Assets the bank knows already keep their registration; their content,
+    /// tags and flags become the exported ones.  Texts keep the edit history
+    /// exported with them.
+    pub fn import_collection(&self, directory: &Path) -> Result<Collection, ImportError> {
+        let manifest = Manifest::read(directory)?;
+        for entry in &manifest.assets {
+            self.restore_record(entry.id, &entry.record)?;
+            match manifest.content(directory, entry)? {
+                Some(Content::Text(markdown)) => {
+                    let text = match manifest.history(directory, entry)? {
+                        Some(stored) => {
+                            // The Markdown may have been edited since.
+                            let mut text = stored.with_id(entry.id);
+                            if text.as_str() != markdown {
+                                text.replace(markdown);
+                            }
+                            text
+                        }
+                        None => Text::new(markdown, Ulid::new().random() as ReplicaId).with_id(entry.id),
+                    };
+                    self.store_block(&text)?;
+                }
+                Some(Content::Image(bytes)) => self.store_block(&Image::new(bytes).with_id(entry.id))?,
+                Some(Content::Assembly(assembly)) => self.store_block(assembly.as_ref())?,
+                Some(Content::Other { content_type, bytes }) => {
+                    let digest = blob::digest(&bytes);
+                    self.store_content(entry.id, &content_type, bytes)?;
+                    self.sign(Operation::Edit { asset: entry.id, digest })?;
+                    self.content_stored(entry.id)?;
+                }
+                None => {}
+            }
+        }
+        self.store_collection(&manifest.collection)?;
+        Ok(manifest.collection)
+    }
+
+    /// Registers an asset under its exported name and origin, unless the bank
+    /// knows it, and sets its tags, flags and connections.
+    fn restore_record(&self, id: Ulid, record: &Record) -> Result<(), cozo::Error> {
+        let origin = record.connections.iter().find(|connection| {
+            connection.dest == id && matches!(connection.kind, ConnectionType::Derivation | ConnectionType::Fork)
+        });
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(id));
+        params.insert("name".to_string(), record.name.as_deref().map_or(DataValue::Null, DataValue::from));
+        params.insert("asset_type".to_string(), DataValue::from(record.asset_type.as_str()));
+        params.insert("derived_from".to_string(), origin.map_or(DataValue::Null, |o| ulid(o.src)));
+        params.insert("derivation_type".to_string(), origin.map_or(DataValue::Null, |o| DataValue::from(o.kind.as_str())));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "?[asset_id, name, derived_from, asset_type, derivation_type, time_registered] :=
+                asset_id = $asset_id, name = $name, derived_from = $derived_from, asset_type = $asset_type,
+                derivation_type = $derivation_type, time_registered = $time, not *asset{asset_id: $asset_id}
+            :put asset {asset_id, name, derived_from => asset_type, derivation_type, time_registered}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+
+        let current: BTreeSet<String> = self.persistent.run_script(
+            "?[tag] := *tags{asset_id: $asset_id, tag}",
+            BTreeMap::from([("asset_id".to_string(), ulid(id))]),
+            ScriptMutability::Immutable
+        )?.rows.iter().filter_map(|row| row[0].get_str().map(str::to_string)).collect();
+        self.retag(id, &record.tags, &current.difference(&record.tags).cloned().collect())?;
+        self.set_flags(id, &record.flags)?;
+        for connection in &record.connections {
+            self.connect(connection)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the flags of an asset.
+    fn set_flags(&self, id: Ulid, flags: &BTreeSet<String>) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(id));
+        params.insert("flags".to_string(), DataValue::List(flags.iter().map(|flag| DataValue::from(flag.as_str())).collect()));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "{
+                ?[asset_id, flag, time_attached] := flag in $flags, asset_id = $asset_id, time_attached = $time,
+                    not *flags{asset_id: $asset_id, flag}
+                :put flags {asset_id, flag => time_attached}
+            }
+            {
+                kept[flag] := flag in $flags
+                ?[asset_id, flag] := *flags{asset_id, flag}, asset_id = $asset_id, not kept[flag]
+                :rm flags {asset_id, flag}
+            }",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Assets and digests of the files imported from `source` before, by
+    /// path.  Folders are kept with an empty digest.
+    fn imported(&self, source: &str) -> Result<HashMap<String, (Ulid, Vec<u8>)>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[path, id, digest] := *imported{source: $source, path, id, digest}",
+            BTreeMap::from([("source".to_string(), DataValue::from(source))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows
+            .iter()
+            .filter_map(|row| {
+                Some((row[0].get_str()?.to_string(), (row[1].get_ulid()?, row[2].get_bytes()?.to_vec())))
+            })
+            .collect())
+    }
+
+    fn record_import(&self, source: &str, path: &str, id: Ulid, digest: &[u8]) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("source".to_string(), DataValue::from(source));
+        params.insert("path".to_string(), DataValue::from(path));
+        params.insert("id".to_string(), ulid(id));
+        params.insert("digest".to_string(), DataValue::Bytes(digest.to_vec()));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "?[source, path, id, digest, time_imported] <- [[$source, $path, $id, $digest, $time]]
+            :put imported {source, path => id, digest, time_imported}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    fn forget_import(&self, source: &str, path: &str) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("source".to_string(), DataValue::from(source));
+        params.insert("path".to_string(), DataValue::from(path));
+        self.persistent.run_script(
+            "?[source, path] <- [[$source, $path]] :rm imported {source, path}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Stores a block as the asset `id`.  A changed note edits the text
+    /// already in the bank, keeping its history, and its tags follow the
+    /// frontmatter: tags it lost are removed, others added to the asset
+    /// are kept.
+    fn store_imported(&self, id: Ulid, block: Block) -> Result<(), cozo::Error> {
+        match block {
+            Block::Text(text) => {
+                let stored = self.content(id)?.and_then(|(_, bytes)| Text::decode(&bytes));
+                let (text, previous) = match stored {
+                    Some(mut stored) if stored.id() == id => {
+                        let previous = vault::tags(stored.as_str());
+                        stored.replace(text.as_str());
+                        (stored, previous)
+                    }
+                    _ if text.id() == id => (text, BTreeSet::new()),
+                    _ => return Err(cozo::Error::msg(format!("text {} is not in the bank", id))),
+                };
+                self.store_block(&text)?;
+                let current = vault::tags(text.as_str());
+                let removed: BTreeSet<String> = previous.difference(&current).cloned().collect();
+                self.retag(id, &current, &removed)
+            }
+            Block::Image(image) => self.store_block(&image.with_id(id)),
+            Block::Heap(heap) => self.store_block(&heap.with_id(id)),
+        }
+    }
+
+    fn store_block<T: Materializable + Asset>(&self, block: &T) -> Result<(), cozo::Error> {
+        self.dematerialize(block, true)
+    }
+
+    fn retag(&self, id: Ulid, add: &BTreeSet<String>, remove: &BTreeSet<String>) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(id));
+        params.insert("add".to_string(), DataValue::List(add.iter().map(|tag| DataValue::from(tag.as_str())).collect()));
+        params.insert("remove".to_string(), DataValue::List(remove.iter().map(|tag| DataValue::from(tag.as_str())).collect()));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "{
+                ?[asset_id, tag, time_attached] := tag in $add, asset_id = $asset_id, time_attached = $time,
+                    not *tags{asset_id: $asset_id, tag}
+                :put tags {asset_id, tag => time_attached}
+            }
+            {
+                ?[asset_id, tag] := tag in $remove, asset_id = $asset_id
+                :rm tags {asset_id, tag}
+            }",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the link connections from a note.
+    fn set_links(&self, note: Ulid, targets: &BTreeSet<Ulid>) -> Result<(), cozo::Error> {
+        let link = ConnectionType::from(LINK_TYPE);
+        for stale in self.neighbors(note, Direction::Outgoing, std::slice::from_ref(&link))? {
+            if !targets.contains(&stale.dest) {
+                self.disconnect(&stale)?;
+            }
+        }
+        for target in targets {
+            self.connect(&Connection::new(note, *target, link.clone()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Collections of folders are named by their path under the vault's name.
+fn collection_name(vault: &Vault, folder: &str) -> String {
+    if folder.is_empty() {
+        vault.name()
+    } else {
+        format!("{}/{}", vault.name(), folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use constellations::asset::{assembly::export::{ExportFormat, Source}, Export};
+
+    use crate::holobank::each_backend;
+
+    fn tags_of(bank: &Holobank, id: Ulid) -> BTreeSet<String> {
+        let rows = bank.persistent.run_script(
+            "?[tag] := *tags{asset_id: $asset_id, tag}",
+            BTreeMap::from([("asset_id".to_string(), ulid(id))]),
+            ScriptMutability::Immutable
+        ).unwrap();
+        rows.rows.iter().filter_map(|row| row[0].get_str().map(str::to_string)).collect()
+    }
+
+    #[test]
+    fn imports_a_vault_again_without_duplicates() {
+        each_backend(|bank| {
+            let root = std::env::temp_dir().join(format!("vault-{}", Ulid::new()));
+            for (path, content) in [
+                ("Index.md", "---\ntags: [home]\n---\nSee [[Trip]] and [[Nowhere]].\n".as_bytes()),
+                ("travel/Trip.md", b"![[map.png]]"),
+                ("travel/map.png", b"\x89PNG"),
+                ("travel/ticket.pdf", b"%PDF"),
+            ] {
+                let path = root.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+
+            let dry = bank.import_vault(&root, true).unwrap();
+            assert_eq!(dry.created.len(), 4);
+            assert_eq!(dry.collections, vec!["".to_string(), "travel".to_string()]);
+            assert_eq!(dry.links, 2);
+            assert_eq!(dry.unresolved, vec![("Index.md".to_string(), "Nowhere".to_string())]);
+            assert!(bank.imported(&root.canonicalize().unwrap().to_string_lossy()).unwrap().is_empty());
+
+            let first = bank.import_vault(&root, false).unwrap();
+            assert_eq!(first, dry);
+            let source = root.canonicalize().unwrap().to_string_lossy().into_owned();
+            let known = bank.imported(&source).unwrap();
+            let (index, _) = known["Index.md"];
+            let (trip, _) = known["travel/Trip.md"];
+            let (map, _) = known["travel/map.png"];
+            let (ticket, _) = known["travel/ticket.pdf"];
+            assert_eq!(bank.content(map).unwrap().unwrap().0, Image::CONTENT_TYPE);
+            assert_eq!(bank.content(ticket).unwrap().unwrap().0, Heap::CONTENT_TYPE);
+            assert_eq!(tags_of(&bank, index), BTreeSet::from(["home".to_string()]));
+            let link = ConnectionType::from(LINK_TYPE);
+            assert_eq!(
+                bank.neighbors(trip, Direction::Outgoing, std::slice::from_ref(&link)).unwrap(),
+                vec![Connection::new(trip, map, link.clone())]
+            );
+            let (travel, _) = known["travel"];
+            let travel = bank.collection(travel).unwrap().unwrap();
+            assert_eq!(travel.name, format!("{}/travel", vault_name(&root)));
+            assert_eq!(travel.len(), 3);
+
+            let again = bank.import_vault(&root, false).unwrap();
+            assert_eq!(again.unchanged.len(), 4);
+            assert!(again.created.is_empty() && again.collections.is_empty());
+            assert_eq!(bank.imported(&source).unwrap(), known);
+
+            fs::write(root.join("Index.md"), "---\ntags: [start]\n---\nSee [[Trip]].\n").unwrap();
+            fs::remove_file(root.join("travel/ticket.pdf")).unwrap();
+            let changed = bank.import_vault(&root, false).unwrap();
+            assert_eq!(changed.updated, vec!["Index.md".to_string()]);
+            assert_eq!(changed.missing, vec!["travel/ticket.pdf".to_string()]);
+            assert_eq!(tags_of(&bank, index), BTreeSet::from(["start".to_string()]));
+            let text = Text::decode(&bank.content(index).unwrap().unwrap().1).unwrap();
+            assert_eq!(text.as_str(), "---\ntags: [start]\n---\nSee [[Trip]].\n");
+            assert!(!bank.collection(known["travel"].0).unwrap().unwrap().contains(ticket));
+            assert!(!bank.imported(&source).unwrap().contains_key("travel/ticket.pdf"));
+
+            // A folder gone from the vault takes its collection with it.
+            fs::remove_dir_all(root.join("travel")).unwrap();
+            let gone = bank.import_vault(&root, false).unwrap();
+            let _ = fs::remove_dir_all(&root);
+            assert_eq!(gone.missing, vec!["travel".to_string(), "travel/Trip.md".to_string(), "travel/map.png".to_string()]);
+            assert!(bank.collection(known["travel"].0).unwrap().is_none());
+            assert!(bank.content(trip).unwrap().is_some());
+            let remaining: BTreeSet<String> = bank.imported(&source).unwrap().into_keys().collect();
+            assert_eq!(remaining, BTreeSet::from(["".to_string(), "Index.md".to_string()]));
+        });
+    }
+
+    #[test]
+    fn exported_collection_comes_back_identical() {
+        each_backend(|bank| {
+            let mut text = Text::new("# Kept", 1);
+            text.replace("# Kept\n\nAs it was.");
+            let derived = text.derive();
+            let image = Image::new(b"\x89PNG\r\n".to_vec());
+            let heap = Heap::new(vec![0, 1, 2]);
+            let mut collection = Collection::new("kept");
+            bank.dematerialize(&text, true).unwrap();
+            bank.dematerialize(&derived, true).unwrap();
+            bank.dematerialize(&image, true).unwrap();
+            bank.dematerialize(&heap, true).unwrap();
+            for id in [text.id(), derived.id(), image.id(), heap.id()] {
+                collection.add_asset(id, AssetType::Block);
+            }
+            collection.share_with(Ulid::new());
+            bank.store_collection(&collection).unwrap();
+            bank.retag(text.id(), &BTreeSet::from(["draft".to_string()]), &BTreeSet::new()).unwrap();
+            bank.set_flags(heap.id(), &BTreeSet::from(["junk".to_string()])).unwrap();
+            bank.connect(&Connection::new(text.id(), image.id(), ConnectionType::from(LINK_TYPE))).unwrap();
+            let original = bank.collection(collection.id).unwrap().unwrap();
+
+            let directory = std::env::temp_dir().join(format!("export-{}", collection.id));
+            original.export(ExportFormat::Markdown, &bank, &directory).unwrap();
+            assert_eq!(fs::read_to_string(directory.join(format!("{}.md", text.id()))).unwrap(), text.as_str());
+            let elsewhere = std::env::temp_dir().join(format!("holobank-{}-{}", bank.backend(), Ulid::new()));
+            let other = Holobank::load(bank.backend(), &elsewhere).unwrap();
+            let restored = other.import_collection(&directory).unwrap();
+
+            assert_eq!(restored, original);
+            assert_eq!(other.collection(collection.id).unwrap(), Some(original.clone()));
+            for (id, _) in original.assets() {
+                assert!(bank.record(id).is_some());
+                assert_eq!(other.record(id), bank.record(id));
+                assert_eq!(other.resolve(id), bank.resolve(id));
+            }
+            assert_eq!(other.content(text.id()).unwrap(), bank.content(text.id()).unwrap());
+
+            fs::write(directory.join(format!("{}.md", text.id())), "# Kept\n\nChanged.").unwrap();
+            other.import_collection(&directory).unwrap();
+            let _ = fs::remove_dir_all(&directory);
+            let mut edited = Text::decode(&bank.content(text.id()).unwrap().unwrap().1).unwrap();
+            edited.replace("# Kept\n\nChanged.");
+            assert_eq!(other.content(text.id()).unwrap().unwrap().1, edited.scan().content);
+            drop(other);
+            let _ = fs::remove_dir_all(&elsewhere);
+        });
+    }
+
+    fn vault_name(root: &Path) -> String {
+        root.file_name().unwrap().to_string_lossy().into_owned()
+    }
+}
@@ -0,0 +1,294 @@
+// Assets registered by a bank land in its inbox, the local buffer collection
+// for data not filed in any collection yet.  Every bank, and so every
+// spaceport, has one inbox, created with the bank and named in the catalog
+// like any collection.  It is never shared, so replication never serves it.
+// Filing moves assets from the inbox into real collections; storing a
+// collection files its assets too.  Assets left in the inbox longer than the
+// retention are flagged `expired` and, when the retention discards them,
+// leave the inbox and lose their content.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use cozo::{DataValue, DbInstance, ScriptMutability};
+use serde::Serialize;
+use tracing::{info, warn};
+use ulid::Ulid;
+
+use super::{now, ulid, Holobank};
+
+/// Name of the inbox in the catalog.
+pub const INBOX_NAME: &str = "inbox";
+
+/// Flag put on assets left in the inbox past the retention.
+pub const EXPIRED_FLAG: &str = "expired";
+
+/// Seconds between retention checks.
+pub const RETENTION_CHECK_SECS: u64 = 3600;
+
+/// How long assets may stay in the inbox.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retention {
+    pub max_age: Duration,
+    /// Drop expired assets from the inbox and their content from the bank
+    /// instead of only flagging them.
+    pub discard: bool,
+}
+
+/// An asset waiting in the inbox.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct InboxItem {
+    pub asset_id: Ulid,
+    pub time_landed: i64,
+}
+
+/// Finds the inbox of a bank, creating it for new banks and banks created by
+/// older versions.
+pub(crate) fn ensure(db: &DbInstance) -> Result<Ulid, cozo::Error> {
+    let found = db.run_default("?[collection_id] := *inbox{collection_id}")?;
+    if let Some(id) = found.rows.first().and_then(|row| row[0].get_ulid()) {
+        return Ok(id);
+    }
+
+    let id = Ulid::new();
+    let mut params = BTreeMap::new();
+    params.insert("collection_id".to_string(), ulid(id));
+    params.insert("name".to_string(), DataValue::from(INBOX_NAME));
+    params.insert("time".to_string(), DataValue::from(now()));
+    db.run_script(
+        "{
+            ?[collection_id, time_created] <- [[$collection_id, $time]]
+            :put inbox {collection_id => time_created}
+        }
+        {
+            ?[collection_id, name, time_created] <- [[$collection_id, $name, $time]]
+            :put catalog {collection_id => name, time_created}
+        }",
+        params,
+        ScriptMutability::Mutable
+    )?;
+    Ok(id)
+}
+
+impl Holobank {
+    /// Expires assets left in the inbox for longer than `retention`.
+    pub fn with_inbox_retention(mut self, retention: Option<Retention>) -> Holobank {
+        self.retention = retention;
+        self
+    }
+
+    /// Id of the inbox collection.
+    pub fn inbox(&self) -> Ulid {
+        self.inbox
+    }
+
+    /// Assets in the inbox, oldest first.
+    pub fn inbox_items(&self) -> Result<Vec<InboxItem>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[time_added, asset_id] := *collection{asset_id, collection_id: $inbox, time_added}
+            :order time_added",
+            BTreeMap::from([("inbox".to_string(), ulid(self.inbox))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows
+            .iter()
+            .filter_map(|row| Some(InboxItem { asset_id: row[1].get_ulid()?, time_landed: row[0].get_int()? }))
+            .collect())
+    }
+
+    /// Files registered assets into a collection, taking them out of the
+    /// inbox.  Returns the number of assets filed.
+    pub fn file(&self, assets: &[Ulid], collection: Ulid) -> Result<usize, cozo::Error> {
+        if collection == self.inbox {
+            return Err(cozo::Error::msg("assets cannot be filed into the inbox"));
+        }
+        let mut params = BTreeMap::new();
+        params.insert("assets".to_string(), DataValue::List(assets.iter().map(|id| ulid(*id)).collect()));
+        params.insert("collection_id".to_string(), ulid(collection));
+        params.insert("time".to_string(), DataValue::from(now()));
+        let filed = self.persistent.run_script(
+            "?[asset_id] := asset_id in $assets, *asset{asset_id},
+                not *collection{asset_id, collection_id: $collection_id}",
+            params.clone(),
+            ScriptMutability::Immutable
+        )?;
+        self.persistent.run_script(
+            "?[asset_id, collection_id, time_added] := asset_id in $assets, *asset{asset_id},
+                collection_id = $collection_id, not *collection{asset_id, collection_id: $collection_id},
+                time_added = $time
+            :put collection {asset_id, collection_id => time_added}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        self.leave_inbox(assets)?;
+        Ok(filed.rows.len())
+    }
+
+    /// Takes assets out of the inbox, e.g. when they are filed.
+    pub(crate) fn leave_inbox(&self, assets: &[Ulid]) -> Result<(), cozo::Error> {
+        self.persistent.run_script(
+            "?[asset_id, collection_id] := asset_id in $assets, collection_id = $inbox,
+                *collection{asset_id, collection_id: $inbox}
+            :rm collection {asset_id, collection_id}",
+            BTreeMap::from([
+                ("assets".to_string(), DataValue::List(assets.iter().map(|id| ulid(*id)).collect())),
+                ("inbox".to_string(), ulid(self.inbox)),
+            ]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Applies the retention to the inbox.  Returns the assets that expired.
+    pub fn expire_inbox(&self) -> Result<Vec<Ulid>, cozo::Error> {
+        let Some(retention) = self.retention else {
+            return Ok(Vec::new());
+        };
+        let cutoff = now() - retention.max_age.as_micros() as i64;
+        let expired: Vec<Ulid> = self.inbox_items()?
+            .into_iter()
+            .filter(|item| item.time_landed <= cutoff)
+            .map(|item| item.asset_id)
+            .collect();
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+
+        let rows = expired
+            .iter()
+            .map(|id| DataValue::List(vec![ulid(*id), DataValue::from(EXPIRED_FLAG), DataValue::from(now())]))
+            .collect();
+        self.persistent.run_script(
+            "?[asset_id, flag, time_attached] <- $rows :put flags {asset_id, flag => time_attached}",
+            BTreeMap::from([("rows".to_string(), DataValue::List(rows))]),
+            ScriptMutability::Mutable
+        )?;
+        if retention.discard {
+            for id in &expired {
+                self.remove_content(*id)?;
+            }
+            self.leave_inbox(&expired)?;
+        }
+        Ok(expired)
+    }
+}
+
+/// Applies the retention of the inbox every `RETENTION_CHECK_SECS`.
+pub async fn retain(holobank: Holobank) {
+    if holobank.retention.is_none() {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(RETENTION_CHECK_SECS));
+    loop {
+        interval.tick().await;
+        let bank = holobank.clone();
+        match tokio::task::spawn_blocking(move || bank.expire_inbox()).await {
+            Ok(Ok(expired)) if !expired.is_empty() => info!("{} assets expired in the inbox", expired.len()),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("Could not apply the inbox retention: {}", e),
+            Err(e) => warn!("Could not apply the inbox retention: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use constellations::{
+        asset::{block::text::Text, Asset},
+        collection::{Collection, SharingPolicy},
+    };
+
+    use crate::holobank::{each_backend, embedding::HashingEmbedder, search::SearchQuery, sharing::Requester};
+
+    fn in_inbox(bank: &Holobank, id: Ulid) -> bool {
+        bank.inbox_items().unwrap().iter().any(|item| item.asset_id == id)
+    }
+
+    #[test]
+    fn new_assets_land_in_the_inbox() {
+        each_backend(|bank| {
+            let text = Text::new("unfiled", 1);
+            bank.dematerialize(&text, true).unwrap();
+            assert!(in_inbox(&bank, text.id()));
+            assert_eq!(bank.collection(bank.inbox()).unwrap().unwrap().name, INBOX_NAME);
+
+            // Registering again does not land it again once filed.
+            let collection = Ulid::new();
+            assert_eq!(bank.file(&[text.id(), Ulid::new()], collection).unwrap(), 1);
+            bank.dematerialize(&text, true).unwrap();
+            assert!(!in_inbox(&bank, text.id()));
+            assert!(bank.collection(collection).unwrap().unwrap().contains(text.id()));
+        });
+    }
+
+    #[test]
+    fn stored_collections_file_their_assets() {
+        each_backend(|bank| {
+            let text = Text::new("filed", 1);
+            bank.dematerialize(&text, true).unwrap();
+            let mut collection = Collection::new("notes");
+            collection.add_asset(text.id(), text.asset_type());
+            bank.store_collection(&collection).unwrap();
+            assert!(!in_inbox(&bank, text.id()));
+            assert!(bank.file(&[text.id()], bank.inbox()).is_err());
+        });
+    }
+
+    #[test]
+    fn is_never_shared() {
+        each_backend(|bank| {
+            let spaceport = Ulid::new();
+            let mut policy = SharingPolicy::default();
+            policy.replicas.insert(spaceport);
+            assert!(bank.set_sharing_policy(bank.inbox(), &policy).is_err());
+            let requester = Requester { spaceport, commander: None };
+            assert!(!bank.readable_collections(&requester).unwrap().contains(&bank.inbox()));
+        });
+    }
+
+    #[test]
+    fn expires_old_assets() {
+        each_backend(|bank| {
+            let text = Text::new("forgotten", 1);
+            bank.dematerialize(&text, true).unwrap();
+            assert!(bank.expire_inbox().unwrap().is_empty());
+
+            let bank = bank.with_inbox_retention(Some(Retention { max_age: Duration::ZERO, discard: false }));
+            assert_eq!(bank.expire_inbox().unwrap(), vec![text.id()]);
+            assert!(in_inbox(&bank, text.id()));
+
+            let bank = bank.with_inbox_retention(Some(Retention { max_age: Duration::ZERO, discard: true }));
+            assert_eq!(bank.expire_inbox().unwrap(), vec![text.id()]);
+            assert!(!in_inbox(&bank, text.id()));
+            assert!(bank.content(text.id()).unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn discards_the_index_of_expired_assets() {
+        each_backend(|bank| {
+            let bank = bank
+                .with_embedder(Arc::new(HashingEmbedder::new(16)))
+                .unwrap()
+                .with_inbox_retention(Some(Retention { max_age: Duration::ZERO, discard: true }));
+            let text = Text::new("stale notes", 1);
+            bank.dematerialize(&text, true).unwrap();
+            let indexed = |relation: &str| {
+                let rows = bank.persistent.run_script(
+                    &format!("?[asset_id] := *{}{{asset_id}}, asset_id = $asset_id", relation),
+                    BTreeMap::from([("asset_id".to_string(), ulid(text.id()))]),
+                    ScriptMutability::Immutable
+                ).unwrap();
+                !rows.rows.is_empty()
+            };
+            assert!(indexed("text") && indexed("embedding"));
+
+            assert_eq!(bank.expire_inbox().unwrap(), vec![text.id()]);
+            assert!(!indexed("text") && !indexed("embedding"));
+            let query = SearchQuery { text: "stale".to_string(), ..Default::default() };
+            assert!(bank.search(&query).unwrap().is_empty());
+        });
+    }
+}
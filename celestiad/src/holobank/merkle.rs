@@ -0,0 +1,232 @@
+// Merkle summaries let two banks find where a collection differs without
+// listing it.  Assets are spread over 16^DEPTH buckets by the leading hex
+// digits of the sha256 of their id.  A bucket hashes the leaves of its
+// assets, each the hash of the asset id and its content digest, and every
+// node above a bucket hashes its sixteen children.  Equal hashes mean equal
+// subtrees, so comparing from the root down only descends into the ranges
+// that differ.
+
+use std::collections::{BTreeMap, HashMap};
+
+use cozo::{DataValue, ScriptMutability};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ulid::Ulid;
+
+use super::{archive::hex, blob, ulid, Holobank};
+
+/// Hex digits of the bucket an asset falls in.
+pub const DEPTH: usize = 3;
+
+/// Children of every node above the buckets.
+pub const FANOUT: usize = 16;
+
+pub type Hash = [u8; 32];
+
+/// Hash of a range without assets.
+pub const EMPTY: Hash = [0; 32];
+
+/// A collection summarized as a Merkle tree.
+#[derive(Clone, Debug, Default)]
+pub struct MerkleSummary {
+    nodes: HashMap<String, Hash>,
+    buckets: HashMap<String, Vec<(Ulid, Hash)>>,
+}
+
+/// One node of a summary as exchanged between banks: the hashes of its
+/// children, or the leaves of a bucket.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleNode {
+    pub prefix: String,
+    pub hash: Hash,
+    pub children: Vec<Hash>,
+    pub leaves: Vec<(Ulid, Hash)>,
+}
+
+impl MerkleSummary {
+    /// Builds the summary of assets and the digests of their content.
+    pub fn build(assets: impl IntoIterator<Item = (Ulid, Option<Vec<u8>>)>) -> MerkleSummary {
+        let mut buckets: HashMap<String, Vec<(Ulid, Hash)>> = HashMap::new();
+        for (id, digest) in assets {
+            buckets.entry(bucket(id)).or_default().push((id, leaf(id, digest.as_deref())));
+        }
+
+        let mut nodes = HashMap::new();
+        for (prefix, leaves) in buckets.iter_mut() {
+            leaves.sort();
+            let mut hasher = Sha256::new();
+            for (_, hash) in leaves.iter() {
+                hasher.update(hash);
+            }
+            nodes.insert(prefix.clone(), hasher.finalize().into());
+        }
+
+        let mut summary = MerkleSummary { nodes, buckets };
+        for depth in (0..DEPTH).rev() {
+            let parents: BTreeMap<String, ()> = summary.nodes
+                .keys()
+                .filter(|prefix| prefix.len() == depth + 1)
+                .map(|prefix| (prefix[..depth].to_string(), ()))
+                .collect();
+            for parent in parents.into_keys() {
+                let mut hasher = Sha256::new();
+                for child in summary.children(&parent) {
+                    hasher.update(child);
+                }
+                summary.nodes.insert(parent, hasher.finalize().into());
+            }
+        }
+        summary
+    }
+
+    pub fn root(&self) -> Hash {
+        self.hash("")
+    }
+
+    /// Hash of the range of ids whose bucket starts with `prefix`.
+    pub fn hash(&self, prefix: &str) -> Hash {
+        self.nodes.get(prefix).copied().unwrap_or(EMPTY)
+    }
+
+    pub fn children(&self, prefix: &str) -> Vec<Hash> {
+        (0..FANOUT)
+            .map(|digit| self.hash(&format!("{}{:x}", prefix, digit)))
+            .collect()
+    }
+
+    /// Leaves of a bucket, ordered by id.
+    pub fn leaves(&self, prefix: &str) -> &[(Ulid, Hash)] {
+        self.buckets.get(prefix).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn node(&self, prefix: &str) -> MerkleNode {
+        let bucket = prefix.len() >= DEPTH;
+        MerkleNode {
+            prefix: prefix.to_string(),
+            hash: self.hash(prefix),
+            children: if bucket { vec![] } else { self.children(prefix) },
+            leaves: if bucket { self.leaves(prefix).to_vec() } else { vec![] },
+        }
+    }
+
+    /// Assets summarized.
+    pub fn len(&self) -> usize {
+        self.buckets.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+/// Bucket an asset falls in.
+pub fn bucket(id: Ulid) -> String {
+    hex(&Sha256::digest(id.to_bytes()))[..DEPTH].to_string()
+}
+
+fn leaf(id: Ulid, digest: Option<&[u8]>) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(id.to_bytes());
+    hasher.update(digest.unwrap_or_default());
+    hasher.finalize().into()
+}
+
+impl Holobank {
+    /// Summarizes the assets of a collection and their content.  Leaves
+    /// hash the sha256 of the content, which other banks agree on; encrypted
+    /// banks store keyed digests, so their content is opened to take it.
+    pub fn merkle_summary(&self, collection: Ulid) -> Result<MerkleSummary, cozo::Error> {
+        if self.cipher.is_some() {
+            let rows = self.persistent.run_script(
+                "member[asset_id] := *collection{asset_id, collection_id: $collection}
+                ?[asset_id, payload] := member[asset_id], *content{asset_id, digest}, *blob{digest, payload}
+                ?[asset_id, payload] := member[asset_id], not *content{asset_id}, payload = null",
+                BTreeMap::from([("collection".to_string(), ulid(collection))]),
+                ScriptMutability::Immutable
+            )?;
+            let mut assets = Vec::new();
+            for row in rows.rows {
+                let Some(id) = row[0].get_ulid() else {
+                    continue;
+                };
+                let content = match row[1] {
+                    DataValue::Null => None,
+                    ref payload => self.unseal(payload)?,
+                };
+                assets.push((id, content.as_deref().map(blob::digest)));
+            }
+            return Ok(MerkleSummary::build(assets));
+        }
+
+        let rows = self.persistent.run_script(
+            "member[asset_id] := *collection{asset_id, collection_id: $collection}
+            ?[asset_id, digest] := member[asset_id], *content{asset_id, digest}
+            ?[asset_id, digest] := member[asset_id], not *content{asset_id}, digest = null",
+            BTreeMap::from([("collection".to_string(), ulid(collection))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(MerkleSummary::build(rows.rows.iter().filter_map(|row| {
+            Some((row[0].get_ulid()?, row[1].get_bytes().map(<[u8]>::to_vec)))
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assets(count: usize) -> Vec<(Ulid, Option<Vec<u8>>)> {
+        (0..count).map(|i| (Ulid::new(), Some(vec![i as u8]))).collect()
+    }
+
+    /// Descends into differing nodes and returns the buckets that differ.
+    fn diverging(a: &MerkleSummary, b: &MerkleSummary) -> Vec<String> {
+        let mut found = Vec::new();
+        let mut pending = vec![String::new()];
+        while let Some(prefix) = pending.pop() {
+            if a.hash(&prefix) == b.hash(&prefix) {
+                continue;
+            }
+            if prefix.len() == DEPTH {
+                found.push(prefix);
+            } else {
+                pending.extend((0..FANOUT).map(|digit| format!("{}{:x}", prefix, digit)));
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn same_assets_same_root() {
+        let assets = assets(200);
+        let mut reversed = assets.clone();
+        reversed.reverse();
+        let (a, b) = (MerkleSummary::build(assets), MerkleSummary::build(reversed));
+        assert_eq!(a.root(), b.root());
+        assert_ne!(a.root(), EMPTY);
+        assert_eq!(a.len(), 200);
+        assert_eq!(MerkleSummary::build(vec![]).root(), EMPTY);
+    }
+
+    #[test]
+    fn finds_diverging_buckets() {
+        let assets = assets(500);
+        let mut changed = assets.clone();
+        changed[42].1 = Some(b"edited".to_vec());
+        changed.remove(7);
+        let (a, b) = (MerkleSummary::build(assets.clone()), MerkleSummary::build(changed));
+        assert_ne!(a.root(), b.root());
+
+        let mut expected = vec![bucket(assets[42].0), bucket(assets[7].0)];
+        expected.sort();
+        expected.dedup();
+        let mut found = diverging(&a, &b);
+        found.sort();
+        assert_eq!(found, expected);
+
+        let node = a.node(&bucket(assets[42].0));
+        assert!(node.children.is_empty());
+        assert!(node.leaves.iter().any(|(id, _)| *id == assets[42].0));
+        assert_eq!(a.node("").children.len(), FANOUT);
+    }
+}
@@ -1,63 +1,184 @@
-use std::path::Path;
+use std::{collections::BTreeMap, path::Path, sync::Arc};
 
 use anyhow::Result;
-use cozo::DbInstance;
+use constellations::{
+    asset::{Asset, AssetError, Holographable, Materializable},
+    user::signed::Operation,
+};
+use cozo::{DataValue, DbInstance, ScriptMutability, UlidWrapper};
+use ulid::Ulid;
+
+use crate::storage::Backend;
 
 mod schema;
+pub mod archive;
+pub mod assembly;
+pub mod blob;
+pub mod changes;
+pub mod cipher;
+pub mod collection;
+pub mod conflict;
+pub mod dynamic;
+pub mod embedding;
+pub mod fsck;
+pub mod graph;
+pub mod identity;
+pub mod import;
+pub mod inbox;
+pub mod merkle;
+pub mod query;
+pub mod quota;
+pub mod replication;
+pub mod search;
+pub mod sharing;
+pub mod temporal;
 
 #[derive(Clone)]
-struct Holobank {
+pub struct Holobank {
+    backend: Backend,
     persistent: DbInstance,
     cache: DbInstance,
+    embedder: Option<Arc<dyn embedding::Embedder>>,
+    /// Bytes of content the bank keeps before evicting.
+    quota: Option<u64>,
+    /// Encrypts payloads at rest when set.
+    cipher: Option<Arc<cipher::Cipher>>,
+    feed: Arc<changes::ChangeFeed>,
+    /// Collection where new assets land.
+    inbox: Ulid,
+    retention: Option<inbox::Retention>,
+    /// Signs operations as a commander when set.
+    signer: Option<Arc<identity::Signer>>,
 }
 
+/// Name of the SQLite file inside the holobank directory.
+pub const HOLOBANK_FILE: &str = "holobank";
+
 impl Holobank {
-    fn load(path: &Path) -> Result<Holobank, cozo::Error> {
-        let persistent = if path.exists() {
-            DbInstance::new("rocksdb", path, "")?
-        }
-        else {
-            Holobank::setup_persistent(path)?
-        };
+    /// Opens the holobank stored under `path` on the given backend.  The
+    /// cache always lives in memory.
+    pub fn load(backend: Backend, path: &Path) -> Result<Holobank, cozo::Error> {
+        Holobank::from_db(backend, backend.open(path, HOLOBANK_FILE)?)
+    }
+
+    fn from_db(backend: Backend, persistent: DbInstance) -> Result<Holobank, cozo::Error> {
+        Holobank::setup_persistent(&persistent)?;
 
         let cache = Holobank::setup_cache()?;
+        let feed = Arc::new(changes::ChangeFeed::start(&persistent)?);
+        let inbox = inbox::ensure(&persistent)?;
 
         Ok(Holobank {
+            backend,
             persistent,
-            cache
+            cache,
+            embedder: None,
+            quota: None,
+            cipher: None,
+            feed,
+            inbox,
+            retention: None,
+            signer: None,
         })
     }
 
-    fn setup_persistent(path: &Path) -> Result<DbInstance, cozo::Error> {
-        let db = DbInstance::new("rocksdb", path, "")?;
-        db.run_default(schema::COMMMANDER_SCHEMA);
-        db.run_default(schema::ASSET_SCHEMA);
-        db.run_default(schema::NAME_SCHEMA);
-        db.run_default(schema::CONTENT_SCHEMA);
-        db.run_default(schema::OWNERSHIP_SCHEMA);
-        db.run_default(schema::SNAPSHOT_SCHEMA);
-        db.run_default(schema::HISTORY_SCHEMA);
-        db.run_default(schema::CONNECTION_SCHEMA);
-        db.run_default(schema::TAG_SCHEMA);
-        db.run_default(schema::FLAG_SCHEMA);
-        db.run_default(schema::COLLECTION_SCHEMA);
-        db.run_default(schema::SPACEPORT_SCHEMA);
-        db.run_default(schema::SYSTEM_SCHEMA);
-        db.run_default(schema::STARMAP_SCHEMA);
-        Ok(db)
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Creates the relations missing from the database, so banks created by
+    /// older versions pick up new relations.
+    fn setup_persistent(db: &DbInstance) -> Result<(), cozo::Error> {
+        blob::migrate_inline_content(db)?;
+        for (relation, scripts) in schema::PERSISTENT {
+            if !relation_exists(db, relation)? {
+                for script in *scripts {
+                    db.run_default(script)?;
+                }
+            }
+        }
+        Ok(())
     }
-    
+
     fn setup_cache() -> Result<DbInstance, cozo::Error> {
-        let db = DbInstance::new("mem", "", "")?;
-        db.run_default(schema::CONTENT_SCHEMA);
-        db.run_default(schema::HISTORY_SCHEMA);
+        let db = Backend::Mem.open(Path::new(""), HOLOBANK_FILE)?;
+        db.run_default(schema::CACHED_CONTENT_SCHEMA)?;
+        db.run_default(schema::HISTORY_SCHEMA)?;
         Ok(db)
     }
-}
 
+    /// Registers an asset, recording the connection to the asset it was
+    /// derived or forked from.  New assets land in the inbox.
+    /// Registering an asset again keeps its original registration.
+    pub fn register<T: Asset>(&self, asset: &T) -> Result<(), cozo::Error> {
+        let origin = asset.origin();
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(asset.id()));
+        params.insert("asset_type".to_string(), DataValue::from(asset.asset_type().as_str()));
+        params.insert("time".to_string(), DataValue::from(now()));
+        params.insert("derived_from".to_string(), origin.as_ref().map_or(DataValue::Null, |o| ulid(o.src)));
+        params.insert("derivation_type".to_string(), origin.as_ref().map_or(DataValue::Null, |o| DataValue::from(o.kind.as_str())));
+        params.insert("inbox".to_string(), ulid(self.inbox));
 
+        self.persistent.run_script(
+            "{
+                ?[asset_id, collection_id, time_added] := asset_id = $asset_id, collection_id = $inbox,
+                    time_added = $time, not *asset{asset_id: $asset_id}
+                :put collection {asset_id, collection_id => time_added}
+            }
+            {
+                ?[asset_id, name, derived_from, asset_type, derivation_type, time_registered] :=
+                    asset_id = $asset_id, name = null, derived_from = $derived_from,
+                    asset_type = $asset_type, derivation_type = $derivation_type, time_registered = $time,
+                    not *asset{asset_id: $asset_id}
+                :put asset {asset_id, name, derived_from => asset_type, derivation_type, time_registered}
+            }",
+            params,
+            ScriptMutability::Mutable
+        )?;
+
+        if let Some(origin) = origin {
+            self.connect(&origin)?;
+        }
+        Ok(())
+    }
+
+    /// Names a registered asset, or takes its name away.
+    pub fn rename(&self, id: Ulid, name: Option<&str>) -> Result<(), cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[name, derived_from, asset_type, derivation_type, time_registered] :=
+                *asset{asset_id: $asset_id, name, derived_from, asset_type, derivation_type, time_registered}",
+            BTreeMap::from([("asset_id".to_string(), ulid(id))]),
+            ScriptMutability::Immutable
+        )?;
+        let Some(row) = rows.rows.into_iter().next() else {
+            return Err(cozo::Error::msg(format!("asset {} is not registered", id)));
+        };
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(id));
+        params.insert("previous".to_string(), row[0].clone());
+        params.insert("name".to_string(), name.map_or(DataValue::Null, DataValue::from));
+        params.insert("derived_from".to_string(), row[1].clone());
+        params.insert("asset_type".to_string(), row[2].clone());
+        params.insert("derivation_type".to_string(), row[3].clone());
+        params.insert("time_registered".to_string(), row[4].clone());
+        self.persistent.run_script(
+            "{
+                ?[asset_id, name, derived_from] <- [[$asset_id, $previous, $derived_from]]
+                :rm asset {asset_id, name, derived_from}
+            }
+            {
+                ?[asset_id, name, derived_from, asset_type, derivation_type, time_registered] <-
+                    [[$asset_id, $name, $derived_from, $asset_type, $derivation_type, $time_registered]]
+                :put asset {asset_id, name, derived_from => asset_type, derivation_type, time_registered}
+            }",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
 
-/// Gets the latest holoframe --subscribing if the data is not held
+    /// Gets the latest holoframe --subscribing if the data is not held
     /// locally.
     pub fn project<T>(id: Ulid) -> Result<T, AssetError> where
     T: Holographable {
@@ -70,7 +191,73 @@ impl Holobank {
     }
     /// Store a material asset in the holobank.
     /// Alias for deposit, digitize, or transfer.
-    pub fn dematerialize<T>(asset: T, release: bool) -> Result<(), AssetError> where
+    /// An asset that is not released stays held here and its content is kept
+    /// in the cache.  Text is indexed for search and embedded as it is stored,
+    /// and content is evicted if that puts the bank over a quota.  The edit
+    /// is signed by the bank's commander.
+    pub fn dematerialize<T>(&self, asset: &T, release: bool) -> Result<(), cozo::Error> where
     T: Materializable + Asset {
-        todo!()
-    }
\ No newline at end of file
+        self.register(asset)?;
+
+        let frame = asset.scan();
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(asset.id()));
+        params.insert("content_type".to_string(), DataValue::from(frame.content_type.as_str()));
+        params.insert("time".to_string(), DataValue::from(now()));
+
+        // Only the persistent copy is encrypted and deduplicated; the cache
+        // never leaves memory.
+        self.store_content(asset.id(), &frame.content_type, frame.content.clone())?;
+        self.sign(Operation::Edit { asset: asset.id(), digest: blob::digest(&frame.content) })?;
+        let put_content = "?[asset_id, content_type, content, time_attached] <- [[$asset_id, $content_type, $content, $time]]
+            :put content {asset_id => content_type, content, time_attached}";
+        params.insert("content".to_string(), DataValue::Bytes(frame.content));
+        if release {
+            self.cache.run_script(
+                "?[asset_id] <- [[$asset_id]] :rm content {asset_id}",
+                params,
+                ScriptMutability::Mutable
+            )?;
+        } else {
+            self.cache.run_script(put_content, params, ScriptMutability::Mutable)?;
+        }
+
+        self.content_stored(asset.id())
+    }
+}
+
+pub(crate) fn relation_exists(db: &DbInstance, relation: &str) -> Result<bool, cozo::Error> {
+    let relations = db.run_default("::relations")?;
+    Ok(relations.rows.iter().any(|row| row[0].get_str() == Some(relation)))
+}
+
+/// Runs a test against a fresh bank on every backend.  Failures name the
+/// backend they happened on.
+#[cfg(test)]
+pub(crate) fn each_backend(test: impl Fn(Holobank)) {
+    for backend in Backend::ALL {
+        let directory = std::env::temp_dir().join(format!("holobank-{}-{}", backend, Ulid::new()));
+        let bank = Holobank::load(backend, &directory)
+            .unwrap_or_else(|e| panic!("could not open {} holobank: {}", backend, e));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| test(bank)));
+        let _ = std::fs::remove_dir_all(&directory);
+        if let Err(payload) = result {
+            let message = payload
+                .downcast_ref::<String>()
+                .map(String::as_str)
+                .or_else(|| payload.downcast_ref::<&str>().copied())
+                .unwrap_or("test panicked");
+            panic!("failed against {}: {}", backend, message);
+        }
+    }
+}
+
+/// Wraps an id for use as a query parameter.
+pub(crate) fn ulid(id: Ulid) -> DataValue {
+    DataValue::Ulid(UlidWrapper(id))
+}
+
+/// Current time in microseconds, the resolution of cozo validity timestamps.
+pub fn now() -> i64 {
+    chrono::Utc::now().timestamp_micros()
+}
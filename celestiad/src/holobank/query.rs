@@ -0,0 +1,362 @@
+use std::{collections::BTreeMap, fmt};
+
+use cozo::{DataValue, NamedRows, ScriptMutability, UlidWrapper};
+use serde::Serialize;
+use serde_json::Value;
+use ulid::Ulid;
+
+use super::Holobank;
+
+/// Stored relations that can be read through a query.
+pub const QUERYABLE_RELATIONS: [&str; 4] = ["asset", "connection", "tags", "collection"];
+
+/// Query options that write to the database.
+const MUTATING_OPTIONS: [&str; 9] = [
+    "put", "rm", "create", "replace", "insert", "update", "delete", "ensure", "ensure_not",
+];
+
+/// Query options set by the holobank from the query limits.
+const RESERVED_OPTIONS: [&str; 2] = ["timeout", "limit"];
+
+/// Bounds placed on a single query.
+#[derive(Clone, Copy, Debug)]
+pub struct QueryLimits {
+    /// Seconds the query may run before it is killed.
+    pub timeout_secs: u64,
+    /// Rows returned before the result is truncated.
+    pub max_rows: usize,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        QueryLimits { timeout_secs: 10, max_rows: 1000 }
+    }
+}
+
+impl QueryLimits {
+    /// Tightens the limits with the ones asked for by a caller.
+    /// Callers can lower the limits but never raise them.
+    pub fn narrow(self, timeout_secs: Option<u64>, max_rows: Option<usize>) -> QueryLimits {
+        QueryLimits {
+            timeout_secs: timeout_secs.map_or(self.timeout_secs, |t| t.clamp(1, self.timeout_secs)),
+            max_rows: max_rows.map_or(self.max_rows, |r| r.min(self.max_rows)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+    /// The script reaches outside the queryable relations or writes.
+    Forbidden(String),
+    /// The script is not a single query the holobank can bound.
+    Invalid(String),
+    /// The database rejected or aborted the query.
+    Database(cozo::Error),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::Forbidden(reason) => write!(f, "forbidden: {}", reason),
+            QueryError::Invalid(reason) => write!(f, "invalid query: {}", reason),
+            QueryError::Database(error) => write!(f, "query failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Rows returned from a query, converted to JSON.
+#[derive(Debug, Serialize)]
+pub struct QueryResult {
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+    /// Set when rows past the row limit were dropped.
+    pub truncated: bool,
+}
+
+impl QueryResult {
+    fn new(result: NamedRows, max_rows: usize) -> QueryResult {
+        let truncated = result.rows.len() > max_rows;
+        let rows = result.rows
+            .into_iter()
+            .take(max_rows)
+            .map(|row| row.into_iter().map(Value::from).collect())
+            .collect();
+        QueryResult { headers: result.headers, rows, truncated }
+    }
+}
+
+impl Holobank {
+    /// Runs a parameterized, read-only CozoScript query against the
+    /// queryable relations.
+    ///
+    /// Parameters written `{"ulid": "<id>"}` are bound as `Ulid` values so
+    /// they can be compared against asset and collection ids.  Every other
+    /// value, strings included, is bound as it is.
+    pub fn query(
+        &self,
+        script: &str,
+        params: BTreeMap<String, Value>,
+        limits: QueryLimits,
+    ) -> Result<QueryResult, QueryError> {
+        validate(script)?;
+
+        // One extra row tells us whether the result was cut short.
+        let bounded = format!(
+            "{}\n:timeout {}\n:limit {}",
+            script,
+            limits.timeout_secs,
+            limits.max_rows + 1
+        );
+        let params = params
+            .into_iter()
+            .map(|(name, value)| Ok((name, bind(value)?)))
+            .collect::<Result<_, QueryError>>()?;
+
+        let result = self.persistent
+            .run_script(&bounded, params, ScriptMutability::Immutable)
+            .map_err(QueryError::Database)?;
+
+        Ok(QueryResult::new(result, limits.max_rows))
+    }
+}
+
+fn bind(value: Value) -> Result<DataValue, QueryError> {
+    match value {
+        Value::Object(object) if object.len() == 1 && object.contains_key("ulid") => {
+            let id = object["ulid"].as_str().and_then(|s| Ulid::from_string(s).ok());
+            id.map(|id| DataValue::Ulid(UlidWrapper(id)))
+                .ok_or_else(|| QueryError::Invalid(format!("{} is not a ULID", object["ulid"])))
+        }
+        other => Ok(DataValue::from(other)),
+    }
+}
+
+/// Checks that a script is a single query which only reads the queryable
+/// relations and leaves the limits to the holobank.
+fn validate(script: &str) -> Result<(), QueryError> {
+    let code = strip_literals(script)?;
+
+    if code.trim_start().starts_with('{') {
+        return Err(QueryError::Invalid("chained scripts are not supported".into()));
+    }
+    if code.contains("::") {
+        return Err(QueryError::Forbidden("system operations are not allowed".into()));
+    }
+    // Fixed rules include readers of files and URLs.
+    if code.contains("<~") {
+        return Err(QueryError::Forbidden("fixed rules are not allowed".into()));
+    }
+
+    let chars: Vec<char> = code.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            // Stored relation: *name{...} or *name[...]
+            '*' => {
+                let (name, end) = identifier(&chars, i + 1);
+                let next = chars[end..].iter().find(|c| !c.is_whitespace());
+                if !name.is_empty() && matches!(next, Some('{') | Some('[')) {
+                    check_relation(&name)?;
+                }
+                i = end.max(i + 1);
+            }
+            // Index search: ~name:index{...}
+            '~' => {
+                let (name, end) = identifier(&chars, i + 1);
+                if !name.is_empty() && chars.get(end) == Some(&':') {
+                    check_relation(&name)?;
+                }
+                i = end.max(i + 1);
+            }
+            // Query option: `:name arg`.  Bindings such as `{id:asset}` are
+            // never followed by whitespace.
+            ':' => {
+                let (name, end) = identifier(&chars, i + 1);
+                if chars.get(end).is_none_or(|c| c.is_whitespace()) {
+                    if MUTATING_OPTIONS.contains(&name.as_str()) {
+                        return Err(QueryError::Forbidden(format!(":{} is not allowed", name)));
+                    }
+                    if RESERVED_OPTIONS.contains(&name.as_str()) {
+                        return Err(QueryError::Invalid(format!(":{} is set by the holobank", name)));
+                    }
+                }
+                i = end.max(i + 1);
+            }
+            // Imperative statements: %if, %loop, %return, ...
+            '%' if chars.get(i + 1).is_some_and(|c| c.is_alphabetic()) => {
+                return Err(QueryError::Invalid("imperative scripts are not supported".into()));
+            }
+            _ => i += 1,
+        }
+    }
+
+    Ok(())
+}
+
+fn check_relation(name: &str) -> Result<(), QueryError> {
+    if QUERYABLE_RELATIONS.contains(&name) {
+        Ok(())
+    } else {
+        Err(QueryError::Forbidden(format!("relation {} is not queryable", name)))
+    }
+}
+
+/// Reads an identifier starting at `start`, returning it and the index after it.
+fn identifier(chars: &[char], start: usize) -> (String, usize) {
+    let mut end = start;
+    while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+        end += 1;
+    }
+    (chars[start..end.min(chars.len())].iter().collect(), end)
+}
+
+/// Blanks out string literals and comments so that their contents are not
+/// mistaken for code.
+fn strip_literals(script: &str) -> Result<String, QueryError> {
+    let chars: Vec<char> = script.chars().collect();
+    let mut code = String::with_capacity(script.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        }
+        else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            match find(&chars, i + 2, &['*', '/']) {
+                Some(end) => i = end + 2,
+                None => return Err(QueryError::Invalid("unterminated comment".into())),
+            }
+            code.push(' ');
+        }
+        else if c == '_' && raw_string_start(&chars, i).is_some() {
+            // Raw string: _"..."_ with any number of underscores.
+            let quote = raw_string_start(&chars, i).unwrap();
+            let mut terminator = vec!['"'];
+            terminator.extend(std::iter::repeat_n('_', quote - i));
+            match find(&chars, quote + 1, &terminator) {
+                Some(end) => i = end + terminator.len(),
+                None => return Err(QueryError::Invalid("unterminated string".into())),
+            }
+            code.push_str("\"\"");
+        }
+        else if c == '"' || c == '\'' {
+            let mut end = i + 1;
+            while end < chars.len() && chars[end] != c {
+                end += if chars[end] == '\\' { 2 } else { 1 };
+            }
+            if end >= chars.len() {
+                return Err(QueryError::Invalid("unterminated string".into()));
+            }
+            i = end + 1;
+            code.push_str("\"\"");
+        }
+        else {
+            code.push(c);
+            i += 1;
+        }
+    }
+
+    Ok(code)
+}
+
+/// Returns the position of the opening quote if a raw string starts at `i`.
+fn raw_string_start(chars: &[char], i: usize) -> Option<usize> {
+    if i > 0 && (chars[i - 1].is_alphanumeric() || chars[i - 1] == '_') {
+        return None;
+    }
+    let mut quote = i;
+    while quote < chars.len() && chars[quote] == '_' {
+        quote += 1;
+    }
+    (chars.get(quote) == Some(&'"')).then_some(quote)
+}
+
+fn find(chars: &[char], start: usize, pattern: &[char]) -> Option<usize> {
+    (start..chars.len()).find(|&i| chars[i..].starts_with(pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_reads() {
+        let script = "
+            ?[id, name] := *asset{asset_id: id, name, asset_type: 'Block'},
+                           *tags{asset_id: id, tag: $tag},
+                           *connection{src: $source, dest: id, type: 'derivation'}
+            :order name
+        ";
+        assert!(validate(script).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_relations() {
+        let script = "?[id, owner] := *owner{entity_id: id, owner_id: owner}";
+        assert!(matches!(validate(script), Err(QueryError::Forbidden(_))));
+
+        let script = "?[] <~ PageRank(*content[])";
+        assert!(matches!(validate(script), Err(QueryError::Forbidden(_))));
+    }
+
+    #[test]
+    fn rejects_fixed_rules() {
+        for script in [
+            "?[] <~ PageRank(*connection[])",
+            "?[line] <~ CsvReader(url: 'file:///etc/passwd', types: ['String'])",
+            "?[doc] <~ JsonReader(url: 'http://localhost:8080/', fields: ['doc'])",
+            "?[a]   <~\n  Constant(data: [[1]])",
+        ] {
+            assert!(matches!(validate(script), Err(QueryError::Forbidden(_))), "{}", script);
+        }
+    }
+
+    #[test]
+    fn binds_ulids_only_when_asked() {
+        let id = Ulid::new();
+        assert_eq!(bind(Value::from(id.to_string())).unwrap(), DataValue::from(id.to_string()));
+        assert_eq!(bind(serde_json::json!({ "ulid": id.to_string() })).unwrap(), DataValue::Ulid(UlidWrapper(id)));
+        assert!(matches!(bind(serde_json::json!({ "ulid": "not an id" })), Err(QueryError::Invalid(_))));
+    }
+
+    #[test]
+    fn rejects_writes() {
+        let script = "?[asset_id, tag, time_attached] <- [[$id, 'x', 0]] :put tags {asset_id, tag => time_attached}";
+        assert!(matches!(validate(script), Err(QueryError::Forbidden(_))));
+        assert!(matches!(validate("::relations"), Err(QueryError::Forbidden(_))));
+    }
+
+    #[test]
+    fn rejects_reserved_options() {
+        let script = "?[id] := *asset{asset_id: id}\n:limit 1000000";
+        assert!(matches!(validate(script), Err(QueryError::Invalid(_))));
+    }
+
+    #[test]
+    fn ignores_literals_and_comments() {
+        let script = "
+            # *owner{entity_id} is mentioned in a comment
+            ?[id] := *tags{asset_id: id, tag: '*owner{x} :put'}
+        ";
+        assert!(validate(script).is_ok());
+    }
+
+    #[test]
+    fn raw_strings_do_not_hide_relations() {
+        let script = r#"?[x] := x = _"\"_, *owner{entity_id: x}, y = _"\"_"#;
+        assert!(matches!(validate(script), Err(QueryError::Forbidden(_))));
+    }
+
+    #[test]
+    fn limits_only_narrow() {
+        let limits = QueryLimits { timeout_secs: 10, max_rows: 100 };
+        let narrowed = limits.narrow(Some(60), Some(5));
+        assert_eq!(narrowed.timeout_secs, 10);
+        assert_eq!(narrowed.max_rows, 5);
+    }
+}
@@ -0,0 +1,407 @@
+// Quotas limit the bytes of content a bank keeps, as a whole and per
+// collection.  Going over a quota evicts content, least recently used first,
+// until usage is back under every quota.  Only hologram content is evicted:
+// it can be fetched again from wherever the asset lives.  Content of material
+// assets, i.e. assets held here, and of assets in pinned collections is kept
+// even when that leaves a quota exceeded.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use cozo::{DataValue, ScriptMutability};
+use serde::Serialize;
+use ulid::Ulid;
+
+use super::{now, ulid, Holobank};
+
+/// Flag put on assets whose content was evicted.
+pub const EVICTED_FLAG: &str = "evicted";
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Usage {
+    /// Bytes of content stored.
+    pub bytes: u64,
+    pub quota_bytes: Option<u64>,
+    /// Assets with content stored.
+    pub assets: usize,
+    /// Bytes held by material assets.
+    pub material_bytes: u64,
+    /// Bytes in pinned collections.
+    pub pinned_bytes: u64,
+    /// Bytes that may be evicted.
+    pub evictable_bytes: u64,
+    /// Assets whose content was evicted.
+    pub evicted_assets: usize,
+    pub collections: Vec<CollectionUsage>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CollectionUsage {
+    pub collection_id: Ulid,
+    pub bytes: u64,
+    pub assets: usize,
+    pub quota_bytes: Option<u64>,
+    pub pinned: bool,
+}
+
+/// Content stored for one asset.
+struct Stored {
+    id: Ulid,
+    bytes: u64,
+    accessed: i64,
+    collections: Vec<Ulid>,
+    material: bool,
+    pinned: bool,
+}
+
+impl Stored {
+    fn evictable(&self) -> bool {
+        !self.material && !self.pinned
+    }
+}
+
+impl Holobank {
+    /// Limits the bytes of content the whole bank keeps.
+    pub fn with_quota(mut self, bytes: Option<u64>) -> Holobank {
+        self.quota = bytes;
+        self
+    }
+
+    /// Sets or clears the quota of a collection.
+    pub fn set_collection_quota(&self, collection: Ulid, bytes: Option<u64>) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("collection_id".to_string(), ulid(collection));
+        match bytes {
+            Some(bytes) => {
+                params.insert("bytes".to_string(), DataValue::from(bytes as i64));
+                self.persistent.run_script(
+                    "?[collection_id, bytes] <- [[$collection_id, $bytes]] :put quota {collection_id => bytes}",
+                    params,
+                    ScriptMutability::Mutable
+                )?;
+            }
+            None => {
+                self.persistent.run_script(
+                    "?[collection_id] <- [[$collection_id]] :rm quota {collection_id}",
+                    params,
+                    ScriptMutability::Mutable
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the content of a collection from being evicted.
+    pub fn pin(&self, collection: Ulid) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("collection_id".to_string(), ulid(collection));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "?[collection_id, time_pinned] <- [[$collection_id, $time]] :put pinned {collection_id => time_pinned}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    pub fn unpin(&self, collection: Ulid) -> Result<(), cozo::Error> {
+        self.persistent.run_script(
+            "?[collection_id] <- [[$collection_id]] :rm pinned {collection_id}",
+            BTreeMap::from([("collection_id".to_string(), ulid(collection))]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Reads the stored content of an asset and its content type, decrypted.
+    pub fn content(&self, id: Ulid) -> Result<Option<(String, Vec<u8>)>, cozo::Error> {
+        let Some((content_type, payload)) = self.sealed_content(id)? else {
+            return Ok(None);
+        };
+        self.touch(id)?;
+        let content = self.unseal(&payload)?;
+        Ok(content.map(|bytes| (content_type, bytes)))
+    }
+
+    /// Records that content was stored, then evicts content if a quota is
+    /// exceeded.
+    pub(crate) fn content_stored(&self, id: Ulid) -> Result<(), cozo::Error> {
+        self.touch(id)?;
+        self.persistent.run_script(
+            "?[asset_id, flag] <- [[$asset_id, $flag]] :rm flags {asset_id, flag}",
+            BTreeMap::from([
+                ("asset_id".to_string(), ulid(id)),
+                ("flag".to_string(), DataValue::from(EVICTED_FLAG)),
+            ]),
+            ScriptMutability::Mutable
+        )?;
+        self.evict()?;
+        Ok(())
+    }
+
+    fn touch(&self, id: Ulid) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(id));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "?[asset_id, time_accessed] <- [[$asset_id, $time]] :put access {asset_id => time_accessed}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Bytes stored by the bank and by each collection.
+    pub fn usage(&self) -> Result<Usage, cozo::Error> {
+        let stored = self.stored_content()?;
+        let quotas = self.collection_quotas()?;
+        let pinned = self.pinned_collections()?;
+
+        let mut usage = Usage { quota_bytes: self.quota, assets: stored.len(), ..Default::default() };
+        let mut collections: BTreeMap<Ulid, (u64, usize)> = BTreeMap::new();
+        for content in &stored {
+            usage.bytes += content.bytes;
+            if content.material {
+                usage.material_bytes += content.bytes;
+            }
+            if content.pinned {
+                usage.pinned_bytes += content.bytes;
+            }
+            if content.evictable() {
+                usage.evictable_bytes += content.bytes;
+            }
+            for collection in &content.collections {
+                let entry = collections.entry(*collection).or_default();
+                entry.0 += content.bytes;
+                entry.1 += 1;
+            }
+        }
+        for collection in quotas.keys().chain(&pinned) {
+            collections.entry(*collection).or_default();
+        }
+        usage.collections = collections
+            .into_iter()
+            .map(|(collection_id, (bytes, assets))| CollectionUsage {
+                collection_id,
+                bytes,
+                assets,
+                quota_bytes: quotas.get(&collection_id).copied(),
+                pinned: pinned.contains(&collection_id),
+            })
+            .collect();
+
+        let evicted = self.persistent.run_script(
+            "?[count(asset_id)] := *flags{asset_id, flag: $flag}",
+            BTreeMap::from([("flag".to_string(), DataValue::from(EVICTED_FLAG))]),
+            ScriptMutability::Immutable
+        )?;
+        usage.evicted_assets = evicted.rows.first().and_then(|row| row[0].get_int()).unwrap_or(0) as usize;
+        Ok(usage)
+    }
+
+    /// Evicts least recently used hologram content until every quota is met
+    /// or nothing evictable is left.  Returns the evicted assets.
+    pub fn evict(&self) -> Result<Vec<Ulid>, cozo::Error> {
+        let quotas = self.collection_quotas()?;
+        if self.quota.is_none() && quotas.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut stored = self.stored_content()?;
+        stored.sort_by_key(|content| content.accessed);
+        let mut evicted = HashSet::new();
+
+        for (collection, quota) in &quotas {
+            let members = || stored
+                .iter()
+                .filter(|content| content.collections.contains(collection) && !evicted.contains(&content.id));
+            let mut used: u64 = members().map(|content| content.bytes).sum();
+            let candidates: Vec<&Stored> = members().filter(|content| content.evictable()).collect();
+            for content in candidates {
+                if used <= *quota {
+                    break;
+                }
+                evicted.insert(content.id);
+                used -= content.bytes;
+            }
+        }
+
+        if let Some(quota) = self.quota {
+            let mut used: u64 = stored
+                .iter()
+                .filter(|content| !evicted.contains(&content.id))
+                .map(|content| content.bytes)
+                .sum();
+            for content in stored.iter().filter(|content| content.evictable()) {
+                if used <= quota {
+                    break;
+                }
+                if evicted.insert(content.id) {
+                    used -= content.bytes;
+                }
+            }
+        }
+
+        let evicted: Vec<Ulid> = stored
+            .iter()
+            .map(|content| content.id)
+            .filter(|id| evicted.contains(id))
+            .collect();
+        for id in &evicted {
+            self.evict_content(*id)?;
+        }
+        // Blobs still shared with content that was kept stay.
+        if !evicted.is_empty() {
+            self.collect_garbage()?;
+        }
+        Ok(evicted)
+    }
+
+    fn evict_content(&self, id: Ulid) -> Result<(), cozo::Error> {
+        self.remove_content(id)?;
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(id));
+        params.insert("flag".to_string(), DataValue::from(EVICTED_FLAG));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "{?[asset_id] <- [[$asset_id]] :rm access {asset_id}}
+            {?[asset_id, flag, time_attached] <- [[$asset_id, $flag, $time]] :put flags {asset_id, flag => time_attached}}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    fn stored_content(&self) -> Result<Vec<Stored>, cozo::Error> {
+        let rows = self.persistent.run_default(
+            "last[asset_id, max(time)] := *content{asset_id, time_attached: time}
+            last[asset_id, max(time)] := *access{asset_id, time_accessed: time}
+            ?[asset_id, bytes, accessed] := *content{asset_id, size: bytes}, last[asset_id, accessed]"
+        )?;
+
+        let mut memberships: HashMap<Ulid, Vec<Ulid>> = HashMap::new();
+        for row in self.persistent.run_default("?[asset_id, collection_id] := *collection{asset_id, collection_id}")?.rows {
+            if let (Some(asset), Some(collection)) = (row[0].get_ulid(), row[1].get_ulid()) {
+                memberships.entry(asset).or_default().push(collection);
+            }
+        }
+        let material = self.material_assets()?;
+        let pinned = self.pinned_collections()?;
+
+        Ok(rows.rows
+            .into_iter()
+            .filter_map(|row| {
+                let id = row[0].get_ulid()?;
+                let collections = memberships.remove(&id).unwrap_or_default();
+                Some(Stored {
+                    id,
+                    bytes: row[1].get_int()? as u64,
+                    accessed: row[2].get_int()?,
+                    material: material.contains(&id),
+                    pinned: collections.iter().any(|c| pinned.contains(c)),
+                    collections,
+                })
+            })
+            .collect())
+    }
+
+    /// Assets currently held here, either by the ledger or in the cache.
+    fn material_assets(&self) -> Result<HashSet<Ulid>, cozo::Error> {
+        let held = self.persistent.run_default(
+            &format!("?[asset] := *ledger{{asset @ {}}}", now())
+        )?;
+        let cached = self.cache.run_default("?[asset_id] := *content{asset_id}")?;
+        Ok(held.rows
+            .iter()
+            .chain(&cached.rows)
+            .filter_map(|row| row[0].get_ulid())
+            .collect())
+    }
+
+    fn pinned_collections(&self) -> Result<HashSet<Ulid>, cozo::Error> {
+        let rows = self.persistent.run_default("?[collection_id] := *pinned{collection_id}")?;
+        Ok(rows.rows.iter().filter_map(|row| row[0].get_ulid()).collect())
+    }
+
+    fn collection_quotas(&self) -> Result<BTreeMap<Ulid, u64>, cozo::Error> {
+        let rows = self.persistent.run_default("?[collection_id, bytes] := *quota{collection_id, bytes}")?;
+        Ok(rows.rows
+            .iter()
+            .filter_map(|row| Some((row[0].get_ulid()?, row[1].get_int()? as u64)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holobank::each_backend;
+    use constellations::asset::{block::text::Text, Asset};
+
+    use crate::holobank::temporal::Holder;
+
+    fn store(bank: &Holobank, body: &str) -> Ulid {
+        let text = Text::new(body, 1);
+        bank.dematerialize(&text, true).unwrap();
+        text.id()
+    }
+
+    fn add(bank: &Holobank, asset: Ulid, collection: Ulid) {
+        bank.persistent.run_script(
+            "?[asset_id, collection_id, time_added] <- [[$asset_id, $collection_id, 0]]
+            :put collection {asset_id, collection_id => time_added}",
+            BTreeMap::from([
+                ("asset_id".to_string(), ulid(asset)),
+                ("collection_id".to_string(), ulid(collection)),
+            ]),
+            ScriptMutability::Mutable
+        ).unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        each_backend(|mut bank| {
+            let (old, recent) = (store(&bank, "old words"), store(&bank, "recent words"));
+            bank.content(recent).unwrap();
+
+            let usage = bank.usage().unwrap();
+            bank = bank.with_quota(Some(usage.bytes - 1));
+
+            assert_eq!(bank.evict().unwrap(), vec![old]);
+            assert!(bank.content(old).unwrap().is_none());
+            assert!(bank.content(recent).unwrap().is_some());
+            assert_eq!(bank.usage().unwrap().evicted_assets, 1);
+        });
+    }
+
+    #[test]
+    fn keeps_material_and_pinned_content() {
+        each_backend(|bank| {
+            let (held, pinned, hologram) = (store(&bank, "held"), store(&bank, "pinned"), store(&bank, "hologram"));
+            bank.record_holder(held, &Holder { spaceport: Ulid::new(), spacecraft: None, commander: None }, now()).unwrap();
+            let collection = Ulid::new();
+            add(&bank, pinned, collection);
+            bank.pin(collection).unwrap();
+
+            let bank = bank.with_quota(Some(0));
+            assert_eq!(bank.evict().unwrap(), vec![hologram]);
+            assert_eq!(bank.usage().unwrap().evictable_bytes, 0);
+        });
+    }
+
+    #[test]
+    fn collection_quotas() {
+        each_backend(|bank| {
+            let collection = Ulid::new();
+            let (inside, outside) = (store(&bank, "inside"), store(&bank, "outside"));
+            add(&bank, inside, collection);
+            bank.set_collection_quota(collection, Some(0)).unwrap();
+
+            assert_eq!(bank.evict().unwrap(), vec![inside]);
+            assert!(bank.content(outside).unwrap().is_some());
+            let usage = bank.usage().unwrap();
+            let quota = usage.collections.iter().find(|c| c.collection_id == collection).unwrap();
+            assert_eq!(quota.quota_bytes, Some(0));
+            assert_eq!(quota.bytes, 0);
+        });
+    }
+}
@@ -0,0 +1,1507 @@
+// Replication copies the assets of chosen collections from one bank into
+// another over zenoh.  The source answers queries under
+// `constellations/holobank/<bank>/replication/`:
+//
+// - `snapshot?collection=<id>` or `snapshot?asset=<id>` replies with the rows
+//   and content of a collection's assets, or of one asset, together with the
+//   sequence number of the latest change when it was taken.
+// - `log?after=<seq>;limit=<n>` replies with logged changes after a cursor,
+//   at most 500 at a time.
+// - `merkle?collection=<id>;prefix=<hex>` replies with a node of the
+//   collection's Merkle summary.
+//
+// A replica copies each collection from a snapshot first and from then on
+// applies the source's change log past the snapshot.  Cursors are kept in the
+// `replica` relation, so a replica cut off from the source, or restarted,
+// resumes where it stopped.  Live changes the source publishes only wake the
+// replica up; it always reads the log so changes apply in order.  When the
+// source trimmed its log past a cursor the collections are copied again.
+//
+// After a partition the replica also reconciles each collection against the
+// source's Merkle summary, which repairs whatever the log cannot, e.g.
+// changes the source's log lost or edits made to the replica meanwhile.
+//
+// Every query names the spaceport it comes from, `;from=<id>`, and the
+// commander asking, `;commander=<id>`, whose signature of the query travels
+// as its attachment; see the sharing module.  Queries not signed with a key
+// the source knows for the commander are refused.  Only collections whose
+// sharing policy lets them read are served: snapshots and Merkle nodes of
+// other collections are refused, and the log leaves out changes to assets
+// outside them.  A replica pins the collections the source's policy pins.
+// A bank replicating a collection it shares itself keeps its own tags, flags
+// and memberships and only takes content a commander allowed to write
+// signed.
+//
+// Assets, tags, flags, collection membership and content are replicated,
+// and so are signed operations, which carry ownership changes and ledger
+// entries; see the identity module.  The replica applies a signed operation
+// only once its signature verifies, and refuses content whose signed edit
+// does not verify, or that is unsigned when it requires signatures or the
+// asset has signed edits.
+// Content travels decrypted and is sealed with the replica's own key.  Assets
+// that leave a collection stay in the replica.  Content changed both here and
+// at the source since they last agreed is kept in both versions; see the
+// conflict module.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::Duration,
+};
+
+use cozo::{DataValue, ScriptMutability};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use constellations::{
+    collection::Role,
+    user::{keys::Keypair, signed::{Operation, Signed}},
+};
+use tracing::{debug, info, warn};
+use ulid::Ulid;
+use zenoh::Session;
+
+use super::{
+    changes::{ChangeOp, HOLOBANK_KEY_PREFIX},
+    blob,
+    identity,
+    identity::{SignatureError, Signer},
+    merkle::{self, Hash, MerkleNode, MerkleSummary},
+    now,
+    sharing::{Credential, Requester},
+    ulid,
+    Holobank,
+};
+
+/// Changes read from the source's log at a time.
+const LOG_BATCH: usize = 500;
+
+/// Seconds between syncs when the source publishes nothing.
+pub const DEFAULT_POLL_SECS: u64 = 30;
+
+/// Relations copied along with an asset, all keyed by `asset_id` first.
+const REPLICATED_RELATIONS: [&str; 4] = ["asset", "tags", "flags", "collection"];
+
+#[derive(Debug)]
+pub enum ReplicationError {
+    /// The source did not answer.
+    Unreachable(String),
+    /// The source answered with something unreadable.
+    Malformed(String),
+    /// The source's sharing policy refused the request.
+    Denied(String),
+    Database(cozo::Error),
+}
+
+impl fmt::Display for ReplicationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicationError::Unreachable(e) => write!(f, "source unreachable: {}", e),
+            ReplicationError::Malformed(e) => write!(f, "malformed reply: {}", e),
+            ReplicationError::Denied(e) => write!(f, "denied: {}", e),
+            ReplicationError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<cozo::Error> for ReplicationError {
+    fn from(e: cozo::Error) -> Self {
+        ReplicationError::Database(e)
+    }
+}
+
+/// What one sync did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Assets copied from snapshots.
+    pub copied: usize,
+    /// Changes applied from the source's log.
+    pub applied: usize,
+    /// Sequence number of the last change applied.
+    pub cursor: u64,
+    /// Bytes received from the source.
+    pub bytes: u64,
+    /// Assets found in conflict.
+    pub conflicts: usize,
+}
+
+/// Progress of reconciling a collection with the source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Nodes of the Merkle summary compared so far.
+    pub compared: usize,
+    /// Nodes left to compare.
+    pub pending: usize,
+    /// Assets found to differ.
+    pub divergent: usize,
+    /// Divergent assets repaired so far.
+    pub repaired: usize,
+    /// Assets found in conflict while repairing.
+    pub conflicts: usize,
+    /// Bytes received from the source.
+    pub bytes: u64,
+}
+
+/// Rows and content of the assets in scope, as of change `seq`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    seq: u64,
+    rows: Vec<(String, Vec<DataValue>)>,
+    content: Vec<Content>,
+    /// Whether the policy of the collection pins it.
+    #[serde(default)]
+    pinned: bool,
+    /// Signed operations on the assets, and every key rotation, oldest
+    /// first.
+    #[serde(default)]
+    signed: Vec<Signed>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Content {
+    asset_id: Ulid,
+    content_type: String,
+    #[serde(with = "serde_bytes")]
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LogBatch {
+    /// Oldest change still logged.
+    oldest: u64,
+    latest: u64,
+    /// Last change read, whether it is among the entries or not.
+    scanned: Option<u64>,
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    seq: u64,
+    relation: String,
+    op: ChangeOp,
+    row: Vec<DataValue>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Scope {
+    Collection(Ulid),
+    Asset(Ulid),
+}
+
+enum Request {
+    Snapshot(Scope),
+    Log { after: u64, limit: usize },
+    Merkle { collection: Ulid, prefix: String },
+}
+
+/// Answers replication queries for the bank until the session closes.
+pub async fn serve(holobank: Holobank, session: Session, bank: Ulid) {
+    let prefix = format!("{}/{}/replication", HOLOBANK_KEY_PREFIX, bank);
+    let queryable = match session.declare_queryable(format!("{}/*", prefix)).await {
+        Ok(queryable) => queryable,
+        Err(e) => {
+            warn!("Could not declare replication queryable: {}", e);
+            return;
+        }
+    };
+
+    let mut summaries = HashMap::new();
+    while let Ok(query) = queryable.recv_async().await {
+        let parameters = query.parameters();
+        let id = |name: &str| parameters.get(name).and_then(|s| s.parse::<Ulid>().ok());
+        let Some(spaceport) = id("from") else {
+            deny(&query, "the request does not name its spaceport").await;
+            continue;
+        };
+        let requester = Requester { spaceport, commander: id("commander") };
+        // The replica signs the selector it asked for.
+        let signed = format!("{}?{}", query.key_expr(), parameters.as_str());
+        let credential = query.attachment().and_then(|attachment| serde_json::from_slice::<Credential>(&attachment.to_bytes()).ok());
+        let request = match query.key_expr().as_str().rsplit('/').next() {
+            Some("log") => Request::Log {
+                after: parameters.get("after").and_then(|s| s.parse().ok()).unwrap_or(0),
+                limit: parameters
+                    .get("limit")
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .map_or(LOG_BATCH, |limit| limit.min(LOG_BATCH)),
+            },
+            Some("snapshot") => match (id("collection"), id("asset")) {
+                (Some(collection), _) => Request::Snapshot(Scope::Collection(collection)),
+                (None, Some(asset)) => Request::Snapshot(Scope::Asset(asset)),
+                (None, None) => {
+                    deny(&query, "a snapshot needs a collection or an asset").await;
+                    continue;
+                }
+            },
+            Some("merkle") => {
+                let prefix = parameters.get("prefix").unwrap_or_default();
+                match id("collection") {
+                    Some(collection) if prefix.len() <= merkle::DEPTH && prefix.chars().all(|c| c.is_ascii_hexdigit()) => {
+                        Request::Merkle { collection, prefix: prefix.to_ascii_lowercase() }
+                    }
+                    _ => {
+                        deny(&query, "a Merkle node needs a collection and a hex prefix").await;
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                deny(&query, &format!("unknown replication request {}", query.key_expr())).await;
+                continue;
+            }
+        };
+
+        let bank = holobank.clone();
+        let readable = tokio::task::spawn_blocking(move || {
+            bank.authenticate(&requester, &signed, credential.as_ref())?;
+            Ok(bank.readable_collections(&requester)?)
+        })
+            .await
+            .map_err(|e| SignatureError::Database(cozo::Error::msg(e)))
+            .and_then(|readable| readable);
+        let readable = match readable {
+            Ok(readable) => readable,
+            Err(SignatureError::Database(e)) => {
+                warn!("Could not answer replication query: {}", e);
+                deny(&query, "the source could not read its bank").await;
+                continue;
+            }
+            Err(e) => {
+                deny(&query, &format!("request from {} not authenticated: {}", spaceport, e)).await;
+                continue;
+            }
+        };
+
+        let payload = match request {
+            Request::Merkle { collection, .. } if !readable.contains(&collection) => Ok(None),
+            Request::Merkle { collection, prefix } => summary(&holobank, &mut summaries, collection)
+                .await
+                .and_then(|summary| to_json(&summary.node(&prefix)))
+                .map(Some),
+            request => {
+                let bank = holobank.clone();
+                tokio::task::spawn_blocking(move || match request {
+                    Request::Snapshot(scope) => bank.snapshot(scope, &readable)
+                        .and_then(|snapshot| snapshot.map(|s| to_json(&s)).transpose()),
+                    Request::Log { after, limit } => bank.log_after(after, limit, &readable)
+                        .and_then(|b| to_json(&b))
+                        .map(Some),
+                    Request::Merkle { .. } => unreachable!(),
+                }).await.map_err(cozo::Error::msg).and_then(|payload| payload)
+            }
+        };
+        match payload {
+            Ok(Some(payload)) => {
+                if let Err(e) = query.reply(query.key_expr().clone(), payload).await {
+                    warn!("Could not answer replication query: {}", e);
+                }
+            }
+            Ok(None) => deny(&query, &format!("not shared with {}", spaceport)).await,
+            Err(e) => {
+                warn!("Could not answer replication query: {}", e);
+                deny(&query, "the source could not read its bank").await;
+            }
+        }
+    }
+}
+
+async fn deny(query: &zenoh::query::Query, reason: &str) {
+    if let Err(e) = query.reply_err(reason.to_string()).await {
+        warn!("Could not answer replication query: {}", e);
+    }
+}
+
+/// Summary of a collection, rebuilt only when the bank changed since the
+/// cached one.  Reconciling asks for many nodes of the same summary.
+async fn summary(
+    holobank: &Holobank,
+    cache: &mut HashMap<Ulid, (u64, Arc<MerkleSummary>)>,
+    collection: Ulid,
+) -> Result<Arc<MerkleSummary>, cozo::Error> {
+    let latest = holobank.latest_change();
+    if let Some((seq, summary)) = cache.get(&collection) {
+        if *seq == latest {
+            return Ok(summary.clone());
+        }
+    }
+    let bank = holobank.clone();
+    let summary = tokio::task::spawn_blocking(move || bank.merkle_summary(collection))
+        .await
+        .map_err(cozo::Error::msg)??;
+    let summary = Arc::new(summary);
+    cache.insert(collection, (latest, summary.clone()));
+    Ok(summary)
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Vec<u8>, cozo::Error> {
+    serde_json::to_vec(value).map_err(cozo::Error::msg)
+}
+
+/// Whether a logged row is a key rotation, which every replica may see.
+fn is_rotation(relation: &str, row: &[DataValue]) -> bool {
+    relation == "signature" && row.get(1).and_then(DataValue::get_str) == Some("rotate")
+}
+
+impl Holobank {
+    /// Snapshot of a scope within the `readable` collections, or `None`
+    /// when the scope is outside them.
+    fn snapshot(&self, scope: Scope, readable: &HashSet<Ulid>) -> Result<Option<Snapshot>, cozo::Error> {
+        let pinned = match scope {
+            Scope::Collection(id) if !readable.contains(&id) => return Ok(None),
+            Scope::Collection(id) => self.sharing_policy(id)?.pinned,
+            Scope::Asset(id) if !self.in_scope(id, readable)? => return Ok(None),
+            Scope::Asset(_) => false,
+        };
+        // Read the cursor first; changes racing the reads are applied again.
+        let seq = self.latest_change();
+        let (rule, id) = match scope {
+            Scope::Collection(id) => ("scope[asset_id] := *collection{asset_id, collection_id: $id}", id),
+            Scope::Asset(id) => ("scope[asset_id] := asset_id = $id", id),
+        };
+        let params = BTreeMap::from([("id".to_string(), ulid(id))]);
+
+        let mut rows = Vec::new();
+        for relation in REPLICATED_RELATIONS {
+            let (columns, _) = self.columns(relation)?;
+            let columns = columns.join(", ");
+            let found = self.persistent.run_script(
+                &format!("{}\n?[{columns}] := scope[asset_id], *{}{{{columns}}}", rule, relation, columns = columns),
+                params.clone(),
+                ScriptMutability::Immutable
+            )?;
+            rows.extend(found.rows
+                .into_iter()
+                .filter(|row| relation != "collection" || row[1].get_ulid().is_some_and(|c| readable.contains(&c)))
+                .map(|row| (relation.to_string(), row)));
+        }
+
+        let stored = self.persistent.run_script(
+            &format!(
+                "{}\n?[asset_id, content_type, payload] := scope[asset_id],
+                    *content{{asset_id, content_type, digest}}, *blob{{digest, payload}}",
+                rule
+            ),
+            params.clone(),
+            ScriptMutability::Immutable
+        )?;
+        let mut content = Vec::new();
+        for row in stored.rows {
+            let (Some(asset_id), Some(content_type)) = (row[0].get_ulid(), row[1].get_str()) else {
+                continue;
+            };
+            if let Some(bytes) = self.unseal(&row[2])? {
+                content.push(Content { asset_id, content_type: content_type.to_string(), bytes });
+            }
+        }
+        let signed = self.persistent.run_script(
+            &format!(
+                "{}\n?[time, signed] := scope[asset_id], *signature{{subject: asset_id, time, signed}}
+                ?[time, signed] := *signature{{kind: \"rotate\", time, signed}}
+                :order time",
+                rule
+            ),
+            params,
+            ScriptMutability::Immutable
+        )?;
+        let signed = signed.rows.iter().filter_map(|row| identity::decode(&row[1])).collect();
+        Ok(Some(Snapshot { seq, rows, content, pinned, signed }))
+    }
+
+    /// Changes after `cursor` to assets in the `readable` collections.
+    fn log_after(&self, cursor: u64, limit: usize, readable: &HashSet<Ulid>) -> Result<LogBatch, cozo::Error> {
+        let latest = self.latest_change();
+        let oldest = self.persistent.run_default("?[min(seq)] := *changes{seq}")?;
+        let oldest = oldest.rows
+            .first()
+            .and_then(|row| row[0].get_int())
+            .map_or(latest + 1, |seq| seq as u64);
+
+        let rows = self.persistent.run_script(
+            &format!(
+                "?[seq, relation, op, row] := *changes{{seq, relation, op, row}}, seq > $cursor
+                :order seq
+                :limit {}",
+                limit.clamp(1, LOG_BATCH)
+            ),
+            BTreeMap::from([("cursor".to_string(), DataValue::from(cursor as i64))]),
+            ScriptMutability::Immutable
+        )?;
+        let scanned = rows.rows.last().and_then(|row| row[0].get_int()).map(|seq| seq as u64);
+        let logged: Vec<Entry> = rows.rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(Entry {
+                    seq: row[0].get_int()? as u64,
+                    relation: row[1].get_str()?.to_string(),
+                    op: if row[2].get_str()? == "rm" { ChangeOp::Rm } else { ChangeOp::Put },
+                    row: row[3].get_slice()?.to_vec(),
+                })
+            })
+            .collect();
+        let mut entries = Vec::new();
+        for entry in logged {
+            let shown = match (entry.relation.as_str(), entry.row.first().and_then(DataValue::get_ulid)) {
+                _ if is_rotation(&entry.relation, &entry.row) => true,
+                ("collection", _) => entry.row.get(1).and_then(DataValue::get_ulid).is_some_and(|c| readable.contains(&c)),
+                (_, Some(asset)) => self.in_scope(asset, readable)?,
+                (_, None) => false,
+            };
+            if shown {
+                entries.push(entry);
+            }
+        }
+        Ok(LogBatch { oldest, latest, scanned, entries })
+    }
+
+    /// Applies a snapshot from `source`, keeping only memberships of
+    /// `collections`.  Content is kept only if its edit is signed, or
+    /// unsigned unless `signed_only` or the asset has signed edits.  Assets in collections the bank shares
+    /// keep their rows, and their content only changes when a commander
+    /// allowed to write signed the edit.  Returns the number of assets found
+    /// in conflict.
+    fn apply_snapshot(&self, source: Ulid, snapshot: &Snapshot, collections: &HashSet<Ulid>, signed_only: bool) -> Result<usize, cozo::Error> {
+        let shared = self.shared(collections)?;
+        let mut guarded = HashSet::new();
+        for (relation, row) in &snapshot.rows {
+            let Some(asset) = row.first().and_then(DataValue::get_ulid) else {
+                continue;
+            };
+            let joins = relation == "collection" && row.get(1).and_then(DataValue::get_ulid).is_some_and(|c| shared.contains(&c));
+            if joins || self.in_scope(asset, &shared)? {
+                guarded.insert(asset);
+            }
+        }
+        for content in &snapshot.content {
+            if self.in_scope(content.asset_id, &shared)? {
+                guarded.insert(content.asset_id);
+            }
+        }
+
+        for (relation, row) in &snapshot.rows {
+            if relation == "collection" && !row.get(1).and_then(DataValue::get_ulid).is_some_and(|c| collections.contains(&c)) {
+                continue;
+            }
+            if row.first().and_then(DataValue::get_ulid).is_some_and(|asset| guarded.contains(&asset)) {
+                continue;
+            }
+            self.put_row(relation, row)?;
+            if relation == "collection" {
+                self.set_received(source, row, true)?;
+            }
+        }
+
+        let (mut verified, mut refused) = (HashMap::<_, Vec<Ulid>>::new(), HashSet::new());
+        for signed in &snapshot.signed {
+            let edit = match &signed.operation {
+                Operation::Edit { asset, digest } => Some((*asset, digest.clone())),
+                _ => None,
+            };
+            match self.integrate(signed) {
+                Ok(()) => {
+                    if let Some(edit) = edit {
+                        verified.entry(edit).or_default().push(signed.commander);
+                    }
+                }
+                Err(e) => {
+                    warn!("Refused {} of {} from {}: {}", signed.operation.kind(), signed.subject(), source, e);
+                    refused.extend(edit);
+                }
+            }
+        }
+
+        // Content of an asset with signed edits must be what one of them
+        // signed.
+        let signed_assets: HashSet<Ulid> = verified.keys().map(|(asset, _)| *asset).collect();
+        let mut conflicts = 0;
+        for content in &snapshot.content {
+            let id = content.asset_id;
+            let edit = (id, blob::digest(&content.bytes));
+            let incoming = self.storage_digest(&content.bytes);
+            let unsigned = signed_only || refused.contains(&edit) || signed_assets.contains(&id);
+            if !verified.contains_key(&edit) && unsigned {
+                warn!("Refused content of {} from {}: its edit is not signed", id, source);
+                continue;
+            }
+            if guarded.contains(&id) && !self.written(id, source, verified.get(&edit).map_or(&[], Vec::as_slice), &shared)? {
+                warn!("Refused content of {} from {}: no commander allowed to write signed it", id, source);
+                continue;
+            }
+            if let Some(local) = self.content_digest(id)? {
+                if local != incoming && self.synced_digest(source, id)?.as_ref() != Some(&local) {
+                    let fork = self.fork_conflict(id)?;
+                    warn!("Asset {} changed here and at {}; our version moved to {}", id, source, fork);
+                    conflicts += 1;
+                }
+            }
+            self.store_content(id, &content.content_type, content.bytes.clone())?;
+            self.set_synced_digest(source, id, incoming)?;
+            self.content_stored(id)?;
+        }
+        Ok(conflicts)
+    }
+
+    /// Whether one of `signers` may write the asset from `source` through a
+    /// `shared` collection it is in.
+    fn written(&self, asset: Ulid, source: Ulid, signers: &[Ulid], shared: &HashSet<Ulid>) -> Result<bool, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[collection_id] := *collection{asset_id: $asset_id, collection_id}",
+            BTreeMap::from([("asset_id".to_string(), ulid(asset))]),
+            ScriptMutability::Immutable
+        )?;
+        for collection in rows.rows.iter().filter_map(|row| row[0].get_ulid()).filter(|c| shared.contains(c)) {
+            for signer in signers {
+                let requester = Requester { spaceport: source, commander: Some(*signer) };
+                if self.authorize(collection, &requester, Role::Write)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Digest of the content last received from `source` for an asset.
+    fn synced_digest(&self, source: Ulid, asset: Ulid) -> Result<Option<Vec<u8>>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[digest] := *synced{source: $source, asset_id: $asset_id, digest}",
+            BTreeMap::from([
+                ("source".to_string(), ulid(source)),
+                ("asset_id".to_string(), ulid(asset)),
+            ]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows.first().and_then(|row| row[0].get_bytes()).map(<[u8]>::to_vec))
+    }
+
+    fn set_synced_digest(&self, source: Ulid, asset: Ulid, digest: Vec<u8>) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("source".to_string(), ulid(source));
+        params.insert("asset_id".to_string(), ulid(asset));
+        params.insert("digest".to_string(), DataValue::Bytes(digest));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "?[source, asset_id, digest, time_synced] <- [[$source, $asset_id, $digest, $time]]
+            :put synced {source, asset_id => digest, time_synced}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Applies changes from the log of `source` in order.  Returns the
+    /// assets whose content changed or that joined a replicated collection,
+    /// which have to be fetched whole.
+    fn apply_entries(&self, source: Ulid, entries: &[Entry], collections: &HashSet<Ulid>) -> Result<Vec<Ulid>, cozo::Error> {
+        let shared = self.shared(collections)?;
+        let mut fetch = Vec::new();
+        for entry in entries {
+            let Some(asset) = entry.row.first().and_then(DataValue::get_ulid) else {
+                continue;
+            };
+            let rotation = is_rotation(&entry.relation, &entry.row);
+            if entry.relation == "collection" {
+                let Some(collection) = entry.row.get(1).and_then(DataValue::get_ulid) else {
+                    continue;
+                };
+                if !collections.contains(&collection) || shared.contains(&collection) {
+                    continue;
+                }
+                if entry.op == ChangeOp::Put && !self.in_scope(asset, collections)? {
+                    fetch.push(asset);
+                }
+            } else if !rotation && !self.in_scope(asset, collections)? {
+                continue;
+            }
+            // Only signed content changes to collections the bank shares.
+            let signed = entry.relation == "signature" || (entry.relation == "content" && entry.op == ChangeOp::Put);
+            if !signed && self.in_scope(asset, &shared)? {
+                continue;
+            }
+
+            match (entry.relation.as_str(), entry.op) {
+                ("signature", ChangeOp::Put) => {
+                    let Some(signed) = entry.row.get(5).and_then(identity::decode) else {
+                        continue;
+                    };
+                    if let Err(e) = self.integrate(&signed) {
+                        warn!("Refused {} of {}: {}", signed.operation.kind(), signed.subject(), e);
+                    }
+                }
+                ("signature", ChangeOp::Rm) => {}
+                ("collection", op) => {
+                    match op {
+                        ChangeOp::Put => self.put_row("collection", &entry.row)?,
+                        ChangeOp::Rm => self.rm_row("collection", &entry.row)?,
+                    }
+                    self.set_received(source, &entry.row, op == ChangeOp::Put)?;
+                }
+                ("content", ChangeOp::Put) => fetch.push(asset),
+                ("content", ChangeOp::Rm) => self.remove_content(asset)?,
+                (relation, ChangeOp::Put) => self.put_row(relation, &entry.row)?,
+                (relation, ChangeOp::Rm) => self.rm_row(relation, &entry.row)?,
+            }
+        }
+        fetch.sort_unstable();
+        fetch.dedup();
+        Ok(fetch)
+    }
+
+    fn in_scope(&self, asset: Ulid, collections: &HashSet<Ulid>) -> Result<bool, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[collection_id] := *collection{asset_id: $asset_id, collection_id}",
+            BTreeMap::from([("asset_id".to_string(), ulid(asset))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows.iter().any(|row| row[0].get_ulid().is_some_and(|c| collections.contains(&c))))
+    }
+
+    fn put_row(&self, relation: &str, row: &[DataValue]) -> Result<(), cozo::Error> {
+        let (columns, keys) = self.columns(relation)?;
+        if row.len() != columns.len() {
+            return Ok(());
+        }
+        self.persistent.run_script(
+            &format!(
+                "?[{}] <- [$row] :put {} {{{} => {}}}",
+                columns.join(", "),
+                relation,
+                columns[..keys].join(", "),
+                columns[keys..].join(", ")
+            ),
+            BTreeMap::from([("row".to_string(), DataValue::List(row.to_vec()))]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    fn rm_row(&self, relation: &str, row: &[DataValue]) -> Result<(), cozo::Error> {
+        let (columns, keys) = self.columns(relation)?;
+        if row.len() < keys {
+            return Ok(());
+        }
+        self.persistent.run_script(
+            &format!("?[{keys}] <- [$row] :rm {} {{{keys}}}", relation, keys = columns[..keys].join(", ")),
+            BTreeMap::from([("row".to_string(), DataValue::List(row[..keys].to_vec()))]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Records whether the membership in `row` was received from `source`.
+    fn set_received(&self, source: Ulid, row: &[DataValue], received: bool) -> Result<(), cozo::Error> {
+        let (Some(asset), Some(collection)) = (row.first(), row.get(1)) else {
+            return Ok(());
+        };
+        let mut params = BTreeMap::new();
+        params.insert("source".to_string(), ulid(source));
+        params.insert("asset_id".to_string(), asset.clone());
+        params.insert("collection_id".to_string(), collection.clone());
+        params.insert("time".to_string(), DataValue::from(now()));
+        let script = if received {
+            "?[source, asset_id, collection_id, time_received] <- [[$source, $asset_id, $collection_id, $time]]
+            :put received {source, asset_id, collection_id => time_received}"
+        } else {
+            "?[source, asset_id, collection_id] <- [[$source, $asset_id, $collection_id]]
+            :rm received {source, asset_id, collection_id}"
+        };
+        self.persistent.run_script(script, params, ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    /// Members of a collection whose membership was received from `source`.
+    fn received(&self, source: Ulid, collection: Ulid) -> Result<HashSet<Ulid>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[asset_id] := *received{source: $source, asset_id, collection_id: $collection_id}",
+            BTreeMap::from([
+                ("source".to_string(), ulid(source)),
+                ("collection_id".to_string(), ulid(collection)),
+            ]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows.iter().filter_map(|row| row[0].get_ulid()).collect())
+    }
+
+    /// Cursor of each collection replicated from `source`.
+    fn replica_cursors(&self, source: Ulid) -> Result<HashMap<Ulid, u64>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            "?[collection_id, cursor] := *replica{source: $source, collection_id, cursor}",
+            BTreeMap::from([("source".to_string(), ulid(source))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows
+            .iter()
+            .filter_map(|row| Some((row[0].get_ulid()?, row[1].get_int()? as u64)))
+            .collect())
+    }
+
+    fn set_replica_cursor(&self, source: Ulid, collections: &[Ulid], cursor: u64) -> Result<(), cozo::Error> {
+        let rows = collections
+            .iter()
+            .map(|collection| DataValue::List(vec![ulid(source), ulid(*collection), DataValue::from(cursor as i64), DataValue::from(now())]))
+            .collect();
+        self.persistent.run_script(
+            "?[source, collection_id, cursor, time_synced] <- $rows
+            :put replica {source, collection_id => cursor, time_synced}",
+            BTreeMap::from([("rows".to_string(), DataValue::List(rows))]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    fn forget_replica(&self, source: Ulid) -> Result<(), cozo::Error> {
+        self.persistent.run_script(
+            "?[source, collection_id] := *replica{source, collection_id}, source = $source
+            :rm replica {source, collection_id}",
+            BTreeMap::from([("source".to_string(), ulid(source))]),
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+}
+
+/// Replicates collections of another bank into this one.
+#[derive(Clone)]
+pub struct Replicator {
+    holobank: Holobank,
+    session: Session,
+    /// Who the source sees asking.
+    requester: Requester,
+    /// Signs requests as the requester's commander.
+    signer: Option<Arc<Signer>>,
+    source: Ulid,
+    collections: Vec<Ulid>,
+    poll: Duration,
+    /// Bytes received from the source.
+    received: Arc<AtomicU64>,
+    /// Refuses content whose edit is not signed.
+    signed_only: bool,
+}
+
+impl Replicator {
+    /// Replicates `collections` of the bank `source` into the bank of
+    /// `spaceport`, asking as the commander the bank signs for.  Without
+    /// one, requests are not signed and the source refuses them.
+    pub fn new(holobank: Holobank, session: Session, spaceport: Ulid, source: Ulid, collections: Vec<Ulid>) -> Replicator {
+        let signer = holobank.signer.clone();
+        Replicator {
+            holobank,
+            session,
+            requester: Requester { spaceport, commander: signer.as_ref().map(|signer| signer.commander) },
+            signer,
+            source,
+            collections,
+            poll: Duration::from_secs(DEFAULT_POLL_SECS),
+            received: Arc::new(AtomicU64::new(0)),
+            signed_only: false,
+        }
+    }
+
+    /// Bytes received from the source since the replicator was created.
+    pub fn bytes_received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Asks the source on behalf of a commander, signing requests with
+    /// their key.
+    pub fn with_commander(mut self, commander: Ulid, keypair: Keypair) -> Replicator {
+        self.requester.commander = Some(commander);
+        self.signer = Some(Arc::new(Signer { commander, keypair }));
+        self
+    }
+
+    /// Refuses content from the source unless a commander signed the edit
+    /// that produced it.  Signatures that do not verify are refused either
+    /// way.
+    pub fn with_signatures_required(mut self) -> Replicator {
+        self.signed_only = true;
+        self
+    }
+
+    /// Sets how often to sync when the source publishes nothing, which also
+    /// bounds how long a replica takes to notice the source is back.
+    pub fn with_poll(mut self, poll: Duration) -> Replicator {
+        self.poll = poll;
+        self
+    }
+
+    /// Syncs whenever the source publishes a change and every poll interval
+    /// until the session closes.  Failed syncs are retried, so replication
+    /// resumes once the source is reachable again.
+    pub async fn run(self) {
+        let key = format!("{}/{}/changes/**", HOLOBANK_KEY_PREFIX, self.source);
+        let subscriber = match self.session.declare_subscriber(key).await {
+            Ok(subscriber) => subscriber,
+            Err(e) => {
+                warn!("Could not subscribe to changes of {}: {}", self.source, e);
+                return;
+            }
+        };
+        let mut poll = tokio::time::interval(self.poll);
+        let mut partitioned = false;
+
+        loop {
+            match self.sync().await {
+                Ok(report) => {
+                    if report.copied + report.applied > 0 {
+                        debug!(
+                            "Replicated from {}: {} assets copied, {} changes applied, {} bytes, at {}",
+                            self.source,
+                            report.copied,
+                            report.applied,
+                            report.bytes,
+                            report.cursor
+                        );
+                    }
+                    if partitioned {
+                        partitioned = false;
+                        self.reconcile_all().await;
+                    }
+                }
+                Err(e) => {
+                    partitioned = true;
+                    warn!("Replication from {} failed, retrying: {}", self.source, e);
+                }
+            }
+            tokio::select! {
+                sample = subscriber.recv_async() => {
+                    if sample.is_err() {
+                        return;
+                    }
+                    // One sync covers every change published meanwhile.
+                    while let Ok(Some(_)) = subscriber.try_recv() {}
+                }
+                _ = poll.tick() => {}
+            }
+        }
+    }
+
+    /// Copies the collections not replicated yet, then applies the source's
+    /// changes since the last sync.
+    pub async fn sync(&self) -> Result<SyncReport, ReplicationError> {
+        let collections: HashSet<Ulid> = self.collections.iter().copied().collect();
+        let mut report = SyncReport::default();
+        let start = self.bytes_received();
+
+        loop {
+            let source = self.source;
+            let mut cursors = self.blocking(move |bank| bank.replica_cursors(source)).await?;
+            for collection in &self.collections {
+                if cursors.contains_key(collection) {
+                    continue;
+                }
+                let snapshot: Snapshot = self.fetch(&format!("snapshot?collection={}", collection)).await?;
+                report.copied += snapshot.rows.iter().filter(|(relation, _)| relation == "asset").count();
+                let (seq, collection) = (snapshot.seq, *collection);
+                let (scope, signed_only) = (collections.clone(), self.signed_only);
+                report.conflicts += self.blocking(move |bank| {
+                    let conflicts = bank.apply_snapshot(source, &snapshot, &scope, signed_only)?;
+                    if snapshot.pinned {
+                        bank.pin(collection)?;
+                    }
+                    bank.set_replica_cursor(source, &[collection], seq)?;
+                    Ok(conflicts)
+                }).await?;
+                cursors.insert(collection, seq);
+            }
+
+            let mut cursor = cursors.values().copied().min().unwrap_or(0);
+            loop {
+                let batch: LogBatch = self.fetch(&format!("log?after={};limit={}", cursor, LOG_BATCH)).await?;
+                if batch.oldest > cursor + 1 && batch.latest > cursor {
+                    break;
+                }
+                let Some(last) = batch.scanned else {
+                    report.cursor = cursor;
+                    report.bytes = self.bytes_received() - start;
+                    return Ok(report);
+                };
+
+                report.applied += batch.entries.len();
+                let scope = collections.clone();
+                let fetch = self.blocking(move |bank| bank.apply_entries(source, &batch.entries, &scope)).await?;
+                for asset in fetch {
+                    let snapshot: Snapshot = self.fetch(&format!("snapshot?asset={}", asset)).await?;
+                    let (scope, signed_only) = (collections.clone(), self.signed_only);
+                    report.conflicts += self.blocking(move |bank| bank.apply_snapshot(source, &snapshot, &scope, signed_only)).await?;
+                }
+                let replicated = self.collections.clone();
+                self.blocking(move |bank| bank.set_replica_cursor(source, &replicated, last)).await?;
+                cursor = last;
+            }
+
+            // The source trimmed changes we have not seen; copy everything again.
+            warn!("Change log of {} was trimmed past {}, copying collections again", self.source, cursor);
+            self.blocking(move |bank| bank.forget_replica(source)).await?;
+        }
+    }
+
+    /// Compares a collection with the source's copy through their Merkle
+    /// summaries and repairs only the assets that differ: assets missing or
+    /// different here are fetched again and assets the source no longer has
+    /// in the collection leave it, if their membership came from the source.
+    /// Members added here, such as forks holding our side of a conflict,
+    /// stay.  `progress` is called after every step.
+    pub async fn reconcile(&self, collection: Ulid, mut progress: impl FnMut(&Progress)) -> Result<Progress, ReplicationError> {
+        let start = self.bytes_received();
+        let local = self.blocking(move |bank| bank.merkle_summary(collection)).await?;
+        let source = self.source;
+        let received = self.blocking(move |bank| bank.received(source, collection)).await?;
+        let mut state = Progress::default();
+        let (mut fetch, mut leave) = (Vec::new(), Vec::new());
+
+        let mut pending = vec![String::new()];
+        while let Some(prefix) = pending.pop() {
+            let node: MerkleNode = self.fetch(&format!("merkle?collection={};prefix={}", collection, prefix)).await?;
+            state.compared += 1;
+            if node.hash != local.hash(&prefix) {
+                if prefix.len() >= merkle::DEPTH {
+                    let remote: HashMap<Ulid, Hash> = node.leaves.into_iter().collect();
+                    let here: HashMap<Ulid, Hash> = local.leaves(&prefix).iter().copied().collect();
+                    leave.extend(here.keys().filter(|id| !remote.contains_key(id) && received.contains(id)));
+                    fetch.extend(remote.iter().filter(|(id, hash)| here.get(id) != Some(hash)).map(|(id, _)| *id));
+                } else {
+                    pending.extend(node.children
+                        .iter()
+                        .enumerate()
+                        .map(|(digit, hash)| (format!("{}{:x}", prefix, digit), hash))
+                        .filter(|(child, hash)| local.hash(child) != **hash)
+                        .map(|(child, _)| child));
+                }
+            }
+            state.pending = pending.len();
+            state.divergent = fetch.len() + leave.len();
+            state.bytes = self.bytes_received() - start;
+            progress(&state);
+        }
+
+        let scope = HashSet::from([collection]);
+        for asset in leave {
+            let row = vec![ulid(asset), ulid(collection)];
+            self.blocking(move |bank| {
+                bank.rm_row("collection", &row)?;
+                bank.set_received(source, &row, false)
+            }).await?;
+            state.repaired += 1;
+            progress(&state);
+        }
+        for asset in fetch {
+            let snapshot: Snapshot = self.fetch(&format!("snapshot?asset={}", asset)).await?;
+            let (source, scope, signed_only) = (self.source, scope.clone(), self.signed_only);
+            state.conflicts += self.blocking(move |bank| bank.apply_snapshot(source, &snapshot, &scope, signed_only)).await?;
+            state.repaired += 1;
+            state.bytes = self.bytes_received() - start;
+            progress(&state);
+        }
+        Ok(state)
+    }
+
+    async fn reconcile_all(&self) {
+        for collection in &self.collections {
+            let reconciled = self.reconcile(*collection, |progress| debug!(
+                "Reconciling {} with {}: {} nodes compared, {} pending, {} of {} divergent assets repaired",
+                collection,
+                self.source,
+                progress.compared,
+                progress.pending,
+                progress.repaired,
+                progress.divergent
+            )).await;
+            match reconciled {
+                Ok(progress) => info!(
+                    "Reconciled {} with {}: {} divergent assets repaired, {} in conflict, {} bytes received",
+                    collection,
+                    self.source,
+                    progress.repaired,
+                    progress.conflicts,
+                    progress.bytes
+                ),
+                Err(e) => warn!("Could not reconcile {} with {}: {}", collection, self.source, e),
+            }
+        }
+    }
+
+    async fn fetch<T: DeserializeOwned>(&self, endpoint: &str) -> Result<T, ReplicationError> {
+        let mut selector = format!(
+            "{}/{}/replication/{};from={}",
+            HOLOBANK_KEY_PREFIX,
+            self.source,
+            endpoint,
+            self.requester.spaceport
+        );
+        if let Some(commander) = self.requester.commander {
+            selector.push_str(&format!(";commander={}", commander));
+        }
+        let mut get = self.session.get(&selector);
+        if let Some(signer) = &self.signer {
+            let credential = Credential::sign(&selector, &signer.keypair);
+            get = get.attachment(serde_json::to_vec(&credential).expect("credentials serialize"));
+        }
+        let replies = get
+            .await
+            .map_err(|e| ReplicationError::Unreachable(e.to_string()))?;
+        let reply = replies
+            .recv_async()
+            .await
+            .map_err(|_| ReplicationError::Unreachable(format!("no reply to {}", selector)))?;
+        let sample = reply
+            .result()
+            .map_err(|e| ReplicationError::Denied(e.payload().try_to_string().unwrap_or_default().to_string()))?;
+        let payload = sample.payload().to_bytes();
+        self.received.fetch_add(payload.len() as u64, Ordering::Relaxed);
+        serde_json::from_slice(&payload)
+            .map_err(|e| ReplicationError::Malformed(e.to_string()))
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> Result<T, ReplicationError> where
+    T: Send + 'static, F: FnOnce(&Holobank) -> Result<T, cozo::Error> + Send + 'static {
+        let bank = self.holobank.clone();
+        tokio::task::spawn_blocking(move || f(&bank))
+            .await
+            .map_err(|e| ReplicationError::Database(cozo::Error::msg(e)))?
+            .map_err(ReplicationError::Database)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    use constellations::{
+        asset::{block::text::Text, Asset},
+        collection::{Role, SharingPolicy},
+        user::keys::Keypair,
+    };
+    use futures::StreamExt;
+
+    use crate::{holobank::{conflict::Resolution, search::SearchQuery, temporal::Holder}, storage::Backend};
+
+    async fn session() -> Session {
+        let mut config = zenoh::Config::default();
+        config.insert_json5("scouting/multicast/enabled", "false").unwrap();
+        zenoh::open(config).await.unwrap()
+    }
+
+    /// Two sessions talking over TCP on the loopback, like two daemons:
+    /// the first listens and the second connects to it.
+    async fn peers() -> (Session, Session) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let endpoint = format!("[\"tcp/127.0.0.1:{}\"]", port);
+        let mut listening = zenoh::Config::default();
+        listening.insert_json5("scouting/multicast/enabled", "false").unwrap();
+        listening.insert_json5("listen/endpoints", &endpoint).unwrap();
+        let listener = zenoh::open(listening).await.unwrap();
+
+        let mut connecting = zenoh::Config::default();
+        connecting.insert_json5("scouting/multicast/enabled", "false").unwrap();
+        connecting.insert_json5("connect/endpoints", &endpoint).unwrap();
+        let connector = zenoh::open(connecting).await.unwrap();
+        while connector.info().peers_zid().await.next().is_none() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        (listener, connector)
+    }
+
+    fn bank() -> Holobank {
+        Holobank::load(Backend::Mem, Path::new("")).unwrap()
+    }
+
+    fn directory() -> PathBuf {
+        std::env::temp_dir().join(format!("holobank-replication-{}", Ulid::new()))
+    }
+
+    /// A bank kept on disk, which survives being closed and opened again.
+    fn stored_bank(directory: &Path) -> Holobank {
+        Holobank::load(Backend::Sqlite, directory).unwrap()
+    }
+
+    fn put(bank: &Holobank, script: &str, asset: Ulid, other: DataValue) {
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(asset));
+        params.insert("other".to_string(), other);
+        bank.persistent.run_script(script, params, ScriptMutability::Mutable).unwrap();
+    }
+
+    fn add(bank: &Holobank, asset: Ulid, collection: Ulid) {
+        put(bank, "?[asset_id, collection_id, time_added] <- [[$asset_id, $other, 0]]
+            :put collection {asset_id, collection_id => time_added}", asset, ulid(collection));
+    }
+
+    /// Replicates from `source` as a commander it knows the key of.
+    fn trusted_replicator(source: &Holobank, replica: &Holobank, session: Session, replica_id: Ulid, source_id: Ulid, collections: Vec<Ulid>) -> Replicator {
+        let (commander, keypair) = (Ulid::new(), Keypair::generate());
+        source.trust_key(commander, keypair.public()).unwrap();
+        Replicator::new(replica.clone(), session, replica_id, source_id, collections).with_commander(commander, keypair)
+    }
+
+    fn share(bank: &Holobank, collection: Ulid, replica: Ulid) {
+        let mut policy = SharingPolicy::default();
+        policy.replicas.insert(replica);
+        bank.set_sharing_policy(collection, &policy).unwrap();
+    }
+
+    fn tag(bank: &Holobank, asset: Ulid, tag: &str) {
+        put(bank, "?[asset_id, tag, time_attached] <- [[$asset_id, $other, 0]]
+            :put tags {asset_id, tag => time_attached}", asset, DataValue::from(tag));
+    }
+
+    fn tags(bank: &Holobank, asset: Ulid) -> Vec<String> {
+        let rows = bank.persistent.run_script(
+            "?[tag] := *tags{asset_id: $asset_id, tag}",
+            BTreeMap::from([("asset_id".to_string(), ulid(asset))]),
+            ScriptMutability::Immutable
+        ).unwrap();
+        rows.rows.iter().filter_map(|row| row[0].get_str().map(str::to_string)).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn copies_then_follows_collections() {
+        let (source_session, replica_session) = peers().await;
+        let (source_directory, replica_directory) = (directory(), directory());
+        let (source, replica) = (stored_bank(&source_directory), stored_bank(&replica_directory));
+        let (source_id, replica_id, collection) = (Ulid::new(), Ulid::new(), Ulid::new());
+        share(&source, collection, replica_id);
+        tokio::spawn(serve(source.clone(), source_session, source_id));
+
+        let text = Text::new("replicated", 1);
+        let private = Text::new("not replicated", 1);
+        source.dematerialize(&text, true).unwrap();
+        source.dematerialize(&private, true).unwrap();
+        add(&source, text.id(), collection);
+        tag(&source, text.id(), "first");
+        source.flush_changes();
+
+        let replicator = trusted_replicator(&source, &replica, replica_session, replica_id, source_id, vec![collection]);
+        let report = replicator.sync().await.unwrap();
+        assert_eq!(report.copied, 1);
+        assert_eq!(replica.content(text.id()).unwrap(), source.content(text.id()).unwrap());
+        assert_eq!(tags(&replica, text.id()), vec!["first"]);
+        assert!(replica.content(private.id()).unwrap().is_none());
+        let query = SearchQuery { text: "replicated".to_string(), ..Default::default() };
+        let found: Vec<Ulid> = replica.search(&query).unwrap().into_iter().map(|hit| hit.asset_id).collect();
+        assert_eq!(found, vec![text.id()]);
+
+        // Incremental changes, including an asset joining the collection.
+        tag(&source, text.id(), "second");
+        add(&source, private.id(), collection);
+        source.flush_changes();
+        let report = replicator.sync().await.unwrap();
+        assert_eq!(report.copied, 0);
+        assert!(report.applied > 0);
+        assert_eq!(tags(&replica, text.id()).len(), 2);
+        assert!(replica.content(private.id()).unwrap().is_some());
+
+        drop((source, replica, replicator));
+        let _ = std::fs::remove_dir_all(&source_directory);
+        let _ = std::fs::remove_dir_all(&replica_directory);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resumes_after_partition() {
+        let (source_session, replica_session) = peers().await;
+        let (source_directory, replica_directory) = (directory(), directory());
+        let (source, replica) = (stored_bank(&source_directory), stored_bank(&replica_directory));
+        let (source_id, replica_id, collection) = (Ulid::new(), Ulid::new(), Ulid::new());
+        share(&source, collection, replica_id);
+        let server = tokio::spawn(serve(source.clone(), source_session.clone(), source_id));
+
+        let text = Text::new("partitioned", 1);
+        source.dematerialize(&text, true).unwrap();
+        add(&source, text.id(), collection);
+        source.flush_changes();
+        let replicator = trusted_replicator(&source, &replica, replica_session.clone(), replica_id, source_id, vec![collection]);
+        let cursor = replicator.sync().await.unwrap().cursor;
+
+        // The source goes away while it keeps changing.
+        server.abort();
+        let _ = server.await;
+        tag(&source, text.id(), "while away");
+        source.flush_changes();
+        assert!(matches!(replicator.sync().await, Err(ReplicationError::Unreachable(_))));
+
+        // The replica restarts from disk and picks up from its stored cursor.
+        drop((replica, replicator));
+        let replica = stored_bank(&replica_directory);
+        tokio::spawn(serve(source.clone(), source_session, source_id));
+        let replicator = trusted_replicator(&source, &replica, replica_session, replica_id, source_id, vec![collection])
+            .with_poll(Duration::from_millis(200));
+        let mut changes = Box::pin(replica.watch(None));
+        tokio::spawn(replicator.clone().run());
+        let caught_up = async {
+            while let Some(change) = changes.next().await {
+                if change.relation == "tags" {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(15), caught_up).await.expect("replica did not catch up");
+        assert_eq!(tags(&replica, text.id()), vec!["while away"]);
+        let report = replicator.sync().await.unwrap();
+        assert_eq!(report.copied, 0);
+        assert!(report.cursor > cursor);
+
+        drop((source, replica, replicator));
+        let _ = std::fs::remove_dir_all(&source_directory);
+        let _ = std::fs::remove_dir_all(&replica_directory);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconciles_divergent_assets() {
+        let session = session().await;
+        let (source, replica) = (bank(), bank());
+        let (source_id, replica_id, collection) = (Ulid::new(), Ulid::new(), Ulid::new());
+        share(&source, collection, replica_id);
+        tokio::spawn(serve(source.clone(), session.clone(), source_id));
+
+        let texts: Vec<Text> = (0..20).map(|i| Text::new(format!("text {}", i), 1)).collect();
+        for text in &texts {
+            source.dematerialize(text, true).unwrap();
+            add(&source, text.id(), collection);
+        }
+        source.flush_changes();
+        let replicator = trusted_replicator(&source, &replica, session.clone(), replica_id, source_id, vec![collection]);
+        replicator.sync().await.unwrap();
+        let in_sync = replicator.reconcile(collection, |_| {}).await.unwrap();
+        assert_eq!((in_sync.compared, in_sync.divergent), (1, 0));
+
+        // Diverge without the change log: lose content here, add a member
+        // only here, one only at the source and drop one at the source.
+        replica.remove_content(texts[3].id()).unwrap();
+        let local = Ulid::new();
+        add(&replica, local, collection);
+        let late = Text::new("late", 1);
+        source.dematerialize(&late, true).unwrap();
+        add(&source, late.id(), collection);
+        source.rm_row("collection", &[ulid(texts[5].id()), ulid(collection)]).unwrap();
+        source.flush_changes();
+
+        let mut steps = Vec::new();
+        let progress = replicator.reconcile(collection, |p| steps.push(*p)).await.unwrap();
+        assert_eq!(progress.divergent, 3);
+        assert_eq!(progress.repaired, 3);
+        assert!(progress.bytes > 0);
+        assert!(progress.compared > 1);
+        assert_eq!(steps.last(), Some(&progress));
+        assert_eq!(replica.content(texts[3].id()).unwrap(), source.content(texts[3].id()).unwrap());
+        assert!(replica.content(late.id()).unwrap().is_some());
+        assert!(!replica.in_scope(texts[5].id(), &HashSet::from([collection])).unwrap());
+
+        // The member added here never came from the source, so it stays.
+        assert!(replica.in_scope(local, &HashSet::from([collection])).unwrap());
+        let again = replicator.reconcile(collection, |_| {}).await.unwrap();
+        assert_eq!(again.divergent, 0);
+        assert!(replica.in_scope(local, &HashSet::from([collection])).unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keeps_both_sides_of_a_conflict() {
+        let session = session().await;
+        let (source, replica) = (bank(), bank());
+        let (source_id, replica_id, collection) = (Ulid::new(), Ulid::new(), Ulid::new());
+        share(&source, collection, replica_id);
+        tokio::spawn(serve(source.clone(), session.clone(), source_id));
+
+        let text = Text::new("shared", 1);
+        source.dematerialize(&text, true).unwrap();
+        add(&source, text.id(), collection);
+        source.flush_changes();
+        let replicator = trusted_replicator(&source, &replica, session.clone(), replica_id, source_id, vec![collection]);
+        replicator.sync().await.unwrap();
+
+        // Only the source changes: no conflict.
+        source.store_content(text.id(), Text::CONTENT_TYPE, b"theirs".to_vec()).unwrap();
+        source.flush_changes();
+        assert_eq!(replicator.sync().await.unwrap().conflicts, 0);
+
+        // Both change while cut off.
+        replica.store_content(text.id(), Text::CONTENT_TYPE, b"ours".to_vec()).unwrap();
+        source.store_content(text.id(), Text::CONTENT_TYPE, b"theirs again".to_vec()).unwrap();
+        source.flush_changes();
+        assert_eq!(replicator.sync().await.unwrap().conflicts, 1);
+
+        let conflicts = replica.conflicts().unwrap();
+        assert_eq!(conflicts.len(), 1);
+        let fork = conflicts[0].fork_id;
+        assert_eq!(replica.content(fork).unwrap().unwrap().1, b"ours");
+        assert_eq!(replica.content(text.id()).unwrap().unwrap().1, b"theirs again");
+        assert!(replica.in_scope(fork, &HashSet::from([collection])).unwrap());
+
+        // Reconciling leaves our side alone until the conflict is resolved.
+        replicator.reconcile(collection, |_| {}).await.unwrap();
+        assert!(replica.in_scope(fork, &HashSet::from([collection])).unwrap());
+        replica.resolve_conflict(text.id(), Resolution::Keep(fork)).unwrap();
+        assert_eq!(replica.content(text.id()).unwrap().unwrap().1, b"ours");
+        assert!(!replica.in_scope(fork, &HashSet::from([collection])).unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn enforces_sharing_policy() {
+        let session = session().await;
+        let (source, replica) = (bank(), bank());
+        let (source_id, replica_id, system) = (Ulid::new(), Ulid::new(), Ulid::new());
+        let (shared, private, reader) = (Ulid::new(), Ulid::new(), Ulid::new());
+        tokio::spawn(serve(source.clone(), session.clone(), source_id));
+
+        let (text, secret) = (Text::new("shared", 1), Text::new("secret", 1));
+        source.dematerialize(&text, true).unwrap();
+        source.dematerialize(&secret, true).unwrap();
+        add(&source, text.id(), shared);
+        add(&source, secret.id(), private);
+        let mut policy = SharingPolicy { pinned: true, ..SharingPolicy::default() };
+        policy.replicas.insert(system);
+        policy.roles.insert(reader, Role::Read);
+        source.set_sharing_policy(shared, &policy).unwrap();
+        source.flush_changes();
+
+        // The source does not know the reader's key yet.
+        let key = Keypair::generate();
+        let replicator = Replicator::new(replica.clone(), session.clone(), replica_id, source_id, vec![shared])
+            .with_commander(reader, key.clone());
+        assert!(matches!(replicator.sync().await, Err(ReplicationError::Denied(_))));
+        source.trust_key(reader, key.public()).unwrap();
+
+        // Shared with a system the replica is not known to be in.
+        assert!(matches!(replicator.sync().await, Err(ReplicationError::Denied(_))));
+        put(&source, "?[system_id, spaceport_id] <- [[$other, $asset_id]] :put starmap {system_id, spaceport_id}",
+            replica_id, ulid(system));
+
+        // Unsigned requests, and requests signed with another key, are
+        // refused, and so are commanders without a role.
+        let anonymous = Replicator::new(replica.clone(), session.clone(), replica_id, source_id, vec![shared]);
+        assert!(matches!(anonymous.sync().await, Err(ReplicationError::Denied(_))));
+        let impostor = Replicator::new(replica.clone(), session.clone(), replica_id, source_id, vec![shared])
+            .with_commander(reader, Keypair::generate());
+        assert!(matches!(impostor.sync().await, Err(ReplicationError::Denied(_))));
+        let stranger = trusted_replicator(&source, &replica, session.clone(), replica_id, source_id, vec![shared]);
+        assert!(matches!(stranger.sync().await, Err(ReplicationError::Denied(_))));
+        assert_eq!(replicator.sync().await.unwrap().copied, 1);
+        assert!(replica.content(text.id()).unwrap().is_some());
+        assert!(replica.sharing_policy(shared).unwrap().pinned);
+
+        let outsider = Replicator::new(replica.clone(), session.clone(), replica_id, source_id, vec![private])
+            .with_commander(reader, key);
+        assert!(matches!(outsider.sync().await, Err(ReplicationError::Denied(_))));
+        assert!(matches!(outsider.reconcile(private, |_| {}).await, Err(ReplicationError::Denied(_))));
+        let readable = source.readable_collections(&Requester { spaceport: replica_id, commander: Some(reader) }).unwrap();
+        let log = source.log_after(0, LOG_BATCH, &readable).unwrap();
+        assert!(!log.entries.is_empty());
+        assert!(log.entries.iter().all(|entry| entry.row.first().and_then(DataValue::get_ulid) != Some(secret.id())));
+        assert!(source.snapshot(Scope::Asset(secret.id()), &readable).unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn verifies_signed_operations() {
+        let session = session().await;
+        let (source, replica) = (bank(), bank());
+        let (source_id, replica_id, collection, commander) = (Ulid::new(), Ulid::new(), Ulid::new(), Ulid::new());
+        share(&source, collection, replica_id);
+        tokio::spawn(serve(source.clone(), session.clone(), source_id));
+
+        let first = Keypair::generate();
+        let signing = source.clone().with_commander(commander, first.clone()).unwrap();
+        replica.trust_key(commander, first.public()).unwrap();
+        let (signed, unsigned) = (Text::new("signed", 1), Text::new("unsigned", 1));
+        signing.dematerialize(&signed, true).unwrap();
+        source.dematerialize(&unsigned, true).unwrap();
+        add(&source, signed.id(), collection);
+        add(&source, unsigned.id(), collection);
+        signing.assert_owner(signed.id(), commander, 10).unwrap();
+        let holder = Holder { spaceport: source_id, spacecraft: None, commander: Some(commander) };
+        signing.record_holder(signed.id(), &holder, 10).unwrap();
+        source.flush_changes();
+
+        let replicator = trusted_replicator(&source, &replica, session.clone(), replica_id, source_id, vec![collection])
+            .with_signatures_required();
+        replicator.sync().await.unwrap();
+        assert_eq!(replica.content(signed.id()).unwrap(), source.content(signed.id()).unwrap());
+        assert!(replica.content(unsigned.id()).unwrap().is_none());
+        assert_eq!(replica.owners_at(signed.id(), 20).unwrap(), vec![commander]);
+        assert_eq!(replica.holder_at(signed.id(), 20).unwrap(), Some(holder));
+
+        // Edits signed with a rotated key follow the rotation.
+        let next = Keypair::generate();
+        source.rotate_key(&Signed::sign(Operation::Rotate { next: next.public() }, commander, &first)).unwrap();
+        let signing = source.clone().with_commander(commander, next.clone()).unwrap();
+        signing.dematerialize(&Text::new("edited", 1).with_id(signed.id()), true).unwrap();
+        source.flush_changes();
+        replicator.sync().await.unwrap();
+        assert_eq!(replica.content(signed.id()).unwrap(), source.content(signed.id()).unwrap());
+        let keys = replica.commander_keys(commander).unwrap();
+        assert_eq!(keys.iter().map(|key| key.key).collect::<Vec<_>>(), vec![first.public(), next.public()]);
+
+        // Content whose signed edit was tampered with is refused even when
+        // unsigned content is accepted.
+        let readable = HashSet::from([collection]);
+        let mut snapshot = source.snapshot(Scope::Asset(signed.id()), &readable).unwrap().unwrap();
+        snapshot.content[0].bytes = b"tampered".to_vec();
+        let digest = blob::digest(b"tampered");
+        for signed in &mut snapshot.signed {
+            if let Operation::Edit { digest: signed_digest, .. } = &mut signed.operation {
+                *signed_digest = digest.clone();
+            }
+        }
+        let other = bank();
+        other.apply_snapshot(source_id, &snapshot, &readable, false).unwrap();
+        assert!(other.content(signed.id()).unwrap().is_none());
+
+        // So is content swapped under signed edits left as they were.
+        let mut snapshot = source.snapshot(Scope::Asset(signed.id()), &readable).unwrap().unwrap();
+        snapshot.content[0].bytes = b"swapped".to_vec();
+        let other = bank();
+        other.trust_key(commander, first.public()).unwrap();
+        other.apply_snapshot(source_id, &snapshot, &readable, false).unwrap();
+        assert_eq!(other.commander_keys(commander).unwrap().len(), 2);
+        assert!(other.content(signed.id()).unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn takes_changes_back_from_writers_only() {
+        let session = session().await;
+        let (owner_bank, replica) = (bank(), bank());
+        let (owner_id, replica_id, collection) = (Ulid::new(), Ulid::new(), Ulid::new());
+        let (owner, writer, reader) = (Ulid::new(), Ulid::new(), Ulid::new());
+        let (owner_key, writer_key, reader_key) = (Keypair::generate(), Keypair::generate(), Keypair::generate());
+        for bank in [&owner_bank, &replica] {
+            bank.trust_key(owner, owner_key.public()).unwrap();
+            bank.trust_key(writer, writer_key.public()).unwrap();
+            bank.trust_key(reader, reader_key.public()).unwrap();
+        }
+        let source = owner_bank.with_commander(owner, owner_key.clone()).unwrap();
+        tokio::spawn(serve(source.clone(), session.clone(), owner_id));
+        tokio::spawn(serve(replica.clone(), session.clone(), replica_id));
+
+        let (first, second) = (Text::new("first draft", 1), Text::new("second draft", 1));
+        for text in [&first, &second] {
+            source.dematerialize(text, true).unwrap();
+            add(&source, text.id(), collection);
+        }
+        tag(&source, first.id(), "draft");
+        let mut policy = SharingPolicy::default();
+        policy.replicas.insert(replica_id);
+        policy.roles.extend([(owner, Role::Write), (writer, Role::Write), (reader, Role::Read)]);
+        source.set_sharing_policy(collection, &policy).unwrap();
+        source.flush_changes();
+        Replicator::new(replica.clone(), session.clone(), replica_id, owner_id, vec![collection])
+            .with_commander(writer, writer_key.clone())
+            .sync()
+            .await
+            .unwrap();
+
+        // The replica shares the collection back, and both commanders edit
+        // there.
+        share(&replica, collection, owner_id);
+        let back = Replicator::new(source.clone(), session.clone(), owner_id, replica_id, vec![collection]);
+        back.sync().await.unwrap();
+        let as_writer = replica.clone().with_commander(writer, writer_key).unwrap();
+        as_writer.dematerialize(&Text::new("written", 1).with_id(first.id()), true).unwrap();
+        let as_reader = replica.clone().with_commander(reader, reader_key).unwrap();
+        as_reader.dematerialize(&Text::new("only read", 1).with_id(second.id()), true).unwrap();
+        tag(&replica, first.id(), "replica");
+        replica.flush_changes();
+
+        back.sync().await.unwrap();
+        assert_eq!(source.content(first.id()).unwrap(), replica.content(first.id()).unwrap());
+        assert_ne!(source.content(second.id()).unwrap(), replica.content(second.id()).unwrap());
+        assert_eq!(tags(&source, first.id()), vec!["draft"]);
+        assert!(source.conflicts().unwrap().is_empty());
+    }
+}
@@ -31,7 +31,7 @@ pub const ASSET_SCHEMA: &str = "
     :create asset {
         asset_id: Ulid,
         name: String? default null,
-        derived_from: Ulid? defualt null,
+        derived_from: Ulid? default null,
         =>
         asset_type: String,
         derivation_type: String? default null,
@@ -51,8 +51,21 @@ pub const NAME_SCHEMA: &str = "
     }
 ";
 
-/// Asset content.  Also used in in-memory database.
+/// Asset content, stored by the sha256 digest of its bytes in `blob`.
+/// `size` is the length of the bytes before encryption.
 pub const CONTENT_SCHEMA: &str = "
+    :create content {
+        asset_id: Ulid,
+        =>
+        content_type: String,
+        digest: Bytes,
+        size: Int,
+        time_attached: Int,
+    }
+";
+
+/// Asset content kept inline in the in-memory database.
+pub const CACHED_CONTENT_SCHEMA: &str = "
     :create content {
         asset_id: Ulid,
         =>
@@ -62,6 +75,18 @@ pub const CONTENT_SCHEMA: &str = "
     }
 ";
 
+/// Content shared by every asset with the same bytes, counting the assets
+/// that refer to it.
+pub const BLOB_SCHEMA: &str = "
+    :create blob {
+        digest: Bytes,
+        =>
+        payload: Any,
+        size: Int,
+        refs: Int,
+    }
+";
+
 // Ownership for all things, i.e., assets and spaceports
 pub const OWNERSHIP_SCHEMA: &str = "
     :create owner {
@@ -152,6 +177,73 @@ pub const COLLECTION_SCHEMA: &str = "
     }
 ";
 
+/// Names of collections, kept apart from their assets so that empty
+/// collections are known too.
+pub const CATALOG_SCHEMA: &str = "
+    :create catalog {
+        collection_id: Ulid,
+        =>
+        name: String,
+        time_created: Int,
+    }
+";
+
+/// The collection of the bank where new assets land until they are filed.
+pub const INBOX_SCHEMA: &str = "
+    :create inbox {
+        collection_id: Ulid,
+        =>
+        time_created: Int,
+    }
+";
+
+/// Spaceports and systems a collection is shared with.
+pub const SHARING_SCHEMA: &str = "
+    :create sharing {
+        collection_id: Ulid,
+        replica_id: Ulid,
+        =>
+        time_shared: Int,
+    }
+";
+
+/// Roles of commanders in shared collections: `read` or `write`.
+pub const ROLE_SCHEMA: &str = "
+    :create role {
+        collection_id: Ulid,
+        commander_id: Ulid,
+        =>
+        role: String,
+        time_granted: Int,
+    }
+";
+
+/// Queries of dynamic collections as JSON.  Their members are kept in
+/// `collection`.
+pub const DYNAMIC_SCHEMA: &str = "
+    :create dynamic {
+        collection_id: Ulid,
+        =>
+        query: String,
+        time_refreshed: Int,
+    }
+";
+
+/// Assets made from files imported from outside the spaceport, by the
+/// directory they came from and their path in it, with the sha256 digest of
+/// the file when it was imported.  Folders are imported as collections and
+/// kept with an empty digest.
+pub const IMPORTED_SCHEMA: &str = "
+    :create imported {
+        source: String,
+        path: String,
+        =>
+        id: Ulid,
+        digest: Bytes,
+        time_imported: Int,
+    }
+";
+
 /// Spaceports can be hosted on celestia or citadels.
 pub const SPACEPORT_SCHEMA: &str = "
     :create spaceport {
@@ -183,4 +275,212 @@ pub const STARMAP_SCHEMA: &str = "
         system_id: Ulid,
         spaceport_id: Ulid,
     }
-";
\ No newline at end of file
+";
+
+/// Plain text of text blocks, kept next to their content for full-text search.
+pub const TEXT_SCHEMA: &str = "
+    :create text {
+        asset_id: Ulid,
+        =>
+        body: String,
+    }
+";
+
+/// Full-text index over text bodies.
+pub const TEXT_INDEX: &str = "
+    ::fts create text:fts {
+        extractor: body,
+        tokenizer: Simple,
+        filters: [Lowercase, AlphaNumOnly, AsciiFolding],
+    }
+";
+
+/// When content was last read or written, for evicting the least recently
+/// used content first.
+pub const ACCESS_SCHEMA: &str = "
+    :create access {
+        asset_id: Ulid,
+        =>
+        time_accessed: Int,
+    }
+";
+
+/// Collections whose content is never evicted.
+pub const PIN_SCHEMA: &str = "
+    :create pinned {
+        collection_id: Ulid,
+        =>
+        time_pinned: Int,
+    }
+";
+
+/// Storage quotas of collections in bytes of content.
+pub const QUOTA_SCHEMA: &str = "
+    :create quota {
+        collection_id: Ulid,
+        =>
+        bytes: Int,
+    }
+";
+
+/// Random salts kept with the bank, e.g. for deriving keys from passphrases.
+pub const SALT_SCHEMA: &str = "
+    :create salt {
+        purpose: String,
+        =>
+        salt: Bytes,
+    }
+";
+
+/// Ordered log of changes to assets, tags, flags, collections and content.
+pub const CHANGES_SCHEMA: &str = "
+    :create changes {
+        seq: Int,
+        =>
+        time: Int,
+        relation: String,
+        op: String,
+        row: Any,
+    }
+";
+
+/// Rows set aside by the integrity checker, kept for inspection.
+pub const QUARANTINE_SCHEMA: &str = "
+    :create quarantine {
+        relation: String,
+        row: Any,
+        =>
+        reason: String,
+        time_quarantined: Int,
+    }
+";
+
+/// Embeddings of asset text for similarity search.  The vector length
+/// depends on the embedder, so the relation is created once one is chosen.
+pub fn embedding_schema(dimensions: usize) -> String {
+    format!("
+    :create embedding {{
+        asset_id: Ulid,
+        =>
+        model: String,
+        digest: Bytes,
+        vector: <F32; {}>,
+    }}
+", dimensions)
+}
+
+/// Nearest neighbor index over embeddings.
+pub fn embedding_index(dimensions: usize) -> String {
+    format!("
+    ::hnsw create embedding:semantic {{
+        dim: {},
+        m: 32,
+        dtype: F32,
+        fields: [vector],
+        distance: Cosine,
+        ef_construction: 50,
+    }}
+", dimensions)
+}
+
+/// How far this bank has replicated each collection from another bank, as
+/// the sequence number of the last change applied from its log.
+pub const REPLICA_SCHEMA: &str = "
+    :create replica {
+        source: Ulid,
+        collection_id: Ulid,
+        =>
+        cursor: Int,
+        time_synced: Int,
+    }
+";
+
+/// Digest of the content last received from another bank for each asset,
+/// the version both banks agreed on.  Content differing from it on both
+/// sides is in conflict.
+pub const SYNCED_SCHEMA: &str = "
+    :create synced {
+        source: Ulid,
+        asset_id: Ulid,
+        =>
+        digest: Bytes,
+        time_synced: Int,
+    }
+";
+
+/// Memberships received from another bank.  Reconciling with the bank only
+/// takes assets out of a collection if their membership came from it.
+pub const RECEIVED_SCHEMA: &str = "
+    :create received {
+        source: Ulid,
+        asset_id: Ulid,
+        collection_id: Ulid,
+        =>
+        time_received: Int,
+    }
+";
+
+/// Keys each commander signed with, from when they were added until a
+/// rotation retired them.  The first key of a commander is valid from the
+/// start.
+pub const COMMANDER_KEY_SCHEMA: &str = "
+    :create commander_key {
+        commander_id: Ulid,
+        public_key: Bytes,
+        =>
+        time_added: Int,
+        time_retired: Int?,
+    }
+";
+
+/// Signed operations, by what they are about: edits, ledger entries and
+/// ownership changes of assets, and key rotations of commanders.
+pub const SIGNATURE_SCHEMA: &str = "
+    :create signature {
+        subject: Ulid,
+        kind: String,
+        time: Int,
+        signature: Bytes,
+        =>
+        commander_id: Ulid,
+        signed: Bytes,
+    }
+";
+
+/// Relations of the persistent database, each with the scripts that create it.
+pub const PERSISTENT: &[(&str, &[&str])] = &[
+    ("commander", &[COMMMANDER_SCHEMA]),
+    ("commander_key", &[COMMANDER_KEY_SCHEMA]),
+    ("signature", &[SIGNATURE_SCHEMA]),
+    ("asset", &[ASSET_SCHEMA]),
+    ("name", &[NAME_SCHEMA]),
+    ("content", &[CONTENT_SCHEMA]),
+    ("blob", &[BLOB_SCHEMA]),
+    ("owner", &[OWNERSHIP_SCHEMA]),
+    ("snapshot", &[SNAPSHOT_SCHEMA]),
+    ("history", &[HISTORY_SCHEMA]),
+    ("connection", &[CONNECTION_SCHEMA]),
+    ("tags", &[TAG_SCHEMA]),
+    ("flags", &[FLAG_SCHEMA]),
+    ("collection", &[COLLECTION_SCHEMA]),
+    ("catalog", &[CATALOG_SCHEMA]),
+    ("inbox", &[INBOX_SCHEMA]),
+    ("sharing", &[SHARING_SCHEMA]),
+    ("role", &[ROLE_SCHEMA]),
+    ("dynamic", &[DYNAMIC_SCHEMA]),
+    ("imported", &[IMPORTED_SCHEMA]),
+    ("ledger", &[LEDGER_SCHEMA]),
+    ("spaceport", &[SPACEPORT_SCHEMA]),
+    ("system", &[SYSTEM_SCHEMA]),
+    ("starmap", &[STARMAP_SCHEMA]),
+    ("text", &[TEXT_SCHEMA, TEXT_INDEX]),
+    ("quarantine", &[QUARANTINE_SCHEMA]),
+    ("access", &[ACCESS_SCHEMA]),
+    ("pinned", &[PIN_SCHEMA]),
+    ("quota", &[QUOTA_SCHEMA]),
+    ("salt", &[SALT_SCHEMA]),
+    ("changes", &[CHANGES_SCHEMA]),
+    ("replica", &[REPLICA_SCHEMA]),
+    ("synced", &[SYNCED_SCHEMA]),
+    ("received", &[RECEIVED_SCHEMA]),
+];
@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+
+use constellations::asset::block::text::Text;
+use cozo::{DataValue, ScriptMutability};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use super::{cipher::CipherError, relation_exists, ulid, Holobank};
+
+/// Characters of context shown on either side of the first match.
+const SNIPPET_CONTEXT: usize = 60;
+
+/// Most hits a search returns.
+pub const MAX_SEARCH_LIMIT: usize = 1000;
+
+/// A full-text query over text blocks.
+///
+/// The text supports `"exact phrases"`, `prefix*` matches and the `AND`,
+/// `OR` and `NOT` operators.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SearchQuery {
+    pub text: String,
+    /// Only match assets carrying every one of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Only match assets carrying every one of these flags.
+    #[serde(default)]
+    pub flags: Vec<String>,
+    /// Only match assets in this collection.
+    pub collection: Option<Ulid>,
+    /// Hits returned, 20 unless given.
+    pub limit: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchHit {
+    pub asset_id: Ulid,
+    pub score: f64,
+    /// Excerpt around the first match with matches wrapped in `<mark>`.
+    pub snippet: String,
+}
+
+impl Holobank {
+    /// Replaces the searchable text of an asset.
+    pub fn index_text(&self, id: Ulid, text: &str) -> Result<(), cozo::Error> {
+        let mut params = BTreeMap::new();
+        params.insert("asset_id".to_string(), ulid(id));
+        params.insert("body".to_string(), DataValue::from(text));
+        self.persistent.run_script(
+            "?[asset_id, body] <- [[$asset_id, $body]] :put text {asset_id => body}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Brings the searchable text and embedding of an asset in line with
+    /// content just stored.  Content that is not a text block is unindexed,
+    /// and so is everything on encrypted banks, which keep no plaintext.
+    pub(crate) fn reindex(&self, id: Ulid, content_type: &str, bytes: &[u8]) -> Result<(), cozo::Error> {
+        let searchable = content_type == Text::CONTENT_TYPE && self.cipher.is_none();
+        let text = if searchable { Text::decode(bytes) } else { None };
+        match text {
+            Some(text) => {
+                self.index_text(id, text.as_str())?;
+                self.embed(id, text.as_str())
+            }
+            None => self.unindex(id),
+        }
+    }
+
+    /// Removes the searchable text and embedding of an asset.
+    pub(crate) fn unindex(&self, id: Ulid) -> Result<(), cozo::Error> {
+        let params = BTreeMap::from([("asset_id".to_string(), ulid(id))]);
+        self.persistent.run_script(
+            "?[asset_id] <- [[$asset_id]] :rm text {asset_id}",
+            params.clone(),
+            ScriptMutability::Mutable
+        )?;
+        if relation_exists(&self.persistent, "embedding")? {
+            self.persistent.run_script(
+                "?[asset_id] <- [[$asset_id]] :rm embedding {asset_id}",
+                params,
+                ScriptMutability::Mutable
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the searchable text and embeddings of every text block from
+    /// its content, or drops them all on encrypted banks.
+    pub(crate) fn rebuild_index(&self) -> Result<(), CipherError> {
+        if self.cipher.is_some() {
+            self.persistent.run_default("?[asset_id] := *text{asset_id} :rm text {asset_id}")?;
+            if relation_exists(&self.persistent, "embedding")? {
+                self.persistent.run_default("?[asset_id] := *embedding{asset_id} :rm embedding {asset_id}")?;
+            }
+            return Ok(());
+        }
+        let rows = self.persistent.run_script(
+            "?[asset_id, payload] := *content{asset_id, content_type: $text, digest}, *blob{digest, payload}",
+            BTreeMap::from([("text".to_string(), DataValue::from(Text::CONTENT_TYPE))]),
+            ScriptMutability::Immutable
+        )?;
+        for row in rows.rows {
+            let (Some(id), Some(bytes)) = (row[0].get_ulid(), self.unseal(&row[1])?) else {
+                continue;
+            };
+            self.reindex(id, Text::CONTENT_TYPE, &bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Searches text blocks, best matches first.
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, cozo::Error> {
+        let limit = query.limit.unwrap_or(20).max(1);
+        let mut params = BTreeMap::new();
+        params.insert("query".to_string(), DataValue::from(query.text.as_str()));
+
+        let mut filters = String::new();
+        for (i, tag) in query.tags.iter().enumerate() {
+            params.insert(format!("tag{}", i), DataValue::from(tag.as_str()));
+            filters.push_str(&format!(", *tags{{asset_id, tag: $tag{}}}", i));
+        }
+        for (i, flag) in query.flags.iter().enumerate() {
+            params.insert(format!("flag{}", i), DataValue::from(flag.as_str()));
+            filters.push_str(&format!(", *flags{{asset_id, flag: $flag{}}}", i));
+        }
+        if let Some(collection) = query.collection {
+            params.insert("collection".to_string(), ulid(collection));
+            filters.push_str(", *collection{asset_id, collection_id: $collection}");
+        }
+
+        // Filters run after the index picks its candidates, so ask it for more.
+        let script = format!(
+            "?[asset_id, score, body] := ~text:fts{{asset_id, body | query: $query, k: {}, bind_score: score}}{}
+            :order -score
+            :limit {}",
+            limit.saturating_mul(10).max(100),
+            filters,
+            limit
+        );
+        let rows = self.persistent.run_script(&script, params, ScriptMutability::Immutable)?;
+
+        let terms = terms(&query.text);
+        Ok(rows.rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(SearchHit {
+                    asset_id: row[0].get_ulid()?,
+                    score: row[1].get_float()?,
+                    snippet: snippet(row[2].get_str()?, &terms),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Words of a query to highlight, lowercased.  Phrases are kept as word
+/// sequences and a trailing `*` marks a prefix.
+fn terms(query: &str) -> Vec<Vec<String>> {
+    let mut terms = Vec::new();
+    let mut negated = false;
+
+    for (i, part) in query.split('"').enumerate() {
+        if i % 2 == 1 {
+            // Inside quotes: one phrase.
+            let phrase = words(part).into_iter().map(|(_, w)| w).collect::<Vec<_>>();
+            if !phrase.is_empty() && !negated {
+                terms.push(phrase);
+            }
+            negated = false;
+            continue;
+        }
+        for token in part.split_whitespace() {
+            match token {
+                "AND" | "OR" | "NEAR" => continue,
+                "NOT" => {
+                    negated = true;
+                    continue;
+                }
+                _ => {}
+            }
+            let prefix = token.ends_with('*');
+            for (_, word) in words(token) {
+                if !negated {
+                    terms.push(vec![if prefix { format!("{}*", word) } else { word }]);
+                }
+            }
+            negated = false;
+        }
+    }
+    terms
+}
+
+/// Splits text into lowercased words with their byte ranges.
+fn words(text: &str) -> Vec<(std::ops::Range<usize>, String)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (start, c.is_alphanumeric()) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                words.push((s..i, text[s..i].to_lowercase()));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+fn matches(word: &str, term: &str) -> bool {
+    match term.strip_suffix('*') {
+        Some(prefix) => word.starts_with(prefix),
+        None => word == term,
+    }
+}
+
+/// Cuts an excerpt around the first matching term and marks every match
+/// inside it.  The text is HTML-escaped so only the marks are markup.
+fn snippet(body: &str, terms: &[Vec<String>]) -> String {
+    let words = words(body);
+
+    let mut marked: Vec<std::ops::Range<usize>> = Vec::new();
+    for i in 0..words.len() {
+        for term in terms {
+            let fits = i + term.len() <= words.len()
+                && term.iter().zip(&words[i..]).all(|(t, (_, w))| matches(w, t));
+            if fits {
+                marked.push(words[i].0.start..words[i + term.len() - 1].0.end);
+            }
+        }
+    }
+    marked.sort_by_key(|r| r.start);
+    marked.dedup_by(|next, prev| {
+        if next.start <= prev.end {
+            prev.end = prev.end.max(next.end);
+            true
+        } else {
+            false
+        }
+    });
+
+    let first = marked.first().map_or(0, |r| r.start);
+    let start = floor_char_boundary(body, first.saturating_sub(SNIPPET_CONTEXT));
+    let last = marked.first().map_or(0, |r| r.end);
+    let end = ceil_char_boundary(body, (last + SNIPPET_CONTEXT).min(body.len()));
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    let mut at = start;
+    for range in marked.iter().filter(|r| r.start >= start && r.end <= end) {
+        snippet.push_str(&escape(&body[at..range.start]));
+        snippet.push_str("<mark>");
+        snippet.push_str(&escape(&body[range.clone()]));
+        snippet.push_str("</mark>");
+        at = range.end;
+    }
+    snippet.push_str(&escape(&body[at..end]));
+    if end < body.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+fn ceil_char_boundary(s: &str, mut i: usize) -> usize {
+    while !s.is_char_boundary(i) {
+        i += 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constellations::asset::Asset;
+
+    use crate::holobank::each_backend;
+
+    fn query(text: &str) -> SearchQuery {
+        SearchQuery { text: text.to_string(), ..Default::default() }
+    }
+
+    fn hits(bank: &Holobank, text: &str) -> Vec<Ulid> {
+        bank.search(&query(text)).unwrap().into_iter().map(|hit| hit.asset_id).collect()
+    }
+
+    #[test]
+    fn parses_terms() {
+        let terms = terms("meeting \"Project Plan\" AND draft* NOT secret");
+        assert_eq!(terms, vec![
+            vec!["meeting".to_string()],
+            vec!["project".to_string(), "plan".to_string()],
+            vec!["draft*".to_string()],
+        ]);
+    }
+
+    #[test]
+    fn marks_words_prefixes_and_phrases() {
+        let terms = terms("\"project plan\" draft*");
+        let snippet = snippet("The Project Plan is in Drafting.", &terms);
+        assert_eq!(snippet, "The <mark>Project Plan</mark> is in <mark>Drafting</mark>.");
+    }
+
+    #[test]
+    fn does_not_mark_partial_words() {
+        let snippet = snippet("Planning the plan", &terms("plan"));
+        assert_eq!(snippet, "Planning the <mark>plan</mark>");
+    }
+
+    #[test]
+    fn escapes_stored_markup() {
+        let snippet = snippet("<script>alert('plan')</script> & the plan", &terms("plan"));
+        assert_eq!(snippet, "&lt;script&gt;alert(&#39;<mark>plan</mark>&#39;)&lt;/script&gt; &amp; the <mark>plan</mark>");
+    }
+
+    #[test]
+    fn trims_long_bodies() {
+        let body = format!("{} needle {}", "a ".repeat(100), "b ".repeat(100));
+        let snippet = snippet(&body, &terms("needle"));
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(snippet.contains("<mark>needle</mark>"));
+        assert!(snippet.len() < body.len());
+    }
+
+    #[test]
+    fn ranks_closer_matches_first() {
+        each_backend(|bank| {
+            let focused = Text::new("apple apple apple", 1);
+            let passing = Text::new("a long note about pears, plums, cherries and one apple", 1);
+            let unrelated = Text::new("quarterly revenue forecast", 1);
+            for text in [&passing, &focused, &unrelated] {
+                bank.dematerialize(text, true).unwrap();
+            }
+
+            let results = bank.search(&query("apple")).unwrap();
+            assert_eq!(results.iter().map(|hit| hit.asset_id).collect::<Vec<_>>(), vec![focused.id(), passing.id()]);
+            assert!(results[0].score >= results[1].score);
+            assert_eq!(results[0].snippet, "<mark>apple</mark> <mark>apple</mark> <mark>apple</mark>");
+        });
+    }
+
+    #[test]
+    fn follows_edits() {
+        each_backend(|bank| {
+            let mut text = Text::new("draft of the launch plan", 1);
+            bank.dematerialize(&text, true).unwrap();
+            assert_eq!(hits(&bank, "launch"), vec![text.id()]);
+
+            text.replace("final landing checklist");
+            bank.dematerialize(&text, true).unwrap();
+            assert!(hits(&bank, "launch").is_empty());
+            assert_eq!(hits(&bank, "landing"), vec![text.id()]);
+        });
+    }
+
+    #[test]
+    fn forgets_removed_content() {
+        each_backend(|bank| {
+            let text = Text::new("notes on the docking procedure", 1);
+            bank.dematerialize(&text, true).unwrap();
+            assert_eq!(hits(&bank, "docking"), vec![text.id()]);
+
+            bank.remove_content(text.id()).unwrap();
+            assert!(hits(&bank, "docking").is_empty());
+        });
+    }
+}
@@ -0,0 +1,304 @@
+// A collection's sharing policy decides who may ask the bank for it.  Remote
+// requests, i.e. replication queries, name the spaceport they come from and
+// the commander asking, who signs the request with their key.  The bank
+// answers only requests signed recently with a key it knows for the
+// commander, see `trust_key`, and only for collections shared with that
+// spaceport, directly or through a system the starmap places it in, when the
+// policy lets the commander read.  Spaceports have no keys: the spaceport a
+// request names is the word of the commander who signed it.  A signed
+// request can be replayed until it expires, which only repeats its answer.
+//
+// Roles to write are checked on changes coming back: a bank replicating a
+// collection it shares itself from one of its replicas only takes content
+// whose edit a commander with write access signed.  Tags, flags and
+// memberships are not signed, so it keeps its own.
+//
+// Shared collections that are pinned are pinned here and by every replica.
+// The inbox is never shared.
+
+use std::collections::{BTreeMap, HashSet};
+
+use constellations::{
+    collection::{Role, SharingPolicy},
+    user::keys::{Keypair, PublicKey},
+};
+use cozo::{DataValue, ScriptMutability};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use super::{identity::SignatureError, now, ulid, Holobank};
+
+/// Microseconds a signed request is taken for, either side of the bank's
+/// clock.
+pub const REQUEST_LIFETIME: i64 = 5 * 60 * 1_000_000;
+
+/// Prefixes every signed request, so a credential can never pass for a
+/// signed operation or the other way round.
+const CREDENTIAL_CONTEXT: &[u8] = b"constellations request credential v1\0";
+
+/// Who a remote request comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Requester {
+    pub spaceport: Ulid,
+    pub commander: Option<Ulid>,
+}
+
+/// A commander's signature of a remote request, sent along with it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credential {
+    pub key: PublicKey,
+    pub time: i64,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl Credential {
+    /// Signs `request`, the request's key expression and parameters.
+    pub fn sign(request: &str, keypair: &Keypair) -> Credential {
+        let time = now();
+        Credential { key: keypair.public(), time, signature: keypair.sign(&message(request, time)) }
+    }
+}
+
+fn message(request: &str, time: i64) -> Vec<u8> {
+    let mut message = CREDENTIAL_CONTEXT.to_vec();
+    message.extend_from_slice(format!("{}\n{}", request, time).as_bytes());
+    message
+}
+
+impl Holobank {
+    pub fn sharing_policy(&self, collection: Ulid) -> Result<SharingPolicy, cozo::Error> {
+        let params = BTreeMap::from([("collection_id".to_string(), ulid(collection))]);
+        let replicas = self.persistent.run_script(
+            "?[replica_id] := *sharing{collection_id: $collection_id, replica_id}",
+            params.clone(),
+            ScriptMutability::Immutable
+        )?;
+        let roles = self.persistent.run_script(
+            "?[commander_id, role] := *role{collection_id: $collection_id, commander_id, role}",
+            params.clone(),
+            ScriptMutability::Immutable
+        )?;
+        let pinned = self.persistent.run_script(
+            "?[collection_id] := *pinned{collection_id}, collection_id = $collection_id",
+            params,
+            ScriptMutability::Immutable
+        )?;
+        Ok(SharingPolicy {
+            replicas: replicas.rows.iter().filter_map(|row| row[0].get_ulid()).collect(),
+            roles: roles.rows
+                .iter()
+                .filter_map(|row| Some((row[0].get_ulid()?, row[1].get_str()?.parse().ok()?)))
+                .collect(),
+            pinned: !pinned.rows.is_empty(),
+        })
+    }
+
+    /// Replaces the policy of a collection.  Replicas already listed keep
+    /// the time they were shared with.
+    pub fn set_sharing_policy(&self, collection: Ulid, policy: &SharingPolicy) -> Result<(), cozo::Error> {
+        if collection == self.inbox && !policy.is_private() {
+            return Err(cozo::Error::msg("the inbox is never shared"));
+        }
+        let replicas = policy.replicas.iter().map(|id| ulid(*id)).collect();
+        let roles = policy.roles
+            .iter()
+            .map(|(commander, role)| DataValue::List(vec![ulid(*commander), DataValue::from(role.as_str())]))
+            .collect();
+        let mut params = BTreeMap::new();
+        params.insert("collection_id".to_string(), ulid(collection));
+        params.insert("replicas".to_string(), DataValue::List(replicas));
+        params.insert("roles".to_string(), DataValue::List(roles));
+        params.insert("time".to_string(), DataValue::from(now()));
+        self.persistent.run_script(
+            "{
+                kept[replica_id] := replica_id in $replicas
+                ?[collection_id, replica_id] := *sharing{collection_id, replica_id},
+                    collection_id = $collection_id, not kept[replica_id]
+                :rm sharing {collection_id, replica_id}
+            }
+            {
+                ?[collection_id, replica_id, time_shared] := replica_id in $replicas, collection_id = $collection_id,
+                    not *sharing{collection_id: $collection_id, replica_id}, time_shared = $time
+                :put sharing {collection_id, replica_id => time_shared}
+            }
+            {
+                ?[collection_id, commander_id] := *role{collection_id, commander_id}, collection_id = $collection_id
+                :rm role {collection_id, commander_id}
+            }
+            {
+                granted[commander_id, role] <- $roles
+                ?[collection_id, commander_id, role, time_granted] := granted[commander_id, role],
+                    collection_id = $collection_id, time_granted = $time
+                :put role {collection_id, commander_id => role, time_granted}
+            }",
+            params,
+            ScriptMutability::Mutable
+        )?;
+
+        if policy.pinned {
+            self.pin(collection)
+        } else {
+            self.unpin(collection)
+        }
+    }
+
+    /// Checks that the commander a request names signed it, recently and
+    /// with a key of theirs.
+    pub fn authenticate(&self, requester: &Requester, request: &str, credential: Option<&Credential>) -> Result<(), SignatureError> {
+        let (Some(commander), Some(credential)) = (requester.commander, credential) else {
+            return Err(SignatureError::Unsigned);
+        };
+        if (now() - credential.time).abs() > REQUEST_LIFETIME {
+            return Err(SignatureError::Expired);
+        }
+        if !credential.key.verify(&message(request, credential.time), &credential.signature) {
+            return Err(SignatureError::Invalid);
+        }
+        self.check_key(commander, credential.key, credential.time)
+    }
+
+    /// Whether a remote request may act on a collection in `role`.
+    pub fn authorize(&self, collection: Ulid, requester: &Requester, role: Role) -> Result<bool, cozo::Error> {
+        if collection == self.inbox {
+            return Ok(false);
+        }
+        let policy = self.sharing_policy(collection)?;
+        if !policy.allows(requester.commander, role) {
+            return Ok(false);
+        }
+        let replicas = self.replicas_of(requester.spaceport)?;
+        Ok(policy.replicas.iter().any(|replica| replicas.contains(replica)))
+    }
+
+    /// Collections a remote request may read.
+    pub fn readable_collections(&self, requester: &Requester) -> Result<HashSet<Ulid>, cozo::Error> {
+        let replicas = self.replicas_of(requester.spaceport)?;
+        let shared = self.persistent.run_script(
+            "?[collection_id] := *sharing{collection_id, replica_id}, replica_id in $replicas",
+            BTreeMap::from([(
+                "replicas".to_string(),
+                DataValue::List(replicas.into_iter().map(ulid).collect()),
+            )]),
+            ScriptMutability::Immutable
+        )?;
+
+        let mut readable = HashSet::new();
+        for collection in shared.rows.iter().filter_map(|row| row[0].get_ulid()) {
+            if collection != self.inbox && self.sharing_policy(collection)?.allows(requester.commander, Role::Read) {
+                readable.insert(collection);
+            }
+        }
+        Ok(readable)
+    }
+
+    /// Those of `collections` the bank shares itself, whose changes only
+    /// commanders allowed to write may make.
+    pub(crate) fn shared(&self, collections: &HashSet<Ulid>) -> Result<HashSet<Ulid>, cozo::Error> {
+        let mut shared = HashSet::new();
+        for collection in collections {
+            if !self.sharing_policy(*collection)?.is_private() {
+                shared.insert(*collection);
+            }
+        }
+        Ok(shared)
+    }
+
+    /// The spaceport and the systems the starmap places it in.
+    fn replicas_of(&self, spaceport: Ulid) -> Result<HashSet<Ulid>, cozo::Error> {
+        let systems = self.persistent.run_script(
+            "?[system_id] := *starmap{system_id, spaceport_id: $spaceport_id}",
+            BTreeMap::from([("spaceport_id".to_string(), ulid(spaceport))]),
+            ScriptMutability::Immutable
+        )?;
+        let mut replicas: HashSet<Ulid> = systems.rows.iter().filter_map(|row| row[0].get_ulid()).collect();
+        replicas.insert(spaceport);
+        Ok(replicas)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holobank::each_backend;
+
+    #[test]
+    fn authorizes_replicas_and_commanders() {
+        each_backend(|bank| {
+            let (collection, spaceport, system, stranger) = (Ulid::new(), Ulid::new(), Ulid::new(), Ulid::new());
+            let (reader, writer) = (Ulid::new(), Ulid::new());
+            let requester = |spaceport, commander| Requester { spaceport, commander };
+            assert!(!bank.authorize(collection, &requester(spaceport, None), Role::Read).unwrap());
+
+            let mut policy = SharingPolicy::default();
+            policy.replicas.insert(system);
+            policy.roles.insert(reader, Role::Read);
+            policy.roles.insert(writer, Role::Write);
+            policy.pinned = true;
+            bank.set_sharing_policy(collection, &policy).unwrap();
+            assert_eq!(bank.sharing_policy(collection).unwrap(), policy);
+
+            // Shared with a system, the spaceport needs to be in it.
+            assert!(!bank.authorize(collection, &requester(spaceport, Some(reader)), Role::Read).unwrap());
+            bank.persistent.run_script(
+                "?[system_id, spaceport_id] <- [[$system, $spaceport]] :put starmap {system_id, spaceport_id}",
+                BTreeMap::from([
+                    ("system".to_string(), ulid(system)),
+                    ("spaceport".to_string(), ulid(spaceport)),
+                ]),
+                ScriptMutability::Mutable
+            ).unwrap();
+            assert!(bank.authorize(collection, &requester(spaceport, Some(reader)), Role::Read).unwrap());
+            assert!(!bank.authorize(collection, &requester(spaceport, Some(reader)), Role::Write).unwrap());
+            assert!(bank.authorize(collection, &requester(spaceport, Some(writer)), Role::Write).unwrap());
+            assert!(!bank.authorize(collection, &requester(spaceport, None), Role::Read).unwrap());
+            assert!(!bank.authorize(collection, &requester(stranger, Some(writer)), Role::Read).unwrap());
+            assert_eq!(
+                bank.readable_collections(&requester(spaceport, Some(reader))).unwrap(),
+                HashSet::from([collection])
+            );
+
+            policy.roles.clear();
+            policy.pinned = false;
+            bank.set_sharing_policy(collection, &policy).unwrap();
+            assert_eq!(bank.sharing_policy(collection).unwrap(), policy);
+            assert!(bank.authorize(collection, &requester(spaceport, None), Role::Read).unwrap());
+            assert!(!bank.authorize(collection, &requester(spaceport, None), Role::Write).unwrap());
+        });
+    }
+
+    #[test]
+    fn authenticates_signed_requests() {
+        each_backend(|bank| {
+            let commander = Ulid::new();
+            let keypair = Keypair::generate();
+            let requester = Requester { spaceport: Ulid::new(), commander: Some(commander) };
+            let request = format!("constellations/holobank/{}/replication/log?after=0;from={}", Ulid::new(), requester.spaceport);
+            let credential = Credential::sign(&request, &keypair);
+
+            assert!(matches!(bank.authenticate(&requester, &request, None), Err(SignatureError::Unsigned)));
+            let anonymous = Requester { commander: None, ..requester };
+            assert!(matches!(bank.authenticate(&anonymous, &request, Some(&credential)), Err(SignatureError::Unsigned)));
+            // Only keys the bank was given are taken.
+            assert!(matches!(bank.authenticate(&requester, &request, Some(&credential)), Err(SignatureError::UnknownKey { .. })));
+            assert!(bank.commander_keys(commander).unwrap().is_empty());
+
+            bank.trust_key(commander, keypair.public()).unwrap();
+            bank.authenticate(&requester, &request, Some(&credential)).unwrap();
+            let other = format!("{};commander={}", request, Ulid::new());
+            assert!(matches!(bank.authenticate(&requester, &other, Some(&credential)), Err(SignatureError::Invalid)));
+            let impostor = Credential::sign(&request, &Keypair::generate());
+            assert!(matches!(bank.authenticate(&requester, &request, Some(&impostor)), Err(SignatureError::UnknownKey { .. })));
+
+            let time = now() - 2 * REQUEST_LIFETIME;
+            let stale = Credential { key: keypair.public(), time, signature: keypair.sign(&message(&request, time)) };
+            assert!(matches!(bank.authenticate(&requester, &request, Some(&stale)), Err(SignatureError::Expired)));
+
+            // Signatures made outside the credential context are refused.
+            let time = now();
+            let untagged = format!("{}\n{}", request, time).into_bytes();
+            let untagged = Credential { key: keypair.public(), time, signature: keypair.sign(&untagged) };
+            assert!(matches!(bank.authenticate(&requester, &request, Some(&untagged)), Err(SignatureError::Invalid)));
+        });
+    }
+}
@@ -0,0 +1,279 @@
+// Ownership, snapshots and the ledger are kept as time travel relations:
+// every fact is stored with the time it became true (an assertion) or
+// stopped being true (a retraction), and nothing is overwritten.  Asking
+// about time T looks at the latest fact at or before T for each key; if that
+// fact is a retraction, the key has no value at T.  Ownership changes and
+// ledger entries are signed by the bank's commander; see the identity module.
+
+use std::collections::BTreeMap;
+
+use constellations::user::signed::Operation;
+use cozo::{DataValue, ScriptMutability};
+use serde::Serialize;
+use ulid::Ulid;
+
+use super::{ulid, Holobank};
+
+/// Where, in what and by whom an asset was materialized.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Holder {
+    pub spaceport: Ulid,
+    pub spacecraft: Option<Ulid>,
+    pub commander: Option<Ulid>,
+}
+
+impl Holobank {
+    /// Records that `owner` owns `entity` from time `at` on.  An entity can
+    /// have several owners at once.
+    pub fn assert_owner(&self, entity: Ulid, owner: Ulid, at: i64) -> Result<(), cozo::Error> {
+        self.put_owner(entity, owner, at, true)
+    }
+
+    /// Records that `owner` no longer owns `entity` from time `at` on.
+    pub fn retract_owner(&self, entity: Ulid, owner: Ulid, at: i64) -> Result<(), cozo::Error> {
+        self.put_owner(entity, owner, at, false)
+    }
+
+    /// Makes `owner` the only owner of `entity` from time `at` on.
+    pub fn transfer(&self, entity: Ulid, owner: Ulid, at: i64) -> Result<(), cozo::Error> {
+        for previous in self.owners_at(entity, at)? {
+            if previous != owner {
+                self.retract_owner(entity, previous, at)?;
+            }
+        }
+        self.assert_owner(entity, owner, at)
+    }
+
+    /// Who owned `entity` at time `at`.
+    pub fn owners_at(&self, entity: Ulid, at: i64) -> Result<Vec<Ulid>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            &format!("?[owner_id] := *owner{{entity_id: $entity, owner_id @ {}}}", at),
+            BTreeMap::from([("entity".to_string(), ulid(entity))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows.iter().filter_map(|row| row[0].get_ulid()).collect())
+    }
+
+    /// Freezes a copy of an asset's content as of time `at`.
+    pub fn record_snapshot(&self, asset: Ulid, content: Vec<u8>, at: i64) -> Result<(), cozo::Error> {
+        self.put_snapshot(asset, self.seal(content), at, true)
+    }
+
+    /// Withdraws the snapshot of an asset from time `at` on, e.g. when the
+    /// asset is destroyed.
+    pub fn retract_snapshot(&self, asset: Ulid, at: i64) -> Result<(), cozo::Error> {
+        self.put_snapshot(asset, DataValue::Null, at, false)
+    }
+
+    /// The latest snapshot of an asset taken at or before time `at`.
+    pub fn snapshot_at(&self, asset: Ulid, at: i64) -> Result<Option<Vec<u8>>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            &format!("?[latest] := *snapshot{{asset_id: $asset, latest @ {}}}", at),
+            BTreeMap::from([("asset".to_string(), ulid(asset))]),
+            ScriptMutability::Immutable
+        )?;
+        match rows.rows.first() {
+            Some(row) => Ok(self.unseal(&row[0])?),
+            None => Ok(None),
+        }
+    }
+
+    /// Records that `holder` materialized `asset` at time `at`.  The
+    /// previous holder, if any, is replaced.
+    pub fn record_holder(&self, asset: Ulid, holder: &Holder, at: i64) -> Result<(), cozo::Error> {
+        self.put_ledger(asset, holder, at, true)
+    }
+
+    /// Records that nobody holds `asset` from time `at` on.
+    pub fn release_holder(&self, asset: Ulid, at: i64) -> Result<(), cozo::Error> {
+        let nobody = Holder { spaceport: Ulid::nil(), spacecraft: None, commander: None };
+        self.put_ledger(asset, &nobody, at, false)
+    }
+
+    /// Who held `asset` at time `at`.
+    pub fn holder_at(&self, asset: Ulid, at: i64) -> Result<Option<Holder>, cozo::Error> {
+        let rows = self.persistent.run_script(
+            &format!(
+                "?[materialized_at, materialized_in, materialized_by] :=
+                    *ledger{{asset: $asset, materialized_at, materialized_in, materialized_by @ {}}}",
+                at
+            ),
+            BTreeMap::from([("asset".to_string(), ulid(asset))]),
+            ScriptMutability::Immutable
+        )?;
+        Ok(rows.rows.first().and_then(|row| {
+            Some(Holder {
+                spaceport: row[0].get_ulid()?,
+                spacecraft: row[1].get_ulid(),
+                commander: row[2].get_ulid(),
+            })
+        }))
+    }
+
+    /// Records an ownership change, signed by the bank's commander.
+    fn put_owner(&self, entity: Ulid, owner: Ulid, at: i64, assert: bool) -> Result<(), cozo::Error> {
+        self.write_owner(entity, owner, at, assert)?;
+        self.sign(Operation::Own { entity, owner, at, owned: assert })
+    }
+
+    pub(super) fn write_owner(&self, entity: Ulid, owner: Ulid, at: i64, assert: bool) -> Result<(), cozo::Error> {
+        let mut params = validity_params(at, assert);
+        params.insert("entity".to_string(), ulid(entity));
+        params.insert("owner".to_string(), ulid(owner));
+        self.persistent.run_script(
+            "?[entity_id, owner_id, time] := entity_id = $entity, owner_id = $owner, time = [$at, $assert]
+            :put owner {entity_id, owner_id, time}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    fn put_snapshot(&self, asset: Ulid, latest: DataValue, at: i64, assert: bool) -> Result<(), cozo::Error> {
+        let mut params = validity_params(at, assert);
+        params.insert("asset".to_string(), ulid(asset));
+        params.insert("latest".to_string(), latest);
+        self.persistent.run_script(
+            "?[asset_id, time, latest] := asset_id = $asset, time = [$at, $assert], latest = $latest
+            :put snapshot {asset_id, time => latest}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+
+    /// Records a ledger entry, signed by the bank's commander.
+    fn put_ledger(&self, asset: Ulid, holder: &Holder, at: i64, assert: bool) -> Result<(), cozo::Error> {
+        self.write_ledger(asset, holder, at, assert)?;
+        self.sign(Operation::Hold {
+            asset,
+            spaceport: holder.spaceport,
+            spacecraft: holder.spacecraft,
+            commander: holder.commander,
+            at,
+            held: assert,
+        })
+    }
+
+    pub(super) fn write_ledger(&self, asset: Ulid, holder: &Holder, at: i64, assert: bool) -> Result<(), cozo::Error> {
+        let mut params = validity_params(at, assert);
+        params.insert("asset".to_string(), ulid(asset));
+        params.insert("at_port".to_string(), ulid(holder.spaceport));
+        params.insert("in_craft".to_string(), holder.spacecraft.map_or(DataValue::Null, ulid));
+        params.insert("by".to_string(), holder.commander.map_or(DataValue::Null, ulid));
+        self.persistent.run_script(
+            "?[asset, time, materialized_at, materialized_in, materialized_by] :=
+                asset = $asset, time = [$at, $assert],
+                materialized_at = $at_port, materialized_in = $in_craft, materialized_by = $by
+            :put ledger {asset, time => materialized_at, materialized_in, materialized_by}",
+            params,
+            ScriptMutability::Mutable
+        )?;
+        Ok(())
+    }
+}
+
+fn validity_params(at: i64, assert: bool) -> BTreeMap<String, DataValue> {
+    BTreeMap::from([
+        ("at".to_string(), DataValue::from(at)),
+        ("assert".to_string(), DataValue::from(assert)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holobank::each_backend;
+
+    fn sorted(mut ids: Vec<Ulid>) -> Vec<Ulid> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn overlapping_owners() {
+        each_backend(|bank| {
+            let (entity, alice, bob) = (Ulid::new(), Ulid::new(), Ulid::new());
+
+            bank.assert_owner(entity, alice, 10).unwrap();
+            bank.assert_owner(entity, bob, 20).unwrap();
+            bank.retract_owner(entity, alice, 30).unwrap();
+            bank.retract_owner(entity, bob, 40).unwrap();
+            bank.assert_owner(entity, alice, 50).unwrap();
+
+            assert!(bank.owners_at(entity, 5).unwrap().is_empty());
+            assert_eq!(bank.owners_at(entity, 15).unwrap(), vec![alice]);
+            assert_eq!(sorted(bank.owners_at(entity, 25).unwrap()), sorted(vec![alice, bob]));
+            assert_eq!(bank.owners_at(entity, 30).unwrap(), vec![bob]);
+            assert!(bank.owners_at(entity, 45).unwrap().is_empty());
+            assert_eq!(bank.owners_at(entity, 55).unwrap(), vec![alice]);
+        });
+    }
+
+    #[test]
+    fn backdated_ownership() {
+        each_backend(|bank| {
+            let (entity, alice, carol) = (Ulid::new(), Ulid::new(), Ulid::new());
+
+            bank.assert_owner(entity, alice, 10).unwrap();
+            bank.retract_owner(entity, alice, 40).unwrap();
+            // Learned later: carol co-owned the entity between 12 and 35.
+            bank.assert_owner(entity, carol, 12).unwrap();
+            bank.retract_owner(entity, carol, 35).unwrap();
+
+            assert_eq!(bank.owners_at(entity, 11).unwrap(), vec![alice]);
+            assert_eq!(sorted(bank.owners_at(entity, 20).unwrap()), sorted(vec![alice, carol]));
+            assert_eq!(bank.owners_at(entity, 36).unwrap(), vec![alice]);
+        });
+    }
+
+    #[test]
+    fn transfer_replaces_owners() {
+        each_backend(|bank| {
+            let (entity, alice, bob, carol) = (Ulid::new(), Ulid::new(), Ulid::new(), Ulid::new());
+
+            bank.assert_owner(entity, alice, 10).unwrap();
+            bank.assert_owner(entity, bob, 10).unwrap();
+            bank.transfer(entity, carol, 20).unwrap();
+
+            assert_eq!(sorted(bank.owners_at(entity, 15).unwrap()), sorted(vec![alice, bob]));
+            assert_eq!(bank.owners_at(entity, 20).unwrap(), vec![carol]);
+        });
+    }
+
+    #[test]
+    fn snapshots_as_of() {
+        each_backend(|bank| {
+            let asset = Ulid::new();
+
+            bank.record_snapshot(asset, b"v1".to_vec(), 10).unwrap();
+            bank.record_snapshot(asset, b"v2".to_vec(), 20).unwrap();
+            bank.retract_snapshot(asset, 30).unwrap();
+            bank.record_snapshot(asset, b"v3".to_vec(), 40).unwrap();
+
+            assert_eq!(bank.snapshot_at(asset, 5).unwrap(), None);
+            assert_eq!(bank.snapshot_at(asset, 15).unwrap(), Some(b"v1".to_vec()));
+            assert_eq!(bank.snapshot_at(asset, 20).unwrap(), Some(b"v2".to_vec()));
+            assert_eq!(bank.snapshot_at(asset, 35).unwrap(), None);
+            assert_eq!(bank.snapshot_at(asset, 45).unwrap(), Some(b"v3".to_vec()));
+        });
+    }
+
+    #[test]
+    fn holders_as_of() {
+        each_backend(|bank| {
+            let asset = Ulid::new();
+            let first = Holder { spaceport: Ulid::new(), spacecraft: None, commander: Some(Ulid::new()) };
+            let second = Holder { spaceport: Ulid::new(), spacecraft: Some(Ulid::new()), commander: Some(Ulid::new()) };
+
+            bank.record_holder(asset, &first, 10).unwrap();
+            bank.release_holder(asset, 20).unwrap();
+            bank.record_holder(asset, &second, 25).unwrap();
+
+            assert_eq!(bank.holder_at(asset, 9).unwrap(), None);
+            assert_eq!(bank.holder_at(asset, 10).unwrap(), Some(first));
+            assert_eq!(bank.holder_at(asset, 22).unwrap(), None);
+            assert_eq!(bank.holder_at(asset, 30).unwrap(), Some(second));
+        });
+    }
+}
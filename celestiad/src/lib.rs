@@ -3,6 +3,7 @@ pub mod settings;
 pub mod api;
 pub mod spaceport;
 pub mod holobank;
+pub mod storage;
 
 use std::net::{IpAddr, Ipv4Addr};
 
@@ -1,10 +1,10 @@
-use std::{collections::BTreeMap, net::SocketAddr};
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
 
 use clap::{Arg, Command};
 use cozo::{DataValue, DbInstance, ScriptMutability, UlidWrapper};
 use dotenv::dotenv;
-use celestiad::{commands, settings, CELESTIAD_DATA_DIR, CELESTIAD_ENV_PREFIX, CELESTIAD_PORT, HOST_SCHEMA, LOCALHOST, SPACEPORT_SCHEMA, TCP_ENDPOINT, UDP_ENDPOINT};
-use tracing::debug;
+use celestiad::{commands, holobank::{changes, dynamic, embedding::HashingEmbedder, inbox, replication::{self, Replicator}, Holobank}, settings, CELESTIAD_ENV_PREFIX, CELESTIAD_PORT, HOST_SCHEMA, LOCALHOST, SPACEPORT_SCHEMA, TCP_ENDPOINT, UDP_ENDPOINT};
+use tracing::{debug, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 use ulid::Ulid;
 use zenoh::config::{WhatAmI, ZenohId};
@@ -44,9 +44,11 @@ pub async fn main() -> anyhow::Result<()> {
         .with(fmt_layer)
         .init();
 
-    commands::handle(&matches, &settings)?;
+    if !commands::handle(&matches, &settings)? {
+        return Ok(());
+    }
 
-    let db = cozo::DbInstance::new("sqlite", format!("{}/celestiad.sqlite", CELESTIAD_DATA_DIR), "").unwrap();
+    let db = settings.host_backend().open(&settings.host_directory(), "celestiad").unwrap();
     let mut parameters = BTreeMap::new();
     parameters.insert("self".to_string(), DataValue::Bool(true));
 
@@ -73,12 +75,37 @@ pub async fn main() -> anyhow::Result<()> {
         }
     };
 
-    run(id, db, CELESTIAD_PORT).await;
+    let mut holobank = Holobank::load(settings.holobank_backend(), &settings.holobank_directory())
+        .unwrap()
+        .with_quota(settings.holobank.quota_bytes)
+        .with_inbox_retention(settings.inbox_retention());
+    if let Some(key) = settings.holobank_key() {
+        holobank = holobank.with_encryption(&key)?;
+    }
+    if let Some(commander) = settings.holobank.commander {
+        let keypair = settings.key_store()
+            .load(commander)?
+            .ok_or_else(|| anyhow::anyhow!("No key for commander {}; create one with `celestiad holobank key generate`", commander))?;
+        holobank = holobank.with_commander(commander, keypair)?;
+    }
+    for trusted in settings.replication.trusted_keys.iter().flatten() {
+        holobank.trust_key(trusted.commander, trusted.key)?;
+    }
+    match settings.holobank.embedder.as_deref() {
+        Some("hashing") => {
+            let dimensions = settings.holobank.embedding_dimensions.unwrap_or(256);
+            holobank = holobank.with_embedder(Arc::new(HashingEmbedder::new(dimensions))).unwrap();
+        }
+        Some(other) => anyhow::bail!("Unknown embedder: {}", other),
+        None => {}
+    }
+
+    run(id, db, holobank, &settings, CELESTIAD_PORT).await;
 
     Ok(())
 }
 
-async fn run(id: Ulid, db: DbInstance, port: u16) {
+async fn run(id: Ulid, db: DbInstance, holobank: Holobank, settings: &settings::Settings, port: u16) {
     let mut config = zenoh::Config::default();
     config.set_id(ZenohId::try_from(id.to_bytes().as_slice()).unwrap()).unwrap();
     config.set_mode(Some(WhatAmI::Peer));
@@ -88,13 +115,39 @@ async fn run(id: Ulid, db: DbInstance, port: u16) {
     ).unwrap();
 
     let zenoh_session = zenoh::open(config).await.unwrap();
+    tokio::spawn(changes::publish(holobank.clone(), zenoh_session.clone(), id));
+    tokio::spawn(replication::serve(holobank.clone(), zenoh_session.clone(), id));
+    tokio::spawn(inbox::retain(holobank.clone()));
+    tokio::spawn(dynamic::follow(holobank.clone()));
+    let poll = Duration::from_secs(settings.replication.poll_secs.unwrap_or(replication::DEFAULT_POLL_SECS));
+    for source in settings.replication.sources.iter().flatten() {
+        let mut replicator = Replicator::new(holobank.clone(), zenoh_session.clone(), id, source.bank, source.collections.clone())
+            .with_poll(poll);
+        if let Some(commander) = source.commander {
+            match settings.key_store().load(commander) {
+                Ok(Some(keypair)) => replicator = replicator.with_commander(commander, keypair),
+                Ok(None) => {
+                    warn!("No key for commander {}, not replicating from {}", commander, source.bank);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Could not read the key of commander {}, not replicating from {}: {}", commander, source.bank, e);
+                    continue;
+                }
+            }
+        }
+        if settings.replication.require_signatures.unwrap_or(false) {
+            replicator = replicator.with_signatures_required();
+        }
+        tokio::spawn(replicator.run());
+    }
     
     let addr = SocketAddr::new(
         LOCALHOST,
         port
     );
 
-    let router = celestiad::api::configure(db, zenoh_session);
+    let router = celestiad::api::configure(db, zenoh_session, holobank, &settings.api, settings.backup_directory());
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
 
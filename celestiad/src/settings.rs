@@ -1,7 +1,15 @@
+use std::path::PathBuf;
+
 use anyhow::Ok;
 use config::{Config, Environment, File};
 use serde::Deserialize;
 
+use ulid::Ulid;
+
+use constellations::user::keys::{KeyStore, PublicKey, KEY_DIRECTORY};
+
+use crate::{holobank::{cipher::KeySource, inbox::Retention}, storage::Backend, CELESTIAD_DATA_DIR, CELESTIAD_USER_DIR};
+
 #[derive(Debug, Deserialize, Default)]
 #[allow(unused)]
 pub struct Logging {
@@ -15,6 +23,84 @@ pub struct Root {
     pub directory: Option<String>,
 }
 
+#[derive(Debug, Deserialize, Default)]
+#[allow(unused)]
+pub struct Host {
+    /// Storage backend of the host database, `sqlite` by default.
+    pub backend: Option<Backend>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[allow(unused)]
+pub struct Holobank {
+    pub directory: Option<String>,
+    /// Storage backend: `rocksdb` (default), `sqlite` or `mem`.
+    pub backend: Option<Backend>,
+    /// Embedding provider for similarity search, e.g. `hashing`.
+    pub embedder: Option<String>,
+    pub embedding_dimensions: Option<usize>,
+    /// Bytes of content kept before hologram content is evicted.
+    pub quota_bytes: Option<u64>,
+    /// Encrypts payloads at rest with a key derived from this passphrase.
+    /// Text is then neither indexed for search nor embedded.
+    pub passphrase: Option<String>,
+    /// Encrypts payloads at rest with the key in this file.  Takes
+    /// precedence over the passphrase.
+    pub key_file: Option<String>,
+    /// Days assets may stay in the inbox before they expire.
+    pub inbox_retention_days: Option<u64>,
+    /// Drops expired assets from the inbox and their content instead of
+    /// only flagging them.
+    pub inbox_discard_expired: Option<bool>,
+    /// Commander whose key signs edits, ledger entries and ownership changes.
+    pub commander: Option<Ulid>,
+    /// Directory of commanders' keys, by default `~/.constellations/keys`.
+    pub key_directory: Option<String>,
+    /// Directory backups are written to, by default under the daemon data
+    /// directory.
+    pub backup_directory: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[allow(unused)]
+pub struct Replication {
+    /// Banks to replicate collections from.
+    pub sources: Option<Vec<ReplicationSource>>,
+    /// Seconds between syncs when a source publishes nothing.
+    pub poll_secs: Option<u64>,
+    /// Refuses content from sources unless its edit is signed.
+    pub require_signatures: Option<bool>,
+    /// First keys of commanders: who may ask for shared collections, and
+    /// whose operations signed at other banks verify.
+    pub trusted_keys: Option<Vec<TrustedKey>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct TrustedKey {
+    pub commander: Ulid,
+    pub key: PublicKey,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct ReplicationSource {
+    pub bank: Ulid,
+    pub collections: Vec<Ulid>,
+    /// Commander to ask the source on behalf of, by default the commander
+    /// of the bank.  Their key is read from the key directory.
+    pub commander: Option<Ulid>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[allow(unused)]
+pub struct Api {
+    /// Bearer token required by authenticated routes.
+    pub token: Option<String>,
+    pub query_timeout_secs: Option<u64>,
+    pub query_max_rows: Option<usize>,
+}
+
 #[derive(Debug, Deserialize, Default)]
 #[allow(unused)]
 pub struct ConfigInfo {
@@ -30,6 +116,14 @@ pub struct Settings {
     #[serde(default)]
     pub root: Root,
     #[serde(default)]
+    pub host: Host,
+    #[serde(default)]
+    pub holobank: Holobank,
+    #[serde(default)]
+    pub replication: Replication,
+    #[serde(default)]
+    pub api: Api,
+    #[serde(default)]
     pub config: ConfigInfo,
 }
 
@@ -54,4 +148,67 @@ impl Settings {
 
         Ok(settings)
     }
-}
\ No newline at end of file
+
+    /// Location of the holobank, by default under the daemon data directory.
+    pub fn holobank_directory(&self) -> PathBuf {
+        self.holobank.directory
+            .clone()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(CELESTIAD_DATA_DIR).join("holobank"))
+    }
+
+    /// Where backups of the holobank are written.
+    pub fn backup_directory(&self) -> PathBuf {
+        self.holobank.backup_directory
+            .clone()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(CELESTIAD_DATA_DIR).join("backups"))
+    }
+
+    pub fn holobank_backend(&self) -> Backend {
+        self.holobank.backend.unwrap_or_default()
+    }
+
+    /// Key for encrypting the holobank at rest, if encryption is on.
+    pub fn holobank_key(&self) -> Option<KeySource> {
+        match (&self.holobank.key_file, &self.holobank.passphrase) {
+            (Some(path), _) => Some(KeySource::KeyFile(PathBuf::from(path))),
+            (None, Some(passphrase)) => Some(KeySource::Passphrase(passphrase.clone())),
+            (None, None) => None,
+        }
+    }
+
+    /// Keys of commanders, under the user's home directory unless
+    /// configured otherwise.
+    pub fn key_store(&self) -> KeyStore {
+        let directory = self.holobank.key_directory
+            .clone()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+                home.join(CELESTIAD_USER_DIR).join(KEY_DIRECTORY)
+            });
+        KeyStore::new(&directory)
+    }
+
+    /// How long assets may stay in the inbox, if they expire at all.
+    pub fn inbox_retention(&self) -> Option<Retention> {
+        self.holobank.inbox_retention_days.map(|days| Retention {
+            max_age: std::time::Duration::from_secs(days * 24 * 60 * 60),
+            discard: self.holobank.inbox_discard_expired.unwrap_or(false),
+        })
+    }
+
+    pub fn host_backend(&self) -> Backend {
+        self.host.backend.unwrap_or(Backend::Sqlite)
+    }
+
+    /// Location of the host database.  The SQLite file sits directly in the
+    /// data directory while other backends get a directory of their own.
+    pub fn host_directory(&self) -> PathBuf {
+        match self.host_backend() {
+            Backend::Sqlite => PathBuf::from(CELESTIAD_DATA_DIR),
+            _ => PathBuf::from(CELESTIAD_DATA_DIR).join("host"),
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::{fmt, fs, path::{Path, PathBuf}, str::FromStr};
+
+use cozo::DbInstance;
+use serde::Deserialize;
+
+/// Storage engines a cozo database can run on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// A directory of RocksDB files.  Fastest under concurrent writes.
+    #[default]
+    Rocksdb,
+    /// A single SQLite file.  Small and light enough for comets.
+    Sqlite,
+    /// Nothing is written to disk.  Gone once the process exits.
+    Mem,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 3] = [Backend::Rocksdb, Backend::Sqlite, Backend::Mem];
+
+    /// Name of the engine in cozo.
+    pub fn engine(&self) -> &'static str {
+        match self {
+            Backend::Rocksdb => "rocksdb",
+            Backend::Sqlite => "sqlite",
+            Backend::Mem => "mem",
+        }
+    }
+
+    /// Opens or creates the database stored under `path`.  RocksDB keeps
+    /// its files in the directory itself, SQLite in a file named `file`
+    /// inside it and memory ignores the path.
+    pub fn open(&self, path: &Path, file: &str) -> Result<DbInstance, cozo::Error> {
+        let location = self.location(path, file);
+        if *self == Backend::Sqlite {
+            // Opening below reports the failure if the directory is missing.
+            let _ = fs::create_dir_all(path);
+        }
+        DbInstance::new(self.engine(), &location, "")
+    }
+
+    /// Where the database lives on disk.
+    pub fn location(&self, path: &Path, file: &str) -> PathBuf {
+        match self {
+            Backend::Rocksdb => path.to_path_buf(),
+            Backend::Sqlite => path.join(format!("{}.sqlite", file)),
+            Backend::Mem => PathBuf::new(),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.engine())
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Backend::ALL
+            .into_iter()
+            .find(|backend| backend.engine() == s)
+            .ok_or_else(|| format!("unknown storage backend {}", s))
+    }
+}
@@ -0,0 +1,742 @@
+// Assemblies are published as a single Markdown document, a static HTML site
+// or an EPUB.  Text blocks are Markdown.  The parts of slots that nest other
+// blueprints, such as the chapters of a book, become sections: a page of the
+// site and a chapter of the EPUB each.  Everything else, the title and cover
+// included, goes on the front page before them.
+//
+// Texts refer to other blocks as `[[<id>]]` or `[[<id>|label]]`.  References
+// to blocks in the assembly become links to where the block is rendered;
+// references to anything else become their label.  Images are written to an
+// `images` directory next to the document, or inside the EPUB.
+//
+// An assembly nested inside itself, directly or further down, is laid out the
+// first time only.  Raw HTML in texts is kept on HTML pages but escaped in the
+// EPUB, whose chapters must be well-formed XHTML.
+
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{self, Write}, path::Path, str::FromStr};
+
+use pulldown_cmark::{html, Event, Parser};
+use ulid::Ulid;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use super::Assembly;
+use crate::{asset::Export, collection::export::Record};
+
+/// Slots whose text titles the assembly.
+const TITLE_SLOTS: [&str; 2] = ["title", "heading"];
+
+/// Slot whose image is the cover.
+const COVER_SLOT: &str = "cover";
+
+/// Directory images are written to.
+const IMAGES: &str = "images";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    Html,
+    Epub,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" | "md" => Ok(ExportFormat::Markdown),
+            "html" => Ok(ExportFormat::Html),
+            "epub" => Ok(ExportFormat::Epub),
+            other => Err(format!("unknown export format {}", other)),
+        }
+    }
+}
+
+/// Content of an asset an export refers to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Content {
+    Text(String),
+    Image(Vec<u8>),
+    Assembly(Box<Assembly>),
+    /// Content of any other type, e.g. a heap, as it is stored.
+    Other { content_type: String, bytes: Vec<u8> },
+}
+
+/// Where an export reads the assets it is made of.  Assets without content
+/// are left out.
+pub trait Source {
+    fn resolve(&self, id: Ulid) -> Option<Content>;
+
+    /// What is known of an asset besides its content, for exports that can
+    /// be imported again.  `None` when the source only serves content.
+    fn record(&self, _id: Ulid) -> Option<Record> {
+        None
+    }
+
+    /// A text as it is stored, edit history included, for exports that can
+    /// be imported again.  `None` for other assets, or when the source only
+    /// serves content.
+    fn history(&self, _id: Ulid) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl Source for HashMap<Ulid, Content> {
+    fn resolve(&self, id: Ulid) -> Option<Content> {
+        self.get(&id).cloned()
+    }
+}
+
+impl Export for Assembly {
+    type Format = ExportFormat;
+    type Source = dyn Source;
+
+    /// Writes a Markdown file, a directory holding the HTML site, or an
+    /// EPUB file to `path`.
+    fn export(&self, format: ExportFormat, source: &dyn Source, path: &Path) -> io::Result<()> {
+        let outline = Outline::new(self, source);
+        match format {
+            ExportFormat::Markdown => outline.markdown(path),
+            ExportFormat::Html => outline.html(path),
+            ExportFormat::Epub => outline.epub(self.id, path),
+        }
+    }
+}
+
+/// An assembly laid out for publishing.
+struct Outline {
+    title: String,
+    /// The text the title comes from.
+    title_id: Option<Ulid>,
+    cover: Option<Ulid>,
+    front: Vec<Piece>,
+    sections: Vec<Section>,
+    images: Vec<Image>,
+    /// Section each rendered asset is in, `None` for the front page.
+    locations: HashMap<Ulid, Option<usize>>,
+    labels: HashMap<Ulid, String>,
+    /// Assemblies being laid out, from the outermost in.
+    nesting: HashSet<Ulid>,
+}
+
+/// Markdown of a piece, after an anchor for the asset it renders if any.
+type Block = (Option<Ulid>, String);
+
+struct Section {
+    title: String,
+    pieces: Vec<Piece>,
+}
+
+enum Piece {
+    Heading { id: Ulid, level: usize, text: String },
+    Text { id: Ulid, text: String },
+    Image { id: Ulid },
+}
+
+struct Image {
+    id: Ulid,
+    bytes: Vec<u8>,
+    media_type: &'static str,
+    extension: &'static str,
+}
+
+impl Image {
+    fn file_name(&self) -> String {
+        format!("{}.{}", self.id, self.extension)
+    }
+}
+
+impl Outline {
+    fn new(assembly: &Assembly, source: &dyn Source) -> Outline {
+        let mut outline = Outline {
+            title: assembly.name.clone(),
+            title_id: None,
+            cover: None,
+            front: Vec::new(),
+            sections: Vec::new(),
+            images: Vec::new(),
+            locations: HashMap::new(),
+            labels: HashMap::new(),
+            nesting: HashSet::from([assembly.id]),
+        };
+        outline.locations.insert(assembly.id, None);
+        outline.labels.insert(assembly.id, assembly.name.clone());
+
+        for slot in &assembly.blueprint().slots {
+            let sections = !slot.blueprints.is_empty();
+            for id in assembly.slot(&slot.name) {
+                let Some(content) = source.resolve(*id) else {
+                    continue;
+                };
+                match content {
+                    Content::Text(text) if outline.title_id.is_none() && TITLE_SLOTS.contains(&slot.name.as_str()) => {
+                        outline.title_id = Some(*id);
+                        outline.title = first_line(&text);
+                        outline.labels.insert(assembly.id, outline.title.clone());
+                        outline.locations.insert(*id, None);
+                        outline.labels.insert(*id, outline.title.clone());
+                    }
+                    Content::Image(bytes) if outline.cover.is_none() && slot.name == COVER_SLOT => {
+                        outline.cover = Some(*id);
+                        outline.add_image(*id, bytes, None);
+                    }
+                    content if sections => {
+                        let index = outline.sections.len();
+                        let mut pieces = Vec::new();
+                        let title = outline.add(*id, content, 2, Some(index), source, &mut pieces);
+                        outline.sections.push(Section { title, pieces });
+                    }
+                    content => {
+                        let mut pieces = Vec::new();
+                        outline.add(*id, content, 2, None, source, &mut pieces);
+                        outline.front.append(&mut pieces);
+                    }
+                }
+            }
+        }
+        outline
+    }
+
+    /// Lays out an asset at `location`, nested assemblies as a heading of
+    /// `level` followed by their parts.  An assembly already being laid out
+    /// is left out.  Returns its label.
+    fn add(
+        &mut self,
+        id: Ulid,
+        content: Content,
+        level: usize,
+        location: Option<usize>,
+        source: &dyn Source,
+        pieces: &mut Vec<Piece>,
+    ) -> String {
+        let label = match content {
+            Content::Text(text) => {
+                let label = first_line(&text);
+                pieces.push(Piece::Text { id, text });
+                label
+            }
+            Content::Image(bytes) => {
+                self.add_image(id, bytes, location);
+                pieces.push(Piece::Image { id });
+                "image".to_string()
+            }
+            Content::Assembly(nested) => {
+                if !self.nesting.insert(id) {
+                    return nested.name;
+                }
+                let heading = pieces.len();
+                pieces.push(Piece::Heading { id, level, text: nested.name.clone() });
+                let mut label = nested.name.clone();
+                let mut titled = false;
+                for (slot, parts) in nested.parts() {
+                    for part in parts {
+                        let Some(content) = source.resolve(*part) else {
+                            continue;
+                        };
+                        match content {
+                            Content::Text(text) if !titled && TITLE_SLOTS.contains(&slot) => {
+                                titled = true;
+                                label = first_line(&text);
+                                pieces[heading] = Piece::Heading { id, level, text: label.clone() };
+                                self.locations.insert(*part, location);
+                                self.labels.insert(*part, label.clone());
+                            }
+                            content => {
+                                self.add(*part, content, level + 1, location, source, pieces);
+                            }
+                        }
+                    }
+                }
+                self.nesting.remove(&id);
+                label
+            }
+            // Assemblies have no place for other content.
+            Content::Other { content_type, .. } => return content_type,
+        };
+        self.locations.insert(id, location);
+        self.labels.insert(id, label.clone());
+        label
+    }
+
+    fn add_image(&mut self, id: Ulid, bytes: Vec<u8>, location: Option<usize>) {
+        let (media_type, extension) = image_type(&bytes);
+        self.images.push(Image { id, bytes, media_type, extension });
+        self.locations.insert(id, location);
+        self.labels.insert(id, "image".to_string());
+    }
+
+    /// Markdown of each piece with the asset it anchors, linking references
+    /// with `href`.
+    fn blocks(&self, pieces: &[Piece], href: &dyn Fn(Ulid) -> Option<String>) -> Vec<Block> {
+        pieces
+            .iter()
+            .filter_map(|piece| match piece {
+                Piece::Heading { id, level, text } => Some((Some(*id), format!("{} {}", "#".repeat(*level), text))),
+                Piece::Text { id, text } => {
+                    let text = resolve_references(text, href, &|id| self.label(id));
+                    Some((Some(*id), text.trim_end().to_string()))
+                }
+                Piece::Image { id } => self.image(*id).map(|image| (Some(*id), image_markdown(image))),
+            })
+            .collect()
+    }
+
+    fn image(&self, id: Ulid) -> Option<&Image> {
+        self.images.iter().find(|image| image.id == id)
+    }
+
+    fn label(&self, id: Ulid) -> String {
+        self.labels.get(&id).cloned().unwrap_or_else(|| id.to_string())
+    }
+
+    /// Where an asset is rendered, given the file of the front page and of
+    /// each section.
+    fn href(&self, id: Ulid, file: &dyn Fn(Option<usize>) -> String) -> Option<String> {
+        self.locations.get(&id).map(|location| format!("{}#{}", file(*location), id))
+    }
+
+    /// The title, the cover and the rest of the front page.
+    fn front(&self, href: &dyn Fn(Ulid) -> Option<String>) -> Vec<Block> {
+        let mut blocks = vec![(self.title_id, format!("# {}", self.title))];
+        if let Some(cover) = self.cover.and_then(|cover| self.image(cover)) {
+            blocks.push((Some(cover.id), image_markdown(cover)));
+        }
+        blocks.extend(self.blocks(&self.front, href));
+        blocks
+    }
+
+    fn write_images(&self, directory: &Path) -> io::Result<()> {
+        if self.images.is_empty() {
+            return Ok(());
+        }
+        let directory = directory.join(IMAGES);
+        fs::create_dir_all(&directory)?;
+        for image in &self.images {
+            fs::write(directory.join(image.file_name()), &image.bytes)?;
+        }
+        Ok(())
+    }
+
+    /// One document, sections one after the other.
+    fn markdown(&self, path: &Path) -> io::Result<()> {
+        let href = |id: Ulid| self.href(id, &|_| String::new());
+        let mut markdown = to_markdown(&self.front(&href));
+        for section in &self.sections {
+            markdown.push_str(&to_markdown(&self.blocks(&section.pieces, &href)));
+        }
+        fs::write(path, markdown.trim_end().to_string() + "\n")?;
+        self.write_images(path.parent().unwrap_or(Path::new(".")))
+    }
+
+    /// `index.html` with the front page and contents, and a page per
+    /// section linked to its neighbours.
+    fn html(&self, directory: &Path) -> io::Result<()> {
+        fs::create_dir_all(directory)?;
+        let file = |location: Option<usize>| match location {
+            None => "index.html".to_string(),
+            Some(index) => format!("section-{}.html", index + 1),
+        };
+        let href = |id: Ulid| self.href(id, &file);
+
+        let mut index = to_html(&self.front(&href), false);
+        index.push_str(&self.contents(&file));
+        fs::write(directory.join(file(None)), page(&self.title, &index, false))?;
+
+        for (i, section) in self.sections.iter().enumerate() {
+            let mut links = vec![format!("<a href=\"{}\">{}</a>", file(None), escape(&self.title))];
+            if i > 0 {
+                links.push(format!("<a href=\"{}\">Previous</a>", file(Some(i - 1))));
+            }
+            if i + 1 < self.sections.len() {
+                links.push(format!("<a href=\"{}\">Next</a>", file(Some(i + 1))));
+            }
+            let nav = format!("<nav>{}</nav>\n", links.join(" | "));
+            let body = format!("{}{}{}", nav, to_html(&self.blocks(&section.pieces, &href), false), nav);
+            fs::write(directory.join(file(Some(i))), page(&section.title, &body, false))?;
+        }
+        self.write_images(directory)
+    }
+
+    /// EPUB 3 with a title page and a chapter per section.
+    fn epub(&self, id: Ulid, path: &Path) -> io::Result<()> {
+        let file = |location: Option<usize>| match location {
+            None => "title.xhtml".to_string(),
+            Some(index) => format!("chapter-{}.xhtml", index + 1),
+        };
+        let href = |id: Ulid| self.href(id, &file);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        let mut zip = ZipWriter::new(File::create(path)?);
+        let mut add = |name: &str, bytes: &[u8]| -> io::Result<()> {
+            zip.start_file(name, options).map_err(io::Error::other)?;
+            zip.write_all(bytes)
+        };
+
+        // The mimetype comes first, uncompressed.
+        add("mimetype", b"application/epub+zip")?;
+        add("META-INF/container.xml", CONTAINER.as_bytes())?;
+
+        let mut manifest = vec![
+            "<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>".to_string(),
+            format!("<item id=\"title\" href=\"{}\" media-type=\"application/xhtml+xml\"/>", file(None)),
+        ];
+        let mut spine = vec!["<itemref idref=\"title\"/>".to_string()];
+        let mut contents = vec![format!("<li><a href=\"{}\">{}</a></li>", file(None), escape(&self.title))];
+        add(&format!("OEBPS/{}", file(None)), page(&self.title, &to_html(&self.front(&href), true), true).as_bytes())?;
+        for (i, section) in self.sections.iter().enumerate() {
+            let body = to_html(&self.blocks(&section.pieces, &href), true);
+            add(&format!("OEBPS/{}", file(Some(i))), page(&section.title, &body, true).as_bytes())?;
+            manifest.push(format!(
+                "<item id=\"chapter-{}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>",
+                i + 1,
+                file(Some(i))
+            ));
+            spine.push(format!("<itemref idref=\"chapter-{}\"/>", i + 1));
+            contents.push(format!("<li><a href=\"{}\">{}</a></li>", file(Some(i)), escape(&section.title)));
+        }
+        for image in &self.images {
+            add(&format!("OEBPS/{}/{}", IMAGES, image.file_name()), &image.bytes)?;
+            let cover = if Some(image.id) == self.cover { " properties=\"cover-image\"" } else { "" };
+            manifest.push(format!(
+                "<item id=\"image-{}\" href=\"{}/{}\" media-type=\"{}\"{}/>",
+                image.id,
+                IMAGES,
+                image.file_name(),
+                image.media_type,
+                cover
+            ));
+        }
+
+        let nav = format!("<nav epub:type=\"toc\"><ol>\n{}\n</ol></nav>\n", contents.join("\n"));
+        add("OEBPS/nav.xhtml", page(&self.title, &nav, true).as_bytes())?;
+        let package = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"id\">
+<metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">
+<dc:identifier id=\"id\">urn:ulid:{}</dc:identifier>
+<dc:title>{}</dc:title>
+<dc:language>en</dc:language>
+<meta property=\"dcterms:modified\">{}</meta>
+</metadata>
+<manifest>
+{}
+</manifest>
+<spine>
+{}
+</spine>
+</package>
+",
+            id,
+            escape(&self.title),
+            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+            manifest.join("\n"),
+            spine.join("\n")
+        );
+        add("OEBPS/content.opf", package.as_bytes())?;
+        zip.finish().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// List of the sections for the front page.
+    fn contents(&self, file: &dyn Fn(Option<usize>) -> String) -> String {
+        if self.sections.is_empty() {
+            return String::new();
+        }
+        let items: Vec<String> = self.sections
+            .iter()
+            .enumerate()
+            .map(|(i, section)| format!("<li><a href=\"{}\">{}</a></li>", file(Some(i)), escape(&section.title)))
+            .collect();
+        format!("<nav><ol>\n{}\n</ol></nav>\n", items.join("\n"))
+    }
+}
+
+const CONTAINER: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">
+<rootfiles>
+<rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\"/>
+</rootfiles>
+</container>
+";
+
+/// Replaces `[[<id>]]` and `[[<id>|label]]` with a Markdown link to where
+/// the asset is rendered, or its label when it is not.  Anything else in
+/// double brackets is left alone.
+fn resolve_references(text: &str, href: &dyn Fn(Ulid) -> Option<String>, label: &dyn Fn(Ulid) -> String) -> String {
+    let mut resolved = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("[[") {
+        let Some(length) = rest[start..].find("]]") else {
+            break;
+        };
+        let inner = &rest[start + 2..start + length];
+        let (target, given) = match inner.split_once('|') {
+            Some((target, given)) => (target, Some(given.trim().to_string())),
+            None => (inner, None),
+        };
+        resolved.push_str(&rest[..start]);
+        match target.trim().parse::<Ulid>() {
+            Ok(id) => {
+                let label = given.unwrap_or_else(|| label(id));
+                match href(id) {
+                    Some(href) => resolved.push_str(&format!("[{}]({})", label, href)),
+                    None => resolved.push_str(&label),
+                }
+            }
+            Err(_) => resolved.push_str(&rest[start..start + length + 2]),
+        }
+        rest = &rest[start + length + 2..];
+    }
+    resolved.push_str(rest);
+    resolved
+}
+
+/// First line of a text without Markdown heading marks, for titles and
+/// labels.
+fn first_line(text: &str) -> String {
+    let line = text.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or_default();
+    let line = line.trim_start_matches('#').trim();
+    match line.char_indices().nth(80) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+fn image_markdown(image: &Image) -> String {
+    format!("![]({}/{})", IMAGES, image.file_name())
+}
+
+fn to_markdown(blocks: &[Block]) -> String {
+    blocks
+        .iter()
+        .map(|(id, markdown)| match id {
+            Some(id) => format!("<a id=\"{}\"></a>\n\n{}\n\n", id, markdown),
+            None => format!("{}\n\n", markdown),
+        })
+        .collect()
+}
+
+/// Renders blocks as HTML, escaping raw HTML in them for XHTML.
+fn to_html(blocks: &[Block], xhtml: bool) -> String {
+    let mut rendered = String::new();
+    for (id, markdown) in blocks {
+        if let Some(id) = id {
+            rendered.push_str(&format!("<a id=\"{}\"></a>\n", id));
+        }
+        let events = Parser::new(markdown).map(|event| match event {
+            Event::Html(raw) | Event::InlineHtml(raw) if xhtml => Event::Text(raw),
+            event => event,
+        });
+        html::push_html(&mut rendered, events);
+    }
+    rendered
+}
+
+/// A complete page, XHTML for EPUBs.
+fn page(title: &str, body: &str, xhtml: bool) -> String {
+    if xhtml {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<!DOCTYPE html>
+<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">
+<head><meta charset=\"utf-8\"/><title>{}</title></head>
+<body>
+{}</body>
+</html>
+",
+            escape(title),
+            body
+        )
+    } else {
+        format!(
+            "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>{}</title></head>
+<body>
+{}</body>
+</html>
+",
+            escape(title),
+            body
+        )
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Media type and extension of an image, from its first bytes.
+pub(crate) fn image_type(bytes: &[u8]) -> (&'static str, &'static str) {
+    if bytes.starts_with(b"\x89PNG") {
+        ("image/png", "png")
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        ("image/jpeg", "jpg")
+    } else if bytes.starts_with(b"GIF8") {
+        ("image/gif", "gif")
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        ("image/webp", "webp")
+    } else if bytes.starts_with(b"<svg") || bytes.starts_with(b"<?xml") {
+        ("image/svg+xml", "svg")
+    } else {
+        ("application/octet-stream", "bin")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::asset::{
+        assembly::{Part, PartKind},
+        blueprint::Blueprint,
+    };
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\nnot really";
+
+    fn part(id: Ulid, kind: &str) -> Part {
+        Part { id, name: None, kind: PartKind::Block(kind.to_string()) }
+    }
+
+    /// A book with a cover and two chapters, the second referring to the
+    /// first and to something outside the book.
+    fn book() -> (Assembly, HashMap<Ulid, Content>) {
+        let (title, cover, first, second) = (Ulid::new(), Ulid::new(), Ulid::new(), Ulid::new());
+        let mut source = HashMap::new();
+        source.insert(title, Content::Text("The Book".to_string()));
+        source.insert(cover, Content::Image(PNG.to_vec()));
+        source.insert(first, Content::Text("# Beginnings\n\nIt starts.".to_string()));
+        source.insert(second, Content::Text(format!(
+            "# Endings\n\nAs in [[{}]], and [[{}|elsewhere]] and [[not an id]].",
+            first,
+            Ulid::new()
+        )));
+        let title = Part { name: Some("title".to_string()), ..part(title, "text") };
+        let parts = [title, part(cover, "image"), part(first, "text"), part(second, "text")];
+        let book = Assembly::construct("book", &Blueprint::book(), &parts).unwrap();
+        (book, source)
+    }
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!("export-{}-{}", name, Ulid::new()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn references() {
+        let known = Ulid::new();
+        let href = |id| (id == known).then(|| format!("#{}", id));
+        let label = |_| "Known".to_string();
+        assert_eq!(
+            resolve_references(&format!("see [[{}]] or [[{}|that]], [[x]] [[", known, Ulid::new()), &href, &label),
+            format!("see [Known](#{}) or that, [[x]] [[", known)
+        );
+        assert_eq!(first_line("\n## Title  \nbody"), "Title");
+    }
+
+    #[test]
+    fn markdown() {
+        let (book, source) = book();
+        let directory = scratch("markdown");
+        let path = directory.join("book.md");
+        book.export(ExportFormat::Markdown, &source, &path).unwrap();
+
+        let markdown = fs::read_to_string(&path).unwrap();
+        let first = book.slot("chapters")[0];
+        assert!(markdown.contains("\n# The Book\n"));
+        assert!(markdown.contains(&format!("As in [Beginnings](#{}), and elsewhere and [[not an id]].", first)));
+        let cover = book.slot("cover")[0];
+        assert!(markdown.contains(&format!("![](images/{}.png)", cover)));
+        assert_eq!(fs::read(directory.join(IMAGES).join(format!("{}.png", cover))).unwrap(), PNG);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn html_site() {
+        let (book, source) = book();
+        let directory = scratch("html");
+        book.export(ExportFormat::Html, &source, &directory).unwrap();
+
+        let index = fs::read_to_string(directory.join("index.html")).unwrap();
+        assert!(index.contains("<h1>The Book</h1>"));
+        assert!(index.contains("<a href=\"section-1.html\">Beginnings</a>"));
+        let second = fs::read_to_string(directory.join("section-2.html")).unwrap();
+        let first = book.slot("chapters")[0];
+        assert!(second.contains(&format!("<a href=\"section-1.html#{}\">Beginnings</a>", first)));
+        assert!(second.contains("<a href=\"section-1.html\">Previous</a>"));
+        assert!(!directory.join("section-3.html").exists());
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn epub() {
+        let (book, source) = book();
+        let directory = scratch("epub");
+        let path = directory.join("book.epub");
+        book.export(ExportFormat::Epub, &source, &path).unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(archive.by_index(0).unwrap().name(), "mimetype");
+        let mut read = |name: &str| {
+            let mut text = String::new();
+            archive.by_name(name).unwrap().read_to_string(&mut text).unwrap();
+            text
+        };
+        let package = read("OEBPS/content.opf");
+        assert!(package.contains("<dc:title>The Book</dc:title>"));
+        assert!(package.contains("properties=\"cover-image\""));
+        assert!(package.contains("<itemref idref=\"chapter-2\"/>"));
+        let first = book.slot("chapters")[0];
+        assert!(read("OEBPS/chapter-2.xhtml").contains(&format!("href=\"chapter-1.xhtml#{}\"", first)));
+        assert!(read("OEBPS/nav.xhtml").contains("epub:type=\"toc\""));
+        assert!(archive.by_name(&format!("OEBPS/images/{}.png", book.slot("cover")[0])).is_ok());
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn escapes_raw_html_in_epub() {
+        let (title, chapter) = (Ulid::new(), Ulid::new());
+        let source = HashMap::from([
+            (title, Content::Text("Raw".to_string())),
+            (chapter, Content::Text("A line<br>broken".to_string())),
+        ]);
+        let title = Part { name: Some("title".to_string()), ..part(title, "text") };
+        let book = Assembly::construct("book", &Blueprint::book(), &[title, part(chapter, "text")]).unwrap();
+        let directory = scratch("raw");
+
+        book.export(ExportFormat::Html, &source, &directory).unwrap();
+        assert!(fs::read_to_string(directory.join("section-1.html")).unwrap().contains("A line<br>broken"));
+
+        let path = directory.join("book.epub");
+        book.export(ExportFormat::Epub, &source, &path).unwrap();
+        let mut archive = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut xhtml = String::new();
+        archive.by_name("OEBPS/chapter-1.xhtml").unwrap().read_to_string(&mut xhtml).unwrap();
+        assert!(xhtml.contains(&format!("<a id=\"{}\"></a>\n<p>A line&lt;br&gt;broken</p>", chapter)));
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn lays_out_nested_assemblies_once() {
+        let (title, note, page) = (Ulid::new(), Ulid::new(), Ulid::new());
+        let title = Part { name: Some("title".to_string()), ..part(title, "text") };
+        let chapter = Part { id: page, name: None, kind: PartKind::Assembly("page".to_string()) };
+        let book = Assembly::construct("book", &Blueprint::book(), &[title, chapter]).unwrap();
+        // The page holds the book it is in, and itself.
+        let parts = [part(note, "text"), part(book.id, "text"), part(page, "text")];
+        let inner = Assembly { id: page, ..Assembly::construct("Inner page", &Blueprint::page(), &parts).unwrap() };
+        let source = HashMap::from([
+            (note, Content::Text("Inside".to_string())),
+            (page, Content::Assembly(Box::new(inner))),
+            (book.id, Content::Assembly(Box::new(book.clone()))),
+        ]);
+        let directory = scratch("nested");
+        let path = directory.join("book.md");
+        book.export(ExportFormat::Markdown, &source, &path).unwrap();
+
+        let markdown = fs::read_to_string(&path).unwrap();
+        assert_eq!(markdown.matches("## Inner page").count(), 1);
+        assert_eq!(markdown.matches("Inside").count(), 1);
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
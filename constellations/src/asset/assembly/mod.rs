@@ -1,19 +1,286 @@
-use super::blueprint::Blueprint;
-
-pub trait Assembly {
-    fn construct<B>(blueprint: B) 
-        where B: Blueprint {
-
-    }
-    /// Create an assembly from another
-    fn derive() -> Self;
-    /// Create an assembly
-    fn create() -> Self;
-    /// Destroy an assembly
-    fn destroy();
-    /// Create an assembly from resources outside the spaceport i.e.,
-    /// documents
-    fn import() -> Self;
-    /// Send an assembly to consumers outside the spaceport
-    fn export(&self);
-}
\ No newline at end of file
+// An assembly is built by filling the slots of a blueprint with blocks, or
+// with other assemblies for nested blueprints.  The holobank keeps the
+// structure as `part:<slot>:<index>` connections from the assembly to its
+// parts and a construction connection from the blueprint.  The assembly keeps
+// a copy of the blueprint as it was when it was built, so changing the
+// blueprint later leaves existing assemblies as they are.
+
+pub mod export;
+
+use std::{collections::BTreeMap, fmt};
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::connection::{Connection, ConnectionType};
+use super::{
+    blueprint::{Blueprint, Cardinality, Slot},
+    Asset, AssetType, Holoframe, Materializable,
+};
+
+/// Something that can fill a slot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Part {
+    pub id: Ulid,
+    /// Parts named like a slot go to that slot first.
+    pub name: Option<String>,
+    pub kind: PartKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartKind {
+    /// A block of a content type, e.g. `text`.
+    Block(String),
+    /// An assembly built from the named blueprint.
+    Assembly(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AssemblyError {
+    /// Not enough parts fit the slot.
+    Unfilled { slot: String, cardinality: Cardinality, found: usize },
+    /// Parts that fit no slot, or only full ones.
+    Unplaced(Vec<Ulid>),
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssemblyError::Unfilled { slot, cardinality, found } => {
+                write!(f, "slot {} takes {} parts but {} fit", slot, cardinality, found)
+            }
+            AssemblyError::Unplaced(ids) => write!(f, "{} parts fit no slot", ids.len()),
+        }
+    }
+}
+
+/// A structure of blocks built from a blueprint.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Assembly {
+    pub id: Ulid,
+    pub name: String,
+    /// The blueprint as it was when the assembly was built.
+    blueprint: Blueprint,
+    /// Parts of each slot, in order.
+    slots: BTreeMap<String, Vec<Ulid>>,
+    #[serde(default)]
+    origin: Option<Connection>,
+}
+
+impl Asset for Assembly {
+    fn id(&self) -> Ulid {
+        self.id
+    }
+
+    fn asset_type(&self) -> AssetType {
+        AssetType::Assembly
+    }
+
+    /// A derived assembly has the same parts under a new id.
+    fn derive(&self) -> Self {
+        let mut assembly = self.clone();
+        assembly.id = Ulid::new();
+        assembly.origin = Some(Connection::new(self.id, assembly.id, ConnectionType::Derivation));
+        assembly
+    }
+
+    fn fork(&self) -> Self {
+        let mut assembly = self.clone();
+        assembly.id = Ulid::new();
+        assembly.origin = Some(Connection::new(self.id, assembly.id, ConnectionType::Fork));
+        assembly
+    }
+
+    fn origin(&self) -> Option<Connection> {
+        self.origin.clone()
+    }
+}
+
+impl Materializable for Assembly {
+    fn scan(&self) -> Holoframe {
+        Holoframe {
+            content_type: Assembly::CONTENT_TYPE.to_string(),
+            content: serde_json::to_vec(self).expect("assemblies serialize"),
+            text: None,
+        }
+    }
+}
+
+impl Assembly {
+    pub const CONTENT_TYPE: &'static str = "assembly";
+
+    /// Fills the slots of a blueprint with parts.  Parts named like a slot
+    /// go there first, then each slot takes the parts it needs to reach its
+    /// minimum, then slots take what is left while they have room, all in
+    /// the order of the blueprint and of the parts.  A slot for a single
+    /// part, e.g. a title, only takes a part another slot also accepts when
+    /// the part is named like it, so chapters never end up as the title.
+    pub fn construct(name: impl Into<String>, blueprint: &Blueprint, parts: &[Part]) -> Result<Assembly, AssemblyError> {
+        let accepts = |slot: &Slot, part: &Part| match &part.kind {
+            PartKind::Block(block_type) => slot.accepts_block(block_type),
+            PartKind::Assembly(nested) => slot.accepts_blueprint(nested),
+        };
+        let fits = |slot: &Slot, part: &Part| {
+            accepts(slot, part) && (slot.cardinality.max != Some(1)
+                || !blueprint.slots.iter().any(|other| other.name != slot.name && accepts(other, part)))
+        };
+        let mut placed: Vec<Option<usize>> = vec![None; parts.len()];
+        let mut counts = vec![0; blueprint.slots.len()];
+        let mut place = |take: &dyn Fn(usize, &Slot, &Part) -> bool| {
+            for (i, slot) in blueprint.slots.iter().enumerate() {
+                for (p, part) in parts.iter().enumerate() {
+                    if placed[p].is_none() && take(counts[i], slot, part) {
+                        placed[p] = Some(i);
+                        counts[i] += 1;
+                    }
+                }
+            }
+        };
+        let room = |count: usize, slot: &Slot| slot.cardinality.max.is_none_or(|max| count < max);
+        place(&|count, slot, part| {
+            room(count, slot) && accepts(slot, part) && part.name.as_deref().is_some_and(|name| name.eq_ignore_ascii_case(&slot.name))
+        });
+        place(&|count, slot, part| count < slot.cardinality.min && fits(slot, part));
+        place(&|count, slot, part| room(count, slot) && fits(slot, part));
+
+        for (slot, count) in blueprint.slots.iter().zip(&counts) {
+            if !slot.cardinality.allows(*count) {
+                return Err(AssemblyError::Unfilled {
+                    slot: slot.name.clone(),
+                    cardinality: slot.cardinality,
+                    found: *count,
+                });
+            }
+        }
+        let unplaced: Vec<Ulid> = parts
+            .iter()
+            .zip(&placed)
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(part, _)| part.id)
+            .collect();
+        if !unplaced.is_empty() {
+            return Err(AssemblyError::Unplaced(unplaced));
+        }
+
+        let mut slots: BTreeMap<String, Vec<Ulid>> = BTreeMap::new();
+        for (part, slot) in parts.iter().zip(placed) {
+            if let Some(slot) = slot {
+                slots.entry(blueprint.slots[slot].name.clone()).or_default().push(part.id);
+            }
+        }
+        Ok(Assembly { id: Ulid::new(), name: name.into(), blueprint: blueprint.clone(), slots, origin: None })
+    }
+
+    /// Rebuild an assembly from its part connections.  Connections that are
+    /// not parts of `id` are ignored.
+    pub fn from_parts(
+        id: Ulid,
+        name: impl Into<String>,
+        blueprint: Blueprint,
+        origin: Option<Connection>,
+        connections: &[Connection],
+    ) -> Assembly {
+        let mut indexed: BTreeMap<String, BTreeMap<usize, Ulid>> = BTreeMap::new();
+        for connection in connections.iter().filter(|connection| connection.src == id) {
+            if let Some((slot, index)) = connection.kind.as_part() {
+                indexed.entry(slot.to_string()).or_default().insert(index, connection.dest);
+            }
+        }
+        let slots = indexed
+            .into_iter()
+            .map(|(slot, parts)| (slot, parts.into_values().collect()))
+            .collect();
+        Assembly { id, name: name.into(), blueprint, slots, origin }
+    }
+
+    pub fn blueprint(&self) -> &Blueprint {
+        &self.blueprint
+    }
+
+    /// Parts of a slot, in order.
+    pub fn slot(&self, name: &str) -> &[Ulid] {
+        self.slots.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Parts of every slot, in the order of the blueprint.
+    pub fn parts(&self) -> impl Iterator<Item = (&str, &[Ulid])> + '_ {
+        self.blueprint.slots.iter().map(|slot| (slot.name.as_str(), self.slot(&slot.name)))
+    }
+
+    /// The connections that make up the assembly: from its blueprint and
+    /// to each part.
+    pub fn connections(&self) -> Vec<Connection> {
+        let mut connections = vec![Connection::new(self.blueprint.id, self.id, ConnectionType::Construction)];
+        for (slot, parts) in &self.slots {
+            for (index, part) in parts.iter().enumerate() {
+                connections.push(Connection::new(self.id, *part, ConnectionType::part(slot, index)));
+            }
+        }
+        connections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(name: Option<&str>) -> Part {
+        Part { id: Ulid::new(), name: name.map(str::to_string), kind: PartKind::Block("text".to_string()) }
+    }
+
+    #[test]
+    fn book_from_chapters() {
+        let chapters: Vec<Part> = (0..3).map(|_| text(None)).collect();
+        let title = text(Some("Title"));
+        let mut parts = chapters.clone();
+        parts.push(title.clone());
+
+        let mut blueprint = Blueprint::book();
+        let book = Assembly::construct("novel", &blueprint, &parts).unwrap();
+        assert_eq!(book.slot("title"), &[title.id]);
+        assert_eq!(book.slot("chapters"), chapters.iter().map(|part| part.id).collect::<Vec<_>>());
+        assert!(book.slot("cover").is_empty());
+
+        let connections = book.connections();
+        assert!(connections.contains(&Connection::new(blueprint.id, book.id, ConnectionType::Construction)));
+        assert!(connections.contains(&Connection::new(book.id, chapters[2].id, ConnectionType::part("chapters", 2))));
+        assert_eq!(Assembly::from_parts(book.id, "novel", blueprint.clone(), None, &connections), book);
+
+        // Changing the blueprint leaves the book as it was built.
+        blueprint.slots.retain(|slot| slot.name != "title");
+        assert_eq!(book.blueprint(), &Blueprint::book());
+        assert_eq!(book.parts().map(|(slot, _)| slot).collect::<Vec<_>>(), vec!["title", "cover", "chapters"]);
+    }
+
+    #[test]
+    fn nests_pages_and_reports_misfits() {
+        let blueprint = Blueprint::book();
+        let page = Part { id: Ulid::new(), name: None, kind: PartKind::Assembly("page".to_string()) };
+        let title = text(Some("title"));
+        let book = Assembly::construct("pages", &blueprint, &[page.clone(), title.clone()]).unwrap();
+        assert_eq!(book.slot("title"), &[title.id]);
+        assert_eq!(book.slot("chapters"), &[page.id]);
+
+        assert_eq!(
+            Assembly::construct("untitled", &blueprint, std::slice::from_ref(&page)).unwrap_err(),
+            AssemblyError::Unfilled { slot: "title".to_string(), cardinality: Cardinality::ONE, found: 0 }
+        );
+        // Chapters also take text, so unnamed text is never the title.
+        let chapters = [text(None), text(None)];
+        assert_eq!(
+            Assembly::construct("untitled", &blueprint, &chapters).unwrap_err(),
+            AssemblyError::Unfilled { slot: "title".to_string(), cardinality: Cardinality::ONE, found: 0 }
+        );
+        let cover = Part { id: Ulid::new(), name: None, kind: PartKind::Block("image".to_string()) };
+        let mut parts = chapters.to_vec();
+        parts.extend([cover.clone(), title.clone()]);
+        let book = Assembly::construct("covered", &blueprint, &parts).unwrap();
+        assert_eq!(book.slot("cover"), &[cover.id]);
+        assert_eq!(book.slot("chapters"), &[chapters[0].id, chapters[1].id]);
+        let audio = Part { id: Ulid::new(), name: None, kind: PartKind::Block("audio".to_string()) };
+        assert_eq!(
+            Assembly::construct("noisy", &blueprint, &[title, page, audio.clone()]).unwrap_err(),
+            AssemblyError::Unplaced(vec![audio.id])
+        );
+    }
+}
@@ -1 +1,80 @@
-// A distributed memory in the network.  Distributed processes can allocate network memory to run program in the network!
\ No newline at end of file
+// A distributed memory in the network.  Distributed processes can allocate network memory to run program in the network!
+// Until then a heap block holds bytes of no particular type, e.g. the
+// attachments of imported documents.
+
+use std::{fs, io, path::Path};
+
+use ulid::Ulid;
+
+use crate::asset::{Asset, AssetType, Holoframe, Import, Materializable};
+use crate::connection::{Connection, ConnectionType};
+use super::Block;
+
+pub struct Heap {
+    id: Ulid,
+    origin: Option<Connection>,
+    bytes: Vec<u8>,
+}
+
+impl Asset for Heap {
+    fn id(&self) -> Ulid {
+        self.id
+    }
+
+    fn asset_type(&self) -> AssetType {
+        AssetType::Block
+    }
+
+    fn derive(&self) -> Self {
+        let mut heap = Heap::new(self.bytes.clone());
+        heap.origin = Some(Connection::new(self.id, heap.id, ConnectionType::Derivation));
+        heap
+    }
+
+    fn fork(&self) -> Self {
+        let mut heap = Heap::new(self.bytes.clone());
+        heap.origin = Some(Connection::new(self.id, heap.id, ConnectionType::Fork));
+        heap
+    }
+
+    fn origin(&self) -> Option<Connection> {
+        self.origin.clone()
+    }
+}
+
+impl Block for Heap {}
+
+impl Materializable for Heap {
+    fn scan(&self) -> Holoframe {
+        Holoframe {
+            content_type: Heap::CONTENT_TYPE.to_string(),
+            content: self.bytes.clone(),
+            text: None,
+        }
+    }
+}
+
+impl Import for Heap {
+    fn import(path: &Path) -> io::Result<Self> {
+        Ok(Heap::new(fs::read(path)?))
+    }
+}
+
+impl Heap {
+    pub const CONTENT_TYPE: &'static str = "heap";
+
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Heap { id: Ulid::new(), origin: None, bytes }
+    }
+
+    /// The same heap as the asset `id`, e.g. a new version of an attachment
+    /// imported before.
+    pub fn with_id(mut self, id: Ulid) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
@@ -0,0 +1,79 @@
+use std::{fs, io, path::Path};
+
+use ulid::Ulid;
+
+use crate::asset::{Asset, AssetType, Holoframe, Import, Materializable};
+use crate::connection::{Connection, ConnectionType};
+use super::Block;
+
+// Image content is kept as the bytes of the encoded image, in whatever
+// format it came in.
+
+pub struct Image {
+    id: Ulid,
+    origin: Option<Connection>,
+    bytes: Vec<u8>,
+}
+
+impl Asset for Image {
+    fn id(&self) -> Ulid {
+        self.id
+    }
+
+    fn asset_type(&self) -> AssetType {
+        AssetType::Block
+    }
+
+    fn derive(&self) -> Self {
+        let mut image = Image::new(self.bytes.clone());
+        image.origin = Some(Connection::new(self.id, image.id, ConnectionType::Derivation));
+        image
+    }
+
+    fn fork(&self) -> Self {
+        let mut image = Image::new(self.bytes.clone());
+        image.origin = Some(Connection::new(self.id, image.id, ConnectionType::Fork));
+        image
+    }
+
+    fn origin(&self) -> Option<Connection> {
+        self.origin.clone()
+    }
+}
+
+impl Block for Image {}
+
+impl Materializable for Image {
+    fn scan(&self) -> Holoframe {
+        Holoframe {
+            content_type: Image::CONTENT_TYPE.to_string(),
+            content: self.bytes.clone(),
+            text: None,
+        }
+    }
+}
+
+impl Import for Image {
+    fn import(path: &Path) -> io::Result<Self> {
+        Ok(Image::new(fs::read(path)?))
+    }
+}
+
+impl Image {
+    pub const CONTENT_TYPE: &'static str = "image";
+
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Image { id: Ulid::new(), origin: None, bytes }
+    }
+
+    /// The same image as the asset `id`, e.g. a new version of an image
+    /// imported before.
+    pub fn with_id(mut self, id: Ulid) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
@@ -1,3 +1,5 @@
+pub mod heap;
+pub mod image;
 pub mod text;
 
 /// A block is a unit of stateless data.
@@ -34,7 +36,7 @@ pub trait Block {}
 // Banks are networked meaning they can request resource transfers from one another
 // Banks, by default, do not synchronize assets unless it is added to a collection
 // A collection is a list of assets that are related in some manner
-// A collection is also a schema which defines which spaceports will track the collection.
+// A collection is also a schema (its sharing policy) which defines which spaceports will track the collection.
 
 // Data not in a collection will instead be stored in a local collection (buffer).
 
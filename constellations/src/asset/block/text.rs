@@ -1,17 +1,45 @@
 use std::ops::Range;
-use std::vec;
+use std::path::Path;
+use std::{fs, io, vec};
 use cola::{Deletion, EncodedReplica, Replica, ReplicaId};
 use serde::{Deserialize, Serialize};
 use postcard;
 use ulid::Ulid;
-use crate::asset::{Asset, Holographable, Materializable};
+use crate::asset::{Asset, AssetType, Holographable, Holoframe, Import, Managed, Materializable};
+use crate::connection::{Connection, ConnectionType};
 use super::Block;
 
 impl Asset for Text {
     fn id(&self) -> Ulid {
-        todo!()
+        self.id
+    }
+
+    fn asset_type(&self) -> AssetType {
+        AssetType::Block
     }
 
+    /// A derived text starts over with its own edit history.
+    fn derive(&self) -> Self {
+        let mut text = Text::new(self.buffer.clone(), Ulid::new().random() as ReplicaId);
+        text.origin = Some(Connection::new(self.id, text.id, ConnectionType::Derivation));
+        text
+    }
+    
+    /// A forked text shares the edit history and can still integrate edits
+    /// from the original.
+    fn fork(&self) -> Self {
+        let mut text = Text::fork(self, Ulid::new().random() as ReplicaId);
+        text.id = Ulid::new();
+        text.origin = Some(Connection::new(self.id, text.id, ConnectionType::Fork));
+        text
+    }
+
+    fn origin(&self) -> Option<Connection> {
+        self.origin.clone()
+    }
+}
+
+impl Managed for Text {
     fn name(&self) {
         todo!()
     }
@@ -36,21 +64,13 @@ impl Asset for Text {
         todo!()
     }
 
-    fn derive() -> Self {
-        todo!()
-    }
-    
     fn upload() {
         todo!()
     }
-    
+
     fn download() -> Self {
         todo!()
     }
-    
-    fn fork() -> Self {
-        todo!()
-    }
 }
 
 impl Block for Text {}
@@ -62,8 +82,20 @@ impl Holographable for Text {
 }
 
 impl Materializable for Text {
-    fn scan() {
-        todo!()
+    fn scan(&self) -> Holoframe {
+        Holoframe {
+            content_type: Text::CONTENT_TYPE.to_string(),
+            content: self.encode(self.crdt.id()),
+            text: Some(self.buffer.clone()),
+        }
+    }
+}
+
+impl Import for Text {
+    /// Reads a UTF-8 document as it is, markup included.
+    fn import(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Ok(Text::new(text, Ulid::new().random() as ReplicaId))
     }
 }
 
@@ -72,7 +104,9 @@ impl Materializable for Text {
 // By default, all blocks follow the single-holder principle,
 // but text can enable simultaneous editing with CRDTs.
 
-struct Text {
+pub struct Text {
+    id: Ulid,
+    origin: Option<Connection>,
     buffer: String,
     crdt: Replica,
     history: Vec<Edit>,
@@ -80,6 +114,8 @@ struct Text {
 
 #[derive(Serialize, Deserialize)]
 struct EncodedText {
+    id: Ulid,
+    origin: Option<Connection>,
     buffer: String,
     crdt: EncodedReplica,
     history: Vec<Edit>,
@@ -87,21 +123,59 @@ struct EncodedText {
 }
 
 impl Text {
-    fn new<S: Into<String>>(text: S, replica_id: ReplicaId) -> Self {
+    pub const CONTENT_TYPE: &'static str = "text";
+
+    pub fn new<S: Into<String>>(text: S, replica_id: ReplicaId) -> Self {
         let buffer = text.into();
         let crdt = Replica::new(replica_id, buffer.len());
         let history: Vec<Edit> = vec![];
-        Text { buffer, crdt, history }
+        Text { id: Ulid::new(), origin: None, buffer, crdt, history }
+    }
+
+    /// The same text as the asset `id`, e.g. a text restored from an
+    /// export.
+    pub fn with_id(mut self, id: Ulid) -> Self {
+        self.id = id;
+        self
     }
 
+    /// Forks the CRDT replica.  The fork is the same asset edited from
+    /// another replica.
     fn fork(&self, new_replica_id: ReplicaId) -> Self {
         let crdt = self.crdt.fork(new_replica_id);
-        Text { buffer: self.buffer.clone(), crdt , history: self.history.clone() }
+        Text { id: self.id, origin: self.origin.clone(), buffer: self.buffer.clone(), crdt , history: self.history.clone() }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.buffer
+    }
+
+    /// Replaces the whole text as one deletion and one insertion, e.g. when
+    /// the document it was imported from changed.
+    pub fn replace<S: Into<String>>(&mut self, text: S) {
+        if !self.buffer.is_empty() {
+            self.delete(0..self.buffer.len());
+        }
+        self.insert(0, text);
     }
 
     fn encode(&self, assigned_id: u64) -> Vec<u8> {
         let encoded = self.crdt.encode();
-        postcard::to_allocvec(&EncodedText{ buffer: self.buffer.clone(), crdt: encoded, history: self.history.clone(), assigned_id }).unwrap()
+        postcard::to_allocvec(&EncodedText{ id: self.id, origin: self.origin.clone(), buffer: self.buffer.clone(), crdt: encoded, history: self.history.clone(), assigned_id }).unwrap()
+    }
+
+    /// Decodes a text scanned into the holobank.  `None` if the bytes are
+    /// not an encoded text.
+    pub fn decode(bytes: &[u8]) -> Option<Text> {
+        let value: EncodedText = postcard::from_bytes(bytes).ok()?;
+        let crdt = Replica::decode(value.assigned_id, &value.crdt).ok()?;
+        Some(Text {
+            id: value.id,
+            origin: value.origin,
+            buffer: value.buffer,
+            crdt,
+            history: value.history,
+        })
     }
 
     fn insert<S: Into<String>>(&mut self, insert_at: usize, text: S) -> Insertion {
@@ -140,6 +214,8 @@ impl Text {
 impl From<EncodedText> for Text {
     fn from(value: EncodedText) -> Self {
         Text {
+            id: value.id,
+            origin: value.origin,
             buffer: value.buffer,
             crdt: Replica::decode(value.assigned_id, &value.crdt).unwrap(),
             history: value.history,
@@ -198,6 +274,29 @@ mod tests {
         assert_eq!(peer_2.crdt.id(), 2);
     }
 
+    #[test]
+    fn decode_scanned() {
+        let text = Text::new("Hello, world", 1);
+        let frame = text.scan();
+
+        let decoded = Text::decode(&frame.content).unwrap();
+        assert_eq!(decoded.id(), text.id());
+        assert_eq!(decoded.as_str(), "Hello, world");
+        assert!(Text::decode(b"not a text").is_none());
+    }
+
+    #[test]
+    fn lineage() {
+        let original = Text::new("Hello, world", 1);
+        let derived = Asset::derive(&original);
+        let forked = Asset::fork(&original);
+
+        assert_ne!(derived.id(), original.id());
+        assert_eq!(derived.origin(), Some(Connection::new(original.id(), derived.id(), ConnectionType::Derivation)));
+        assert_eq!(forked.origin(), Some(Connection::new(original.id(), forked.id(), ConnectionType::Fork)));
+        assert!(original.origin().is_none());
+    }
+
     #[test]
     fn history() {
         let mut peer_1 = Text::new("Hello, world", 1);
@@ -1,17 +1,461 @@
 // Blueprints define how an assembly is constructed from a collection of blocks.
 // Blueprints can alternatively be called templates.
 // Once a blueprint is used to build an assembly, modifying the assembly does not modify the blueprint v.v.
+//
+// A blueprint is a list of named slots.  Each slot takes a number of blocks
+// of the types it accepts, or assemblies built from the blueprints it nests,
+// so a book nests pages and a bookshelf nests books.  Blueprints are written
+// as JSON files:
+//
+//     {
+//         "name": "book",
+//         "slots": [
+//             { "name": "title", "blocks": ["text"] },
+//             { "name": "cover", "cardinality": "0..1", "blocks": ["image"] },
+//             { "name": "chapters", "cardinality": "1..", "blocks": ["text"], "blueprints": ["page"] }
+//         ]
+//     }
+//
+// A cardinality is either an exact count, "1" by default, or a range, "0..1",
+// open ended when it has no upper bound, "1..".  Blueprints refer to the
+// blueprints they nest by name, so a library of blueprints validates them
+// together.
 
-pub trait Blueprint {
-    /// Create a blueprint from another
-    fn derive() -> Self;
+use std::{collections::{BTreeMap, BTreeSet}, fmt, fs, io, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::connection::{Connection, ConnectionType};
+
+/// Types of blocks slots may accept.
+pub const BLOCK_TYPES: [&str; 5] = ["text", "image", "audio", "heap", "config"];
+
+/// Name of the built-in page blueprint.
+pub const PAGE: &str = "page";
+
+/// Name of the built-in book blueprint.
+pub const BOOK: &str = "book";
+
+/// Describes the slots of an assembly.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Blueprint {
+    #[serde(default = "Ulid::new")]
+    pub id: Ulid,
+    pub name: String,
+    pub slots: Vec<Slot>,
+    /// The derivation or fork connection from the blueprint this one was
+    /// created from.
+    #[serde(default)]
+    origin: Option<Connection>,
+}
+
+/// A named part of an assembly.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Slot {
+    pub name: String,
+    #[serde(default)]
+    pub cardinality: Cardinality,
+    /// Types of blocks the slot accepts, e.g. `text`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocks: Vec<String>,
+    /// Names of the blueprints whose assemblies the slot accepts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blueprints: Vec<String>,
+}
+
+/// How many items a slot takes.  Without a maximum there is no limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cardinality {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Cardinality {
+    pub const ONE: Cardinality = Cardinality { min: 1, max: Some(1) };
+    pub const OPTIONAL: Cardinality = Cardinality { min: 0, max: Some(1) };
+    pub const ANY: Cardinality = Cardinality { min: 0, max: None };
+    pub const SOME: Cardinality = Cardinality { min: 1, max: None };
+
+    pub fn allows(&self, count: usize) -> bool {
+        count >= self.min && self.max.is_none_or(|max| count <= max)
+    }
+
+    /// Whether the slot may hold a number of items at all.
+    pub fn is_valid(&self) -> bool {
+        self.max.is_none_or(|max| max > 0 && max >= self.min)
+    }
+}
+
+impl Default for Cardinality {
+    fn default() -> Self {
+        Cardinality::ONE
+    }
+}
+
+impl FromStr for Cardinality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let count = |n: &str| n.trim().parse::<usize>().map_err(|_| format!("invalid cardinality {}", s));
+        match s.split_once("..") {
+            None => count(s).map(|n| Cardinality { min: n, max: Some(n) }),
+            Some((min, max)) if max.trim().is_empty() => Ok(Cardinality { min: count(min)?, max: None }),
+            Some((min, max)) => Ok(Cardinality { min: count(min)?, max: Some(count(max)?) }),
+        }
+    }
+}
+
+impl TryFrom<String> for Cardinality {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cardinality> for String {
+    fn from(value: Cardinality) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for Cardinality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{}..{}", self.min, max),
+            None => write!(f, "{}..", self.min),
+        }
+    }
+}
+
+/// A structural error in a blueprint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlueprintError {
+    Unnamed,
+    NoSlots,
+    UnnamedSlot,
+    DuplicateSlot(String),
+    /// The slot accepts neither blocks nor assemblies.
+    EmptySlot(String),
+    /// The slot can never hold anything, or its minimum exceeds its maximum.
+    InvalidCardinality { slot: String, cardinality: Cardinality },
+    UnknownBlockType { slot: String, block_type: String },
+    UnknownBlueprint { slot: String, blueprint: String },
+    /// Every way to build the blueprint requires an assembly of itself,
+    /// directly or through nested blueprints.
+    Unbuildable(String),
+}
+
+impl fmt::Display for BlueprintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlueprintError::Unnamed => write!(f, "blueprint has no name"),
+            BlueprintError::NoSlots => write!(f, "blueprint has no slots"),
+            BlueprintError::UnnamedSlot => write!(f, "slot has no name"),
+            BlueprintError::DuplicateSlot(slot) => write!(f, "slot {} is defined more than once", slot),
+            BlueprintError::EmptySlot(slot) => write!(f, "slot {} accepts neither blocks nor blueprints", slot),
+            BlueprintError::InvalidCardinality { slot, cardinality } => {
+                write!(f, "slot {} has an invalid cardinality {}", slot, cardinality)
+            }
+            BlueprintError::UnknownBlockType { slot, block_type } => {
+                write!(f, "slot {} accepts unknown block type {}", slot, block_type)
+            }
+            BlueprintError::UnknownBlueprint { slot, blueprint } => {
+                write!(f, "slot {} nests unknown blueprint {}", slot, blueprint)
+            }
+            BlueprintError::Unbuildable(name) => write!(f, "blueprint {} can only be built from itself", name),
+        }
+    }
+}
+
+impl Blueprint {
     /// Create an empty blueprint
-    fn create() -> Self;
-    /// Destroy an blueprint
-    fn destroy();
-    /// Create an blueprint from resources outside the spaceport i.e.,
-    /// documents
-    fn import() -> Self;
-    /// Send an blueprint to consumers outside the spaceport
-    fn export(&self);
-}
\ No newline at end of file
+    pub fn new(name: impl Into<String>) -> Blueprint {
+        Blueprint { id: Ulid::new(), name: name.into(), slots: Vec::new(), origin: None }
+    }
+    /// Create a blueprint from another.  Assemblies built from the original
+    /// are not affected by changes to the derived blueprint.
+    pub fn derive(&self, name: impl Into<String>) -> Blueprint {
+        let id = Ulid::new();
+        Blueprint {
+            id,
+            name: name.into(),
+            slots: self.slots.clone(),
+            origin: Some(Connection::new(self.id, id, ConnectionType::Derivation)),
+        }
+    }
+    /// Load a blueprint from file
+    pub fn from_file(path: &Path) -> io::Result<Blueprint> {
+        let bytes = fs::read(path)?;
+        serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    /// Save a blueprint to file
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, json)
+    }
+
+    /// Adds a slot accepting blocks of the given types and assemblies of
+    /// the given blueprints.
+    pub fn with_slot(mut self, name: &str, cardinality: Cardinality, blocks: &[&str], blueprints: &[&str]) -> Blueprint {
+        self.slots.push(Slot {
+            name: name.to_string(),
+            cardinality,
+            blocks: blocks.iter().map(|block| block.to_string()).collect(),
+            blueprints: blueprints.iter().map(|blueprint| blueprint.to_string()).collect(),
+        });
+        self
+    }
+
+    /// A page: an optional heading above text and images.
+    pub fn page() -> Blueprint {
+        Blueprint { id: Ulid(1), ..Blueprint::new(PAGE) }
+            .with_slot("heading", Cardinality::OPTIONAL, &["text"], &[])
+            .with_slot("body", Cardinality::SOME, &["text", "image"], &[])
+    }
+
+    /// A book: a title, an optional cover, and chapters that are either
+    /// text blocks or pages.
+    pub fn book() -> Blueprint {
+        Blueprint { id: Ulid(2), ..Blueprint::new(BOOK) }
+            .with_slot("title", Cardinality::ONE, &["text"], &[])
+            .with_slot("cover", Cardinality::OPTIONAL, &["image"], &[])
+            .with_slot("chapters", Cardinality::SOME, &["text"], &[PAGE])
+    }
+
+    pub fn slot(&self, name: &str) -> Option<&Slot> {
+        self.slots.iter().find(|slot| slot.name == name)
+    }
+
+    pub fn origin(&self) -> Option<Connection> {
+        self.origin.clone()
+    }
+}
+
+impl Slot {
+    pub fn accepts_block(&self, block_type: &str) -> bool {
+        self.blocks.iter().any(|accepted| accepted == block_type)
+    }
+
+    pub fn accepts_blueprint(&self, name: &str) -> bool {
+        self.blueprints.iter().any(|accepted| accepted == name)
+    }
+}
+
+/// Blueprints known to a spaceport, by name.
+#[derive(Clone, Debug, Default)]
+pub struct Library {
+    blueprints: BTreeMap<String, Blueprint>,
+}
+
+impl Library {
+    /// A library of the built-in page and book blueprints.
+    pub fn builtin() -> Library {
+        let mut library = Library::default();
+        for blueprint in [Blueprint::page(), Blueprint::book()] {
+            library.blueprints.insert(blueprint.name.clone(), blueprint);
+        }
+        library
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Blueprint> {
+        self.blueprints.get(name)
+    }
+
+    pub fn blueprints(&self) -> impl Iterator<Item = &Blueprint> {
+        self.blueprints.values()
+    }
+
+    /// Adds a blueprint once it validates, replacing the blueprint of the
+    /// same name.
+    pub fn insert(&mut self, blueprint: Blueprint) -> Result<(), Vec<BlueprintError>> {
+        self.validate(&blueprint)?;
+        self.blueprints.insert(blueprint.name.clone(), blueprint);
+        Ok(())
+    }
+
+    /// Reports every structural error of a blueprint, taking it to replace
+    /// the blueprint of the same name in the library.
+    pub fn validate(&self, blueprint: &Blueprint) -> Result<(), Vec<BlueprintError>> {
+        let mut errors = Vec::new();
+        if blueprint.name.trim().is_empty() {
+            errors.push(BlueprintError::Unnamed);
+        }
+        if blueprint.slots.is_empty() {
+            errors.push(BlueprintError::NoSlots);
+        }
+
+        let mut names = BTreeSet::new();
+        for slot in &blueprint.slots {
+            if slot.name.trim().is_empty() {
+                errors.push(BlueprintError::UnnamedSlot);
+            } else if !names.insert(slot.name.as_str()) {
+                errors.push(BlueprintError::DuplicateSlot(slot.name.clone()));
+            }
+            if slot.blocks.is_empty() && slot.blueprints.is_empty() {
+                errors.push(BlueprintError::EmptySlot(slot.name.clone()));
+            }
+            if !slot.cardinality.is_valid() {
+                errors.push(BlueprintError::InvalidCardinality {
+                    slot: slot.name.clone(),
+                    cardinality: slot.cardinality,
+                });
+            }
+            for block_type in slot.blocks.iter().filter(|block| !BLOCK_TYPES.contains(&block.as_str())) {
+                errors.push(BlueprintError::UnknownBlockType {
+                    slot: slot.name.clone(),
+                    block_type: block_type.clone(),
+                });
+            }
+            for nested in &slot.blueprints {
+                if *nested != blueprint.name && !self.blueprints.contains_key(nested) {
+                    errors.push(BlueprintError::UnknownBlueprint {
+                        slot: slot.name.clone(),
+                        blueprint: nested.clone(),
+                    });
+                }
+            }
+        }
+
+        if errors.is_empty() && !self.buildable(blueprint).contains(blueprint.name.as_str()) {
+            errors.push(BlueprintError::Unbuildable(blueprint.name.clone()));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Names of the blueprints that can be built from blocks alone, with
+    /// `blueprint` in place of its namesake.  A blueprint can be built when
+    /// each of its required slots accepts blocks or a buildable blueprint.
+    fn buildable<'a>(&'a self, blueprint: &'a Blueprint) -> BTreeSet<&'a str> {
+        let mut all: BTreeMap<&str, &Blueprint> = self.blueprints
+            .iter()
+            .map(|(name, blueprint)| (name.as_str(), blueprint))
+            .collect();
+        all.insert(blueprint.name.as_str(), blueprint);
+
+        let mut buildable = BTreeSet::new();
+        loop {
+            let found: Vec<&str> = all
+                .iter()
+                .filter(|(name, _)| !buildable.contains(*name))
+                .filter(|(_, blueprint)| {
+                    blueprint.slots.iter().all(|slot| {
+                        slot.cardinality.min == 0
+                            || !slot.blocks.is_empty()
+                            || slot.blueprints.iter().any(|nested| buildable.contains(nested.as_str()))
+                    })
+                })
+                .map(|(name, _)| *name)
+                .collect();
+            if found.is_empty() {
+                return buildable;
+            }
+            buildable.extend(found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cardinality() {
+        for (text, cardinality) in [
+            ("1", Cardinality::ONE),
+            ("0..1", Cardinality::OPTIONAL),
+            ("0..", Cardinality::ANY),
+            ("1..", Cardinality::SOME),
+            ("2..4", Cardinality { min: 2, max: Some(4) }),
+        ] {
+            assert_eq!(text.parse::<Cardinality>().unwrap(), cardinality);
+            assert_eq!(cardinality.to_string(), text);
+        }
+        assert!("many".parse::<Cardinality>().is_err());
+        assert!("1..x".parse::<Cardinality>().is_err());
+        assert!(Cardinality::SOME.allows(7) && !Cardinality::SOME.allows(0));
+        assert!(!Cardinality::OPTIONAL.allows(2));
+        assert!(!Cardinality { min: 3, max: Some(2) }.is_valid());
+        assert!(!Cardinality { min: 0, max: Some(0) }.is_valid());
+    }
+
+    #[test]
+    fn builtin_blueprints_are_valid() {
+        let library = Library::builtin();
+        for blueprint in library.blueprints() {
+            assert_eq!(library.validate(blueprint), Ok(()));
+        }
+        assert!(library.get(BOOK).unwrap().slot("chapters").unwrap().accepts_blueprint(PAGE));
+        assert_eq!(Blueprint::page().id, library.get(PAGE).unwrap().id);
+    }
+
+    #[test]
+    fn reports_structural_errors() {
+        let library = Library::builtin();
+        let mut broken = Blueprint::new("")
+            .with_slot("body", Cardinality { min: 2, max: Some(1) }, &["text", "hologram"], &[])
+            .with_slot("body", Cardinality::ONE, &[], &[])
+            .with_slot("shelf", Cardinality::ANY, &[], &["bookshelf"]);
+        assert_eq!(library.validate(&broken).unwrap_err(), vec![
+            BlueprintError::Unnamed,
+            BlueprintError::InvalidCardinality { slot: "body".to_string(), cardinality: Cardinality { min: 2, max: Some(1) } },
+            BlueprintError::UnknownBlockType { slot: "body".to_string(), block_type: "hologram".to_string() },
+            BlueprintError::DuplicateSlot("body".to_string()),
+            BlueprintError::EmptySlot("body".to_string()),
+            BlueprintError::UnknownBlueprint { slot: "shelf".to_string(), blueprint: "bookshelf".to_string() },
+        ]);
+
+        broken.slots.clear();
+        broken.name = "nothing".to_string();
+        assert_eq!(library.validate(&broken).unwrap_err(), vec![BlueprintError::NoSlots]);
+    }
+
+    #[test]
+    fn nesting() {
+        let mut library = Library::builtin();
+        let bookshelf = Blueprint::new("bookshelf").with_slot("books", Cardinality::SOME, &[], &[BOOK]);
+        library.insert(bookshelf).unwrap();
+
+        // Sections may hold sections, as long as they need not.
+        let section = Blueprint::new("section")
+            .with_slot("text", Cardinality::SOME, &["text"], &[])
+            .with_slot("subsections", Cardinality::ANY, &[], &["section"]);
+        library.insert(section).unwrap();
+
+        // A matryoshka needs a smaller matryoshka, forever.
+        let matryoshka = Blueprint::new("matryoshka").with_slot("inner", Cardinality::ONE, &[], &["doll"]);
+        let doll = Blueprint::new("doll").with_slot("inner", Cardinality::ONE, &[], &["matryoshka"]);
+        assert!(library.insert(doll.clone()).is_err());
+        library.blueprints.insert(doll.name.clone(), doll);
+        assert_eq!(
+            library.validate(&matryoshka).unwrap_err(),
+            vec![BlueprintError::Unbuildable("matryoshka".to_string())]
+        );
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let path = std::env::temp_dir().join(format!("blueprint-{}.json", Ulid::new()));
+        let original = Blueprint::book().derive("novel");
+        original.save(&path).unwrap();
+        let loaded = Blueprint::from_file(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(loaded, original);
+        assert_eq!(loaded.origin().unwrap().src, Blueprint::book().id);
+
+        let written: Blueprint = serde_json::from_str(r#"{
+            "name": "card",
+            "slots": [{ "name": "face", "blocks": ["image"] }, { "name": "notes", "cardinality": "0..", "blocks": ["text"] }]
+        }"#).unwrap();
+        assert_eq!(written.slot("face").unwrap().cardinality, Cardinality::ONE);
+        assert_eq!(written.slot("notes").unwrap().cardinality, Cardinality::ANY);
+        assert_eq!(Library::builtin().validate(&written), Ok(()));
+    }
+}
@@ -1,14 +1,34 @@
 pub mod block;
-mod blueprint;
-mod assembly;
+pub mod blueprint;
+pub mod assembly;
+pub mod vault;
 mod file;
 
+use std::{io, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
 use ulid::Ulid;
 
+use crate::connection::Connection;
+
 /// 
 pub trait Asset {
     /// Get asset ID
     fn id(&self) -> Ulid;
+    /// Get the kind of asset
+    fn asset_type(&self) -> AssetType;
+    /// Create a new asset from another (crop, slice, paste, etc)
+    fn derive(&self) -> Self;
+    /// Create an alternative version of an asset
+    fn fork(&self) -> Self;
+    /// The derivation or fork connection from the asset this one was created
+    /// from.  The holobank records it when the asset is registered.
+    fn origin(&self) -> Option<Connection>;
+}
+
+/// Changes made to an asset itself that it keeps in step with the holobank.
+/// Assets without it are named, tagged and flagged in the holobank only.
+pub trait Managed: Asset {
     /// Set asset name
     fn name(&self);
     /// Add a tag to the asset
@@ -25,12 +45,10 @@ pub trait Asset {
     fn upload();
     /// Downloads asset data from holobank.  Not materialize.
     fn download() -> Self;
-    /// Create a new asset from another (crop, slice, paste, etc)
-    fn derive() -> Self;
-    /// Create an alternative version of an asset
-    fn fork() -> Self;
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AssetType {
     Block,
     Blueprint,
@@ -38,6 +56,31 @@ pub enum AssetType {
     File,
 }
 
+impl AssetType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AssetType::Block => "block",
+            AssetType::Blueprint => "blueprint",
+            AssetType::Assembly => "assembly",
+            AssetType::File => "file",
+        }
+    }
+}
+
+impl FromStr for AssetType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(AssetType::Block),
+            "blueprint" => Ok(AssetType::Blueprint),
+            "assembly" => Ok(AssetType::Assembly),
+            "file" => Ok(AssetType::File),
+            other => Err(format!("unknown asset type {}", other)),
+        }
+    }
+}
+
 pub struct AssetState {
     held: bool,
     here: bool,
@@ -67,7 +110,15 @@ pub trait Holographable {
 pub trait Materializable {
     /// Scan and upload asset current state to holobank.  Generally called upon
     /// remote query.
-    fn scan();
+    fn scan(&self) -> Holoframe;
+}
+
+/// The state of an asset at a point in time, as stored in the holobank.
+pub struct Holoframe {
+    pub content_type: String,
+    pub content: Vec<u8>,
+    /// Plain text of the asset, if any, for full-text search.
+    pub text: Option<String>,
 }
 
 pub struct Hologram<T: Asset + Holographable + Materializable> {
@@ -84,13 +135,19 @@ pub enum AssetError {
     UndefinedError
 }
 
-pub trait Import {
+pub trait Import: Sized {
     /// Create a block from resources outside the spaceport i.e.,
-    /// documents
-    fn import() -> Self;
+    /// documents, read from `path`
+    fn import(path: &Path) -> io::Result<Self>;
 }
 
 pub trait Export {
-     /// Send a block to consumers outside the spaceport
-     fn export(&self);
+    /// What the asset can be written as, e.g. Markdown or EPUB.
+    type Format;
+    /// Where the assets it is made of are read from.
+    type Source: ?Sized;
+
+    /// Send an asset to consumers outside the spaceport, written to `path`
+    /// in `format`.  `source` provides the assets it is made of.
+    fn export(&self, format: Self::Format, source: &Self::Source, path: &Path) -> io::Result<()>;
 }
\ No newline at end of file
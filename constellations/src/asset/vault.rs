@@ -0,0 +1,331 @@
+// A vault is a directory of Markdown notes and their attachments, as kept by
+// Obsidian and similar editors.  Notes link to each other and embed
+// attachments with `[[wikilinks]]`, which name a note by its file name
+// without `.md`, or by its path in the vault when the name is ambiguous.
+// Notes carry tags in a YAML frontmatter.  Hidden files and folders, e.g. the
+// editor's `.obsidian` settings, are not part of the vault.
+
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Extensions of notes.
+pub const NOTE_EXTENSIONS: [&str; 2] = ["md", "markdown"];
+
+/// Extensions of attachments imported as images.
+pub const IMAGE_EXTENSIONS: [&str; 8] = ["png", "jpg", "jpeg", "gif", "webp", "svg", "bmp", "avif"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Note,
+    Image,
+    /// Any other attachment.
+    Attachment,
+}
+
+/// A file of a vault, by its path in the vault with `/` separators.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VaultFile {
+    pub path: String,
+    pub kind: FileKind,
+}
+
+impl VaultFile {
+    /// The path of the folder holding the file; empty for the vault itself.
+    pub fn folder(&self) -> &str {
+        self.path.rsplit_once('/').map_or("", |(folder, _)| folder)
+    }
+
+    pub fn file_name(&self) -> &str {
+        self.path.rsplit_once('/').map_or(&self.path, |(_, name)| name)
+    }
+
+    /// The name links use: the file name, without the extension for notes.
+    pub fn link_name(&self) -> &str {
+        match self.kind {
+            FileKind::Note => strip_note_extension(self.file_name()),
+            _ => self.file_name(),
+        }
+    }
+}
+
+/// A `[[wikilink]]`, or an `![[embed]]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Wikilink {
+    /// The note or attachment linked to, without heading or alias.
+    pub target: String,
+    pub embed: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Vault {
+    root: PathBuf,
+    /// Files ordered by path.
+    files: Vec<VaultFile>,
+    /// Folders ordered by path, the vault itself first as the empty path.
+    folders: Vec<String>,
+}
+
+impl Vault {
+    /// Lists the notes, attachments and folders of the vault at `root`.
+    /// Symbolic links are not followed.
+    pub fn scan(root: &Path) -> io::Result<Vault> {
+        let mut files = Vec::new();
+        let mut folders = vec![String::new()];
+        let mut pending = vec![String::new()];
+        while let Some(folder) = pending.pop() {
+            for entry in fs::read_dir(root.join(&folder))? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') {
+                    continue;
+                }
+                let path = if folder.is_empty() { name.clone() } else { format!("{}/{}", folder, name) };
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    folders.push(path.clone());
+                    pending.push(path);
+                } else if file_type.is_file() {
+                    files.push(VaultFile { kind: kind_of(&name), path });
+                }
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        folders.sort();
+        Ok(Vault { root: root.to_path_buf(), files, folders })
+    }
+
+    /// Name of the vault, the name of its directory.
+    pub fn name(&self) -> String {
+        self.root
+            .file_name()
+            .map_or_else(|| "vault".to_string(), |name| name.to_string_lossy().into_owned())
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn files(&self) -> &[VaultFile] {
+        &self.files
+    }
+
+    pub fn folders(&self) -> &[String] {
+        &self.folders
+    }
+
+    /// Where a file of the vault is on disk.
+    pub fn path(&self, file: &VaultFile) -> PathBuf {
+        file.path.split('/').fold(self.root.clone(), |path, part| path.join(part))
+    }
+
+    /// The file a link in the note at `from` leads to.  Links with a folder
+    /// name the path of the file; otherwise the file name decides, and of
+    /// several files with the name the one closest to the note wins: in the
+    /// same folder, then with the shortest path.  Names ignore case.
+    pub fn resolve(&self, from: &str, target: &str) -> Option<&VaultFile> {
+        let target = strip_note_extension(target.trim().trim_start_matches('/'));
+        if target.is_empty() {
+            return None;
+        }
+        let from_folder = from.rsplit_once('/').map_or("", |(folder, _)| folder);
+        self.files
+            .iter()
+            .filter(|file| {
+                if target.contains('/') {
+                    let path = match file.kind {
+                        FileKind::Note => strip_note_extension(&file.path),
+                        _ => &file.path,
+                    };
+                    path.eq_ignore_ascii_case(target)
+                } else {
+                    file.link_name().eq_ignore_ascii_case(target)
+                }
+            })
+            .min_by_key(|file| (file.folder() != from_folder, file.path.len(), file.path.as_str()))
+    }
+}
+
+fn kind_of(name: &str) -> FileKind {
+    let extension = name.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some(extension) if NOTE_EXTENSIONS.contains(&extension) => FileKind::Note,
+        Some(extension) if IMAGE_EXTENSIONS.contains(&extension) => FileKind::Image,
+        _ => FileKind::Attachment,
+    }
+}
+
+fn strip_note_extension(name: &str) -> &str {
+    NOTE_EXTENSIONS
+        .iter()
+        .find_map(|extension| {
+            let (stem, found) = name.rsplit_once('.')?;
+            found.eq_ignore_ascii_case(extension).then_some(stem)
+        })
+        .unwrap_or(name)
+}
+
+/// The frontmatter of a note: the lines between a `---` on the first line
+/// and the next `---` or `...`.
+fn frontmatter(note: &str) -> Option<Vec<&str>> {
+    let mut lines = note.lines();
+    if lines.next()?.trim_end() != "---" {
+        return None;
+    }
+    let mut found = Vec::new();
+    for line in lines {
+        if matches!(line.trim_end(), "---" | "...") {
+            return Some(found);
+        }
+        found.push(line);
+    }
+    None
+}
+
+/// Tags of a note's frontmatter `tags` or `tag`, as a list, a flow sequence
+/// or a string of tags separated by commas or spaces.  Leading `#`s are
+/// dropped.
+pub fn tags(note: &str) -> BTreeSet<String> {
+    let mut tags = BTreeSet::new();
+    let Some(lines) = frontmatter(note) else {
+        return tags;
+    };
+    let mut add = |value: &str| {
+        for tag in value.split(|c: char| c == ',' || c.is_whitespace()) {
+            let tag = tag.trim_matches(|c| c == '"' || c == '\'').trim_start_matches('#');
+            if !tag.is_empty() {
+                tags.insert(tag.to_string());
+            }
+        }
+    };
+    let mut in_list = false;
+    for line in lines {
+        if in_list {
+            if let Some(item) = line.trim_start().strip_prefix('-').filter(|_| line.starts_with([' ', '\t', '-'])) {
+                add(item);
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            in_list = false;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        if !matches!(key.trim(), "tags" | "tag") || line.starts_with([' ', '\t']) {
+            continue;
+        }
+        let value = value.trim();
+        if value.is_empty() {
+            in_list = true;
+        } else {
+            add(value.trim_start_matches('[').trim_end_matches(']'));
+        }
+    }
+    tags
+}
+
+/// The wikilinks and embeds of a note, in order, leaving out those in code.
+pub fn wikilinks(note: &str) -> Vec<Wikilink> {
+    let mut links = Vec::new();
+    let mut fenced = false;
+    for line in note.lines() {
+        if line.trim_start().starts_with("```") || line.trim_start().starts_with("~~~") {
+            fenced = !fenced;
+            continue;
+        }
+        if fenced {
+            continue;
+        }
+        let mut code = false;
+        let mut rest = line;
+        while let Some(start) = rest.find(['`', '[']) {
+            if rest[start..].starts_with('`') {
+                code = !code;
+                rest = &rest[start + 1..];
+                continue;
+            }
+            if code || !rest[start..].starts_with("[[") {
+                rest = &rest[start + 1..];
+                continue;
+            }
+            let Some(end) = rest[start + 2..].find("]]") else {
+                break;
+            };
+            let inner = &rest[start + 2..start + 2 + end];
+            let embed = rest[..start].ends_with('!');
+            let target = inner.split(['|', '#', '^']).next().unwrap_or("").trim();
+            if !target.is_empty() {
+                links.push(Wikilink { target: target.to_string(), embed });
+            }
+            rest = &rest[start + 4 + end..];
+        }
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_tags_and_links() {
+        let note = "---\ntitle: Trip\ntags:\n  - travel\n  - '#planning'\naliases: [journey]\n---\n\
+            # Trip\nSee [[Packing list|what to pack]] and [[Budget#Food]].\n![[map.png]]\n\
+            `[[not a link]]`\n```\n[[also not]]\n```\n";
+        assert_eq!(tags(note), BTreeSet::from(["travel".to_string(), "planning".to_string()]));
+        assert_eq!(
+            wikilinks(note),
+            vec![
+                Wikilink { target: "Packing list".to_string(), embed: false },
+                Wikilink { target: "Budget".to_string(), embed: false },
+                Wikilink { target: "map.png".to_string(), embed: true },
+            ]
+        );
+        assert_eq!(tags("---\ntags: [a, \"b\"]\n---\n"), BTreeSet::from(["a".to_string(), "b".to_string()]));
+        assert_eq!(tags("---\ntag: a b, #c\n---\n").len(), 3);
+        assert!(tags("tags: [a]\n").is_empty());
+    }
+
+    #[test]
+    fn scans_and_resolves() {
+        let root = std::env::temp_dir().join(format!("vault-{}", ulid::Ulid::new()));
+        for (path, content) in [
+            ("Index.md", "[[Budget]]"),
+            ("trips/Budget.md", ""),
+            ("trips/Packing list.md", "[[Budget]]"),
+            ("work/Budget.md", ""),
+            ("work/map.png", ""),
+            ("work/notes.pdf", ""),
+            (".obsidian/app.json", "{}"),
+        ] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        let vault = Vault::scan(&root).unwrap();
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(vault.folders(), ["", "trips", "work"]);
+        let kinds: Vec<(&str, FileKind)> = vault.files().iter().map(|file| (file.path.as_str(), file.kind)).collect();
+        assert_eq!(kinds, vec![
+            ("Index.md", FileKind::Note),
+            ("trips/Budget.md", FileKind::Note),
+            ("trips/Packing list.md", FileKind::Note),
+            ("work/Budget.md", FileKind::Note),
+            ("work/map.png", FileKind::Image),
+            ("work/notes.pdf", FileKind::Attachment),
+        ]);
+
+        let resolved = |from: &str, target: &str| vault.resolve(from, target).map(|file| file.path.as_str());
+        assert_eq!(resolved("trips/Packing list.md", "Budget"), Some("trips/Budget.md"));
+        assert_eq!(resolved("Index.md", "budget"), Some("work/Budget.md"));
+        assert_eq!(resolved("Index.md", "trips/Budget"), Some("trips/Budget.md"));
+        assert_eq!(resolved("Index.md", "map.png"), Some("work/map.png"));
+        assert_eq!(resolved("Index.md", "Packing list.md"), Some("trips/Packing list.md"));
+        assert_eq!(resolved("Index.md", "Nowhere"), None);
+    }
+}
@@ -0,0 +1,291 @@
+// A collection is exported as a plain directory: a file per asset with
+// content, named by its id, and `manifest.json` describing the collection and
+// everything the bank knows of its assets besides their content, so the
+// directory can be imported again into an identical collection.  Texts are
+// written as Markdown, with their edit history beside them when the source
+// keeps it, images with the extension of their format, assemblies as JSON and
+// other content as it is stored.  Files named in a manifest are only read from
+// the directory itself.
+
+use std::{collections::BTreeSet, fs, io, path::{Component, Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use super::Collection;
+use crate::{
+    asset::{
+        assembly::{
+            export::{image_type, Content, ExportFormat, Source},
+            Assembly,
+        },
+        block::{image::Image, text::Text},
+        AssetType, Export, Import,
+    },
+    connection::Connection,
+};
+
+/// Name of the manifest in an exported collection.
+pub const MANIFEST: &str = "manifest.json";
+
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Everything known of an asset besides its content.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub asset_type: AssetType,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub flags: BTreeSet<String>,
+    /// Connections from and to the asset.
+    #[serde(default)]
+    pub connections: Vec<Connection>,
+}
+
+impl Record {
+    pub fn new(asset_type: AssetType) -> Record {
+        Record { asset_type, name: None, tags: BTreeSet::new(), flags: BTreeSet::new(), connections: Vec::new() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub collection: Collection,
+    /// Assets of the collection, ordered by id.
+    pub assets: Vec<Entry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: Ulid,
+    #[serde(flatten)]
+    pub record: Record,
+    /// Type of the content, absent for assets without content.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// File holding the content, next to the manifest.
+    #[serde(default)]
+    pub file: Option<String>,
+    /// File holding the stored text with its edit history, next to the
+    /// manifest.
+    #[serde(default)]
+    pub history: Option<String>,
+}
+
+impl Manifest {
+    /// Reads the manifest of the collection exported to `directory`.
+    pub fn read(directory: &Path) -> io::Result<Manifest> {
+        let bytes = fs::read(directory.join(MANIFEST))?;
+        let manifest: Manifest = serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if manifest.version > MANIFEST_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("manifest version {} is newer than {}", manifest.version, MANIFEST_VERSION),
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// The content of an entry as written to `directory`.
+    pub fn content(&self, directory: &Path, entry: &Entry) -> io::Result<Option<Content>> {
+        let (Some(content_type), Some(file)) = (&entry.content_type, &entry.file) else {
+            return Ok(None);
+        };
+        let bytes = fs::read(beside(directory, file)?)?;
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, e));
+        Ok(Some(match content_type.as_str() {
+            Text::CONTENT_TYPE => Content::Text(String::from_utf8(bytes).map_err(|e| invalid(e.to_string()))?),
+            Image::CONTENT_TYPE => Content::Image(bytes),
+            Assembly::CONTENT_TYPE => {
+                Content::Assembly(Box::new(serde_json::from_slice(&bytes).map_err(|e| invalid(e.to_string()))?))
+            }
+            _ => Content::Other { content_type: content_type.clone(), bytes },
+        }))
+    }
+
+    /// The text of an entry with the edit history written to `directory`.
+    /// `None` if none was written.
+    pub fn history(&self, directory: &Path, entry: &Entry) -> io::Result<Option<Text>> {
+        let Some(file) = &entry.history else {
+            return Ok(None);
+        };
+        let text = Text::decode(&fs::read(beside(directory, file)?)?)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{}: not a stored text", file)))?;
+        Ok(Some(text))
+    }
+}
+
+/// Path of a file named in a manifest, which must be a plain file name so
+/// nothing outside `directory` is read.
+fn beside(directory: &Path, file: &str) -> io::Result<PathBuf> {
+    let mut components = Path::new(file).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(directory.join(file)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a file next to the manifest", file))),
+    }
+}
+
+impl Export for Collection {
+    type Format = ExportFormat;
+    type Source = dyn Source;
+
+    /// Writes the collection to the directory `path`.  Collections are only
+    /// exported as Markdown.
+    fn export(&self, format: ExportFormat, source: &dyn Source, path: &Path) -> io::Result<()> {
+        if format != ExportFormat::Markdown {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "collections are exported as Markdown"));
+        }
+        fs::create_dir_all(path)?;
+        let mut assets = Vec::new();
+        for (id, asset_type) in self.assets() {
+            let record = source.record(id).unwrap_or_else(|| Record::new(asset_type));
+            let (content_type, file, bytes) = match source.resolve(id) {
+                Some(Content::Text(text)) => (Text::CONTENT_TYPE.to_string(), format!("{}.md", id), text.into_bytes()),
+                Some(Content::Image(bytes)) => {
+                    let (_, extension) = image_type(&bytes);
+                    (Image::CONTENT_TYPE.to_string(), format!("{}.{}", id, extension), bytes)
+                }
+                Some(Content::Assembly(assembly)) => {
+                    let json = serde_json::to_vec_pretty(&assembly).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    (Assembly::CONTENT_TYPE.to_string(), format!("{}.json", id), json)
+                }
+                Some(Content::Other { content_type, bytes }) => (content_type, format!("{}.bin", id), bytes),
+                None => {
+                    assets.push(Entry { id, record, content_type: None, file: None, history: None });
+                    continue;
+                }
+            };
+            fs::write(path.join(&file), bytes)?;
+            let history = match source.history(id) {
+                Some(bytes) if content_type == Text::CONTENT_TYPE => {
+                    let history = format!("{}.history", id);
+                    fs::write(path.join(&history), bytes)?;
+                    Some(history)
+                }
+                _ => None,
+            };
+            assets.push(Entry { id, record, content_type: Some(content_type), file: Some(file), history });
+        }
+
+        let manifest = Manifest { version: MANIFEST_VERSION, collection: self.clone(), assets };
+        let json = serde_json::to_vec_pretty(&manifest).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path.join(MANIFEST), json)
+    }
+}
+
+impl Import for Collection {
+    /// Reads the collection exported to the directory `path`.  Its assets are
+    /// restored by the holobank from the manifest.
+    fn import(path: &Path) -> io::Result<Self> {
+        Ok(Manifest::read(path)?.collection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{collection::CollectionQuery, connection::ConnectionType};
+
+    struct Bank {
+        content: HashMap<Ulid, Content>,
+        records: HashMap<Ulid, Record>,
+    }
+
+    impl Source for Bank {
+        fn resolve(&self, id: Ulid) -> Option<Content> {
+            self.content.get(&id).cloned()
+        }
+
+        fn record(&self, id: Ulid) -> Option<Record> {
+            self.records.get(&id).cloned()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let (note, picture, heap, empty) = (Ulid::new(), Ulid::new(), Ulid::new(), Ulid::new());
+        let mut collection = Collection::new("exported");
+        for id in [note, picture, heap, empty] {
+            collection.add_asset(id, AssetType::Block);
+        }
+        collection.share_with(Ulid::new());
+        let mut note_record = Record::new(AssetType::Block);
+        note_record.name = Some("Note".to_string());
+        note_record.tags.insert("draft".to_string());
+        note_record.flags.insert("expired".to_string());
+        note_record.connections = vec![
+            Connection::new(note, picture, ConnectionType::from("link")),
+            Connection::new(Ulid::new(), note, ConnectionType::Derivation),
+        ];
+        let bank = Bank {
+            content: HashMap::from([
+                (note, Content::Text("# Note\n\nSee [[picture]].".to_string())),
+                (picture, Content::Image(b"\x89PNG\r\n".to_vec())),
+                (heap, Content::Other { content_type: "heap".to_string(), bytes: vec![0, 1, 2] }),
+            ]),
+            records: HashMap::from([(note, note_record.clone())]),
+        };
+
+        let directory = std::env::temp_dir().join(format!("collection-{}", Ulid::new()));
+        collection.export(ExportFormat::Markdown, &bank, &directory).unwrap();
+        assert!(collection.export(ExportFormat::Epub, &bank, &directory).is_err());
+        assert!(directory.join(format!("{}.md", note)).exists());
+        assert!(directory.join(format!("{}.png", picture)).exists());
+
+        let imported = Collection::import(&directory).unwrap();
+        let manifest = Manifest::read(&directory).unwrap();
+        let contents: HashMap<Ulid, Option<Content>> = manifest
+            .assets
+            .iter()
+            .map(|entry| (entry.id, manifest.content(&directory, entry).unwrap()))
+            .collect();
+        let _ = fs::remove_dir_all(&directory);
+
+        assert_eq!(imported, collection);
+        assert_eq!(manifest.assets.iter().map(|entry| entry.id).collect::<Vec<_>>(), collection.assets().map(|(id, _)| id).collect::<Vec<_>>());
+        let records: HashMap<Ulid, &Record> = manifest.assets.iter().map(|entry| (entry.id, &entry.record)).collect();
+        assert_eq!(records[&note], &note_record);
+        assert_eq!(records[&empty], &Record::new(AssetType::Block));
+        for id in [note, picture, heap] {
+            assert_eq!(contents[&id].as_ref(), bank.content.get(&id));
+        }
+        assert_eq!(contents[&empty], None);
+
+        let dynamic = Collection::dynamic("dynamic", CollectionQuery { tags: vec!["draft".to_string()], ..CollectionQuery::default() });
+        let directory = std::env::temp_dir().join(format!("collection-{}", Ulid::new()));
+        dynamic.export(ExportFormat::Markdown, &bank, &directory).unwrap();
+        let imported = Collection::import(&directory).unwrap();
+        let _ = fs::remove_dir_all(&directory);
+        assert_eq!(imported, dynamic);
+    }
+
+    #[test]
+    fn reads_only_files_next_to_the_manifest() {
+        let directory = std::env::temp_dir().join(format!("collection-{}", Ulid::new()));
+        fs::create_dir_all(directory.join("inner")).unwrap();
+        fs::write(directory.join("note.md"), "note").unwrap();
+        fs::write(directory.join("inner").join("note.md"), "inner").unwrap();
+        let manifest = Manifest { version: MANIFEST_VERSION, collection: Collection::new("paths"), assets: Vec::new() };
+        let entry = |file: &str| Entry {
+            id: Ulid::new(),
+            record: Record::new(AssetType::Block),
+            content_type: Some(Text::CONTENT_TYPE.to_string()),
+            file: Some(file.to_string()),
+            history: Some(file.to_string()),
+        };
+
+        assert_eq!(manifest.content(&directory, &entry("note.md")).unwrap(), Some(Content::Text("note".to_string())));
+        for file in ["../note.md", "inner/note.md", "./note.md", "/etc/passwd", ""] {
+            let entry = entry(file);
+            assert_eq!(manifest.content(&directory, &entry).unwrap_err().kind(), io::ErrorKind::InvalidData, "{}", file);
+            assert!(matches!(manifest.history(&directory, &entry), Err(e) if e.kind() == io::ErrorKind::InvalidData), "{}", file);
+        }
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
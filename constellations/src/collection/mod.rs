@@ -0,0 +1,314 @@
+pub mod export;
+
+use std::{collections::{BTreeMap, BTreeSet}, fs, io, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use super::asset::AssetType;
+use super::connection::{Connection, ConnectionType};
+
+
+/// A list of asset ids that can be shared between spaceports.
+/// The holobank keeps collections in its `collection` relation; on disk a
+/// collection is a JSON file.  The members of a dynamic collection are the
+/// assets matching its query, which the holobank keeps up to date.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Collection {
+    pub id: Ulid,
+    pub name: String,
+    assets: BTreeMap<Ulid, AssetType>,
+    /// The fork connection from the collection this one was created from.
+    #[serde(default)]
+    origin: Option<Connection>,
+    #[serde(default)]
+    pub policy: SharingPolicy,
+    /// Makes the collection dynamic.
+    #[serde(default)]
+    pub query: Option<CollectionQuery>,
+}
+
+/// Which assets belong to a dynamic collection.  Assets must meet every
+/// condition given; an empty query matches every asset.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CollectionQuery {
+    /// Tags the assets carry, all of them.
+    pub tags: Vec<String>,
+    /// Flags the assets carry, all of them.
+    pub flags: Vec<String>,
+    pub asset_type: Option<AssetType>,
+    /// Type of the assets' content, e.g. `text`.
+    pub content_type: Option<String>,
+    /// Text the assets' names contain, ignoring case.
+    pub name: Option<String>,
+    /// Ids the assets are connected to, in either direction.
+    pub connected_to: Vec<ConnectionFilter>,
+    /// Only assets registered in the last this many seconds.
+    pub max_age_secs: Option<u64>,
+}
+
+/// A connection members of a dynamic collection must have.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionFilter {
+    pub id: Ulid,
+    /// Any type when absent.
+    #[serde(default, rename = "type")]
+    pub kind: Option<ConnectionType>,
+}
+
+/// What a commander may do with a shared collection.  Writing implies
+/// reading.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Write,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Read => "read",
+            Role::Write => "write",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Role::Read),
+            "write" => Ok(Role::Write),
+            other => Err(format!("unknown role {}", other)),
+        }
+    }
+}
+
+/// Which spaceports track a collection and what their commanders may do
+/// with it.  A collection without replicas is private to its bank.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharingPolicy {
+    /// Spaceports and systems that replicate the collection.  Listing a
+    /// system shares the collection with every spaceport in it.
+    pub replicas: BTreeSet<Ulid>,
+    /// Roles of commanders.  Without any, every commander of a replica may
+    /// read and none may write.
+    pub roles: BTreeMap<Ulid, Role>,
+    /// Replicas keep the content of the collection from being evicted.
+    pub pinned: bool,
+}
+
+impl SharingPolicy {
+    pub fn is_private(&self) -> bool {
+        self.replicas.is_empty()
+    }
+
+    /// Whether a commander, or an anonymous request, may act in `role`.
+    /// Replicas are checked separately.
+    pub fn allows(&self, commander: Option<Ulid>, role: Role) -> bool {
+        if self.roles.is_empty() {
+            return role == Role::Read;
+        }
+        commander
+            .and_then(|commander| self.roles.get(&commander))
+            .is_some_and(|granted| *granted >= role)
+    }
+}
+
+impl Collection {
+    /// Create a new collection
+    pub fn new(name: impl Into<String>) -> Collection {
+        Collection::from_parts(Ulid::new(), name, None, [])
+    }
+    /// Rebuild a collection from what was stored of it
+    pub fn from_parts(
+        id: Ulid,
+        name: impl Into<String>,
+        origin: Option<Connection>,
+        assets: impl IntoIterator<Item = (Ulid, AssetType)>,
+    ) -> Collection {
+        Collection {
+            id,
+            name: name.into(),
+            assets: assets.into_iter().collect(),
+            origin,
+            policy: SharingPolicy::default(),
+            query: None,
+        }
+    }
+    /// Create a collection whose members are the assets matching `query`
+    pub fn dynamic(name: impl Into<String>, query: CollectionQuery) -> Collection {
+        let mut collection = Collection::new(name);
+        collection.query = Some(query);
+        collection
+    }
+    /// Fork an exisiting collection.  The fork starts with the same assets,
+    /// or query, and is connected to the original by a fork connection.  It
+    /// is not shared until given a policy of its own.
+    pub fn fork(&self, name: impl Into<String>) -> Collection {
+        let id = Ulid::new();
+        let origin = Connection::new(self.id, id, ConnectionType::Fork);
+        let mut fork = Collection::from_parts(id, name, Some(origin), self.assets.clone());
+        fork.query = self.query.clone();
+        fork
+    }
+    /// Load a collection from file
+    pub fn from_file(path: &Path) -> io::Result<Collection> {
+        let bytes = fs::read(path)?;
+        serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    /// Save a collection to file
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, json)
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        self.query.is_some()
+    }
+
+    /// Adds an asset, returning whether it was not in the collection yet.
+    /// The members of a dynamic collection are replaced whenever it is
+    /// refreshed.
+    pub fn add_asset(&mut self, id: Ulid, asset_type: AssetType) -> bool {
+        self.assets.insert(id, asset_type).is_none()
+    }
+
+    /// Removes an asset, returning its type if it was in the collection.
+    pub fn remove_asset(&mut self, id: Ulid) -> Option<AssetType> {
+        self.assets.remove(&id)
+    }
+
+    pub fn contains(&self, id: Ulid) -> bool {
+        self.assets.contains_key(&id)
+    }
+
+    /// Assets of the collection, ordered by id.
+    pub fn assets(&self) -> impl Iterator<Item = (Ulid, AssetType)> + '_ {
+        self.assets.iter().map(|(id, asset_type)| (*id, *asset_type))
+    }
+
+    pub fn len(&self) -> usize {
+        self.assets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assets.is_empty()
+    }
+
+    pub fn origin(&self) -> Option<Connection> {
+        self.origin.clone()
+    }
+
+    /// Lets a spaceport or system replicate the collection.
+    pub fn share_with(&mut self, replica: Ulid) {
+        self.policy.replicas.insert(replica);
+    }
+
+    pub fn unshare_with(&mut self, replica: Ulid) {
+        self.policy.replicas.remove(&replica);
+    }
+
+    pub fn grant(&mut self, commander: Ulid, role: Role) {
+        self.policy.roles.insert(commander, role);
+    }
+
+    pub fn revoke(&mut self, commander: Ulid) {
+        self.policy.roles.remove(&commander);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collection() -> Collection {
+        let mut collection = Collection::new("notes");
+        collection.add_asset(Ulid::new(), AssetType::Block);
+        collection.add_asset(Ulid::new(), AssetType::Assembly);
+        collection
+    }
+
+    #[test]
+    fn add_remove() {
+        let mut collection = Collection::new("notes");
+        let id = Ulid::new();
+
+        assert!(collection.add_asset(id, AssetType::Block));
+        assert!(!collection.add_asset(id, AssetType::Block));
+        assert!(collection.contains(id));
+        assert_eq!(collection.remove_asset(id), Some(AssetType::Block));
+        assert_eq!(collection.remove_asset(id), None);
+        assert!(collection.is_empty());
+    }
+
+    #[test]
+    fn fork() {
+        let original = collection();
+        let mut forked = original.fork("notes, again");
+        forked.add_asset(Ulid::new(), AssetType::File);
+
+        assert_ne!(forked.id, original.id);
+        assert_eq!(forked.origin(), Some(Connection::new(original.id, forked.id, ConnectionType::Fork)));
+        assert_eq!(forked.len(), 3);
+        assert_eq!(original.len(), 2);
+        assert!(original.origin().is_none());
+
+        let meetings = Collection::dynamic("meetings", CollectionQuery {
+            tags: vec!["meeting".to_string()],
+            ..CollectionQuery::default()
+        });
+        assert!(meetings.is_dynamic() && !original.is_dynamic());
+        assert_eq!(meetings.fork("copy").query, meetings.query);
+    }
+
+    #[test]
+    fn sharing_policy() {
+        let mut collection = collection();
+        let (reader, writer) = (Ulid::new(), Ulid::new());
+        assert!(collection.policy.is_private());
+        assert!(collection.policy.allows(None, Role::Read));
+        assert!(!collection.policy.allows(Some(reader), Role::Write));
+
+        collection.share_with(Ulid::new());
+        collection.grant(reader, Role::Read);
+        collection.grant(writer, Role::Write);
+        assert!(!collection.policy.is_private());
+        assert!(collection.policy.allows(Some(reader), Role::Read));
+        assert!(!collection.policy.allows(Some(reader), Role::Write));
+        assert!(collection.policy.allows(Some(writer), Role::Read));
+        assert!(collection.policy.allows(Some(writer), Role::Write));
+        assert!(!collection.policy.allows(None, Role::Read));
+        assert!(!collection.policy.allows(Some(Ulid::new()), Role::Read));
+        assert!(collection.fork("fork").policy.is_private());
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let path = std::env::temp_dir().join(format!("collection-{}.json", Ulid::new()));
+        let mut original = collection().fork("forked");
+        original.query = Some(CollectionQuery {
+            tags: vec!["meeting".to_string()],
+            asset_type: Some(AssetType::Block),
+            connected_to: vec![ConnectionFilter { id: Ulid::new(), kind: Some(ConnectionType::Fork) }],
+            max_age_secs: Some(30 * 24 * 60 * 60),
+            ..CollectionQuery::default()
+        });
+        original.share_with(Ulid::new());
+        original.grant(Ulid::new(), Role::Write);
+        original.policy.pinned = true;
+
+        original.save(&path).unwrap();
+        let loaded = Collection::from_file(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded, original);
+        assert_eq!(
+            Collection::from_file(&path).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
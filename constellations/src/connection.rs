@@ -0,0 +1,99 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+const PART_PREFIX: &str = "part:";
+
+/// Describes what a connection between two ids means.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum ConnectionType {
+    /// The destination was created from the source (crop, slice, paste, etc).
+    Derivation,
+    /// The destination is an alternative version of the source.
+    Fork,
+    /// The destination is a version that diverged from the source while
+    /// their banks were cut off, awaiting resolution.
+    Conflict,
+    /// The destination is an assembly built from the source blueprint.
+    Construction,
+    Other(String),
+}
+
+impl ConnectionType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ConnectionType::Derivation => "derivation",
+            ConnectionType::Fork => "fork",
+            ConnectionType::Conflict => "conflict",
+            ConnectionType::Construction => "construction",
+            ConnectionType::Other(name) => name,
+        }
+    }
+
+    /// The connection from an assembly to the item at `index` of one of its
+    /// slots, named `part:<slot>:<index>`.
+    pub fn part(slot: &str, index: usize) -> ConnectionType {
+        ConnectionType::Other(format!("{}{}:{}", PART_PREFIX, slot, index))
+    }
+
+    /// The slot and index of a part connection.
+    pub fn as_part(&self) -> Option<(&str, usize)> {
+        let ConnectionType::Other(name) = self else {
+            return None;
+        };
+        let (slot, index) = name.strip_prefix(PART_PREFIX)?.rsplit_once(':')?;
+        Some((slot, index.parse().ok()?))
+    }
+
+    /// Connection types that make up the lineage of an asset.
+    pub fn lineage() -> [ConnectionType; 2] {
+        [ConnectionType::Derivation, ConnectionType::Fork]
+    }
+}
+
+impl From<&str> for ConnectionType {
+    fn from(value: &str) -> Self {
+        match value {
+            "derivation" => ConnectionType::Derivation,
+            "fork" => ConnectionType::Fork,
+            "conflict" => ConnectionType::Conflict,
+            "construction" => ConnectionType::Construction,
+            other => ConnectionType::Other(other.to_string()),
+        }
+    }
+}
+
+impl From<String> for ConnectionType {
+    fn from(value: String) -> Self {
+        ConnectionType::from(value.as_str())
+    }
+}
+
+impl From<ConnectionType> for String {
+    fn from(value: ConnectionType) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+impl fmt::Display for ConnectionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A typed, directed edge between two ids.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Connection {
+    pub src: Ulid,
+    pub dest: Ulid,
+    #[serde(rename = "type")]
+    pub kind: ConnectionType,
+}
+
+impl Connection {
+    pub fn new(src: Ulid, dest: Ulid, kind: ConnectionType) -> Connection {
+        Connection { src, dest, kind }
+    }
+}
@@ -1,3 +1,4 @@
 pub mod collection;
 pub mod asset;
+pub mod connection;
 pub mod user;
\ No newline at end of file
@@ -0,0 +1,317 @@
+// Commanders sign what they do with Ed25519 keys.  The secret key of each
+// commander lives in a file of its own, `<commander id>.key`, in the user's
+// key directory and is readable by the user only.
+//
+// A rotation is prepared first: the next key and the rotation signed with the
+// current one wait in `pending/` while the holobank applies the rotation.
+// Preparing again picks the waiting rotation up, so one the bank applied but
+// the store did not finish can still be finished.  Finishing moves the old key
+// and its rotation to `retired/`, so the key that signed older operations is
+// never lost, and makes the next key the current one.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use ulid::Ulid;
+
+use super::signed::{Operation, Signed};
+
+/// Directory of the key files, under the user's `.constellations` directory.
+pub const KEY_DIRECTORY: &str = "keys";
+
+/// Directory of rotated keys, inside the key directory.
+pub const RETIRED_DIRECTORY: &str = "retired";
+
+/// Directory of rotations waiting to be applied, inside the key directory.
+pub const PENDING_DIRECTORY: &str = "pending";
+
+pub const KEY_LENGTH: usize = 32;
+
+/// The public half of a commander's key, written as hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PublicKey([u8; KEY_LENGTH]);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: &[u8]) -> Option<PublicKey> {
+        Some(PublicKey(bytes.try_into().ok()?))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+        &self.0
+    }
+
+    /// Whether `signature` was made over `message` with the secret half of
+    /// the key.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let (Ok(key), Ok(signature)) = (VerifyingKey::from_bytes(&self.0), Signature::from_slice(signature)) else {
+            return false;
+        };
+        key.verify(message, &signature).is_ok()
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&to_hex(&self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        from_hex(s).and_then(|bytes| PublicKey::from_bytes(&bytes)).ok_or_else(|| format!("not a public key: {}", s))
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A commander's secret key and its public half.
+#[derive(Clone)]
+pub struct Keypair(SigningKey);
+
+impl Keypair {
+    pub fn generate() -> Keypair {
+        Keypair(SigningKey::generate(&mut OsRng))
+    }
+
+    pub fn from_bytes(secret: &[u8; KEY_LENGTH]) -> Keypair {
+        Keypair(SigningKey::from_bytes(secret))
+    }
+
+    pub fn public(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.0.sign(message).to_vec()
+    }
+}
+
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Keypair({})", self.public())
+    }
+}
+
+/// The key files of a user.
+#[derive(Clone, Debug)]
+pub struct KeyStore {
+    directory: PathBuf,
+}
+
+impl KeyStore {
+    /// Keeps keys in `directory`, created when the first key is stored.
+    pub fn new(directory: &Path) -> KeyStore {
+        KeyStore { directory: directory.to_path_buf() }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn path(&self, commander: Ulid) -> PathBuf {
+        self.directory.join(format!("{}.key", commander))
+    }
+
+    /// The current key of a commander, if the store has one.
+    pub fn load(&self, commander: Ulid) -> io::Result<Option<Keypair>> {
+        KeyStore::read(&self.path(commander))
+    }
+
+    fn read(path: &Path) -> io::Result<Option<Keypair>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let secret = from_hex(contents.trim())
+            .and_then(|bytes| <[u8; KEY_LENGTH]>::try_from(bytes).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{}: not a key", path.display())))?;
+        Ok(Some(Keypair::from_bytes(&secret)))
+    }
+
+    /// Generates the first key of a commander.  Fails if the commander has
+    /// one already; replace it with [`KeyStore::rotate`].
+    pub fn create(&self, commander: Ulid) -> io::Result<Keypair> {
+        let keypair = Keypair::generate();
+        self.write(&self.path(commander), &keypair)?;
+        Ok(keypair)
+    }
+
+    /// Prepares replacing the key of a commander with a new one, or picks
+    /// up the rotation prepared before.  The rotation is signed with the
+    /// current key so peers that know it accept the new one.  The current
+    /// key stays in use until [`KeyStore::finish_rotation`].
+    pub fn rotate(&self, commander: Ulid) -> io::Result<(Keypair, Signed)> {
+        let current = self
+            .load(commander)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no key for commander {}", commander)))?;
+        if let Some(pending) = self.pending(commander, &current)? {
+            return Ok(pending);
+        }
+        // Whatever waits does not rotate the current key.
+        self.cancel_rotation(commander)?;
+        let next = Keypair::generate();
+        let rotation = Signed::sign(Operation::Rotate { next: next.public() }, commander, &current);
+
+        let (key, signed) = self.pending_paths(commander);
+        self.write(&key, &next)?;
+        let json = serde_json::to_vec(&rotation).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Err(e) = fs::write(&signed, json) {
+            let _ = fs::remove_file(&key);
+            return Err(e);
+        }
+        Ok((next, rotation))
+    }
+
+    /// Retires the current key of a commander, with the rotation that
+    /// replaced it, and makes the next key of the prepared rotation current.
+    pub fn finish_rotation(&self, commander: Ulid) -> io::Result<()> {
+        let current = self
+            .load(commander)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no key for commander {}", commander)))?;
+        if self.pending(commander, &current)?.is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no rotation prepared for commander {}", commander)));
+        }
+        let (key, signed) = self.pending_paths(commander);
+        let retired = self.directory.join(RETIRED_DIRECTORY);
+        fs::create_dir_all(&retired)?;
+        fs::rename(&signed, retired.join(format!("{}-{}.rotation", commander, current.public())))?;
+        fs::rename(self.path(commander), retired.join(format!("{}-{}.key", commander, current.public())))?;
+        fs::rename(&key, self.path(commander))
+    }
+
+    /// Drops the prepared rotation of a commander, e.g. one the holobank
+    /// refused.  The current key stays.
+    pub fn cancel_rotation(&self, commander: Ulid) -> io::Result<()> {
+        let (key, signed) = self.pending_paths(commander);
+        for path in [signed, key] {
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn pending_paths(&self, commander: Ulid) -> (PathBuf, PathBuf) {
+        let pending = self.directory.join(PENDING_DIRECTORY);
+        (pending.join(format!("{}.key", commander)), pending.join(format!("{}.rotation", commander)))
+    }
+
+    /// The prepared rotation of a commander, if it rotates `current`.
+    fn pending(&self, commander: Ulid, current: &Keypair) -> io::Result<Option<(Keypair, Signed)>> {
+        let (key, signed) = self.pending_paths(commander);
+        let (Some(next), Ok(json)) = (KeyStore::read(&key)?, fs::read(&signed)) else {
+            return Ok(None);
+        };
+        let rotation: Signed = serde_json::from_slice(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let prepared = rotation.commander == commander
+            && rotation.key == current.public()
+            && rotation.operation == Operation::Rotate { next: next.public() }
+            && rotation.is_valid();
+        Ok(prepared.then_some((next, rotation)))
+    }
+
+    fn write(&self, path: &Path, keypair: &Keypair) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        io::Write::write_all(&mut options.open(path)?, to_hex(&keypair.0.to_bytes()).as_bytes())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_and_rotates_keys() {
+        let directory = std::env::temp_dir().join(format!("keys-{}", Ulid::new()));
+        let store = KeyStore::new(&directory);
+        let commander = Ulid::new();
+        assert!(store.load(commander).unwrap().is_none());
+
+        let first = store.create(commander).unwrap();
+        assert!(store.create(commander).is_err());
+        assert_eq!(store.load(commander).unwrap().map(|k| k.public()), Some(first.public()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(store.path(commander)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A prepared rotation leaves the current key in place and is picked
+        // up again until it is finished.
+        let (next, rotation) = store.rotate(commander).unwrap();
+        assert_eq!(store.load(commander).unwrap().map(|k| k.public()), Some(first.public()));
+        let (again, resumed) = store.rotate(commander).unwrap();
+        assert_eq!((again.public(), &resumed), (next.public(), &rotation));
+        store.finish_rotation(commander).unwrap();
+        assert!(store.finish_rotation(commander).is_err());
+        let loaded = store.load(commander).unwrap().map(|k| k.public());
+        let retired = directory.join(RETIRED_DIRECTORY);
+        let kept = fs::read(retired.join(format!("{}-{}.rotation", commander, first.public()))).unwrap();
+        let retired = retired.join(format!("{}-{}.key", commander, first.public())).exists();
+
+        // A cancelled rotation changes nothing.
+        let (cancelled, _) = store.rotate(commander).unwrap();
+        store.cancel_rotation(commander).unwrap();
+        let after_cancel = store.load(commander).unwrap().map(|k| k.public());
+        let replaced = store.rotate(commander).unwrap().0.public();
+        let _ = fs::remove_dir_all(&directory);
+        assert_eq!(loaded, Some(next.public()));
+        assert!(retired);
+        assert_eq!(serde_json::from_slice::<Signed>(&kept).unwrap(), rotation);
+        assert_eq!(after_cancel, Some(next.public()));
+        assert_ne!(replaced, cancelled.public());
+        assert_eq!(rotation.key, first.public());
+        assert_eq!(rotation.operation, Operation::Rotate { next: next.public() });
+        assert!(rotation.is_valid());
+
+        let message = b"message";
+        assert!(next.public().verify(message, &next.sign(message)));
+        assert!(!first.public().verify(message, &next.sign(message)));
+        assert_eq!(next.public().to_string().parse::<PublicKey>(), Ok(next.public()));
+    }
+}
@@ -1,7 +1,22 @@
+pub mod keys;
+pub mod signed;
+
+use serde::{Deserialize, Serialize};
 use ulid::Ulid;
 
-#[derive(Clone)]
+use keys::{Keypair, PublicKey};
+
+/// Someone acting in the network.  Peers know a commander by the public
+/// half of the key they sign with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Commander {
-    id: Ulid,
-    name: String,
-}
\ No newline at end of file
+    pub id: Ulid,
+    pub name: String,
+    pub key: PublicKey,
+}
+
+impl Commander {
+    pub fn new(name: &str, keypair: &Keypair) -> Commander {
+        Commander { id: Ulid::new(), name: name.to_string(), key: keypair.public() }
+    }
+}
@@ -0,0 +1,128 @@
+// Edits, ledger entries and ownership changes are signed by the commander
+// who made them, so a bank receiving them from another can tell who did what
+// and that nothing changed on the way.  The signature covers the commander,
+// the time of signing and the operation, encoded with postcard after a
+// context tag, so nothing else signed with the same key, such as a request
+// credential, can pass for an operation or the other way round.  A valid
+// signature only proves the key signed it; whether the key belongs to the
+// commander is up to whoever keeps track of the commander's keys.
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use super::keys::{Keypair, PublicKey};
+
+/// Prefixes every signed operation, and nothing else signed with a
+/// commander's key.
+const OPERATION_CONTEXT: &[u8] = b"constellations signed operation v1\0";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation {
+    /// The content of an asset was set to bytes with this sha256 digest.
+    Edit {
+        asset: Ulid,
+        #[serde(with = "serde_bytes")]
+        digest: Vec<u8>,
+    },
+    /// An asset was held, or released when `held` is false, as of `at`.
+    Hold {
+        asset: Ulid,
+        spaceport: Ulid,
+        spacecraft: Option<Ulid>,
+        commander: Option<Ulid>,
+        at: i64,
+        held: bool,
+    },
+    /// An owner gained, or lost when `owned` is false, an entity as of `at`.
+    Own { entity: Ulid, owner: Ulid, at: i64, owned: bool },
+    /// The commander's key is replaced by `next`.  Signed with the key it
+    /// replaces.
+    Rotate { next: PublicKey },
+}
+
+impl Operation {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Operation::Edit { .. } => "edit",
+            Operation::Hold { .. } => "hold",
+            Operation::Own { .. } => "own",
+            Operation::Rotate { .. } => "rotate",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signed {
+    pub operation: Operation,
+    pub commander: Ulid,
+    /// When it was signed, in microseconds since the epoch.
+    pub time: i64,
+    pub key: PublicKey,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl Signed {
+    /// Signs an operation of `commander` with their key, now.
+    pub fn sign(operation: Operation, commander: Ulid, keypair: &Keypair) -> Signed {
+        let time = chrono::Utc::now().timestamp_micros();
+        let signature = keypair.sign(&message(&operation, commander, time));
+        Signed { operation, commander, time, key: keypair.public(), signature }
+    }
+
+    /// Whether the signature was made over the operation with `key`.
+    pub fn is_valid(&self) -> bool {
+        self.key.verify(&message(&self.operation, self.commander, self.time), &self.signature)
+    }
+
+    /// What the operation is about: the asset or entity, or the commander
+    /// for rotations.
+    pub fn subject(&self) -> Ulid {
+        match &self.operation {
+            Operation::Edit { asset, .. } | Operation::Hold { asset, .. } => *asset,
+            Operation::Own { entity, .. } => *entity,
+            Operation::Rotate { .. } => self.commander,
+        }
+    }
+}
+
+fn message(operation: &Operation, commander: Ulid, time: i64) -> Vec<u8> {
+    postcard::to_extend(&(commander, time, operation), OPERATION_CONTEXT.to_vec()).expect("encoding in memory cannot fail")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_tampering() {
+        let keypair = Keypair::generate();
+        let commander = Ulid::new();
+        let asset = Ulid::new();
+        let signed = Signed::sign(Operation::Edit { asset, digest: vec![1; 32] }, commander, &keypair);
+        assert!(signed.is_valid());
+        assert_eq!(signed.subject(), asset);
+
+        let bytes = postcard::to_allocvec(&signed).unwrap();
+        assert_eq!(postcard::from_bytes::<Signed>(&bytes).unwrap(), signed);
+
+        let mut changed = signed.clone();
+        changed.operation = Operation::Edit { asset, digest: vec![2; 32] };
+        assert!(!changed.is_valid());
+        let mut impostor = signed.clone();
+        impostor.commander = Ulid::new();
+        assert!(!impostor.is_valid());
+        let mut rekeyed = signed.clone();
+        rekeyed.key = Keypair::generate().public();
+        assert!(!rekeyed.is_valid());
+    }
+
+    #[test]
+    fn signs_under_its_own_context() {
+        let keypair = Keypair::generate();
+        let mut signed = Signed::sign(Operation::Rotate { next: keypair.public() }, Ulid::new(), &keypair);
+        let untagged = postcard::to_allocvec(&(signed.commander, signed.time, &signed.operation)).unwrap();
+        signed.signature = keypair.sign(&untagged);
+        assert!(!signed.is_valid());
+    }
+}